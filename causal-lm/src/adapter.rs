@@ -0,0 +1,35 @@
+use std::{error, fmt};
+
+/// 常驻适配器的状态。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct AdapterInfo {
+    /// 适配器的名字。
+    pub name: String,
+    /// 适配器占用的字节数。
+    pub bytes: usize,
+}
+
+/// 加载或卸载适配器的错误。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum AdapterError {
+    /// 模型不支持适配器。
+    Unsupported,
+    /// 同名的适配器已经加载。
+    Duplicate,
+    /// 没有这个名字的适配器。
+    NotFound,
+    /// 无法加载适配器，附带原因。
+    Load(String),
+}
+
+impl error::Error for AdapterError {}
+impl fmt::Display for AdapterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "adapters are not supported"),
+            Self::Duplicate => write!(f, "adapter already loaded"),
+            Self::NotFound => write!(f, "adapter not found"),
+            Self::Load(e) => write!(f, "failed to load adapter: {e}"),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{CausalLM, DecodingMeta, QueryContext};
+use common::{bf16, f16, upos, utok};
+use digit_layout::{
+    types::{BF16, F16, F32},
+    DigitLayout,
+};
+use std::{env::var_os, fs, iter::zip, path::Path};
+use tensor::Tensor;
+
+/// 对比模型计算的 logits 与保存的参考值。
+///
+/// 先一次计算 `prompt` 所有位置的 logits，再分两次计算并在后一次使用缓存，两者应当一致；
+/// 设置了环境变量 `UPDATE_GOLDEN` 时记录参考值，否则按 logits 的数据类型允许一定误差地比较，参考值文件不存在时失败。
+/// `to_host` 把 logits 复制为主机上的连续张量。
+pub fn test_golden<M>(
+    model: &M,
+    prompt: &[utok],
+    golden: impl AsRef<Path>,
+    to_host: impl Fn(&M, Tensor<M::Storage>) -> Tensor<Vec<u8>>,
+) where
+    M: CausalLM,
+{
+    let golden = golden.as_ref();
+    let half = prompt.len() / 2;
+    assert!(half > 0);
+
+    let mut cache = model.new_cache();
+    let (dt, voc, logits) = run(model, &mut cache, prompt, 0, &to_host);
+    let tol = tolerance(dt);
+
+    let mut cache = model.new_cache();
+    run(model, &mut cache, &prompt[..half], 0, &to_host);
+    let (_, _, tail) = run(model, &mut cache, &prompt[half..], half as _, &to_host);
+    assert_close(&tail, &logits[half * voc..], tol, "cached");
+
+    if var_os("UPDATE_GOLDEN").is_some() {
+        let mut text = format!("# {} {voc}\n", prompt.len());
+        for row in logits.chunks(voc) {
+            let row = row.iter().map(|x| format!("{x:?}")).collect::<Vec<_>>();
+            text.push_str(&row.join(" "));
+            text.push('\n');
+        }
+        if let Some(dir) = golden.parent() {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(golden, text).unwrap();
+        println!("golden values recorded to {}", golden.display());
+        return;
+    }
+
+    let text = fs::read_to_string(golden).unwrap_or_else(|e| {
+        panic!(
+            "failed to read golden values from {}: {e}, set UPDATE_GOLDEN to record them",
+            golden.display(),
+        )
+    });
+    let (header, values) = text.split_once('\n').unwrap();
+    assert_eq!(
+        header,
+        format!("# {} {voc}", prompt.len()),
+        "golden values of a different shape, set UPDATE_GOLDEN to record again",
+    );
+    let expected = values
+        .split_whitespace()
+        .map(|x| x.parse::<f32>().unwrap())
+        .collect::<Vec<_>>();
+    assert_close(&logits, &expected, tol, "golden");
+}
+
+/// 计算 `tokens` 所有位置的 logits，返回数据类型、词表大小和转换为 f32 的 logits。
+fn run<M: CausalLM>(
+    model: &M,
+    cache: &mut Tensor<M::Storage>,
+    tokens: &[utok],
+    pos: upos,
+    to_host: &impl Fn(&M, Tensor<M::Storage>) -> Tensor<Vec<u8>>,
+) -> (DigitLayout, usize, Vec<f32>) {
+    let len = tokens.len();
+    let token_embedded = model.token_embed(tokens.iter().copied());
+    let queries = [QueryContext {
+        cache: Some(cache),
+        range: pos..pos + len as upos,
+        adapter: None,
+        steering: None,
+    }];
+    let hidden_state = model.forward(queries, token_embedded);
+    let decoding = [DecodingMeta {
+        num_query: len,
+        num_decode: len,
+    }];
+    let logits = to_host(model, model.decode(decoding, hidden_state));
+
+    let &[nt, voc] = logits.shape() else {
+        panic!("logits should be 2-dimensional")
+    };
+    assert_eq!(nt as usize, len);
+    let dt = logits.data_layout();
+    let bytes = logits.as_slice();
+    let values = match dt {
+        F32 => bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect(),
+        F16 => bytes
+            .chunks_exact(2)
+            .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        BF16 => bytes
+            .chunks_exact(2)
+            .map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        _ => panic!("unsupported logits type {dt:?}"),
+    };
+    (dt, voc as _, values)
+}
+
+/// 不同数据类型的 logits 允许的误差，按 `|x - y| <= tol * (1 + |y|)` 比较。
+fn tolerance(dt: DigitLayout) -> f32 {
+    match dt {
+        F32 => 1e-4,
+        F16 => 1e-2,
+        BF16 => 5e-2,
+        _ => unreachable!(),
+    }
+}
+
+fn assert_close(actual: &[f32], expected: &[f32], tol: f32, what: &str) {
+    assert_eq!(actual.len(), expected.len(), "{what}: lengths differ");
+    for (i, (&x, &y)) in zip(actual, expected).enumerate() {
+        assert!(
+            (x - y).abs() <= tol * (1. + y.abs()),
+            "{what}: logit {i} is {x}, expected {y} within {tol}",
+        );
+    }
+}
+
+#[test]
+fn test_assert_close() {
+    use std::panic::catch_unwind;
+
+    assert_close(&[1., -2.], &[1.005, -2.02], tolerance(F16), "f16");
+    assert!(catch_unwind(|| assert_close(&[1.], &[1.005], tolerance(F32), "f32")).is_err());
+    assert!(catch_unwind(|| assert_close(&[f32::NAN], &[0.], tolerance(BF16), "nan")).is_err());
+}
@@ -1,7 +1,10 @@
 #![doc = include_str!("../README.md")]
 #![deny(warnings, missing_docs)]
 
+mod adapter;
 mod decoding;
+mod golden;
+mod mock;
 mod query_context;
 
 use common::{upos, utok};
@@ -9,9 +12,13 @@ use digit_layout::types::U32;
 use std::path::Path;
 use tensor::{udim, Tensor};
 
+pub use adapter::{AdapterError, AdapterInfo};
 pub use decoding::DecodingMeta;
+pub use golden::test_golden;
+pub use mock::MockModel;
 pub use query_context::QueryContext;
-pub use sample::SampleArgs;
+pub use sample::{LogitProcessor, LogitProcessors, SampleArgs, SampleStage};
+pub use tensor::ShapeError;
 
 /// 从文件系统加载的模型。
 pub trait Model: Sized {
@@ -43,14 +50,18 @@ pub trait CausalLM: Model {
     type Storage;
     /// 最大序列长度。
     fn max_seq_len(&self) -> upos;
-    /// 模型定义的句子结束符。
-    fn eos_token(&self) -> utok;
+    /// 模型定义的句子结束符集合，第一个是补全句子时使用的结束符。
+    fn eos_tokens(&self) -> &[utok];
     /// 创建一个未填充的缓存张量（`num_layers x 2 x num_kv_head x max_seq_len x head_dim`）。
     fn new_cache(&self) -> Tensor<Self::Storage>;
     /// 复制一个有效长度为 `pos` 的缓存。
     ///
-    /// 有效部分：`.., .., .., ..pos, ..`
-    fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage>;
+    /// 有效部分：`.., .., .., ..pos, ..`，`pos` 超出缓存的最大长度时返回错误。
+    fn duplicate_cache(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        pos: upos,
+    ) -> Result<Tensor<Self::Storage>, ShapeError>;
     /// 对所有词执行词嵌入（`num_tokens x hidden_size`）。
     ///
     /// 词嵌入是上下文无关的，对于每个词独立进行，因此多个请求的查询序列可以 flatten 同时计算。
@@ -79,6 +90,60 @@ pub trait CausalLM: Model {
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok>;
+    /// 解码并采样，即依次调用 [`decode`](CausalLM::decode) 和 [`sample`](CausalLM::sample)。
+    ///
+    /// 实现可以融合这两步，避免为每个解码的序列生成完整的 logits。
+    fn decode_sample(
+        &self,
+        decoding: impl IntoIterator<Item = DecodingMeta>,
+        hidden_state: Tensor<Self::Storage>,
+        args: impl IntoIterator<Item = SampleMeta>,
+    ) -> Vec<utok> {
+        let logits = self.decode(decoding, hidden_state);
+        self.sample(args, logits)
+    }
+    /// 对 `targets` 中的每一项 `(row, token)`，计算 logits 第 `row` 行中 `token` 的对数概率。
+    ///
+    /// 用于为提示词打分；默认不支持，返回 `None`。
+    fn logprobs(
+        &self,
+        _logits: &Tensor<Self::Storage>,
+        _targets: &[(usize, utok)],
+    ) -> Option<Vec<f32>> {
+        None
+    }
+    /// 查找名为 `name` 的常驻适配器，返回的序号用于 [`QueryContext::adapter`]。
+    ///
+    /// 适配器在矩阵乘时为各查询叠加低秩增量，不修改模型的权重；默认不支持适配器。
+    fn adapter(&self, _name: &str) -> Option<usize> {
+        None
+    }
+    /// 查找名为 `name` 的控制向量，返回的序号用于 [`QueryContext::steering`]。
+    ///
+    /// 控制向量在每层之后按强度叠加到残差上，引导生成的风格或主题；默认不支持控制向量。
+    fn steering(&self, _name: &str) -> Option<usize> {
+        None
+    }
+    /// 查找名为 `name` 的软提示词，返回代表它的虚拟词，它们接在会话的开头。
+    ///
+    /// 虚拟词的序号不小于词表的大小，只用于 [`token_embed`](CausalLM::token_embed)；默认不支持软提示词。
+    fn soft_prompt(&self, _name: &str) -> Option<Vec<utok>> {
+        None
+    }
+    /// 所有常驻的适配器。
+    fn adapters(&self) -> Vec<AdapterInfo> {
+        Vec::new()
+    }
+    /// 从 `dir` 加载名为 `name` 的适配器，加载后即可被查询选择。
+    fn load_adapter(&self, _name: &str, _dir: &Path) -> Result<(), AdapterError> {
+        Err(AdapterError::Unsupported)
+    }
+    /// 卸载名为 `name` 的适配器，已经选择它的查询之后按基础模型推理。
+    ///
+    /// 序号不会被之后加载的适配器复用。
+    fn unload_adapter(&self, _name: &str) -> Result<(), AdapterError> {
+        Err(AdapterError::Unsupported)
+    }
 }
 
 /// 解码的要求。
@@ -102,6 +167,13 @@ pub fn pos<'a, S: 'a>(
     Tensor::new(U32, &[ans.len() as _], ans)
 }
 
+/// 以 log-softmax 计算 `logits` 中 `token` 的对数概率。
+pub fn log_softmax(logits: &[f32], token: utok) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum = logits.iter().map(|&x| (x - max).exp()).sum::<f32>();
+    logits[token as usize] - max - sum.ln()
+}
+
 /// 测试模型实现。
 pub fn test_impl<M>(meta: M::Meta, prompt: &[utok])
 where
@@ -125,12 +197,14 @@ where
     let mut prompt = prompt.to_vec();
     let mut pos = 0;
 
-    while prompt != [model.eos_token()] {
+    while !matches!(&*prompt, [t] if model.eos_tokens().contains(t)) {
         let token_embedded = CausalLM::token_embed(&model, prompt.iter().copied());
 
         let queries = [QueryContext {
             cache: Some(&mut cache),
             range: pos..pos + prompt.len() as upos,
+            adapter: None,
+            steering: None,
         }];
         let hidden_state = CausalLM::forward(&model, queries, token_embedded);
 
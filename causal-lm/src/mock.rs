@@ -0,0 +1,326 @@
+use crate::{log_softmax, CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use common::{upos, utok};
+use digit_layout::types::{F32, U32};
+use std::{
+    convert::Infallible,
+    fs,
+    iter::repeat_n,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+use tensor::{slice, udim, ShapeError, Tensor};
+
+type Next = Box<dyn Fn(&[utok]) -> Option<utok> + Send + Sync>;
+
+/// 不需要权重的确定性模型，用于测试推理服务。
+///
+/// 模型根据上下文中的所有词决定下一个词，得到 `None` 时生成第一个结束符；
+/// 缓存保存上下文中的词，词嵌入、隐藏状态都是词本身，logits 是下一个词的独热编码。
+pub struct MockModel {
+    next: Next,
+    eos: Vec<utok>,
+    voc: udim,
+    max_seq_len: upos,
+    delay: Duration,
+}
+
+impl MockModel {
+    /// 由 `next` 根据上下文决定下一个词。
+    pub fn new(next: impl Fn(&[utok]) -> Option<utok> + Send + Sync + 'static) -> Self {
+        Self {
+            next: Box::new(next),
+            eos: vec![2],
+            voc: 512,
+            max_seq_len: 256,
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// 不断重复上下文的最后一个词。
+    pub fn echo() -> Self {
+        Self::new(|context| context.last().copied())
+    }
+
+    /// 按顺序生成 `reply` 中的词，然后生成结束符。
+    ///
+    /// 根据上下文结尾已经生成的部分决定下一个词，因此上下文以 `reply` 的开头结尾时会跳过这些词。
+    pub fn script(reply: impl IntoIterator<Item = utok>) -> Self {
+        let reply = reply.into_iter().collect::<Vec<_>>();
+        Self::new(move |context| {
+            (0..=reply.len())
+                .rev()
+                .find(|&k| context.ends_with(&reply[..k]))
+                .and_then(|k| reply.get(k).copied())
+        })
+    }
+
+    /// 设置结束符集合。
+    pub fn with_eos(mut self, eos: impl IntoIterator<Item = utok>) -> Self {
+        self.eos = eos.into_iter().collect();
+        assert!(!self.eos.is_empty());
+        self
+    }
+
+    /// 设置词表大小，即 logits 的宽度。
+    pub fn with_vocab_size(mut self, voc: udim) -> Self {
+        self.voc = voc;
+        self
+    }
+
+    /// 设置最大序列长度。
+    pub fn with_max_seq_len(mut self, max_seq_len: upos) -> Self {
+        self.max_seq_len = max_seq_len;
+        self
+    }
+
+    /// 每次前向传播等待 `delay`，模拟耗时的计算。
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// 在临时目录中创建 `{name}-{进程号}` 目录，写入只有 3 个特殊词和 256 个单字节词的 vocabs.txt，
+    /// 供测试加载服务，字节 `b` 编码为词 `b + 3`。无法写入时 panic。
+    pub fn model_dir(name: &str) -> PathBuf {
+        let model_dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        fs::create_dir_all(&model_dir).unwrap();
+        let vocabs = ["<unk>".into(), "<s>".into(), "</s>".into()]
+            .into_iter()
+            .chain((0..=255u8).map(|b| format!("<0x{b:02X}>")))
+            .map(|piece| format!("\"{piece}\"\n"))
+            .collect::<String>();
+        fs::write(model_dir.join("vocabs.txt"), vocabs).unwrap();
+        model_dir
+    }
+}
+
+impl Model for MockModel {
+    type Meta = Self;
+    type Error = Infallible;
+
+    #[inline]
+    fn load(_model_dir: impl AsRef<Path>, meta: Self::Meta) -> Result<Self, Self::Error> {
+        Ok(meta)
+    }
+}
+
+impl CausalLM for MockModel {
+    type Storage = Vec<u8>;
+
+    #[inline]
+    fn max_seq_len(&self) -> upos {
+        self.max_seq_len
+    }
+
+    #[inline]
+    fn eos_tokens(&self) -> &[utok] {
+        &self.eos
+    }
+
+    fn new_cache(&self) -> Tensor<Self::Storage> {
+        Tensor::alloc(U32, &[1, 2, 1, self.max_seq_len, 1], |len| vec![0; len])
+    }
+
+    fn duplicate_cache(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        pos: upos,
+    ) -> Result<Tensor<Self::Storage>, ShapeError> {
+        cache.as_ref().try_slice(&[
+            slice![=>],
+            slice![=>],
+            slice![=>],
+            slice![=>pos],
+            slice![=>],
+        ])?;
+        Ok(cache.clone())
+    }
+
+    fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
+        tokens_tensor(queries.into_iter().collect())
+    }
+
+    fn forward<'a>(
+        &self,
+        queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
+        token_embedded: Tensor<Self::Storage>,
+    ) -> Tensor<Self::Storage>
+    where
+        Self: 'a,
+    {
+        thread::sleep(self.delay);
+
+        let embedded = tokens(token_embedded.physical());
+        let mut rest = &embedded[..];
+        let mut next = Vec::with_capacity(embedded.len());
+        for query in queries {
+            let pos = query.pos() as usize;
+            let (seq, tail) = rest.split_at(query.seq_len() as usize);
+            rest = tail;
+            // 没有缓存时只能看到查询本身
+            let mut context = match query.cache {
+                Some(cache) => {
+                    // 缓存第 0 层的 k 保存上下文中的词
+                    let cache = cache.physical_mut();
+                    for (i, t) in seq.iter().enumerate() {
+                        cache[(pos + i) * 4..][..4].copy_from_slice(&t.to_ne_bytes());
+                    }
+                    tokens(&cache[..pos * 4])
+                }
+                None => Vec::new(),
+            };
+            for &t in seq {
+                context.push(t);
+                next.push((self.next)(&context).unwrap_or(self.eos[0]));
+            }
+        }
+        tokens_tensor(next)
+    }
+
+    fn decode(
+        &self,
+        decoding: impl IntoIterator<Item = DecodingMeta>,
+        hidden_state: Tensor<Self::Storage>,
+    ) -> Tensor<Self::Storage> {
+        let next = tokens(hidden_state.physical());
+        let mut offset = 0;
+        let mut logits = Vec::new();
+        for DecodingMeta {
+            num_query,
+            num_decode,
+        } in decoding
+        {
+            offset += num_query;
+            for &t in &next[offset - num_decode..offset] {
+                assert!(t < self.voc, "token {t} out of vocabulary");
+                let mut row = vec![0f32; self.voc as usize];
+                row[t as usize] = 1.;
+                logits.extend(row.iter().flat_map(|x| x.to_ne_bytes()));
+            }
+        }
+        let n = logits.len() / (self.voc as usize * 4);
+        Tensor::new(F32, &[n as _, self.voc], logits)
+    }
+
+    fn sample(
+        &self,
+        args: impl IntoIterator<Item = SampleMeta>,
+        logits: Tensor<Self::Storage>,
+    ) -> Vec<utok> {
+        let logits = self.rows(&logits);
+        let mut rows = logits.chunks_exact(self.voc as usize);
+        args.into_iter()
+            .flat_map(|meta| repeat_n(meta.args, meta.num_decode))
+            .map(|args| args.random(rows.next().unwrap()))
+            .collect()
+    }
+
+    fn logprobs(
+        &self,
+        logits: &Tensor<Self::Storage>,
+        targets: &[(usize, utok)],
+    ) -> Option<Vec<f32>> {
+        let logits = self.rows(logits);
+        let voc = self.voc as usize;
+        Some(
+            targets
+                .iter()
+                .map(|&(row, token)| log_softmax(&logits[row * voc..][..voc], token))
+                .collect(),
+        )
+    }
+}
+
+impl MockModel {
+    fn rows(&self, logits: &Tensor<Vec<u8>>) -> Vec<f32> {
+        logits
+            .physical()
+            .chunks_exact(4)
+            .map(|b| f32::from_ne_bytes(b.try_into().unwrap()))
+            .collect()
+    }
+}
+
+fn tokens(bytes: &[u8]) -> Vec<utok> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| utok::from_ne_bytes(b.try_into().unwrap()))
+        .collect()
+}
+
+fn tokens_tensor(tokens: Vec<utok>) -> Tensor<Vec<u8>> {
+    let shape = [tokens.len() as udim, 1];
+    let bytes = tokens.into_iter().flat_map(utok::to_ne_bytes).collect();
+    Tensor::new(U32, &shape, bytes)
+}
+
+#[test]
+fn test_mock() {
+    use crate::SampleArgs;
+
+    fn step(model: &MockModel, prompt: &[utok], pos: upos, cache: &mut Tensor<Vec<u8>>) -> utok {
+        let embedded = model.token_embed(prompt.iter().copied());
+        let queries = [QueryContext {
+            cache: Some(cache),
+            range: pos..pos + prompt.len() as upos,
+            adapter: None,
+            steering: None,
+        }];
+        let hidden_state = model.forward(queries, embedded);
+        let decoding = [DecodingMeta {
+            num_query: prompt.len(),
+            num_decode: 1,
+        }];
+        let logits = model.decode(decoding, hidden_state);
+        let args = [SampleMeta {
+            num_decode: 1,
+            args: SampleArgs::default(),
+        }];
+        model.sample(args, logits)[0]
+    }
+
+    let model = MockModel::script([5, 6]).with_max_seq_len(8);
+    let mut cache = model.new_cache();
+    assert_eq!(step(&model, &[1, 9, 9], 0, &mut cache), 5);
+    assert_eq!(step(&model, &[5], 3, &mut cache), 6);
+    assert_eq!(step(&model, &[6], 4, &mut cache), 2);
+    // 复制的缓存保留上下文
+    let mut fork = model.duplicate_cache(&cache, 5).unwrap();
+    assert_eq!(step(&model, &[5], 5, &mut fork), 6);
+    assert!(model.duplicate_cache(&cache, 9).is_err());
+
+    // 屏蔽结束符时选择其他的词
+    let suppressed = SampleArgs {
+        suppressed: vec![2],
+        ..Default::default()
+    };
+    let logits = model.decode(
+        [DecodingMeta {
+            num_query: 1,
+            num_decode: 1,
+        }],
+        tokens_tensor(vec![2]),
+    );
+    let args = [SampleMeta {
+        num_decode: 1,
+        args: suppressed,
+    }];
+    assert_ne!(model.sample(args, logits)[0], 2);
+
+    // 下一个词的对数概率最大
+    let logits = model.decode(
+        [DecodingMeta {
+            num_query: 2,
+            num_decode: 2,
+        }],
+        tokens_tensor(vec![5, 6]),
+    );
+    let logprobs = model.logprobs(&logits, &[(0, 5), (1, 5)]).unwrap();
+    assert!(logprobs[0] > logprobs[1]);
+    assert!((log_softmax(&[0., 0.], 1) + 2f32.ln()).abs() < 1e-6);
+
+    let echo = MockModel::echo();
+    let mut cache = echo.new_cache();
+    assert_eq!(step(&echo, &[3, 4], 0, &mut cache), 4);
+}
@@ -8,6 +8,10 @@ pub struct QueryContext<'a, Storage> {
     pub cache: Option<&'a mut Tensor<Storage>>,
     /// 查询在上下文中的位置。
     pub range: Range<upos>,
+    /// 查询使用的适配器，参见 [`CausalLM::adapter`](crate::CausalLM::adapter)。
+    pub adapter: Option<usize>,
+    /// 查询使用的控制向量和强度，参见 [`CausalLM::steering`](crate::CausalLM::steering)。
+    pub steering: Option<(usize, f32)>,
 }
 
 impl<'a, Storage> QueryContext<'a, Storage> {
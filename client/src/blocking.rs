@@ -0,0 +1,295 @@
+//! 同步的客户端，在内部的单线程运行时上执行异步客户端的请求。
+
+use crate::{
+    models::{
+        AdapterReport, CacheReport, CompleteRequest, DocumentReport, FinishReason, InferRequest,
+        ScoreReport, ScoreRequest, ThroughputReport,
+    },
+    Error,
+};
+use std::io;
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+
+/// web 服务的同步客户端。
+pub struct Client {
+    inner: crate::Client,
+    rt: Runtime,
+}
+
+impl Client {
+    /// 连接 `addr`（形如 `127.0.0.1:8000`）上的服务。
+    pub fn new(addr: impl Into<String>) -> io::Result<Self> {
+        Ok(Self {
+            inner: crate::Client::new(addr),
+            rt: tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()?,
+        })
+    }
+
+    /// 发送推理请求，返回生成的文本的迭代器。
+    pub fn infer(&self, req: &InferRequest) -> Result<InferStream<'_>, Error> {
+        let inner = self.rt.block_on(self.inner.infer(req))?;
+        Ok(InferStream {
+            inner,
+            rt: &self.rt,
+        })
+    }
+
+    /// 发送续写请求，返回生成的文本的迭代器。
+    pub fn complete(&self, req: &CompleteRequest) -> Result<InferStream<'_>, Error> {
+        let inner = self.rt.block_on(self.inner.complete(req))?;
+        Ok(InferStream {
+            inner,
+            rt: &self.rt,
+        })
+    }
+
+    /// 计算每个候选接在提示词之后的对数似然。
+    #[inline]
+    pub fn score(&self, req: &ScoreRequest) -> Result<ScoreReport, Error> {
+        self.rt.block_on(self.inner.score(req))
+    }
+
+    /// 复制会话。
+    #[inline]
+    pub fn fork(
+        &self,
+        session_id: impl Into<String>,
+        new_session_id: impl Into<String>,
+    ) -> Result<(), Error> {
+        self.rt
+            .block_on(self.inner.fork(session_id, new_session_id))
+    }
+
+    /// 删除会话。
+    #[inline]
+    pub fn drop_(&self, session_id: impl Into<String>) -> Result<(), Error> {
+        self.rt.block_on(self.inner.drop_(session_id))
+    }
+
+    /// 查询所有会话的缓存占用。
+    #[inline]
+    pub fn cache(&self) -> Result<CacheReport, Error> {
+        self.rt.block_on(self.inner.cache())
+    }
+
+    /// 查询推理吞吐量。
+    #[inline]
+    pub fn throughput(&self) -> Result<ThroughputReport, Error> {
+        self.rt.block_on(self.inner.throughput())
+    }
+
+    /// 查询常驻的适配器。
+    #[inline]
+    pub fn adapters(&self) -> Result<AdapterReport, Error> {
+        self.rt.block_on(self.inner.adapters())
+    }
+
+    /// 从服务所在机器上的 `path` 目录加载名为 `name` 的适配器。
+    #[inline]
+    pub fn load_adapter(
+        &self,
+        name: impl Into<String>,
+        path: impl Into<String>,
+    ) -> Result<(), Error> {
+        self.rt.block_on(self.inner.load_adapter(name, path))
+    }
+
+    /// 卸载名为 `name` 的适配器。
+    #[inline]
+    pub fn unload_adapter(&self, name: impl Into<String>) -> Result<(), Error> {
+        self.rt.block_on(self.inner.unload_adapter(name))
+    }
+
+    /// 查询已注册的文档。
+    #[inline]
+    pub fn documents(&self) -> Result<DocumentReport, Error> {
+        self.rt.block_on(self.inner.documents())
+    }
+
+    /// 注册名为 `document_id` 的文档，服务计算完它的缓存后返回。
+    #[inline]
+    pub fn register_document(
+        &self,
+        document_id: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Result<(), Error> {
+        self.rt
+            .block_on(self.inner.register_document(document_id, content))
+    }
+
+    /// 删除名为 `document_id` 的文档。
+    #[inline]
+    pub fn drop_document(&self, document_id: impl Into<String>) -> Result<(), Error> {
+        self.rt.block_on(self.inner.drop_document(document_id))
+    }
+
+    /// 使用 `session_id` 指定的会话对话。
+    #[inline]
+    pub fn session(&self, session_id: impl Into<String>) -> Session<'_> {
+        Session {
+            inner: self.inner.session(session_id),
+            rt: &self.rt,
+        }
+    }
+}
+
+/// 推理生成的文本的迭代器。
+pub struct InferStream<'a> {
+    inner: crate::InferStream,
+    rt: &'a Runtime,
+}
+
+impl InferStream<'_> {
+    /// 流中第一个字节在生成的文本中的位置。
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.inner.offset()
+    }
+
+    /// 服务原样返回的 `user`。
+    #[inline]
+    pub fn user(&self) -> Option<&str> {
+        self.inner.user()
+    }
+
+    /// 服务原样返回的 `metadata`。
+    #[inline]
+    pub fn metadata(&self) -> Option<&str> {
+        self.inner.metadata()
+    }
+
+    /// 提示词中除第一个词以外每个词的对数概率，请求中没有设置 `prompt_logprobs` 或模型不支持时为 `None`。
+    #[inline]
+    pub fn prompt_logprobs(&self) -> Option<Vec<f32>> {
+        self.inner.prompt_logprobs()
+    }
+
+    /// 生成结束的原因，迭代结束之前或服务没有发送 trailer 时为 `None`。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.inner.finish_reason()
+    }
+
+    /// 生成的词，请求中没有设置 `return_ids` 或迭代结束之前为 `None`。
+    #[inline]
+    pub fn output_ids(&self) -> Option<&[u32]> {
+        self.inner.output_ids()
+    }
+}
+
+impl Iterator for InferStream<'_> {
+    type Item = Result<String, Error>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rt.block_on(self.inner.next())
+    }
+}
+
+/// 具名会话，参见 [`crate::Session`]。
+pub struct Session<'a> {
+    inner: crate::Session<'a>,
+    rt: &'a Runtime,
+}
+
+impl<'a> std::ops::Deref for Session<'a> {
+    type Target = crate::Session<'a>;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl std::ops::DerefMut for Session<'_> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<'a> Session<'a> {
+    /// 发送一句话，生成的每段文本传给 `on_text`，返回完整的回答和结束的原因。
+    #[inline]
+    pub fn chat(
+        &mut self,
+        content: impl Into<String>,
+        on_text: impl FnMut(&str),
+    ) -> Result<(String, Option<FinishReason>), Error> {
+        self.rt.block_on(self.inner.chat(content, on_text))
+    }
+
+    /// 复制会话，新会话继承本地记录的对话和生成参数。
+    #[inline]
+    pub fn fork(&self, new_id: impl Into<String>) -> Result<Session<'a>, Error> {
+        let inner = self.rt.block_on(self.inner.fork(new_id))?;
+        Ok(Session { inner, rt: self.rt })
+    }
+
+    /// 删除服务端的会话。
+    #[inline]
+    pub fn drop_(self) -> Result<(), Error> {
+        self.rt.block_on(self.inner.drop_())
+    }
+}
+
+#[test]
+fn test_blocking() {
+    use crate::models::{FinishReason, Message};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let responses: [&[u8]; 2] = [
+            // “你好”的第二个字被拆到两个块中
+            b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ntrailer: x-finish-reason, x-output-ids\r\n\
+              x-stream-offset: 3\r\nx-prompt-logprobs: -0.5,-2\r\ntransfer-encoding: chunked\r\n\r\n\
+              4\r\n\xe4\xbd\xa0\xe5\r\n2\r\n\xa5\xbd\r\n0\r\nx-finish-reason: timeout\r\nx-output-ids: 7,8\r\n\r\n",
+            b"HTTP/1.1 406 Not Acceptable\r\ncontent-type: application/json\r\ncontent-length: 51\r\n\r\n\
+              {\"status\":406,\"code\":0,\"message\":\"Session is busy\"}",
+        ];
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    len = v.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            reader.read_exact(&mut vec![0; len]).unwrap();
+            stream.write_all(response).unwrap();
+        }
+    });
+
+    let client = Client::new(addr.to_string()).unwrap();
+    let mut stream = client
+        .infer(&InferRequest::new([Message::user("Hi")]))
+        .unwrap();
+    assert_eq!(stream.offset(), 3);
+    assert_eq!(stream.prompt_logprobs(), Some(vec![-0.5, -2.]));
+    let text = stream.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(text, ["你", "好"]);
+    assert_eq!(stream.finish_reason(), Some(FinishReason::Timeout));
+    assert_eq!(stream.output_ids(), Some(&[7, 8][..]));
+
+    match client.session("a").chat("Hi", |_| {}) {
+        Err(Error::Api(body)) => {
+            assert_eq!((body.status, &*body.message), (406, "Session is busy"))
+        }
+        _ => panic!(),
+    }
+    server.join().unwrap();
+}
@@ -0,0 +1,265 @@
+#![doc = include_str!("../README.md")]
+#![deny(warnings)]
+
+pub mod blocking;
+mod session;
+mod stream;
+
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    client::conn::http1,
+    header::{CONTENT_TYPE, HOST, TE},
+    Method, Request, Response,
+};
+use hyper_util::rt::TokioIo;
+use models::{
+    AdapterReport, CacheReport, CompleteRequest, DocumentReport, Drop, DropDocument, ErrorBody,
+    Fork, InferRequest, LoadAdapter, RegisterDocument, ScoreReport, ScoreRequest, ThroughputReport,
+    UnloadAdapter,
+};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use tokio::net::TcpStream;
+
+pub use infinilm_schemas::v1 as models;
+pub use session::Session;
+pub use stream::InferStream;
+
+/// 调用 web 服务时的错误。
+#[derive(Debug)]
+pub enum Error {
+    /// 无法连接服务。
+    Io(std::io::Error),
+    /// HTTP 协议错误。
+    Http(hyper::Error),
+    /// 无法解析响应体。
+    Json(serde_json::Error),
+    /// 服务返回的错误。
+    Api(ErrorBody),
+}
+
+impl From<std::io::Error> for Error {
+    #[inline]
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    #[inline]
+    fn from(e: hyper::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    #[inline]
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "io error: {e}"),
+            Self::Http(e) => write!(f, "http error: {e}"),
+            Self::Json(e) => write!(f, "invalid response: {e}"),
+            Self::Api(body) => write!(f, "{} ({}): {}", body.status, body.code, body.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// web 服务的异步客户端，每个请求使用一个新的连接。
+#[derive(Clone, Debug)]
+pub struct Client {
+    addr: String,
+}
+
+impl Client {
+    /// 连接 `addr`（形如 `127.0.0.1:8000`）上的服务。
+    #[inline]
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self { addr: addr.into() }
+    }
+
+    /// 发送推理请求，返回生成的文本流。
+    pub async fn infer(&self, req: &InferRequest) -> Result<InferStream, Error> {
+        let body = serde_json::to_vec(req)?;
+        self.send(Method::POST, "/infer", body, req.idempotency_key.as_deref())
+            .await
+            .map(InferStream::new)
+    }
+
+    /// 发送续写请求，返回生成的文本流。
+    pub async fn complete(&self, req: &CompleteRequest) -> Result<InferStream, Error> {
+        let body = serde_json::to_vec(req)?;
+        self.send(Method::POST, "/completions", body, None)
+            .await
+            .map(InferStream::new)
+    }
+
+    /// 计算每个候选接在提示词之后的对数似然。
+    pub async fn score(&self, req: &ScoreRequest) -> Result<ScoreReport, Error> {
+        let body = serde_json::to_vec(req)?;
+        let response = self.send(Method::POST, "/score", body, None).await?;
+        let body = response.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// 复制会话。
+    pub async fn fork(
+        &self,
+        session_id: impl Into<String>,
+        new_session_id: impl Into<String>,
+    ) -> Result<(), Error> {
+        let body = serde_json::to_vec(&Fork {
+            session_id: session_id.into(),
+            new_session_id: new_session_id.into(),
+        })?;
+        self.send(Method::POST, "/fork", body, None).await?;
+        Ok(())
+    }
+
+    /// 删除会话。
+    pub async fn drop_(&self, session_id: impl Into<String>) -> Result<(), Error> {
+        let body = serde_json::to_vec(&Drop {
+            session_id: session_id.into(),
+        })?;
+        self.send(Method::POST, "/drop", body, None).await?;
+        Ok(())
+    }
+
+    /// 查询所有会话的缓存占用。
+    #[inline]
+    pub async fn cache(&self) -> Result<CacheReport, Error> {
+        self.get("/cache").await
+    }
+
+    /// 查询推理吞吐量。
+    #[inline]
+    pub async fn throughput(&self) -> Result<ThroughputReport, Error> {
+        self.get("/throughput").await
+    }
+
+    /// 查询常驻的适配器。
+    #[inline]
+    pub async fn adapters(&self) -> Result<AdapterReport, Error> {
+        self.get("/admin/adapters").await
+    }
+
+    /// 从服务所在机器上的 `path` 目录加载名为 `name` 的适配器。
+    pub async fn load_adapter(
+        &self,
+        name: impl Into<String>,
+        path: impl Into<String>,
+    ) -> Result<(), Error> {
+        let body = serde_json::to_vec(&LoadAdapter {
+            name: name.into(),
+            path: path.into(),
+        })?;
+        self.send(Method::POST, "/admin/adapters/load", body, None)
+            .await?;
+        Ok(())
+    }
+
+    /// 卸载名为 `name` 的适配器。
+    pub async fn unload_adapter(&self, name: impl Into<String>) -> Result<(), Error> {
+        let body = serde_json::to_vec(&UnloadAdapter { name: name.into() })?;
+        self.send(Method::POST, "/admin/adapters/unload", body, None)
+            .await?;
+        Ok(())
+    }
+
+    /// 查询已注册的文档。
+    #[inline]
+    pub async fn documents(&self) -> Result<DocumentReport, Error> {
+        self.get("/documents").await
+    }
+
+    /// 注册名为 `document_id` 的文档，服务计算完它的缓存后返回。
+    pub async fn register_document(
+        &self,
+        document_id: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Result<(), Error> {
+        let body = serde_json::to_vec(&RegisterDocument {
+            document_id: document_id.into(),
+            content: content.into(),
+            encoding: Some("text".into()),
+        })?;
+        self.send(Method::POST, "/documents/register", body, None)
+            .await?;
+        Ok(())
+    }
+
+    /// 删除名为 `document_id` 的文档。
+    pub async fn drop_document(&self, document_id: impl Into<String>) -> Result<(), Error> {
+        let body = serde_json::to_vec(&DropDocument {
+            document_id: document_id.into(),
+        })?;
+        self.send(Method::POST, "/documents/drop", body, None)
+            .await?;
+        Ok(())
+    }
+
+    /// 使用 `session_id` 指定的会话对话。
+    #[inline]
+    pub fn session(&self, session_id: impl Into<String>) -> Session<'_> {
+        Session::new(self, session_id.into())
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let response = self.send(Method::GET, path, Vec::new(), None).await?;
+        let body = response.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// 发送请求，服务返回错误时解析错误的响应体。
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Vec<u8>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Response<Incoming>, Error> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let (mut sender, conn) = http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            let _ = conn.await;
+        });
+
+        let mut req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(HOST, &self.addr)
+            .header(TE, "trailers");
+        if !body.is_empty() {
+            req = req.header(CONTENT_TYPE, "application/json");
+        }
+        if let Some(key) = idempotency_key {
+            req = req.header("idempotency-key", key);
+        }
+        let req = req.body(Full::new(Bytes::from(body))).unwrap();
+
+        let response = sender.send_request(req).await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status().as_u16();
+        let body = response.into_body().collect().await?.to_bytes();
+        Err(Error::Api(serde_json::from_slice(&body).unwrap_or_else(
+            |_| ErrorBody {
+                status,
+                code: 0,
+                message: String::from_utf8_lossy(&body).into_owned(),
+                kind: Default::default(),
+                param: None,
+                detail: None,
+            },
+        )))
+    }
+}
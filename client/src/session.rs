@@ -0,0 +1,99 @@
+use crate::{
+    models::{FinishReason, Generation, InferRequest, Message},
+    Client, Error,
+};
+use hyper::StatusCode;
+use tokio_stream::StreamExt;
+
+/// 具名会话，在本地记录对话，每轮只发送新的句子。
+pub struct Session<'a> {
+    client: &'a Client,
+    id: String,
+    history: Vec<Message>,
+    /// 每轮对话的生成参数。
+    pub generation: Generation,
+    /// 每轮对话使用的预设。
+    pub preset: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    pub(crate) fn new(client: &'a Client, id: String) -> Self {
+        Self {
+            client,
+            id,
+            history: Vec::new(),
+            generation: Default::default(),
+            preset: None,
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 本地记录的对话。
+    #[inline]
+    pub fn history(&self) -> &[Message] {
+        &self.history
+    }
+
+    fn request(&self, dialog_pos: usize, inputs: &[Message]) -> InferRequest {
+        InferRequest {
+            session_id: Some(self.id.clone()),
+            dialog_pos: Some(dialog_pos),
+            preset: self.preset.clone(),
+            generation: self.generation.clone(),
+            ..InferRequest::new(inputs.iter().cloned())
+        }
+    }
+
+    /// 发送一句话，生成的每段文本传给 `on_text`，返回完整的回答和结束的原因。
+    ///
+    /// 服务端总结对话后会话变短，此时以完整的对话重置会话。
+    pub async fn chat(
+        &mut self,
+        content: impl Into<String>,
+        mut on_text: impl FnMut(&str),
+    ) -> Result<(String, Option<FinishReason>), Error> {
+        let message = Message::user(content);
+        let req = self.request(self.history.len(), std::slice::from_ref(&message));
+        let mut stream = match self.client.infer(&req).await {
+            Err(Error::Api(body)) if body.status == StatusCode::RANGE_NOT_SATISFIABLE.as_u16() => {
+                let mut inputs = self.history.clone();
+                inputs.push(message.clone());
+                self.client.infer(&self.request(0, &inputs)).await?
+            }
+            ret => ret?,
+        };
+
+        let mut answer = String::new();
+        while let Some(text) = stream.next().await {
+            let text = text?;
+            on_text(&text);
+            answer.push_str(&text);
+        }
+        self.history.push(message);
+        self.history.push(Message::assistant(answer.clone()));
+        Ok((answer, stream.finish_reason()))
+    }
+
+    /// 复制会话，新会话继承本地记录的对话和生成参数。
+    pub async fn fork(&self, new_id: impl Into<String>) -> Result<Session<'a>, Error> {
+        let new_id = new_id.into();
+        self.client.fork(&*self.id, &*new_id).await?;
+        Ok(Self {
+            client: self.client,
+            id: new_id,
+            history: self.history.clone(),
+            generation: self.generation.clone(),
+            preset: self.preset.clone(),
+        })
+    }
+
+    /// 删除服务端的会话。
+    #[inline]
+    pub async fn drop_(self) -> Result<(), Error> {
+        self.client.drop_(self.id).await
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{models::FinishReason, Error};
+use hyper::{body::Incoming, header::HeaderName, HeaderMap, Response};
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio_stream::Stream;
+
+const FINISH_REASON: HeaderName = HeaderName::from_static("x-finish-reason");
+const OUTPUT_IDS: HeaderName = HeaderName::from_static("x-output-ids");
+const STREAM_OFFSET: HeaderName = HeaderName::from_static("x-stream-offset");
+const USER: HeaderName = HeaderName::from_static("x-user");
+const METADATA: HeaderName = HeaderName::from_static("x-metadata");
+const PROMPT_LOGPROBS: HeaderName = HeaderName::from_static("x-prompt-logprobs");
+
+/// 推理生成的文本流。
+///
+/// 每项是一段完整的 UTF-8 文本，流结束后可以从 [`InferStream::finish_reason`] 得到结束的原因。
+pub struct InferStream {
+    headers: HeaderMap,
+    body: Incoming,
+    /// 不完整的 UTF-8 字符。
+    pending: Vec<u8>,
+    finish_reason: Option<FinishReason>,
+    output_ids: Option<Vec<u32>>,
+    done: bool,
+}
+
+impl InferStream {
+    pub(crate) fn new(response: Response<Incoming>) -> Self {
+        let (parts, body) = response.into_parts();
+        Self {
+            headers: parts.headers,
+            body,
+            pending: Vec::new(),
+            finish_reason: None,
+            output_ids: None,
+            done: false,
+        }
+    }
+
+    /// 流中第一个字节在生成的文本中的位置。
+    pub fn offset(&self) -> usize {
+        self.headers
+            .get(STREAM_OFFSET)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// 服务原样返回的 `user`。
+    #[inline]
+    pub fn user(&self) -> Option<&str> {
+        self.headers.get(USER).and_then(|v| v.to_str().ok())
+    }
+
+    /// 服务原样返回的 `metadata`。
+    #[inline]
+    pub fn metadata(&self) -> Option<&str> {
+        self.headers.get(METADATA).and_then(|v| v.to_str().ok())
+    }
+
+    /// 提示词中除第一个词以外每个词的对数概率，请求中没有设置 `prompt_logprobs` 或模型不支持时为 `None`。
+    pub fn prompt_logprobs(&self) -> Option<Vec<f32>> {
+        let logprobs = self.headers.get(PROMPT_LOGPROBS)?.to_str().ok()?;
+        logprobs
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.trim().parse().ok())
+            .collect()
+    }
+
+    /// 生成结束的原因，流结束之前或服务没有发送 trailer 时为 `None`。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    /// 生成的词，请求中没有设置 `return_ids` 或流结束之前为 `None`。
+    #[inline]
+    pub fn output_ids(&self) -> Option<&[u32]> {
+        self.output_ids.as_deref()
+    }
+
+    /// 从缓冲区中取出完整的字符。
+    fn take_text(&mut self) -> Option<String> {
+        let len = match std::str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        if len == 0 {
+            return None;
+        }
+        let rest = self.pending.split_off(len);
+        let text = std::mem::replace(&mut self.pending, rest);
+        Some(String::from_utf8(text).unwrap())
+    }
+}
+
+impl Stream for InferStream {
+    type Item = Result<String, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        use hyper::body::Body;
+
+        while !self.done {
+            match ready!(Pin::new(&mut self.body).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => {
+                        self.pending.extend_from_slice(&data);
+                        if let Some(text) = self.take_text() {
+                            return Poll::Ready(Some(Ok(text)));
+                        }
+                    }
+                    Err(frame) => {
+                        if let Ok(trailers) = frame.into_trailers() {
+                            self.finish_reason = trailers
+                                .get(FINISH_REASON)
+                                .and_then(|v| v.to_str().ok())
+                                .and_then(|v| v.parse().ok());
+                            self.output_ids = trailers
+                                .get(OUTPUT_IDS)
+                                .and_then(|v| v.to_str().ok())
+                                .and_then(|v| {
+                                    v.split(',')
+                                        .filter(|s| !s.is_empty())
+                                        .map(|s| s.trim().parse().ok())
+                                        .collect()
+                                });
+                        }
+                    }
+                },
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+                None => self.done = true,
+            }
+        }
+        // 流结束时剩下的字节不是完整的字符，按有损的方式输出
+        if self.pending.is_empty() {
+            Poll::Ready(None)
+        } else {
+            let text = String::from_utf8_lossy(&std::mem::take(&mut self.pending)).into_owned();
+            Poll::Ready(Some(Ok(text)))
+        }
+    }
+}
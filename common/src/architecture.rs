@@ -0,0 +1,145 @@
+//! 从模型目录中的 `config.json` 识别的模型架构。
+
+use crate::{
+    gguf::{self, GGuf, MetaValue},
+    FileLoadError::{self, Io, Json},
+};
+use serde::Deserialize;
+use std::{
+    fmt,
+    fs::File,
+    io::{Error, ErrorKind::InvalidData},
+    path::Path,
+};
+
+/// 支持的模型架构，决定以哪种存储布局加载模型。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Architecture {
+    /// llama 及与之布局相同的模型。
+    Llama,
+    /// mixtral 混合专家模型。
+    Mixtral,
+}
+
+/// 支持的 `architectures` 和 `model_type` 的取值及对应的架构。
+const SUPPORTED: [(&str, &str, Architecture); 3] = [
+    ("LlamaForCausalLM", "llama", Architecture::Llama),
+    // mistral 与 llama 的布局相同，滑动窗口注意力按完整的注意力计算
+    ("MistralForCausalLM", "mistral", Architecture::Llama),
+    ("MixtralForCausalLM", "mixtral", Architecture::Mixtral),
+];
+
+#[derive(Deserialize)]
+struct ConfigJson {
+    #[serde(default)]
+    architectures: Vec<String>,
+    #[serde(default)]
+    model_type: Option<String>,
+}
+
+impl Architecture {
+    /// 从模型目录的 `config.json` 识别架构，没有 `config.json` 时从 GGUF 文件的元数据识别。
+    pub fn detect(model_dir: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        let model_dir = model_dir.as_ref();
+        let config = model_dir.join("config.json");
+        if !config.is_file() {
+            if let Some(path) = gguf::find(model_dir)? {
+                return Self::from_gguf(&GGuf::load(path)?);
+            }
+        }
+        let file = File::open(config).map_err(Io)?;
+        let config: ConfigJson = serde_json::from_reader(file).map_err(Json)?;
+        Self::from_config(&config.architectures, config.model_type.as_deref())
+    }
+
+    /// 按 `architectures` 和 `model_type` 识别架构，`architectures` 优先，两者都没有时视为 llama。
+    ///
+    /// 不支持的架构返回列出所有支持的架构的错误。
+    pub fn from_config(
+        architectures: &[String],
+        model_type: Option<&str>,
+    ) -> Result<Self, FileLoadError> {
+        let found = match (architectures.first(), model_type) {
+            (Some(name), _) => SUPPORTED.iter().find(|(arch, ..)| arch == name),
+            (None, Some(name)) => SUPPORTED.iter().find(|(_, ty, _)| *ty == name),
+            (None, None) => return Ok(Self::Llama),
+        };
+        found.map(|&(.., arch)| arch).ok_or_else(|| {
+            let name = architectures
+                .first()
+                .map_or(model_type.unwrap_or(""), |s| s);
+            Io(Error::new(
+                InvalidData,
+                format!("unsupported architecture: {name}, {Supported}"),
+            ))
+        })
+    }
+
+    /// 按 GGUF 文件元数据中的 `general.architecture` 识别架构。
+    pub fn from_gguf(gguf: &GGuf) -> Result<Self, FileLoadError> {
+        let name = gguf
+            .get("general.architecture")
+            .and_then(MetaValue::as_str)
+            .unwrap_or("");
+        Self::from_name(name).ok_or_else(|| {
+            Io(Error::new(
+                InvalidData,
+                format!("unsupported architecture: {name}, {Supported}"),
+            ))
+        })
+    }
+
+    /// 按名字识别架构，名字可以是 `architectures` 或 `model_type` 的取值，不区分大小写。
+    pub fn from_name(name: &str) -> Option<Self> {
+        SUPPORTED
+            .iter()
+            .find(|(arch, ty, _)| arch.eq_ignore_ascii_case(name) || ty.eq_ignore_ascii_case(name))
+            .map(|&(.., arch)| arch)
+    }
+}
+
+/// 列出支持的架构，用于错误信息。
+pub struct Supported;
+
+impl fmt::Display for Supported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "supported:")?;
+        for (i, (arch, ty, _)) in SUPPORTED.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{sep}{arch} ({ty})")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_architecture() {
+    let arch = |archs: &[&str], ty| {
+        let archs = archs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        Architecture::from_config(&archs, ty)
+    };
+    assert_eq!(
+        arch(&["LlamaForCausalLM"], None).unwrap(),
+        Architecture::Llama
+    );
+    assert_eq!(
+        arch(&["MistralForCausalLM"], Some("mistral")).unwrap(),
+        Architecture::Llama
+    );
+    assert_eq!(arch(&[], Some("mixtral")).unwrap(), Architecture::Mixtral);
+    assert_eq!(arch(&[], None).unwrap(), Architecture::Llama);
+    assert_eq!(
+        Architecture::from_name("Mixtral"),
+        Some(Architecture::Mixtral)
+    );
+    assert_eq!(Architecture::from_name("gemma"), None);
+
+    let Err(Io(e)) = arch(&["Qwen2ForCausalLM"], Some("qwen2")) else {
+        panic!("qwen2 is not supported")
+    };
+    assert_eq!(
+        e.to_string(),
+        "unsupported architecture: Qwen2ForCausalLM, \
+         supported: LlamaForCausalLM (llama), MistralForCausalLM (mistral), MixtralForCausalLM (mixtral)"
+    );
+}
@@ -37,3 +37,18 @@ impl BetweenF32 for half::f16 {
         Self::to_f32(*self)
     }
 }
+
+impl BetweenF32 for half::bf16 {
+    #[inline]
+    fn zero() -> Self {
+        Self::ZERO
+    }
+    #[inline]
+    fn cast(f: f32) -> Self {
+        Self::from_f32(f)
+    }
+    #[inline]
+    fn get(&self) -> f32 {
+        Self::to_f32(*self)
+    }
+}
@@ -4,37 +4,169 @@
     ops::{Deref, DerefMut},
     ptr::NonNull,
     slice::{from_raw_parts, from_raw_parts_mut},
+    sync::atomic::{AtomicU8, Ordering::Relaxed},
 };
 
 /// A wrapper around a dynamically allocated byte array.
 pub struct Blob {
     ptr: NonNull<u8>,
     len: usize,
+    align: usize,
+    mapped: bool,
+    locked: bool,
 }
 
 unsafe impl Send for Blob {}
 unsafe impl Sync for Blob {}
 
+/// How `Blob` uses huge pages.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum HugePages {
+    /// Use normal pages.
+    #[default]
+    Disabled,
+    /// Align large blocks to huge pages and advise the kernel to back them with transparent huge pages.
+    Transparent,
+    /// Map large blocks from the explicit huge page pool, falling back to transparent huge pages.
+    Explicit,
+}
+
+/// Process-wide options of the `Blob` allocator.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct BlobOptions {
+    /// How to use huge pages.
+    pub huge_pages: HugePages,
+    /// Lock allocated blocks and mapped weights into RAM so that they are never swapped out.
+    pub mlock: bool,
+}
+
+static OPTIONS: AtomicU8 = AtomicU8::new(0);
+
+impl BlobOptions {
+    /// Sets the options used by all `Blob`s allocated afterwards.
+    #[inline]
+    pub fn set_global(self) {
+        let huge_pages = match self.huge_pages {
+            HugePages::Disabled => 0,
+            HugePages::Transparent => 1,
+            HugePages::Explicit => 2,
+        };
+        OPTIONS.store(huge_pages | ((self.mlock as u8) << 2), Relaxed);
+    }
+
+    /// Gets the options currently used by the allocator.
+    #[inline]
+    pub fn global() -> Self {
+        let bits = OPTIONS.load(Relaxed);
+        Self {
+            huge_pages: match bits & 0b11 {
+                0 => HugePages::Disabled,
+                1 => HugePages::Transparent,
+                _ => HugePages::Explicit,
+            },
+            mlock: bits & 0b100 != 0,
+        }
+    }
+}
+
+/// Size of a huge page.
+const HUGE_PAGE: usize = 2 << 20;
+
 impl Blob {
     /// Creates a new `Blob` with the given size.
     ///
     /// The allocated block of memory may or may not be initialized.
     #[inline]
     pub fn new(size: usize) -> Self {
-        const ALIGN: usize = align_of::<usize>();
-        let layout = Layout::from_size_align(size, ALIGN).unwrap();
-        Self {
-            ptr: NonNull::new(unsafe { alloc(layout) }).unwrap(),
-            len: size,
+        let options = BlobOptions::global();
+        let huge = options.huge_pages != HugePages::Disabled && size >= HUGE_PAGE;
+
+        #[cfg(target_os = "linux")]
+        let blob = (huge && options.huge_pages == HugePages::Explicit)
+            .then(|| map_huge_pages(size))
+            .flatten();
+        #[cfg(not(target_os = "linux"))]
+        let blob = None;
+
+        let mut blob = blob.unwrap_or_else(|| {
+            let align = if huge { HUGE_PAGE } else { align_of::<usize>() };
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = NonNull::new(unsafe { alloc(layout) }).unwrap();
+            #[cfg(target_os = "linux")]
+            if huge {
+                unsafe { libc::madvise(ptr.as_ptr().cast(), size, libc::MADV_HUGEPAGE) };
+            }
+            Self {
+                ptr,
+                len: size,
+                align,
+                mapped: false,
+                locked: false,
+            }
+        });
+        if options.mlock && size > 0 {
+            blob.locked = lock(blob.ptr, size);
         }
+        blob
     }
 }
 
+/// Locks a block into RAM, returns whether it succeeds.
+#[cfg(unix)]
+#[inline]
+fn lock(ptr: NonNull<u8>, len: usize) -> bool {
+    unsafe { libc::mlock(ptr.as_ptr().cast(), len) == 0 }
+}
+
+#[cfg(not(unix))]
+#[inline]
+fn lock(_ptr: NonNull<u8>, _len: usize) -> bool {
+    false
+}
+
+/// Maps a block from the explicit huge page pool.
+#[cfg(target_os = "linux")]
+fn map_huge_pages(size: usize) -> Option<Blob> {
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            size.next_multiple_of(HUGE_PAGE),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return None;
+    }
+    Some(Blob {
+        ptr: NonNull::new(ptr.cast())?,
+        len: size,
+        align: HUGE_PAGE,
+        mapped: true,
+        locked: false,
+    })
+}
+
 impl Drop for Blob {
     #[inline]
     fn drop(&mut self) {
-        const ALIGN: usize = align_of::<usize>();
-        let layout = Layout::from_size_align(self.len, ALIGN).unwrap();
+        #[cfg(unix)]
+        if self.locked {
+            unsafe { libc::munlock(self.ptr.as_ptr().cast(), self.len) };
+        }
+        if self.mapped {
+            #[cfg(target_os = "linux")]
+            unsafe {
+                libc::munmap(
+                    self.ptr.as_ptr().cast(),
+                    self.len.next_multiple_of(HUGE_PAGE),
+                )
+            };
+            return;
+        }
+        let layout = Layout::from_size_align(self.len, self.align).unwrap();
         unsafe { dealloc(self.ptr.as_ptr(), layout) }
     }
 }
@@ -54,3 +186,20 @@ impl DerefMut for Blob {
         unsafe { from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
     }
 }
+
+#[test]
+fn test_huge_pages() {
+    let options = BlobOptions {
+        huge_pages: HugePages::Transparent,
+        mlock: false,
+    };
+    options.set_global();
+    assert_eq!(BlobOptions::global(), options);
+
+    let mut blob = Blob::new(HUGE_PAGE);
+    assert_eq!(blob.as_ptr() as usize % HUGE_PAGE, 0);
+    blob.fill(1);
+    assert!(blob.iter().all(|&b| b == 1));
+
+    BlobOptions::default().set_global();
+}
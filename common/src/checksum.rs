@@ -0,0 +1,181 @@
+//! 以 SHA-256 校验模型目录中的文件。
+//!
+//! 清单的格式与 `sha256sum` 的输出相同，每行是一个文件的摘要和相对模型目录的路径，
+//! 可以用 `sha256sum model*.safetensors > SHA256SUMS` 生成。
+
+use crate::{
+    safe_tensors::SafeTensorsIndex,
+    FileLoadError::{self, Io, Json},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Error, ErrorKind::InvalidData, Read},
+    path::Path,
+    thread,
+};
+
+/// 模型目录中默认的清单文件名。
+pub const MANIFEST: &str = "SHA256SUMS";
+
+/// 一个文件的校验结果。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Status {
+    /// 摘要与清单一致。
+    Ok,
+    /// 摘要与清单不一致。
+    Mismatch {
+        /// 清单中的摘要。
+        expected: String,
+        /// 计算出的摘要。
+        actual: String,
+    },
+    /// 清单中的文件不存在或无法读取。
+    Missing,
+    /// 权重文件不在清单中。
+    Unlisted,
+}
+
+/// 读取清单，返回每个文件的路径和小写的十六进制摘要。
+pub fn read_manifest(path: impl AsRef<Path>) -> Result<Vec<(String, String)>, FileLoadError> {
+    let text = fs::read_to_string(path).map_err(Io)?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            // 文件名前是一个空格和表示文本或二进制模式的 ' ' 或 '*'
+            let (digest, name) = line.split_once(' ').unwrap_or((line, ""));
+            let name = name.strip_prefix([' ', '*']).unwrap_or(name);
+            if digest.len() != 64
+                || !digest.bytes().all(|b| b.is_ascii_hexdigit())
+                || name.is_empty()
+            {
+                let msg = format!("invalid checksum line: {line}");
+                return Err(Io(Error::new(InvalidData, msg)));
+            }
+            Ok((name.to_string(), digest.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// 计算文件的 SHA-256 摘要，返回小写的十六进制字符串。
+pub fn sha256_file(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .fold(String::with_capacity(64), |mut s, b| {
+            let _ = write!(s, "{b:02x}");
+            s
+        }))
+}
+
+/// 模型目录中的权重文件：`model.safetensors` 或索引中的所有分片。
+pub fn weight_files(model_dir: impl AsRef<Path>) -> Result<Vec<String>, FileLoadError> {
+    let model_dir = model_dir.as_ref();
+    if model_dir.join("model.safetensors").is_file() {
+        return Ok(vec!["model.safetensors".into()]);
+    }
+    let index = File::open(model_dir.join("model.safetensors.index.json")).map_err(Io)?;
+    let index: SafeTensorsIndex = serde_json::from_reader(index).map_err(Json)?;
+    let mut files = index.weight_map.into_values().collect::<Vec<_>>();
+    files.sort_unstable();
+    files.dedup();
+    Ok(files)
+}
+
+/// 按清单校验模型目录中的文件，清单默认为模型目录中的 [`MANIFEST`]。
+///
+/// 清单中的每个文件并行计算摘要；不在清单中的权重文件无法校验，报告为 [`Status::Unlisted`]。
+pub fn verify(
+    model_dir: impl AsRef<Path>,
+    manifest: Option<&Path>,
+) -> Result<Vec<(String, Status)>, FileLoadError> {
+    let model_dir = model_dir.as_ref();
+    let entries = match manifest {
+        Some(path) => read_manifest(path)?,
+        None => read_manifest(model_dir.join(MANIFEST))?,
+    };
+    let mut ans = thread::scope(|s| {
+        let tasks = entries
+            .iter()
+            .map(|(name, expected)| {
+                s.spawn(move || match sha256_file(model_dir.join(name)) {
+                    Ok(actual) if actual == *expected => Status::Ok,
+                    Ok(actual) => Status::Mismatch {
+                        expected: expected.clone(),
+                        actual,
+                    },
+                    Err(_) => Status::Missing,
+                })
+            })
+            .collect::<Vec<_>>();
+        entries
+            .iter()
+            .zip(tasks)
+            .map(|((name, _), task)| (name.clone(), task.join().unwrap()))
+            .collect::<Vec<_>>()
+    });
+    for file in weight_files(model_dir)? {
+        if !entries.iter().any(|(name, _)| *name == file) {
+            ans.push((file, Status::Unlisted));
+        }
+    }
+    Ok(ans)
+}
+
+/// 为模型目录中的权重文件生成清单。
+pub fn write_manifest(
+    model_dir: impl AsRef<Path>,
+    manifest: impl AsRef<Path>,
+) -> Result<(), FileLoadError> {
+    let model_dir = model_dir.as_ref();
+    let mut text = String::new();
+    for file in weight_files(model_dir)? {
+        let digest = sha256_file(model_dir.join(&file)).map_err(Io)?;
+        let _ = writeln!(text, "{digest}  {file}");
+    }
+    fs::write(manifest, text).map_err(Io)
+}
+
+#[test]
+fn test_verify() {
+    let dir = std::env::temp_dir().join(format!("checksum-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("model.safetensors"), b"abc").unwrap();
+    // `echo -n abc | sha256sum`
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    assert_eq!(sha256_file(dir.join("model.safetensors")).unwrap(), ABC);
+
+    write_manifest(&dir, dir.join(MANIFEST)).unwrap();
+    assert_eq!(
+        verify(&dir, None).unwrap(),
+        [("model.safetensors".to_string(), Status::Ok)]
+    );
+
+    fs::write(dir.join("model.safetensors"), b"abd").unwrap();
+    let report = verify(&dir, None).unwrap();
+    assert!(matches!(&report[0].1, Status::Mismatch { expected, .. } if expected == ABC));
+
+    let other = dir.join("other");
+    fs::write(&other, format!("{} *missing.bin\n", ABC.to_uppercase())).unwrap();
+    assert_eq!(
+        verify(&dir, Some(&other)).unwrap(),
+        [
+            ("missing.bin".to_string(), Status::Missing),
+            ("model.safetensors".to_string(), Status::Unlisted),
+        ]
+    );
+
+    fs::write(&other, "abc  model.safetensors\n").unwrap();
+    assert!(read_manifest(&other).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,146 @@
+//! 8 位浮点数，只用于存储权重，计算前转换为 f16 或 f32。
+
+use crate::BetweenF32;
+use digit_layout::{AsDigit, DigitLayout};
+use half::f16;
+
+/// E4M3 格式的 8 位浮点数的布局。
+pub const F8E4M3: DigitLayout = DigitLayout::new(1, true, 4, 3);
+/// E5M2 格式的 8 位浮点数的布局。
+pub const F8E5M2: DigitLayout = DigitLayout::new(1, true, 5, 2);
+
+/// E4M3 格式的 8 位浮点数，指数偏置 7，没有无穷大，最大值 448。
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+#[repr(transparent)]
+pub struct f8e4m3(pub u8);
+
+/// E5M2 格式的 8 位浮点数，即截去低 8 位的 f16。
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+#[repr(transparent)]
+pub struct f8e5m2(pub u8);
+
+impl f8e4m3 {
+    /// 最大的有限值。
+    pub const MAX: f32 = 448.;
+
+    /// 舍入到最近的偶数，超出范围的值饱和到最大值。
+    pub fn from_f32(x: f32) -> Self {
+        let sign = if x.is_sign_negative() { 0x80 } else { 0 };
+        if x.is_nan() {
+            return Self(sign | 0x7f);
+        }
+        let a = x.abs().min(Self::MAX);
+        // 最小的正规数是 2^-6，以下按 2^-9 的间隔表示
+        let bits = if a < 2f32.powi(-6) {
+            (a * 512.).round_ties_even() as u8
+        } else {
+            let bits = a.to_bits();
+            let exp = (bits >> 23) + 7 - 127;
+            let man = bits & 0x7f_ffff;
+            let mut v = (exp << 3) | (man >> 20);
+            let rest = man & 0xf_ffff;
+            if rest > 0x8_0000 || (rest == 0x8_0000 && v & 1 == 1) {
+                v += 1;
+            }
+            v.min(0x7e) as u8
+        };
+        Self(sign | bits)
+    }
+
+    /// 转换为 f32。
+    pub fn to_f32(self) -> f32 {
+        let sign = if self.0 & 0x80 == 0 { 1. } else { -1. };
+        let exp = (self.0 >> 3) & 0xf;
+        let man = (self.0 & 7) as f32;
+        sign * match exp {
+            0 => man * 2f32.powi(-9),
+            0xf if man == 7. => f32::NAN,
+            _ => (1. + man / 8.) * 2f32.powi(exp as i32 - 7),
+        }
+    }
+}
+
+impl f8e5m2 {
+    /// 舍入到最近的偶数，超出范围的值变为无穷大。
+    pub fn from_f32(x: f32) -> Self {
+        let h = f16::from_f32(x).to_bits();
+        if f16::from_bits(h).is_nan() {
+            return Self((h >> 8) as u8 | 0x02);
+        }
+        let round = 0x7f + ((h >> 8) & 1);
+        Self((h.wrapping_add(round) >> 8) as u8)
+    }
+
+    /// 转换为 f32。
+    pub fn to_f32(self) -> f32 {
+        f16::from_bits((self.0 as u16) << 8).to_f32()
+    }
+}
+
+macro_rules! impl_fp8 {
+    ($ty:ty, $layout:expr) => {
+        impl AsDigit for $ty {
+            const LAYOUT: DigitLayout = $layout;
+        }
+
+        impl BetweenF32 for $ty {
+            #[inline]
+            fn zero() -> Self {
+                Self(0)
+            }
+            #[inline]
+            fn cast(f: f32) -> Self {
+                Self::from_f32(f)
+            }
+            #[inline]
+            fn get(&self) -> f32 {
+                self.to_f32()
+            }
+        }
+    };
+}
+
+impl_fp8!(f8e4m3, F8E4M3);
+impl_fp8!(f8e5m2, F8E5M2);
+
+/// 查表将 8 位浮点数转换为 f32，不是 8 位浮点数的布局返回 `None`。
+pub fn fp8_table(dt: DigitLayout) -> Option<[f32; 256]> {
+    let to_f32: fn(u8) -> f32 = match dt {
+        F8E4M3 => |b| f8e4m3(b).to_f32(),
+        F8E5M2 => |b| f8e5m2(b).to_f32(),
+        _ => return None,
+    };
+    Some(std::array::from_fn(|i| to_f32(i as u8)))
+}
+
+#[test]
+fn test_fp8() {
+    // 所有的有限值都能精确地往返
+    for b in 0..=u8::MAX {
+        let x = f8e4m3(b).to_f32();
+        if !x.is_nan() {
+            assert_eq!(f8e4m3::from_f32(x).to_f32(), x, "e4m3 {b:#04x}");
+        }
+        let x = f8e5m2(b).to_f32();
+        if !x.is_nan() {
+            assert_eq!(f8e5m2::from_f32(x).to_f32(), x, "e5m2 {b:#04x}");
+        }
+    }
+
+    assert_eq!(f8e4m3::from_f32(1.).0, 0x38);
+    assert_eq!(f8e4m3::from_f32(-448.).0, 0xfe);
+    assert_eq!(f8e4m3::from_f32(1e4).to_f32(), 448.);
+    assert_eq!(f8e4m3(0x01).to_f32(), 2f32.powi(-9));
+    // 1.0625 在 1 和 1.125 正中，舍入到偶数
+    assert_eq!(f8e4m3::from_f32(1.0625).to_f32(), 1.);
+    assert_eq!(f8e4m3::from_f32(1.07).to_f32(), 1.125);
+    assert!(f8e4m3::from_f32(f32::NAN).to_f32().is_nan());
+
+    assert_eq!(f8e5m2::from_f32(1.).0, 0x3c);
+    assert_eq!(f8e5m2::from_f32(1.125).to_f32(), 1.);
+    assert_eq!(f8e5m2::from_f32(1.2).to_f32(), 1.25);
+    assert_eq!(f8e5m2::from_f32(1e6).to_f32(), f32::INFINITY);
+    assert!(f8e5m2::from_f32(f32::NAN).to_f32().is_nan());
+}
@@ -0,0 +1,87 @@
+//! 从模型目录中的 `generation_config.json` 读取的生成配置。
+
+use crate::{utok, FileLoadError};
+use serde::{Deserialize, Deserializer};
+use std::{fs::File, io::ErrorKind::NotFound, path::Path};
+
+/// 生成配置。
+#[derive(Clone, Default, Debug, Deserialize)]
+pub struct GenerationConfig {
+    /// 结束生成的词，可能有多个。
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub eos_token_id: Vec<utok>,
+    /// 是否随机采样，否则使用贪心采样。
+    #[serde(default)]
+    pub do_sample: bool,
+    /// 随机采样的温度。
+    pub temperature: Option<f32>,
+    /// 随机采样的硬阈值。
+    pub top_k: Option<usize>,
+    /// 随机采样的软阈值。
+    pub top_p: Option<f32>,
+}
+
+impl GenerationConfig {
+    /// 从模型目录加载生成配置，文件不存在时返回默认值。
+    pub fn load(model_dir: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        match File::open(model_dir.as_ref().join("generation_config.json")) {
+            Ok(file) => serde_json::from_reader(file).map_err(FileLoadError::Json),
+            Err(e) if e.kind() == NotFound => Ok(Self::default()),
+            Err(e) => Err(FileLoadError::Io(e)),
+        }
+    }
+
+    /// 将 `eos` 和生成配置中的结束符合并为结束符集合，`eos` 排在最前。
+    pub fn eos_tokens(&self, eos: utok) -> Vec<utok> {
+        let mut ans = vec![eos];
+        for &t in &self.eos_token_id {
+            if !ans.contains(&t) {
+                ans.push(t);
+            }
+        }
+        ans
+    }
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<utok>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(utok),
+        Many(Vec<utok>),
+    }
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(t)) => vec![t],
+        Some(OneOrMany::Many(v)) => v,
+        None => vec![],
+    })
+}
+
+#[test]
+fn test_eos_tokens() {
+    let config: GenerationConfig = serde_json::from_str(r#"{"eos_token_id": 2}"#).unwrap();
+    assert_eq!(config.eos_tokens(2), [2]);
+
+    let config: GenerationConfig =
+        serde_json::from_str(r#"{"eos_token_id": [128001, 128009], "do_sample": true}"#).unwrap();
+    assert_eq!(config.eos_tokens(128001), [128001, 128009]);
+
+    let config: GenerationConfig = serde_json::from_str("{}").unwrap();
+    assert_eq!(config.eos_tokens(2), [2]);
+}
+
+#[test]
+fn test_sample() {
+    let config: GenerationConfig =
+        serde_json::from_str(r#"{"do_sample": true, "temperature": 0.6, "top_p": 0.9}"#).unwrap();
+    assert!(config.do_sample);
+    assert_eq!(config.temperature, Some(0.6));
+    assert_eq!(config.top_k, None);
+    assert_eq!(config.top_p, Some(0.9));
+
+    let config: GenerationConfig = serde_json::from_str("{}").unwrap();
+    assert!(!config.do_sample);
+}
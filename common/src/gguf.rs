@@ -0,0 +1,534 @@
+//! GGUF 文件的解析和张量的反量化。
+//!
+//! 文件由头部、元数据、张量信息和按 `general.alignment` 对齐的张量数据组成，
+//! 张量的维度以 `ne` 的顺序存储，即第一维是最内层的维度。
+
+use crate::{
+    f16,
+    safe_tensors::map_weights,
+    FileLoadError::{self, Io},
+};
+use memmap2::Mmap;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Error, ErrorKind::InvalidData},
+    path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: usize = 32;
+
+/// 映射到内存的 GGUF 文件。
+pub struct GGuf {
+    mmap: Mmap,
+    version: u32,
+    metadata: HashMap<String, MetaValue>,
+    tensors: HashMap<String, GGufTensor>,
+    data_offset: usize,
+}
+
+/// 元数据的值。
+#[derive(Clone, PartialEq, Debug)]
+pub enum MetaValue {
+    /// 无符号整数，包括 `u8`、`u16`、`u32` 和 `u64`。
+    UInt(u64),
+    /// 有符号整数，包括 `i8`、`i16`、`i32` 和 `i64`。
+    Int(i64),
+    /// 浮点数，包括 `f32` 和 `f64`。
+    Float(f64),
+    /// 布尔值。
+    Bool(bool),
+    /// 字符串。
+    String(String),
+    /// 数组。
+    Array(Vec<MetaValue>),
+}
+
+impl MetaValue {
+    /// 非负的整数值。
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::UInt(v) => Some(v),
+            Self::Int(v) => v.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    /// 浮点数值，整数也转换为浮点数。
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::Float(v) => Some(v),
+            Self::UInt(v) => Some(v as _),
+            Self::Int(v) => Some(v as _),
+            _ => None,
+        }
+    }
+
+    /// 字符串值。
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// 数组值。
+    pub fn as_array(&self) -> Option<&[MetaValue]> {
+        match self {
+            Self::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// GGUF 文件中一个张量的信息。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GGufTensor {
+    /// 以 `ne` 的顺序排列的维度，第一维是最内层的维度。
+    pub dims: Vec<u64>,
+    /// 元素类型。
+    pub ty: GGmlType,
+    /// 数据在数据区中的偏移。
+    pub offset: u64,
+}
+
+impl GGufTensor {
+    /// 元素的数量。
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.dims.iter().product::<u64>() as _
+    }
+
+    /// 张量是否没有元素。
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 按行主序排列的形状，即维度反序。
+    #[inline]
+    pub fn shape(&self) -> Vec<usize> {
+        self.dims.iter().rev().map(|&d| d as _).collect()
+    }
+
+    /// 数据的字节数，类型未知或元素数不是块大小的整数倍时返回 `None`。
+    pub fn nbytes(&self) -> Option<usize> {
+        let (elements, bytes) = self.ty.block()?;
+        let n = self.len();
+        n.is_multiple_of(elements).then_some(n / elements * bytes)
+    }
+}
+
+/// ggml 的元素类型。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct GGmlType(pub u32);
+
+#[allow(missing_docs)]
+impl GGmlType {
+    pub const F32: Self = Self(0);
+    pub const F16: Self = Self(1);
+    pub const Q4_0: Self = Self(2);
+    pub const Q4_1: Self = Self(3);
+    pub const Q8_0: Self = Self(8);
+    pub const Q4_K: Self = Self(12);
+    pub const Q6_K: Self = Self(14);
+    pub const BF16: Self = Self(30);
+}
+
+impl GGmlType {
+    /// 每块的元素数和字节数，不支持的类型返回 `None`。
+    pub const fn block(self) -> Option<(usize, usize)> {
+        match self {
+            Self::F32 => Some((1, 4)),
+            Self::F16 | Self::BF16 => Some((1, 2)),
+            Self::Q4_0 => Some((32, 18)),
+            Self::Q4_1 => Some((32, 20)),
+            Self::Q8_0 => Some((32, 34)),
+            Self::Q4_K => Some((256, 144)),
+            Self::Q6_K => Some((256, 210)),
+            _ => None,
+        }
+    }
+
+    /// 是否是分块量化的类型。
+    #[inline]
+    pub const fn is_quantized(self) -> bool {
+        matches!(self.block(), Some((n, _)) if n > 1)
+    }
+
+    /// 将 `data` 反量化到 `out`，`out` 的长度决定元素数。
+    ///
+    /// # Panics
+    ///
+    /// 类型不支持或 `data` 的长度与 `out` 不匹配时 panic。
+    pub fn dequantize(self, data: &[u8], out: &mut [f32]) {
+        let (elements, bytes) = self
+            .block()
+            .unwrap_or_else(|| panic!("unsupported ggml type: {}", self.0));
+        assert_eq!(out.len() % elements, 0);
+        assert_eq!(data.len(), out.len() / elements * bytes);
+        let blocks = data.chunks_exact(bytes).zip(out.chunks_exact_mut(elements));
+        let half = |b: &[u8]| f16::from_le_bytes([b[0], b[1]]).to_f32();
+        match self {
+            Self::F32 => blocks.for_each(|(b, y)| y[0] = f32::from_le_bytes(b.try_into().unwrap())),
+            Self::F16 => blocks.for_each(|(b, y)| y[0] = half(b)),
+            Self::BF16 => {
+                blocks.for_each(|(b, y)| y[0] = crate::bf16::from_le_bytes([b[0], b[1]]).to_f32())
+            }
+            Self::Q4_0 => blocks.for_each(|(b, y)| {
+                let d = half(b);
+                for (j, &q) in b[2..].iter().enumerate() {
+                    y[j] = ((q & 0xf) as i32 - 8) as f32 * d;
+                    y[j + 16] = ((q >> 4) as i32 - 8) as f32 * d;
+                }
+            }),
+            Self::Q4_1 => blocks.for_each(|(b, y)| {
+                let (d, m) = (half(b), half(&b[2..]));
+                for (j, &q) in b[4..].iter().enumerate() {
+                    y[j] = (q & 0xf) as f32 * d + m;
+                    y[j + 16] = (q >> 4) as f32 * d + m;
+                }
+            }),
+            Self::Q8_0 => blocks.for_each(|(b, y)| {
+                let d = half(b);
+                for (y, &q) in y.iter_mut().zip(&b[2..]) {
+                    *y = q as i8 as f32 * d;
+                }
+            }),
+            Self::Q4_K => blocks.for_each(|(b, y)| {
+                let (d, min) = (half(b), half(&b[2..]));
+                let scales = &b[4..16];
+                // 8 组 6 位的缩放和最小值打包在 12 字节中
+                let scale_min = |j: usize| {
+                    if j < 4 {
+                        (scales[j] & 63, scales[j + 4] & 63)
+                    } else {
+                        (
+                            (scales[j + 4] & 0xf) | ((scales[j - 4] >> 6) << 4),
+                            (scales[j + 4] >> 4) | ((scales[j] >> 6) << 4),
+                        )
+                    }
+                };
+                for (i, (q, y)) in b[16..]
+                    .chunks_exact(32)
+                    .zip(y.chunks_exact_mut(64))
+                    .enumerate()
+                {
+                    let (sc, m) = scale_min(2 * i);
+                    let (d1, m1) = (d * sc as f32, min * m as f32);
+                    let (sc, m) = scale_min(2 * i + 1);
+                    let (d2, m2) = (d * sc as f32, min * m as f32);
+                    for (l, &q) in q.iter().enumerate() {
+                        y[l] = d1 * (q & 0xf) as f32 - m1;
+                        y[l + 32] = d2 * (q >> 4) as f32 - m2;
+                    }
+                }
+            }),
+            Self::Q6_K => blocks.for_each(|(b, y)| {
+                let (ql, rest) = b.split_at(128);
+                let (qh, rest) = rest.split_at(64);
+                let (sc, d) = rest.split_at(16);
+                let d = half(d);
+                for n in 0..2 {
+                    let (ql, qh) = (&ql[n * 64..], &qh[n * 32..]);
+                    let sc = |i: usize| sc[n * 8 + i] as i8 as f32;
+                    let y = &mut y[n * 128..];
+                    for l in 0..32 {
+                        let is = l / 16;
+                        let q = |low: u8, shift: u8| {
+                            (low as i32 | (((qh[l] >> shift) & 3) as i32) << 4) - 32
+                        };
+                        y[l] = d * sc(is) * q(ql[l] & 0xf, 0) as f32;
+                        y[l + 32] = d * sc(is + 2) * q(ql[l + 32] & 0xf, 2) as f32;
+                        y[l + 64] = d * sc(is + 4) * q(ql[l] >> 4, 4) as f32;
+                        y[l + 96] = d * sc(is + 6) * q(ql[l + 32] >> 4, 6) as f32;
+                    }
+                }
+            }),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl GGuf {
+    /// 映射并解析 GGUF 文件，支持第 2、3 版。
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        let file = File::open(path).map_err(Io)?;
+        let mmap = map_weights(&file)?;
+        let mut r = Reader { buf: &mmap, pos: 0 };
+
+        if r.bytes(4)? != MAGIC {
+            return Err(invalid("not a gguf file".into()));
+        }
+        let version = r.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(invalid(format!("unsupported gguf version: {version}")));
+        }
+        let n_tensors = r.u64()?;
+        let n_kv = r.u64()?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..n_kv {
+            let key = r.string()?;
+            let ty = r.u32()?;
+            metadata.insert(key, r.value(ty)?);
+        }
+        let mut tensors = HashMap::new();
+        for _ in 0..n_tensors {
+            let name = r.string()?;
+            let n_dims = r.u32()?;
+            let dims = (0..n_dims).map(|_| r.u64()).collect::<Result<_, _>>()?;
+            let ty = GGmlType(r.u32()?);
+            let offset = r.u64()?;
+            tensors.insert(name, GGufTensor { dims, ty, offset });
+        }
+
+        let alignment = metadata
+            .get("general.alignment")
+            .and_then(MetaValue::as_u64)
+            .map_or(DEFAULT_ALIGNMENT, |a| a as usize);
+        let data_offset = r.pos.div_ceil(alignment) * alignment;
+        let ans = Self {
+            version,
+            metadata,
+            tensors,
+            data_offset,
+            mmap,
+        };
+        // 检查所有已知类型的张量都在文件范围内
+        for (name, tensor) in &ans.tensors {
+            if let Some(n) = tensor.nbytes() {
+                if ans.data_offset + tensor.offset as usize + n > ans.mmap.len() {
+                    return Err(invalid(format!("tensor {name} out of file")));
+                }
+            }
+        }
+        Ok(ans)
+    }
+
+    /// 文件格式的版本。
+    #[inline]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// 按键获取元数据。
+    #[inline]
+    pub fn get(&self, key: &str) -> Option<&MetaValue> {
+        self.metadata.get(key)
+    }
+
+    /// 所有元数据。
+    #[inline]
+    pub fn metadata(&self) -> &HashMap<String, MetaValue> {
+        &self.metadata
+    }
+
+    /// 按名字获取张量的信息和数据，类型不支持时数据为空。
+    pub fn tensor(&self, name: &str) -> Option<(&GGufTensor, &[u8])> {
+        let tensor = self.tensors.get(name)?;
+        let data = match tensor.nbytes() {
+            Some(n) => &self.mmap[self.data_offset + tensor.offset as usize..][..n],
+            None => &[],
+        };
+        Some((tensor, data))
+    }
+
+    /// 所有张量的名字和信息。
+    #[inline]
+    pub fn tensors(&self) -> impl Iterator<Item = (&str, &GGufTensor)> {
+        self.tensors.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// 将张量反量化为 `f32`，张量不存在时返回 `None`。
+    ///
+    /// # Panics
+    ///
+    /// 张量的类型不支持时 panic。
+    pub fn dequantize(&self, name: &str) -> Option<Vec<f32>> {
+        let (tensor, data) = self.tensor(name)?;
+        let mut ans = vec![0.; tensor.len()];
+        tensor.ty.dequantize(data, &mut ans);
+        Some(ans)
+    }
+}
+
+/// 查找 GGUF 文件：`path` 是文件时返回它本身，是目录时返回其中唯一的 `.gguf` 文件。
+///
+/// 目录中没有 GGUF 文件时返回 `None`，有多个时返回错误。
+pub fn find(path: impl AsRef<Path>) -> Result<Option<PathBuf>, FileLoadError> {
+    let path = path.as_ref();
+    if path.is_file() {
+        return Ok(Some(path.into()));
+    }
+    let mut found = None;
+    for entry in path.read_dir().map_err(Io)? {
+        let file = entry.map_err(Io)?.path();
+        if file.is_file() && file.extension().is_some_and(|ext| ext == "gguf") {
+            if found.is_some() {
+                return Err(invalid(format!(
+                    "more than one gguf file in {}",
+                    path.display()
+                )));
+            }
+            found = Some(file);
+        }
+    }
+    Ok(found)
+}
+
+fn invalid(msg: String) -> FileLoadError {
+    Io(Error::new(InvalidData, msg))
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], FileLoadError> {
+        let ans = self
+            .buf
+            .get(self.pos..)
+            .and_then(|b| b.get(..n))
+            .ok_or_else(|| invalid("unexpected end of gguf file".into()))?;
+        self.pos += n;
+        Ok(ans)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], FileLoadError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, FileLoadError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, FileLoadError> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    fn string(&mut self) -> Result<String, FileLoadError> {
+        let len = self.u64()? as usize;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| invalid(e.to_string()))
+    }
+
+    fn value(&mut self, ty: u32) -> Result<MetaValue, FileLoadError> {
+        use MetaValue::*;
+        Ok(match ty {
+            0 => UInt(self.array::<1>()?[0] as _),
+            1 => Int(self.array::<1>()?[0] as i8 as _),
+            2 => UInt(u16::from_le_bytes(self.array()?) as _),
+            3 => Int(i16::from_le_bytes(self.array()?) as _),
+            4 => UInt(self.u32()? as _),
+            5 => Int(i32::from_le_bytes(self.array()?) as _),
+            6 => Float(f32::from_le_bytes(self.array()?) as _),
+            7 => Bool(self.array::<1>()?[0] != 0),
+            8 => String(self.string()?),
+            9 => {
+                let ty = self.u32()?;
+                let len = self.u64()?;
+                Array((0..len).map(|_| self.value(ty)).collect::<Result<_, _>>()?)
+            }
+            10 => UInt(self.u64()?),
+            11 => Int(i64::from_le_bytes(self.array()?)),
+            12 => Float(f64::from_le_bytes(self.array()?)),
+            _ => return Err(invalid(format!("unknown gguf value type: {ty}"))),
+        })
+    }
+}
+
+#[test]
+fn test_gguf() {
+    // 手工构造一个包含元数据和两个张量的文件
+    fn string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&3u32.to_le_bytes());
+    buf.extend_from_slice(&2u64.to_le_bytes());
+    buf.extend_from_slice(&3u64.to_le_bytes());
+    string(&mut buf, "general.architecture");
+    buf.extend_from_slice(&8u32.to_le_bytes());
+    string(&mut buf, "llama");
+    string(&mut buf, "llama.rope.freq_base");
+    buf.extend_from_slice(&6u32.to_le_bytes());
+    buf.extend_from_slice(&10000f32.to_le_bytes());
+    string(&mut buf, "tokenizer.ggml.tokens");
+    buf.extend_from_slice(&9u32.to_le_bytes());
+    buf.extend_from_slice(&8u32.to_le_bytes());
+    buf.extend_from_slice(&2u64.to_le_bytes());
+    string(&mut buf, "<s>");
+    string(&mut buf, "</s>");
+    // q8_0 的 [2, 32] 和 f32 的 [2]
+    string(&mut buf, "w");
+    buf.extend_from_slice(&2u32.to_le_bytes());
+    buf.extend_from_slice(&32u64.to_le_bytes());
+    buf.extend_from_slice(&2u64.to_le_bytes());
+    buf.extend_from_slice(&GGmlType::Q8_0.0.to_le_bytes());
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    string(&mut buf, "b");
+    buf.extend_from_slice(&1u32.to_le_bytes());
+    buf.extend_from_slice(&2u64.to_le_bytes());
+    buf.extend_from_slice(&GGmlType::F32.0.to_le_bytes());
+    buf.extend_from_slice(&(2 * 34u64).to_le_bytes());
+    buf.resize(buf.len().div_ceil(32) * 32, 0);
+    for row in 0..2 {
+        buf.extend_from_slice(&f16::from_f32(0.5 * (row + 1) as f32).to_le_bytes());
+        buf.extend((0..32).map(|i| (i as i8 - 16) as u8));
+    }
+    buf.extend_from_slice(&1.5f32.to_le_bytes());
+    buf.extend_from_slice(&(-2f32).to_le_bytes());
+
+    let dir = std::env::temp_dir().join(format!("gguf-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("model.gguf");
+    std::fs::write(&path, &buf).unwrap();
+    assert_eq!(find(&dir).unwrap(), Some(path.clone()));
+    let gguf = GGuf::load(&path).unwrap();
+    assert_eq!(gguf.version(), 3);
+    assert_eq!(
+        gguf.get("general.architecture").and_then(MetaValue::as_str),
+        Some("llama")
+    );
+    assert_eq!(
+        gguf.get("llama.rope.freq_base").and_then(MetaValue::as_f64),
+        Some(10000.)
+    );
+    let tokens = gguf
+        .get("tokenizer.ggml.tokens")
+        .unwrap()
+        .as_array()
+        .unwrap();
+    assert_eq!(tokens[1].as_str(), Some("</s>"));
+
+    let (w, _) = gguf.tensor("w").unwrap();
+    assert_eq!(w.shape(), [2, 32]);
+    assert!(w.ty.is_quantized());
+    let w = gguf.dequantize("w").unwrap();
+    assert_eq!(w[0], -8.);
+    assert_eq!(w[32 + 31], 15.);
+    assert_eq!(gguf.dequantize("b").unwrap(), [1.5, -2.]);
+    assert!(gguf.tensor("c").is_none());
+
+    // 截断的文件
+    std::fs::write(&path, &buf[..buf.len() - 4]).unwrap();
+    assert!(GGuf::load(&path).is_err());
+    std::fs::write(dir.join("other.gguf"), []).unwrap();
+    assert!(find(&dir).is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+
+    // q4_0 的低 4 位是前半块，高 4 位是后半块
+    let mut block = f16::from_f32(2.).to_le_bytes().to_vec();
+    block.extend((0..16).map(|i| (15 - i) << 4 | i));
+    let mut out = [0.; 32];
+    GGmlType::Q4_0.dequantize(&block, &mut out);
+    assert_eq!(out[0], -16.);
+    assert_eq!(out[15], 14.);
+    assert_eq!(out[16], 14.);
+}
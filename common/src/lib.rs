@@ -10,13 +10,22 @@ pub type utok = u32;
 #[allow(non_camel_case_types)]
 pub type upos = u32;
 
+mod architecture;
 mod between_f32;
 mod blob;
+pub mod checksum;
+pub mod fp8;
+mod generation_config;
+pub mod gguf;
+pub mod profiler;
+pub mod quant;
 pub mod safe_tensors;
 pub mod test_model;
 
+pub use architecture::{Architecture, Supported};
 pub use between_f32::BetweenF32;
-pub use blob::Blob;
+pub use blob::{Blob, BlobOptions, HugePages};
+pub use generation_config::GenerationConfig;
 pub use half::{bf16, f16};
 
 /// 加载 safetensors 文件可能产生的错误。
@@ -0,0 +1,189 @@
+//! 按层、按算子统计前向传播耗时的性能分析器。
+//!
+//! 分析器默认关闭，关闭时计时器不读取时钟也不加锁。
+//! 计时以主机时钟为准，异步执行的后端需要在每个阶段后同步才能得到准确的结果。
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PROFILE: Mutex<Profile> = Mutex::new(Profile {
+    stages: Vec::new(),
+    layers: Vec::new(),
+});
+
+/// 启用或关闭性能分析。
+#[inline]
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Relaxed);
+}
+
+/// 性能分析是否启用。
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Relaxed)
+}
+
+/// 取出至今为止的统计结果并清空。
+#[inline]
+pub fn take() -> Profile {
+    std::mem::take(&mut *PROFILE.lock().unwrap())
+}
+
+/// 一层内各阶段的计时器。
+pub struct Timer {
+    layer: usize,
+    last: Option<Instant>,
+}
+
+impl Timer {
+    /// 开始为第 `layer` 层计时，分析器关闭时不计时。
+    #[inline]
+    pub fn start(layer: usize) -> Self {
+        Self {
+            layer,
+            last: if enabled() {
+                Some(Instant::now())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// 将上次计时以来的时间记入阶段 `stage`，并重新开始计时。
+    #[inline]
+    pub fn lap(&mut self, stage: &'static str) {
+        if let Some(last) = self.last {
+            let now = Instant::now();
+            PROFILE
+                .lock()
+                .unwrap()
+                .record(self.layer, stage, now - last);
+            self.last = Some(now);
+        }
+    }
+}
+
+/// 一个阶段的统计。
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Stat {
+    /// 执行次数。
+    pub calls: usize,
+    /// 总耗时。
+    pub time: Duration,
+}
+
+/// 各层各阶段的统计结果。
+#[derive(Clone, Default, Debug)]
+pub struct Profile {
+    /// 按首次出现的顺序排列的阶段名。
+    pub stages: Vec<&'static str>,
+    /// 每层各阶段的统计，与 `stages` 一一对应。
+    pub layers: Vec<Vec<Stat>>,
+}
+
+impl Profile {
+    fn record(&mut self, layer: usize, stage: &'static str, time: Duration) {
+        let i = match self.stages.iter().position(|s| *s == stage) {
+            Some(i) => i,
+            None => {
+                self.stages.push(stage);
+                self.stages.len() - 1
+            }
+        };
+        if self.layers.len() <= layer {
+            self.layers.resize_with(layer + 1, Vec::new);
+        }
+        let stats = &mut self.layers[layer];
+        if stats.len() <= i {
+            stats.resize(i + 1, Stat::default());
+        }
+        stats[i].calls += 1;
+        stats[i].time += time;
+    }
+
+    /// 第 `layer` 层第 `stage` 个阶段的统计。
+    #[inline]
+    pub fn get(&self, layer: usize, stage: usize) -> Stat {
+        self.layers
+            .get(layer)
+            .and_then(|stats| stats.get(stage))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// 生成火焰图工具接受的折叠栈格式，每行一个阶段，数值为微秒。
+    pub fn folded(&self) -> String {
+        let mut ans = String::new();
+        for (layer, stats) in self.layers.iter().enumerate() {
+            for (stage, stat) in self.stages.iter().zip(stats) {
+                if stat.calls > 0 {
+                    ans.push_str(&format!(
+                        "forward;layer{layer};{stage} {}\n",
+                        stat.time.as_micros()
+                    ));
+                }
+            }
+        }
+        ans
+    }
+}
+
+/// 以表格显示各层各阶段的总耗时，单位为毫秒。
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const WIDTH: usize = 12;
+        let ms = |d: Duration| d.as_secs_f64() * 1e3;
+
+        write!(f, "{:>8}", "layer")?;
+        for stage in &self.stages {
+            write!(f, "{stage:>WIDTH$}")?;
+        }
+        writeln!(f, "{:>WIDTH$}", "total")?;
+
+        let mut sum = vec![Duration::ZERO; self.stages.len()];
+        for layer in 0..self.layers.len() {
+            write!(f, "{layer:>8}")?;
+            let mut total = Duration::ZERO;
+            for (i, sum) in sum.iter_mut().enumerate() {
+                let time = self.get(layer, i).time;
+                total += time;
+                *sum += time;
+                write!(f, "{:>WIDTH$.3}", ms(time))?;
+            }
+            writeln!(f, "{:>WIDTH$.3}", ms(total))?;
+        }
+
+        write!(f, "{:>8}", "total")?;
+        for time in &sum {
+            write!(f, "{:>WIDTH$.3}", ms(*time))?;
+        }
+        writeln!(f, "{:>WIDTH$.3}", ms(sum.iter().sum()))
+    }
+}
+
+#[test]
+fn test_profile() {
+    let mut profile = Profile::default();
+    profile.record(0, "qkv", Duration::from_micros(10));
+    profile.record(1, "qkv", Duration::from_micros(20));
+    profile.record(1, "mlp", Duration::from_micros(30));
+    profile.record(1, "mlp", Duration::from_micros(30));
+
+    assert_eq!(profile.stages, ["qkv", "mlp"]);
+    assert_eq!(profile.get(1, 1).calls, 2);
+    assert_eq!(profile.get(0, 1).calls, 0);
+    assert_eq!(
+        profile.folded(),
+        "forward;layer0;qkv 10\nforward;layer1;qkv 20\nforward;layer1;mlp 60\n"
+    );
+    let table = profile.to_string();
+    assert_eq!(table.lines().count(), 4);
+    assert!(table.lines().last().unwrap().ends_with("0.090"));
+}
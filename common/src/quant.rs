@@ -0,0 +1,117 @@
+//! 分块量化的整数权重，只用于存储投影矩阵，矩阵乘时逐行反量化。
+//!
+//! 块的格式与 GGUF 的 `Q4_0`、`Q4_1` 和 `Q8_0` 相同，每 [`BLOCK`] 个元素共享一个 f16 的缩放，`Q4_1` 还有一个 f16 的最小值。
+//! 分块量化的矩阵以字节为元素存储，形状为 `[行数, 每行的字节数]`，每行由若干个块组成；
+//! 布局只标记量化的格式，每个元素是一个字节，不能逐元素转换。
+
+use crate::{f16, gguf::GGmlType};
+use digit_layout::DigitLayout;
+
+/// 每块 32 个 8 位整数和一个缩放，共 34 字节。
+pub const Q8_0: DigitLayout = DigitLayout::new(1, false, 8, 0);
+/// 每块 32 个 4 位整数和一个缩放，共 18 字节。
+pub const Q4_0: DigitLayout = DigitLayout::new(2, false, 4, 0);
+/// 每块 32 个 4 位无符号整数、一个缩放和一个最小值，共 20 字节；GPTQ 和 AWQ 的 4 位权重重新打包为这个格式。
+pub const Q4_1: DigitLayout = DigitLayout::new(2, false, 0, 4);
+/// 每块的元素数。
+pub const BLOCK: usize = 32;
+
+/// 每块的字节数，不是分块量化的布局返回 `None`。
+pub fn block_bytes(dt: DigitLayout) -> Option<usize> {
+    match dt {
+        Q8_0 => Some(34),
+        Q4_0 => Some(18),
+        Q4_1 => Some(20),
+        _ => None,
+    }
+}
+
+/// `k` 个元素的一行量化后的字节数，`k` 不是块的整数倍或不是分块量化的布局时返回 `None`。
+pub fn row_bytes(dt: DigitLayout, k: usize) -> Option<usize> {
+    let bytes = block_bytes(dt)?;
+    k.is_multiple_of(BLOCK).then_some(k / BLOCK * bytes)
+}
+
+/// 将一行量化到 `dst`，`dst` 的长度必须是 [`row_bytes`]。
+pub fn quantize_row(dt: DigitLayout, x: &[f32], dst: &mut [u8]) {
+    assert_eq!(Some(dst.len()), row_bytes(dt, x.len()));
+    let bytes = block_bytes(dt).unwrap();
+    for (x, b) in x.chunks_exact(BLOCK).zip(dst.chunks_exact_mut(bytes)) {
+        match dt {
+            Q8_0 => {
+                let amax = x.iter().fold(0f32, |m, x| m.max(x.abs()));
+                let d = amax / 127.;
+                let id = if d == 0. { 0. } else { d.recip() };
+                b[..2].copy_from_slice(&f16::from_f32(d).to_le_bytes());
+                for (q, x) in b[2..].iter_mut().zip(x) {
+                    *q = (x * id).round() as i8 as u8;
+                }
+            }
+            Q4_0 => {
+                // 绝对值最大的元素量化为 -8，其他元素按同一个缩放
+                let max = x
+                    .iter()
+                    .fold(0f32, |m, &x| if x.abs() > m.abs() { x } else { m });
+                let d = max / -8.;
+                let id = if d == 0. { 0. } else { d.recip() };
+                b[..2].copy_from_slice(&f16::from_f32(d).to_le_bytes());
+                let q = |x: f32| ((x * id + 8.5) as u8).min(15);
+                for (j, q_) in b[2..].iter_mut().enumerate() {
+                    *q_ = q(x[j]) | q(x[j + 16]) << 4;
+                }
+            }
+            Q4_1 => {
+                let (min, max) = x
+                    .iter()
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(a, b), &x| {
+                        (a.min(x), b.max(x))
+                    });
+                let d = (max - min) / 15.;
+                let id = if d == 0. { 0. } else { d.recip() };
+                b[..2].copy_from_slice(&f16::from_f32(d).to_le_bytes());
+                b[2..4].copy_from_slice(&f16::from_f32(min).to_le_bytes());
+                let q = |x: f32| (((x - min) * id + 0.5) as u8).min(15);
+                for (j, q_) in b[4..].iter_mut().enumerate() {
+                    *q_ = q(x[j]) | q(x[j + 16]) << 4;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// 将一行反量化到 `y`，`src` 的长度必须是 [`row_bytes`]。
+pub fn dequantize_row(dt: DigitLayout, src: &[u8], y: &mut [f32]) {
+    assert_eq!(Some(src.len()), row_bytes(dt, y.len()));
+    let ty = match dt {
+        Q8_0 => GGmlType::Q8_0,
+        Q4_0 => GGmlType::Q4_0,
+        Q4_1 => GGmlType::Q4_1,
+        _ => unreachable!(),
+    };
+    ty.dequantize(src, y)
+}
+
+#[test]
+fn test_quantize() {
+    let x = (0..2 * BLOCK)
+        .map(|i| (i as f32 * 0.37).sin() * (1 + i / BLOCK) as f32)
+        .collect::<Vec<_>>();
+    for (dt, tolerance) in [(Q8_0, 1. / 127.), (Q4_0, 1. / 7.), (Q4_1, 1. / 15.)] {
+        let mut q = vec![0; row_bytes(dt, x.len()).unwrap()];
+        quantize_row(dt, &x, &mut q);
+        let mut y = vec![0.; x.len()];
+        dequantize_row(dt, &q, &mut y);
+        // 误差不超过每块最大绝对值的一个量化步长
+        for (x, y) in x.chunks_exact(BLOCK).zip(y.chunks_exact(BLOCK)) {
+            let amax = x.iter().fold(0f32, |m, x| m.max(x.abs()));
+            assert!(x
+                .iter()
+                .zip(y)
+                .all(|(x, y)| (x - y).abs() <= amax * tolerance));
+        }
+    }
+    assert_eq!(row_bytes(Q4_0, 64), Some(36));
+    assert_eq!(row_bytes(Q8_0, 48), None);
+    assert_eq!(block_bytes(digit_layout::types::U8), None);
+}
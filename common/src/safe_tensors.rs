@@ -63,7 +63,7 @@ impl SafeTensors {
     /// 加载单个 `.safetensors` 文件。
     pub fn single_file(path: impl AsRef<Path>) -> Result<Self, FileLoadError> {
         let file = File::open(path).map_err(Io)?;
-        let file = unsafe { Mmap::map(&file) }.map_err(Io)?;
+        let file = map_weights(&file)?;
         let header = load_header(&file)?;
         Ok(Self {
             tensors: header
@@ -104,7 +104,7 @@ impl SafeTensors {
                 Entry::Vacant(e) => {
                     // 打开文件
                     let file = File::open(dir.join(e.key())).map_err(Io)?;
-                    let file = unsafe { Mmap::map(&file) }.map_err(Io)?;
+                    let file = map_weights(&file)?;
                     let header = load_header(&file)?;
                     // 迭代文件中的张量
                     let i = files.len();
@@ -285,6 +285,16 @@ pub struct SafeTensorsHeaderMetadata {
     pub format: String,
 }
 
+/// 映射权重文件，按 [`crate::BlobOptions`] 锁定在内存中。
+pub(crate) fn map_weights(file: &File) -> Result<Mmap, FileLoadError> {
+    let map = unsafe { Mmap::map(file) }.map_err(Io)?;
+    #[cfg(unix)]
+    if crate::BlobOptions::global().mlock {
+        map.lock().map_err(Io)?;
+    }
+    Ok(map)
+}
+
 fn load_header(file: &Mmap) -> Result<SafeTensorsHeader, FileLoadError> {
     let header_len = unsafe { *file.as_ptr().cast::<u64>() };
     let header = &file[size_of_val(&header_len)..][..header_len as _];
@@ -0,0 +1,12 @@
+fn main() {
+    // 启用 `blas` 时链接系统的 BLAS，默认是 OpenBLAS，使用 MKL 时设为 `mkl_rt`
+    if std::env::var_os("CARGO_FEATURE_BLAS").is_some() {
+        println!("cargo:rerun-if-env-changed=INFINILM_BLAS_LIB");
+        println!("cargo:rerun-if-env-changed=INFINILM_BLAS_LIB_DIR");
+        if let Ok(dir) = std::env::var("INFINILM_BLAS_LIB_DIR") {
+            println!("cargo:rustc-link-search=native={dir}");
+        }
+        let lib = std::env::var("INFINILM_BLAS_LIB").unwrap_or_else(|_| "openblas".into());
+        println!("cargo:rustc-link-lib={lib}");
+    }
+}
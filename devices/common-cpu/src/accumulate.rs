@@ -0,0 +1,245 @@
+//! 以 f32 累加的 f16 算子，参见 [`Accumulation`]。
+//!
+//! 内置的算子对 f16 的张量以 f16 累加，长序列上平方和、指数和与内积的舍入误差随长度增长。
+
+use crate::provider::{Dst, Src};
+use common::f16;
+use digit_layout::{
+    types::{F16, F32},
+    DigitLayout,
+};
+use std::iter::zip;
+use tensor::{idim, udim};
+
+/// 选择以 f32 累加的算子，未选择的算子由外部或内置的算子计算。
+///
+/// 只影响 f16 的张量。以 f32 累加的实现是单线程的朴素循环，选择 `attention` 时长上下文的注意力明显变慢。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct Accumulation {
+    /// 归一化的平方和。
+    pub rms_norm: bool,
+    /// softmax 的指数和。
+    pub softmax: bool,
+    /// 注意力中 q·k 和 att·v 的内积，只作用于 [`attention_mat_mul`](crate::KernelsA::attention_mat_mul)，
+    /// 其他三维的矩阵乘不受影响。
+    pub attention: bool,
+}
+
+impl Accumulation {
+    /// 所有可选的算子都以 f32 累加。
+    pub const ALL: Self = Self {
+        rms_norm: true,
+        softmax: true,
+        attention: true,
+    };
+}
+
+/// 以 f32 累加的归一化，`y` 和 `x` 可以是同一块存储。
+///
+/// 只支持二维的 f16 张量，`w` 为 f16 或 f32；不支持的参数返回 `false`。
+pub(crate) fn rms_norm(y: Dst, x: Src, w: Src, epsilon: f32) -> bool {
+    let &[n, d] = y.shape else {
+        return false;
+    };
+    if y.dt != F16 || x.dt != F16 || x.shape != y.shape || w.shape != [d] {
+        return false;
+    }
+    let Some(w) = (0..d)
+        .map(|j| unsafe { read(w.dt, w.base, j as isize * w.strides[0] as isize) })
+        .collect::<Option<Vec<_>>>()
+    else {
+        return false;
+    };
+    let (yp, xp) = (y.base.cast::<f16>(), x.base.cast::<f16>());
+    for i in 0..n {
+        let x = |j: udim| unsafe { xp.offset(offset(x.strides, &[i, j])).read().to_f32() };
+        let sum = (0..d).map(|j| x(j) * x(j)).sum::<f32>();
+        let k = (sum / d as f32 + epsilon).sqrt().recip();
+        for (j, w) in zip(0..d, &w) {
+            let val = f16::from_f32(x(j) * k * w);
+            unsafe { yp.offset(offset(y.strides, &[i, j])).write(val) };
+        }
+    }
+    true
+}
+
+/// 以 f32 累加的因果 softmax，与内置的算子相同，第 `i` 个查询只看到前 `att_len - seq_len + i + 1` 个位置。
+///
+/// 只支持形状为 `[nh, seq_len, att_len]` 的 f16 张量；不支持的参数返回 `false`。
+pub(crate) fn softmax(att: Dst) -> bool {
+    let &[nh, seq_len, att_len] = att.shape else {
+        return false;
+    };
+    if att.dt != F16 || seq_len > att_len {
+        return false;
+    }
+    let p = att.base.cast::<f16>();
+    let mut row = vec![0f32; att_len as usize];
+    for h in 0..nh {
+        for i in 0..seq_len {
+            let at = |j: udim| unsafe { p.offset(offset(att.strides, &[h, i, j])) };
+            let visible = (att_len - seq_len + i + 1) as usize;
+            let row = &mut row[..visible];
+            for (j, r) in row.iter_mut().enumerate() {
+                *r = unsafe { at(j as _).read() }.to_f32();
+            }
+            let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let mut sum = 0.;
+            for r in row.iter_mut() {
+                *r = (*r - max).exp();
+                sum += *r;
+            }
+            for j in 0..att_len {
+                let val = row.get(j as usize).map_or(0., |r| r / sum);
+                unsafe { at(j).write(f16::from_f32(val)) };
+            }
+        }
+    }
+    true
+}
+
+/// 以 f32 累加的批量矩阵乘 `c = beta * c + alpha * a · b`，只由注意力的矩阵乘调用。
+///
+/// 只支持三维、批大小相同的 f16 张量；不支持的参数返回 `false`。
+pub(crate) fn mat_mul(c: Dst, beta: f32, a: Src, b: Src, alpha: f32) -> bool {
+    let (&[batch, m, n], &[batch_a, m_, k], &[batch_b, k_, n_]) = (c.shape, a.shape, b.shape)
+    else {
+        return false;
+    };
+    if [c.dt, a.dt, b.dt] != [F16; 3]
+        || [batch_a, batch_b] != [batch; 2]
+        || m != m_
+        || n != n_
+        || k != k_
+    {
+        return false;
+    }
+    let (cp, ap, bp) = (
+        c.base.cast::<f16>(),
+        a.base.cast::<f16>(),
+        b.base.cast::<f16>(),
+    );
+    for l in 0..batch {
+        for i in 0..m {
+            for j in 0..n {
+                let dot = (0..k)
+                    .map(|kk| unsafe {
+                        let a = ap.offset(offset(a.strides, &[l, i, kk])).read();
+                        let b = bp.offset(offset(b.strides, &[l, kk, j])).read();
+                        a.to_f32() * b.to_f32()
+                    })
+                    .sum::<f32>();
+                let c = unsafe { cp.offset(offset(c.strides, &[l, i, j])) };
+                // beta 为 0 时不读取 c，其中可能是未初始化的值
+                let prev = if beta == 0. {
+                    0.
+                } else {
+                    beta * unsafe { c.read() }.to_f32()
+                };
+                unsafe { c.write(f16::from_f32(prev + alpha * dot)) };
+            }
+        }
+    }
+    true
+}
+
+#[inline]
+fn offset(strides: &[idim], idx: &[udim]) -> isize {
+    zip(strides, idx)
+        .map(|(&s, &i)| s as isize * i as isize)
+        .sum()
+}
+
+unsafe fn read(dt: DigitLayout, base: *const u8, offset: isize) -> Option<f32> {
+    match dt {
+        F16 => Some(base.cast::<f16>().offset(offset).read().to_f32()),
+        F32 => Some(base.cast::<f32>().offset(offset).read()),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_numerics() {
+    use tensor::{reslice, reslice_mut, Tensor};
+
+    fn att_tensor(att: &mut [f16]) -> Tensor<&mut [u8]> {
+        let att_len = att.len() as _;
+        Tensor::new(F16, &[1, 1, att_len], reslice_mut(att))
+    }
+    let max_err = |x: &[f16], reference: &[f64]| {
+        zip(x, reference)
+            .map(|(x, r)| (x.to_f64() - r).abs())
+            .fold(0., f64::max)
+    };
+
+    // 长上下文的注意力：一个查询与 4096 个位置的内积
+    let (dh, att_len) = (128u32, 4096u32);
+    let q = (0..dh)
+        .map(|i| f16::from_f32((i as f32 * 0.37).sin()))
+        .collect::<Vec<_>>();
+    let k = (0..dh * att_len)
+        .map(|i| f16::from_f32((i as f32 * 0.113).cos() * 0.5))
+        .collect::<Vec<_>>();
+    let scale = (dh as f32).sqrt().recip();
+    let (q_, k_) = (&q, &k);
+    let dot = move |j: u32| (0..dh).map(move |i| (q_[i as usize], k_[(j * dh + i) as usize]));
+
+    let reference = (0..att_len)
+        .map(|j| dot(j).map(|(q, k)| q.to_f64() * k.to_f64()).sum::<f64>() * scale as f64)
+        .collect::<Vec<_>>();
+    let f16_acc = (0..att_len)
+        .map(|j| dot(j).fold(f16::ZERO, |acc, (q, k)| acc + q * k) * f16::from_f32(scale))
+        .collect::<Vec<_>>();
+    let mut att = vec![f16::ZERO; att_len as usize];
+    let a = Tensor::new(F16, &[1, 1, dh], reslice::<f16, u8>(&q));
+    let b = Tensor::new(F16, &[1, att_len, dh], reslice::<f16, u8>(&k)).transpose(&[0, 2, 1]);
+    assert!(mat_mul(
+        Dst::of(&mut att_tensor(&mut att)),
+        0.,
+        Src::of(&a),
+        Src::of(&b),
+        scale
+    ));
+    let (err16, err32) = (max_err(&f16_acc, &reference), max_err(&att, &reference));
+    assert!(err32 < err16, "attention: f32 {err32} vs f16 {err16}");
+    // 只剩存储结果时 f16 的舍入误差
+    assert!(err32 < 4e-3, "attention: {err32}");
+
+    // 对这些分数做 softmax
+    let max = reference.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let exp = att
+        .iter()
+        .map(|x| (x.to_f64() - max).exp())
+        .collect::<Vec<_>>();
+    let sum = exp.iter().sum::<f64>();
+    let reference = exp.iter().map(|e| e / sum).collect::<Vec<_>>();
+    let max16 = att.iter().copied().fold(f16::NEG_INFINITY, f16::max);
+    let exp16 = att
+        .iter()
+        .map(|&x| f16::from_f32((x - max16).to_f32().exp()))
+        .collect::<Vec<_>>();
+    let sum16 = exp16.iter().fold(f16::ZERO, |acc, &e| acc + e);
+    let f16_acc = exp16.iter().map(|&e| e / sum16).collect::<Vec<_>>();
+    assert!(softmax(Dst::of(&mut att_tensor(&mut att))));
+    let (err16, err32) = (max_err(&f16_acc, &reference), max_err(&att, &reference));
+    assert!(err32 < err16, "softmax: f32 {err32} vs f16 {err16}");
+
+    // 归一化：f16 累加长向量的平方和会溢出
+    let d = 4096u32;
+    let x = (0..d)
+        .map(|i| f16::from_f32((i as f32 * 0.71).sin() * 8.))
+        .collect::<Vec<_>>();
+    assert!(x
+        .iter()
+        .fold(f16::ZERO, |acc, &x| acc + x * x)
+        .is_infinite());
+    let w = vec![f16::ONE; d as usize];
+    let mut y = vec![f16::ZERO; d as usize];
+    let xt = Tensor::new(F16, &[1, d], reslice::<f16, u8>(&x));
+    let wt = Tensor::new(F16, &[d], reslice::<f16, u8>(&w));
+    let mut yt = Tensor::new(F16, &[1, d], reslice_mut::<f16, u8>(&mut y));
+    assert!(rms_norm(Dst::of(&mut yt), Src::of(&xt), Src::of(&wt), 1e-5));
+    // 归一化后的均方为 1
+    let mean = y.iter().map(|y| y.to_f64().powi(2)).sum::<f64>() / d as f64;
+    assert!((mean - 1.).abs() < 1e-2, "rms_norm: {mean}");
+}
@@ -0,0 +1,108 @@
+//! 通过系统的 BLAS（OpenBLAS、MKL 等）计算大矩阵乘。
+//!
+//! 只接管 f32 的二维矩阵乘，即各层的投影和 lm_head；注意力中的批量矩阵乘和小矩阵仍由内置的算子计算。
+
+use crate::provider::{Dst, KernelProvider, Src};
+use digit_layout::types::F32;
+use std::ffi::c_int;
+use tensor::{idim, udim};
+
+/// 小于这个规模（`m * n * k`）的矩阵乘调用 BLAS 不划算。
+const MIN_WORK: usize = 1 << 20;
+
+const ROW_MAJOR: c_int = 101;
+const NO_TRANS: c_int = 111;
+const TRANS: c_int = 112;
+
+extern "C" {
+    #[allow(clippy::too_many_arguments)]
+    fn cblas_sgemm(
+        layout: c_int,
+        trans_a: c_int,
+        trans_b: c_int,
+        m: c_int,
+        n: c_int,
+        k: c_int,
+        alpha: f32,
+        a: *const f32,
+        lda: c_int,
+        b: *const f32,
+        ldb: c_int,
+        beta: f32,
+        c: *mut f32,
+        ldc: c_int,
+    );
+}
+
+/// 系统的 BLAS，名字是 `blas`。
+pub(crate) struct Blas;
+
+impl KernelProvider for Blas {
+    fn name(&self) -> &str {
+        "blas"
+    }
+
+    fn mat_mul(&self, c: Dst, beta: f32, a: Src, b: Src, alpha: f32) -> bool {
+        if [c.dt, a.dt, b.dt] != [F32; 3] {
+            return false;
+        }
+        let (&[m, n], &[m_, k], &[k_, n_]) = (c.shape, a.shape, b.shape) else {
+            return false;
+        };
+        if m != m_ || n != n_ || k != k_ || (m as usize * n as usize * k as usize) < MIN_WORK {
+            return false;
+        }
+        let (Some((NO_TRANS, ldc)), Some((ta, lda)), Some((tb, ldb))) = (
+            matrix(c.strides, m, n),
+            matrix(a.strides, m, k),
+            matrix(b.strides, k, n),
+        ) else {
+            return false;
+        };
+        unsafe {
+            cblas_sgemm(
+                ROW_MAJOR,
+                ta,
+                tb,
+                m as _,
+                n as _,
+                k as _,
+                alpha,
+                a.base.cast(),
+                lda,
+                b.base.cast(),
+                ldb,
+                beta,
+                c.base.cast(),
+                ldc,
+            )
+        };
+        true
+    }
+}
+
+/// `rows` 行 `cols` 列的矩阵相对行主序的转置方式和主维度，元素不能按行主序或列主序访问时返回 `None`。
+fn matrix(strides: &[idim], rows: udim, cols: udim) -> Option<(c_int, c_int)> {
+    let &[rs, cs] = strides else { return None };
+    let (rows, cols) = (rows as idim, cols as idim);
+    if cs == 1 && (rows == 1 || rs >= cols) {
+        Some((NO_TRANS, if rows == 1 { cols.max(1) } else { rs }))
+    } else if rs == 1 && (cols == 1 || cs >= rows) {
+        Some((TRANS, if cols == 1 { rows.max(1) } else { cs }))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_matrix() {
+    // 连续的行主序矩阵
+    assert_eq!(matrix(&[4, 1], 3, 4), Some((NO_TRANS, 4)));
+    // 转置的视图
+    assert_eq!(matrix(&[1, 3], 3, 4), Some((TRANS, 3)));
+    // 单行矩阵的行步长没有意义
+    assert_eq!(matrix(&[0, 1], 1, 4), Some((NO_TRANS, 4)));
+    // 广播或切片产生的跨步矩阵
+    assert_eq!(matrix(&[8, 2], 3, 4), None);
+    assert_eq!(matrix(&[2, 4], 3, 4), None);
+}
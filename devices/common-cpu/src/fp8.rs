@@ -0,0 +1,177 @@
+//! 以 8 位浮点数存储的权重的矩阵乘，参见 [`common::fp8`]。
+
+use crate::provider::{Dst, Src};
+use common::{f16, fp8::fp8_table, BetweenF32, Blob};
+use digit_layout::{
+    types::{F16, F32},
+    DigitLayout,
+};
+use std::{ops::Deref, thread};
+use tensor::{idim, udim, Tensor};
+
+/// 不超过这个行数的矩阵乘逐个读取 8 位的权重；更多的行逐块转换，再调用内置的算子。
+const FUSED_ROWS: udim = 8;
+
+/// 行数多时每次转换的权重列数，临时占用的内存不超过这么多列。
+pub(crate) const TILE: udim = 256;
+
+/// 计算 `c = beta * c + alpha * a · b`，`b` 以 8 位浮点数存储，读取时查表转换，各线程计算一段输出列。
+///
+/// 只支持二维矩阵，`a` 和 `c` 为相同的 f16 或 f32，且 `a` 不超过 [`FUSED_ROWS`] 行；
+/// 不支持的参数返回 `false`。
+pub(crate) fn mat_mul(c: Dst, beta: f32, a: Src, b: Src, alpha: f32) -> bool {
+    let Some(table) = fp8_table(b.dt) else {
+        return false;
+    };
+    let (&[m, n], &[m_, k], &[k_, n_]) = (c.shape, a.shape, b.shape) else {
+        return false;
+    };
+    if m != m_ || n != n_ || k != k_ || m > FUSED_ROWS || a.dt != c.dt {
+        return false;
+    }
+    match c.dt {
+        F16 => unsafe { fused::<f16>(c, beta, a, b, alpha, &table) },
+        F32 => unsafe { fused::<f32>(c, beta, a, b, alpha, &table) },
+        _ => return false,
+    }
+    true
+}
+
+/// 按列分块转换时二维的 8 位浮点数权重的列数；`b` 不是这样的权重或 `dt` 不是 f16 或 f32 时返回 `None`。
+pub(crate) fn columns<U>(b: &Tensor<U>, dt: DigitLayout) -> Option<udim> {
+    fp8_table(b.data_layout())?;
+    match (b.shape(), dt) {
+        (&[_, n], F16 | F32) => Some(n),
+        _ => None,
+    }
+}
+
+/// 将二维的 8 位浮点数 `b` 从第 `start` 列起的 `len` 列转换为 `dt`，返回 `[k, len]` 的转置视图。
+///
+/// 转换的结果按列连续存储，与模型投影矩阵的转置视图相同，逐列读取原来的存储。
+pub(crate) fn upcast<U>(b: &Tensor<U>, dt: DigitLayout, start: udim, len: udim) -> Tensor<Blob>
+where
+    U: Deref<Target = [u8]>,
+{
+    let table = fp8_table(b.data_layout()).unwrap();
+    let &[k, _] = b.shape() else { unreachable!() };
+    let &[s0, s1] = b.strides() else {
+        unreachable!()
+    };
+    let base = b.base();
+    let read = |kk: udim, j: udim| unsafe {
+        let offset = kk as isize * s0 as isize + (start + j) as isize * s1 as isize;
+        table[base.offset(offset).read() as usize]
+    };
+    let mut ans = Blob::new((k * len) as usize * dt.nbytes());
+    match dt {
+        F16 => typed::<f16>(&mut ans, k, read),
+        F32 => typed::<f32>(&mut ans, k, read),
+        _ => unreachable!(),
+    }
+    Tensor::new(dt, &[len, k], ans).transpose(&[1, 0])
+}
+
+fn typed<T: BetweenF32>(dst: &mut [u8], k: udim, read: impl Fn(udim, udim) -> f32) {
+    let dst = tensor::reslice_mut::<u8, T>(dst);
+    for (j, col) in dst.chunks_exact_mut(k as usize).enumerate() {
+        for (kk, d) in col.iter_mut().enumerate() {
+            *d = T::cast(read(kk as _, j as _));
+        }
+    }
+}
+
+unsafe fn fused<T: BetweenF32>(c: Dst, beta: f32, a: Src, b: Src, alpha: f32, table: &[f32; 256]) {
+    let (m, n, k) = (
+        c.shape[0] as usize,
+        c.shape[1] as usize,
+        a.shape[1] as usize,
+    );
+    let offset = |strides: &[idim], i: usize, j: usize| {
+        i as isize * strides[0] as isize + j as isize * strides[1] as isize
+    };
+    // 输入只有几行，先转换为 f32
+    let x = (0..m)
+        .flat_map(|i| (0..k).map(move |j| (i, j)))
+        .map(|(i, j)| {
+            a.base
+                .cast::<T>()
+                .offset(offset(a.strides, i, j))
+                .read()
+                .get()
+        })
+        .collect::<Vec<_>>();
+
+    // 裸指针不能跨线程，以地址传递；各线程写入不相交的列
+    let (c_base, b_base) = (c.base as usize, b.base as usize);
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(n);
+    let chunk = n.div_ceil(threads);
+    thread::scope(|s| {
+        for start in (0..n).step_by(chunk) {
+            let x = &x;
+            s.spawn(move || {
+                let w = b_base as *const u8;
+                let y = c_base as *mut T;
+                let mut acc = [0f32; FUSED_ROWS as usize];
+                for j in start..(start + chunk).min(n) {
+                    acc.fill(0.);
+                    for kk in 0..k {
+                        let w = table[w.offset(offset(b.strides, kk, j)).read() as usize];
+                        for (acc, x) in acc.iter_mut().zip(x.chunks_exact(k)) {
+                            *acc += x[kk] * w;
+                        }
+                    }
+                    for (i, &acc) in acc.iter().enumerate().take(m) {
+                        let y = y.offset(offset(c.strides, i, j));
+                        let old = if beta == 0. {
+                            0.
+                        } else {
+                            beta * y.read().get()
+                        };
+                        y.write(T::cast(old + alpha * acc));
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn test_mat_mul() {
+    use common::fp8::{f8e4m3, F8E4M3};
+    use tensor::{reslice, reslice_mut};
+
+    let (m, k, n) = (3, 16, 5);
+    let x = (0..m * k)
+        .map(|i| (i % 7) as f32 / 4. - 0.5)
+        .collect::<Vec<_>>();
+    // 权重按 `[n, k]` 存储，以转置的视图参与矩阵乘，与模型的投影矩阵相同
+    let w = (0..n * k)
+        .map(|i| f8e4m3::from_f32((i % 11) as f32 / 8. - 0.75))
+        .collect::<Vec<_>>();
+    let w_bytes = w.iter().map(|w| w.0).collect::<Vec<_>>();
+
+    let a = Tensor::new(F32, &[m as _, k as _], reslice::<f32, u8>(&x));
+    let b = Tensor::new(F8E4M3, &[n as _, k as _], &*w_bytes).transpose(&[1, 0]);
+    let mut y = vec![1f32; m * n];
+    let mut c = Tensor::new(F32, &[m as _, n as _], reslice_mut::<f32, u8>(&mut y));
+    assert!(mat_mul(Dst::of(&mut c), 0.5, Src::of(&a), Src::of(&b), 2.));
+
+    for i in 0..m {
+        for j in 0..n {
+            let dot = (0..k)
+                .map(|kk| x[i * k + kk] * w[j * k + kk].to_f32())
+                .sum::<f32>();
+            assert!((y[i * n + j] - (0.5 + 2. * dot)).abs() < 1e-4);
+        }
+    }
+
+    // 按列分块转换，每块是原来的权重的一段列
+    assert_eq!(columns(&b, F32), Some(n as _));
+    let up = upcast(&b, F32, 1, 3);
+    assert_eq!(up.shape(), [k as udim, 3]);
+    let up = reslice::<u8, f32>(up.physical());
+    assert!(up.iter().zip(&w[k..4 * k]).all(|(u, w)| *u == w.to_f32()));
+}
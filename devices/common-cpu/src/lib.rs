@@ -5,7 +5,14 @@ macro_rules! slice {
     };
 }
 
+mod accumulate;
+#[cfg(feature = "blas")]
+mod blas;
+mod fp8;
 mod gather;
+pub mod provider;
+mod quant;
+mod rotary;
 
 use common::utok;
 use common_devices::{Operators, SliceOn};
@@ -14,15 +21,124 @@ use operators::{
     reform::common_cpu as reform, rms_norm::common_cpu as rms_norm, rope::common_cpu as rope,
     swiglu::common_cpu as swiglu, Operator, QueueOf,
 };
-use std::ops::{Deref, DerefMut};
-use tensor::Tensor;
+use provider::{Dst, KernelProvider, Src};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+use tensor::{udim, SliceDim, Tensor};
 
 pub extern crate tensor;
 
+pub use accumulate::Accumulation;
 pub use common_devices::{Kernels, KernelsA, KernelsB};
 pub use operators::common_cpu::{Handle as Cpu, ThisThread};
 
+/// CPU 上的算子，优先调用运行时选择的外部算子库，参见 [`provider`]。
 pub struct CpuKernels {
+    builtin: Builtin,
+    provider: Option<Arc<dyn KernelProvider>>,
+    batch_invariant: bool,
+    accumulation: Accumulation,
+}
+
+impl Default for CpuKernels {
+    /// 使用环境变量指定的或最后注册的外部算子库。
+    #[inline]
+    fn default() -> Self {
+        Self::with_provider(provider::resolve())
+    }
+}
+
+impl CpuKernels {
+    /// 使用指定的外部算子库，`None` 表示只使用内置的算子。
+    #[inline]
+    pub fn with_provider(provider: Option<Arc<dyn KernelProvider>>) -> Self {
+        Self {
+            builtin: Default::default(),
+            provider,
+            batch_invariant: false,
+            accumulation: Default::default(),
+        }
+    }
+
+    /// 设置批不变模式：二维的矩阵乘逐行计算，每一行的累加顺序与同一批中的其他行无关。
+    ///
+    /// 否则批的行数会改变矩阵乘选择的算子和分块，同一个序列在不同的批中得到略有差异的结果。
+    /// 逐行计算不能在行之间复用读取的权重，预填充和大批量解码的矩阵乘明显变慢。
+    #[inline]
+    pub fn batch_invariant(mut self, on: bool) -> Self {
+        self.batch_invariant = on;
+        self
+    }
+
+    /// 选择以 f32 累加的算子，它们优先于外部和内置的算子，参见 [`Accumulation`]。
+    #[inline]
+    pub fn accumulation(mut self, accumulation: Accumulation) -> Self {
+        self.accumulation = accumulation;
+        self
+    }
+
+    /// 不拆分行的矩阵乘。
+    fn mat_mul_rows<T, U, V>(
+        &self,
+        c: &mut Tensor<T>,
+        beta: f32,
+        a: &Tensor<U>,
+        b: &Tensor<V>,
+        alpha: f32,
+        queue: &QueueOf<Cpu>,
+    ) where
+        T: DerefMut<Target = SliceOn<Cpu>>,
+        U: Deref<Target = SliceOn<Cpu>>,
+        V: Deref<Target = SliceOn<Cpu>>,
+    {
+        if let Some(p) = &self.provider {
+            if p.mat_mul(Dst::of(c), beta, Src::of(a), Src::of(b), alpha) {
+                return;
+            }
+        }
+        // 分块量化的权重没有整体转换的回退，总是逐行反量化
+        if quant::mat_mul(Dst::of(c), beta, Src::of(a), Src::of(b), alpha) {
+            return;
+        }
+        // 8 位浮点数的权重：行数少时逐个转换，否则逐块转换后使用内置的算子，不保留转换的结果
+        if fp8::mat_mul(Dst::of(c), beta, Src::of(a), Src::of(b), alpha) {
+            return;
+        }
+        if let Some(n) = fp8::columns(b, a.data_layout()) {
+            for start in (0..n).step_by(fp8::TILE as usize) {
+                let len = fp8::TILE.min(n - start);
+                let cols = [
+                    SliceDim {
+                        start: 0,
+                        step: 1,
+                        len: udim::MAX,
+                    },
+                    SliceDim {
+                        start,
+                        step: 1,
+                        len,
+                    },
+                ];
+                let mut c = c.as_mut().map_physical(|u| &mut **u).slice(&cols);
+                let b = fp8::upcast(b, a.data_layout(), start, len);
+                self.builtin.mat_mul(&mut c, beta, a, &b, alpha, queue);
+            }
+            return;
+        }
+        self.builtin.mat_mul(c, beta, a, b, alpha, queue)
+    }
+
+    /// 使用的外部算子库的名字。
+    #[inline]
+    pub fn provider_name(&self) -> Option<&str> {
+        self.provider.as_deref().map(KernelProvider::name)
+    }
+}
+
+/// 内置的算子。
+struct Builtin {
     reform: reform::Operator,
     mat_mul: mat_mul::Operator,
     rms_norm: rms_norm::Operator,
@@ -31,7 +147,7 @@ pub struct CpuKernels {
     swiglu: swiglu::Operator,
 }
 
-impl Default for CpuKernels {
+impl Default for Builtin {
     fn default() -> Self {
         Self {
             reform: reform::Operator::new(&Cpu),
@@ -46,7 +162,7 @@ impl Default for CpuKernels {
 
 impl Kernels<Cpu> for CpuKernels {}
 
-impl Operators for CpuKernels {
+impl Operators for Builtin {
     type Handle = Cpu;
 
     fn reform_op(
@@ -84,6 +200,171 @@ impl Operators for CpuKernels {
     }
 }
 
+impl KernelsA for CpuKernels {
+    type Handle = Cpu;
+
+    #[inline]
+    fn reform<T, U>(&self, dst: &mut Tensor<T>, src: &Tensor<U>, queue: &QueueOf<Self::Handle>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.builtin.reform(dst, src, queue)
+    }
+
+    fn rms_norm<T, U, V>(
+        &self,
+        y: &mut Tensor<T>,
+        x: &Tensor<U>,
+        w: &Tensor<V>,
+        epsilon: f32,
+        queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        if self.accumulation.rms_norm
+            && accumulate::rms_norm(Dst::of(y), Src::of(x), Src::of(w), epsilon)
+        {
+            return;
+        }
+        if let Some(p) = &self.provider {
+            if p.rms_norm(Dst::of(y), Src::of(x), Src::of(w), epsilon) {
+                return;
+            }
+        }
+        self.builtin.rms_norm(y, x, w, epsilon, queue)
+    }
+
+    fn rope<T, U>(
+        &self,
+        t: &mut Tensor<T>,
+        pos: &Tensor<U>,
+        theta: f32,
+        queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        if let Some(p) = &self.provider {
+            if p.rope(Dst::of(t), Src::of(pos), theta) {
+                return;
+            }
+        }
+        self.builtin.rope(t, pos, theta, queue)
+    }
+
+    fn rope_to<T, U, V>(
+        &self,
+        dst: &mut Tensor<T>,
+        src: &Tensor<U>,
+        pos: &Tensor<V>,
+        theta: f32,
+        queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        if let Some(p) = &self.provider {
+            if p.rope_to(Dst::of(dst), Src::of(src), Src::of(pos), theta) {
+                return;
+            }
+        }
+        if !rotary::rope_to(Dst::of(dst), Src::of(src), Src::of(pos), theta) {
+            self.builtin.rope_to(dst, src, pos, theta, queue)
+        }
+    }
+
+    fn mat_mul<T, U, V>(
+        &self,
+        c: &mut Tensor<T>,
+        beta: f32,
+        a: &Tensor<U>,
+        b: &Tensor<V>,
+        alpha: f32,
+        queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        match *c.shape() {
+            [m, _] if self.batch_invariant && m > 1 => {
+                for i in 0..m {
+                    // 本 crate 的 `slice!` 与 tensor 的同名宏冲突，直接构造切片
+                    let row = [
+                        SliceDim {
+                            start: i,
+                            step: 1,
+                            len: 1,
+                        },
+                        SliceDim {
+                            start: 0,
+                            step: 1,
+                            len: udim::MAX,
+                        },
+                    ];
+                    let mut c = c.as_mut().map_physical(|u| &mut **u).slice(&row);
+                    let a = a.as_ref().map_physical(|u| &**u).slice(&row);
+                    self.mat_mul_rows(&mut c, beta, &a, b, alpha, queue);
+                }
+            }
+            _ => self.mat_mul_rows(c, beta, a, b, alpha, queue),
+        }
+    }
+
+    fn attention_mat_mul<T, U, V>(
+        &self,
+        c: &mut Tensor<T>,
+        beta: f32,
+        a: &Tensor<U>,
+        b: &Tensor<V>,
+        alpha: f32,
+        queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        if self.accumulation.attention
+            && accumulate::mat_mul(Dst::of(c), beta, Src::of(a), Src::of(b), alpha)
+        {
+            return;
+        }
+        self.mat_mul(c, beta, a, b, alpha, queue)
+    }
+
+    fn softmax<T>(&self, att: &mut Tensor<T>, queue: &QueueOf<Self::Handle>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+        if self.accumulation.softmax && accumulate::softmax(Dst::of(att)) {
+            return;
+        }
+        if let Some(p) = &self.provider {
+            if p.softmax(Dst::of(att)) {
+                return;
+            }
+        }
+        self.builtin.softmax(att, queue)
+    }
+
+    fn swiglu<T, U>(&self, gate: &mut Tensor<T>, up: &Tensor<U>, queue: &QueueOf<Self::Handle>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        if let Some(p) = &self.provider {
+            if p.swiglu(Dst::of(gate), Src::of(up)) {
+                return;
+            }
+        }
+        self.builtin.swiglu(gate, up, queue)
+    }
+}
+
 impl KernelsB for CpuKernels {
     type Handle = Cpu;
 
@@ -101,3 +382,33 @@ impl KernelsB for CpuKernels {
         gather::gather(x, table, tokens);
     }
 }
+
+#[test]
+fn test_batch_invariant() {
+    use common::fp8::{f8e4m3, F8E4M3};
+    use digit_layout::types::F32;
+    use tensor::{reslice, reslice_mut};
+
+    let (m, k, n) = (3, 16, 5);
+    let x = (0..m * k)
+        .map(|i| (i % 7) as f32 / 4. - 0.5)
+        .collect::<Vec<_>>();
+    let w = (0..n * k)
+        .map(|i| f8e4m3::from_f32((i % 11) as f32 / 8. - 0.75).0)
+        .collect::<Vec<_>>();
+    let b = Tensor::new(F8E4M3, &[n as _, k as _], &*w).transpose(&[1, 0]);
+    let kernels = CpuKernels::with_provider(None).batch_invariant(true);
+
+    let a = Tensor::new(F32, &[m as _, k as _], reslice::<f32, u8>(&x));
+    let mut y = vec![0f32; m * n];
+    let mut c = Tensor::new(F32, &[m as _, n as _], reslice_mut::<f32, u8>(&mut y));
+    kernels.mat_mul(&mut c, 0., &a, &b, 1., &ThisThread);
+    // 每一行与单独计算的结果逐位相同
+    for i in 0..m {
+        let a = Tensor::new(F32, &[1, k as _], reslice::<f32, u8>(&x[i * k..][..k]));
+        let mut row = vec![0f32; n];
+        let mut c = Tensor::new(F32, &[1, n as _], reslice_mut::<f32, u8>(&mut row));
+        kernels.mat_mul(&mut c, 0., &a, &b, 1., &ThisThread);
+        assert_eq!(row, y[i * n..][..n]);
+    }
+}
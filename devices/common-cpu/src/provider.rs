@@ -0,0 +1,128 @@
+//! 运行时选择的外部算子库。
+//!
+//! 第三方库（MKL、BLIS、oneDNN 或厂商提供的库）实现 [`KernelProvider`] 并 [`register`]，
+//! 之后创建的 [`CpuKernels`](crate::CpuKernels) 优先调用它，不支持的参数回退到内置的算子。
+
+use digit_layout::DigitLayout;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, RwLock},
+};
+use tensor::{idim, udim, Tensor};
+
+/// 选择外部算子库的环境变量，值为 [`KernelProvider::name`]。
+pub const PROVIDER_ENV: &str = "INFINILM_CPU_KERNELS";
+
+static PROVIDERS: RwLock<Vec<Arc<dyn KernelProvider>>> = RwLock::new(Vec::new());
+
+/// 交给外部算子库的张量，步长以元素为单位，可以为负。
+#[derive(Clone, Copy, Debug)]
+pub struct TensorArg<'a, P> {
+    pub dt: DigitLayout,
+    pub shape: &'a [udim],
+    pub strides: &'a [idim],
+    pub base: P,
+}
+
+/// 只读的张量参数。
+pub type Src<'a> = TensorArg<'a, *const u8>;
+/// 写入结果的张量参数。
+pub type Dst<'a> = TensorArg<'a, *mut u8>;
+
+impl<'a> Src<'a> {
+    #[inline]
+    pub(crate) fn of<T: Deref<Target = [u8]>>(t: &'a Tensor<T>) -> Self {
+        Self {
+            dt: t.data_layout(),
+            shape: t.shape(),
+            strides: t.strides(),
+            base: t.base(),
+        }
+    }
+}
+
+impl<'a> Dst<'a> {
+    #[inline]
+    pub(crate) fn of<T: DerefMut<Target = [u8]>>(t: &'a mut Tensor<T>) -> Self {
+        let base = t.base_mut();
+        Self {
+            dt: t.data_layout(),
+            shape: t.shape(),
+            strides: t.strides(),
+            base,
+        }
+    }
+}
+
+/// 外部算子库，参数的含义与 [`KernelsA`](crate::KernelsA) 中的同名算子相同。
+///
+/// 每个算子返回 `false` 表示不支持这组参数（数据类型、布局等），由内置的算子计算；默认都不支持。
+/// 实现只能在参数描述的范围内读写。
+pub trait KernelProvider: Send + Sync {
+    /// 库的名字，用于 [`PROVIDER_ENV`] 选择。
+    fn name(&self) -> &str;
+
+    fn mat_mul(&self, _c: Dst, _beta: f32, _a: Src, _b: Src, _alpha: f32) -> bool {
+        false
+    }
+    fn rms_norm(&self, _y: Dst, _x: Src, _w: Src, _epsilon: f32) -> bool {
+        false
+    }
+    fn rope(&self, _t: Dst, _pos: Src, _theta: f32) -> bool {
+        false
+    }
+    fn rope_to(&self, _dst: Dst, _src: Src, _pos: Src, _theta: f32) -> bool {
+        false
+    }
+    fn softmax(&self, _att: Dst) -> bool {
+        false
+    }
+    fn swiglu(&self, _gate: Dst, _up: Src) -> bool {
+        false
+    }
+}
+
+/// 注册外部算子库，同名的库被替换。
+pub fn register(provider: Arc<dyn KernelProvider>) {
+    let mut providers = PROVIDERS.write().unwrap();
+    providers.retain(|p| p.name() != provider.name());
+    providers.push(provider);
+}
+
+/// 已注册的外部算子库的名字。
+pub fn registered() -> Vec<String> {
+    PROVIDERS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|p| p.name().into())
+        .collect()
+}
+
+/// 选择 [`PROVIDER_ENV`] 指定的库，未指定时选择最后注册的库；指定为 `builtin` 时不使用外部库。
+pub(crate) fn resolve() -> Option<Arc<dyn KernelProvider>> {
+    // 启用 `blas` 时系统的 BLAS 排在最前，优先级低于其他注册的库
+    #[cfg(feature = "blas")]
+    {
+        static BLAS: std::sync::Once = std::sync::Once::new();
+        BLAS.call_once(|| {
+            PROVIDERS
+                .write()
+                .unwrap()
+                .insert(0, Arc::new(crate::blas::Blas))
+        });
+    }
+
+    let providers = PROVIDERS.read().unwrap();
+    match std::env::var(PROVIDER_ENV) {
+        Ok(name) if name == "builtin" => None,
+        Ok(name) => {
+            let found = providers.iter().find(|p| p.name() == name).cloned();
+            if found.is_none() {
+                log::warn!("{PROVIDER_ENV}: kernel provider \"{name}\" is not registered");
+            }
+            found
+        }
+        Err(_) => providers.last().cloned(),
+    }
+}
@@ -0,0 +1,125 @@
+//! 分块量化的权重的矩阵乘，参见 [`common::quant`]。
+
+use crate::provider::{Dst, Src};
+use common::{
+    f16,
+    quant::{dequantize_row, row_bytes},
+    BetweenF32,
+};
+use digit_layout::types::{F16, F32};
+use std::thread;
+use tensor::idim;
+
+/// 计算 `c = beta * c + alpha * a · b`，`b` 是分块量化的矩阵的转置视图，各线程计算一段输出列。
+///
+/// `b` 的形状为 `[每行的字节数, n]`，每一列是量化矩阵连续存储的一行，读取时逐列反量化，
+/// 反量化的一列与 `a` 的所有行相乘，预填充时不重复反量化。
+/// 只支持二维矩阵，`a` 和 `c` 为相同的 f16 或 f32；不支持的参数返回 `false`。
+pub(crate) fn mat_mul(c: Dst, beta: f32, a: Src, b: Src, alpha: f32) -> bool {
+    let (&[m, n], &[m_, k], &[bytes, n_]) = (c.shape, a.shape, b.shape) else {
+        return false;
+    };
+    if m != m_ || n != n_ || a.dt != c.dt || b.strides[0] != 1 {
+        return false;
+    }
+    if row_bytes(b.dt, k as _) != Some(bytes as _) {
+        return false;
+    }
+    match c.dt {
+        F16 => unsafe { fused::<f16>(c, beta, a, b, alpha) },
+        F32 => unsafe { fused::<f32>(c, beta, a, b, alpha) },
+        _ => return false,
+    }
+    true
+}
+
+unsafe fn fused<T: BetweenF32>(c: Dst, beta: f32, a: Src, b: Src, alpha: f32) {
+    let (m, n, k, bytes) = (
+        c.shape[0] as usize,
+        c.shape[1] as usize,
+        a.shape[1] as usize,
+        b.shape[0] as usize,
+    );
+    let offset = |strides: &[idim], i: usize, j: usize| {
+        i as isize * strides[0] as isize + j as isize * strides[1] as isize
+    };
+    let x = (0..m)
+        .flat_map(|i| (0..k).map(move |j| (i, j)))
+        .map(|(i, j)| {
+            a.base
+                .cast::<T>()
+                .offset(offset(a.strides, i, j))
+                .read()
+                .get()
+        })
+        .collect::<Vec<_>>();
+
+    // 裸指针不能跨线程，以地址传递；各线程写入不相交的列
+    let (c_base, b_base) = (c.base as usize, b.base as usize);
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(n);
+    let chunk = n.div_ceil(threads);
+    thread::scope(|s| {
+        for start in (0..n).step_by(chunk) {
+            let x = &x;
+            s.spawn(move || {
+                let y = c_base as *mut T;
+                let mut w = vec![0f32; k];
+                for j in start..(start + chunk).min(n) {
+                    let row = (b_base as *const u8).offset(j as isize * b.strides[1] as isize);
+                    dequantize_row(b.dt, std::slice::from_raw_parts(row, bytes), &mut w);
+                    for (i, x) in x.chunks_exact(k).enumerate() {
+                        let acc = x.iter().zip(&w).map(|(x, w)| x * w).sum::<f32>();
+                        let y = y.offset(offset(c.strides, i, j));
+                        let old = if beta == 0. {
+                            0.
+                        } else {
+                            beta * y.read().get()
+                        };
+                        y.write(T::cast(old + alpha * acc));
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn test_mat_mul() {
+    use common::quant::{quantize_row, Q4_0, Q4_1, Q8_0};
+    use tensor::{reslice, reslice_mut, Tensor};
+
+    let (m, k, n) = (3, 64, 5);
+    let x = (0..m * k)
+        .map(|i| (i % 7) as f32 / 4. - 0.5)
+        .collect::<Vec<_>>();
+    let w = (0..n * k)
+        .map(|i| (i % 11) as f32 / 8. - 0.75)
+        .collect::<Vec<_>>();
+    for dt in [Q8_0, Q4_0, Q4_1] {
+        // 权重按 `[n, 每行的字节数]` 存储，以转置的视图参与矩阵乘，与模型的投影矩阵相同
+        let bytes = row_bytes(dt, k).unwrap();
+        let mut q = vec![0u8; n * bytes];
+        for (w, q) in w.chunks_exact(k).zip(q.chunks_exact_mut(bytes)) {
+            quantize_row(dt, w, q);
+        }
+        let mut dq = vec![0f32; n * k];
+        for (q, dq) in q.chunks_exact(bytes).zip(dq.chunks_exact_mut(k)) {
+            dequantize_row(dt, q, dq);
+        }
+
+        let a = Tensor::new(F32, &[m as _, k as _], reslice::<f32, u8>(&x));
+        let b = Tensor::new(dt, &[n as _, bytes as _], &*q).transpose(&[1, 0]);
+        let mut y = vec![1f32; m * n];
+        let mut c = Tensor::new(F32, &[m as _, n as _], reslice_mut::<f32, u8>(&mut y));
+        assert!(mat_mul(Dst::of(&mut c), 0.5, Src::of(&a), Src::of(&b), 2.));
+
+        for i in 0..m {
+            for j in 0..n {
+                let dot = (0..k).map(|kk| x[i * k + kk] * dq[j * k + kk]).sum::<f32>();
+                assert!((y[i * n + j] - (0.5 + 2. * dot)).abs() < 1e-4);
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! 一次遍历完成的旋转位置编码和重排，参见 [`KernelsA::rope_to`](crate::KernelsA::rope_to)。
+
+use crate::provider::{Dst, Src};
+use common::f16;
+use digit_layout::types::{F16, F32, U32};
+use tensor::{idim, udim};
+
+/// 读取 `src`，旋转后写入 `dst`。与内置的算子相同，最后一维上相邻的两个元素构成一对。
+///
+/// 只支持 f16 和 f32，且 `dh` 维度连续；不支持的参数返回 `false`。
+pub(crate) fn rope_to(dst: Dst, src: Src, pos: Src, theta: f32) -> bool {
+    let &[nt, _, dh] = dst.shape else {
+        return false;
+    };
+    if src.shape != dst.shape
+        || src.dt != dst.dt
+        || pos.dt != U32
+        || pos.shape != [nt]
+        || dh % 2 != 0
+        || dst.strides[2] != 1
+        || src.strides[2] != 1
+    {
+        return false;
+    }
+    match dst.dt {
+        F16 => unsafe { rotate::<f16>(dst, src, pos, theta) },
+        F32 => unsafe { rotate::<f32>(dst, src, pos, theta) },
+        _ => return false,
+    }
+    true
+}
+
+trait Float: Copy {
+    fn into_f32(self) -> f32;
+    fn from_f32(x: f32) -> Self;
+}
+
+impl Float for f16 {
+    #[inline]
+    fn into_f32(self) -> f32 {
+        self.to_f32()
+    }
+    #[inline]
+    fn from_f32(x: f32) -> Self {
+        f16::from_f32(x)
+    }
+}
+
+impl Float for f32 {
+    #[inline]
+    fn into_f32(self) -> f32 {
+        self
+    }
+    #[inline]
+    fn from_f32(x: f32) -> Self {
+        x
+    }
+}
+
+unsafe fn rotate<T: Float>(dst: Dst, src: Src, pos: Src, theta: f32) {
+    let &[nt, nh, dh] = dst.shape else {
+        unreachable!()
+    };
+    let half = dh / 2;
+    // 各对的频率与位置无关，只计算一次
+    let freq = (0..half)
+        .map(|k| theta.powf(k as f32 / half as f32).recip())
+        .collect::<Vec<_>>();
+    let mut sin_cos = vec![(0., 0.); half as usize];
+
+    let p = pos.base.cast::<u32>();
+    let d = dst.base.cast::<T>();
+    let s = src.base.cast::<T>();
+    let offset = |strides: &[idim], i: udim, j: udim| {
+        i as isize * strides[0] as isize + j as isize * strides[1] as isize
+    };
+
+    for i in 0..nt {
+        let p = p.offset(i as isize * pos.strides[0] as isize).read() as f32;
+        for (sc, f) in sin_cos.iter_mut().zip(&freq) {
+            *sc = (p * f).sin_cos();
+        }
+        for j in 0..nh {
+            let d = d.offset(offset(dst.strides, i, j));
+            let s = s.offset(offset(src.strides, i, j));
+            for (k, &(sin, cos)) in sin_cos.iter().enumerate() {
+                let a = s.add(2 * k).read().into_f32();
+                let b = s.add(2 * k + 1).read().into_f32();
+                d.add(2 * k).write(T::from_f32(a * cos - b * sin));
+                d.add(2 * k + 1).write(T::from_f32(a * sin + b * cos));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rope_to() {
+    use tensor::{reslice, reslice_mut, Tensor};
+
+    let (nt, nh, dh) = (3, 2, 4);
+    let src = (0..nt * nh * dh).map(|x| x as f32).collect::<Vec<_>>();
+    let p = [0u32, 1, 7];
+    let theta = 1e4;
+
+    let src = Tensor::new(F32, &[nt, nh, dh], reslice::<f32, u8>(&src));
+    let pos = Tensor::new(U32, &[nt], reslice::<u32, u8>(&p));
+    // 目标按 [nh, nt, dh] 存储，与注意力的缓冲区相同
+    let mut buf = vec![0f32; (nt * nh * dh) as usize];
+    let mut dst =
+        Tensor::new(F32, &[nh, nt, dh], reslice_mut::<f32, u8>(&mut buf)).transpose(&[1, 0, 2]);
+    assert!(rope_to(
+        Dst::of(&mut dst),
+        Src::of(&src),
+        Src::of(&pos),
+        theta
+    ));
+
+    let src = reslice::<u8, f32>(src.physical());
+    for i in 0..nt {
+        for j in 0..nh {
+            for k in 0..dh / 2 {
+                let x = (i * nh + j) * dh + 2 * k;
+                let y = (j * nt + i) * dh + 2 * k;
+                let angle = p[i as usize] as f32 / theta.powf(k as f32 / (dh / 2) as f32);
+                let (sin, cos) = angle.sin_cos();
+                let (a, b) = (src[x as usize], src[x as usize + 1]);
+                assert!((buf[y as usize] - (a * cos - b * sin)).abs() < 1e-4);
+                assert!((buf[y as usize + 1] - (a * sin + b * cos)).abs() < 1e-4);
+            }
+        }
+    }
+}
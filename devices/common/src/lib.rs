@@ -51,6 +51,25 @@ pub trait KernelsA {
         T: DerefMut<Target = SliceOn<Self::Handle>>,
         U: Deref<Target = SliceOn<Self::Handle>>;
 
+    /// 将 `src` 施加旋转位置编码后写入 `dst`，两者的形状都是 `[nt, nh, dh]`，`src` 不变。
+    ///
+    /// 默认实现先重排再原地旋转，后端可以在一次遍历中完成，省去对 `dst` 的第二次读写。
+    fn rope_to<T, U, V>(
+        &self,
+        dst: &mut Tensor<T>,
+        src: &Tensor<U>,
+        pos: &Tensor<V>,
+        theta: f32,
+        queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.reform(dst, src, queue);
+        self.rope(dst, pos, theta, queue);
+    }
+
     fn mat_mul<T, U, V>(
         &self,
         c: &mut Tensor<T>,
@@ -64,6 +83,25 @@ pub trait KernelsA {
         U: Deref<Target = SliceOn<Self::Handle>>,
         V: Deref<Target = SliceOn<Self::Handle>>;
 
+    /// 注意力中 q·k 和 att·v 的三维矩阵乘，参数与 [`mat_mul`](Self::mat_mul) 相同。
+    ///
+    /// 默认实现调用 `mat_mul`，后端可以为注意力选择不同的算子。
+    fn attention_mat_mul<T, U, V>(
+        &self,
+        c: &mut Tensor<T>,
+        beta: f32,
+        a: &Tensor<U>,
+        b: &Tensor<V>,
+        alpha: f32,
+        queue: &QueueOf<Self::Handle>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Handle>>,
+        U: Deref<Target = SliceOn<Self::Handle>>,
+        V: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        self.mat_mul(c, beta, a, b, alpha, queue)
+    }
+
     fn softmax<T>(&self, att: &mut Tensor<T>, queue: &QueueOf<Self::Handle>)
     where
         T: DerefMut<Target = SliceOn<Self::Handle>>;
@@ -1,7 +1,8 @@
-﻿#![cfg(detected_cuda)]
+#![cfg(detected_cuda)]
 
 mod gather;
 mod sample;
+mod transfer;
 
 use common::utok;
 use common_devices::{Operators, SliceOn};
@@ -21,7 +22,8 @@ use std::{
 pub use common_devices::{Kernels, KernelsA, KernelsB};
 pub use operators::{cuda, nvidia_gpu::Handle as Gpu};
 pub use sample::{sample_cpu, sample_nv};
-pub use tensor::{reslice, reslice_mut, slice, split, udim, LocalSplitable, Tensor};
+pub use tensor::{reslice, reslice_mut, slice, split, udim, LocalSplitable, Tensor, Transfer};
+pub use transfer::StreamTransfer;
 
 pub struct NvidiaKernels(HashMap<i32, Internal>);
 
@@ -4,6 +4,7 @@ use sample::SampleArgs;
 use std::{
     collections::HashMap,
     ffi::{c_int, c_void},
+    mem::size_of,
     ptr::{null, null_mut},
     sync::{Mutex, OnceLock},
 };
@@ -201,13 +202,22 @@ pub fn sample_nv(
 
     let mut temp_sum = prealloc_inclusive_sum(stream, voc);
 
+    let dev = logits;
     let logits = logits.as_ptr().cast::<f16>();
     let ans = args
         .into_iter()
         .map(|(i, args)| {
             let logits = unsafe { logits.add(i * voc) };
 
-            if args.is_argmax() {
+            if !args.is_basic() {
+                // 只有默认流程有 GPU 实现，其他情况拷贝到主机上采样
+                let mut row = vec![f16::ZERO; voc];
+                memcpy_d2h(
+                    &mut row,
+                    &dev[i * voc * size_of::<f16>()..][..voc * size_of::<f16>()],
+                );
+                args.random(&row)
+            } else if args.is_argmax() {
                 assert_eq!(0, unsafe {
                     argmax_half(
                         temp_argmax.as_mut_ptr().cast(),
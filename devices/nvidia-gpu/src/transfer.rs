@@ -0,0 +1,28 @@
+use operators::cuda::{memcpy_d2h, DevByte, DevMem, Stream};
+use tensor::Transfer;
+
+/// 在 cuda 流上进行主机和设备之间的复制。
+#[repr(transparent)]
+pub struct StreamTransfer<'a, 'ctx>(pub &'a Stream<'ctx>);
+
+impl<'ctx> Transfer for StreamTransfer<'_, 'ctx> {
+    type Byte = DevByte;
+    type Mem = DevMem<'ctx>;
+
+    #[inline]
+    fn malloc(&self, len: usize) -> Self::Mem {
+        self.0.malloc::<u8>(len)
+    }
+
+    #[inline]
+    fn copy_h2d(&self, dst: &mut [DevByte], src: &[u8]) {
+        self.0.memcpy_h2d(dst, src);
+    }
+
+    #[inline]
+    fn copy_d2h(&self, dst: &mut [u8], src: &[DevByte]) {
+        // 等待流上之前的计算完成
+        self.0.synchronize();
+        memcpy_d2h(dst, src);
+    }
+}
@@ -0,0 +1,63 @@
+/* InfiniLM 推理引擎的 C 接口。所有字符串都是 UTF-8 编码。 */
+
+#ifndef INFINILM_H
+#define INFINILM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define INFINILM_ABI_VERSION 1
+
+typedef struct InfinilmService InfinilmService;
+typedef struct InfinilmSession InfinilmSession;
+
+/* 生成结束的原因。 */
+typedef enum InfinilmFinishReason {
+    INFINILM_FINISH_INVALID_ARGUMENT = -1,
+    INFINILM_FINISH_STOP = 0,
+    INFINILM_FINISH_CANCELLED = 1,
+    INFINILM_FINISH_CONTENT_FILTER = 2,
+    INFINILM_FINISH_TIMEOUT = 3,
+    INFINILM_FINISH_ERROR = 4,
+} InfinilmFinishReason;
+
+/* 生成的一段文本，长度为 len，不以 NUL 结尾。返回非 0 时停止生成。 */
+typedef int (*InfinilmOnText)(void *user_data, const char *text, size_t len);
+
+/* 库的 ABI 版本，与 INFINILM_ABI_VERSION 不同时不应使用。 */
+uint32_t infinilm_abi_version(void);
+
+/* 加载模型目录中的模型，失败时返回 NULL。 */
+InfinilmService *infinilm_service_load(const char *model_dir);
+/* 销毁服务，已创建的会话仍然可用。 */
+void infinilm_service_destroy(InfinilmService *service);
+
+/* 将文本编码为词，向 tokens 写入至多 cap 个词，返回词的总数。 */
+size_t infinilm_encode(const InfinilmService *service, const char *text, uint32_t *tokens, size_t cap);
+/* 向 buf 写入至多 cap 个词对应的字节，返回字节的总数，不写入 NUL。 */
+size_t infinilm_decode(const InfinilmService *service, uint32_t token, uint8_t *buf, size_t cap);
+
+/* 启动一个会话，失败时返回 NULL。 */
+InfinilmSession *infinilm_session_create(const InfinilmService *service);
+/* 复制会话，失败时返回 NULL。 */
+InfinilmSession *infinilm_session_fork(const InfinilmSession *session);
+/* 销毁会话。 */
+void infinilm_session_destroy(InfinilmSession *session);
+/* 向会话连接 n 个句子，用户和助手的句子交替出现，成功时返回 0。 */
+int infinilm_session_extend(InfinilmSession *session, const char *const *sentences, size_t n);
+/* 将会话回滚到第 dialog_pos 个句子，成功时返回 0。 */
+int infinilm_session_revert(InfinilmSession *session, size_t dialog_pos);
+/* 会话中的句子数，为奇数时可以推理。 */
+size_t infinilm_session_dialog_pos(const InfinilmSession *session);
+/* 推理并阻塞到生成结束，生成的回答追加到会话中。同一个会话不能在多个线程上同时使用。 */
+InfinilmFinishReason infinilm_session_decode(InfinilmSession *session, InfinilmOnText on_text, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* INFINILM_H */
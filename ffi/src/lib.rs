@@ -0,0 +1,367 @@
+#![doc = include_str!("../README.md")]
+#![deny(warnings)]
+
+use causal_lm::CausalLM;
+use common::utok;
+use service::{FinishReason, Service, Session};
+use std::{
+    ffi::{c_char, c_int, c_void, CStr},
+    fmt::Debug,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+    ptr::{copy_nonoverlapping, null_mut},
+    slice,
+    sync::Arc,
+};
+use tokio::runtime::{Builder, Runtime};
+
+/// 导出的模型类型。
+type Model = llama_cpu::Transformer;
+
+/// ABI 的版本，有不兼容的修改时递增，与头文件中的 `INFINILM_ABI_VERSION` 一致。
+pub const ABI_VERSION: u32 = 1;
+
+/// 推理服务和执行会话的运行时。
+pub struct InfinilmService<M: CausalLM = Model> {
+    service: Service<M>,
+    runtime: Arc<Runtime>,
+}
+
+/// 会话。会话持有服务的组件和运行时，可以在服务销毁之后继续使用。
+pub struct InfinilmSession<M: CausalLM = Model> {
+    session: Session<M>,
+    runtime: Arc<Runtime>,
+}
+
+/// 生成的一段文本的回调，`text` 是长度为 `len` 的 UTF-8 文本，不以 NUL 结尾。返回非 0 时停止生成。
+pub type InfinilmOnText =
+    extern "C" fn(user_data: *mut c_void, text: *const c_char, len: usize) -> c_int;
+
+/// 生成结束的原因。
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InfinilmFinishReason {
+    Stop = 0,
+    Cancelled = 1,
+    ContentFilter = 2,
+    Timeout = 3,
+    Error = 4,
+    /// 参数无效，没有进行推理。
+    InvalidArgument = -1,
+}
+
+impl From<FinishReason> for InfinilmFinishReason {
+    #[inline]
+    fn from(reason: FinishReason) -> Self {
+        match reason {
+            FinishReason::Stop => Self::Stop,
+            FinishReason::Cancelled => Self::Cancelled,
+            FinishReason::ContentFilter => Self::ContentFilter,
+            FinishReason::Timeout => Self::Timeout,
+            FinishReason::Error => Self::Error,
+        }
+    }
+}
+
+impl<M> InfinilmService<M>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+    M::Error: Debug,
+{
+    /// 加载模型，失败时返回 `None` 而不是让 panic 越过 FFI 边界。
+    fn load(model_dir: &Path, meta: M::Meta) -> Option<Self> {
+        let runtime = Builder::new_multi_thread().enable_all().build().ok()?;
+        let service = {
+            let _rt = runtime.enter();
+            catch_unwind(AssertUnwindSafe(|| Service::load(model_dir, meta).0)).ok()?
+        };
+        Some(Self {
+            service,
+            runtime: Arc::new(runtime),
+        })
+    }
+}
+
+impl<M: CausalLM> InfinilmService<M> {
+    #[inline]
+    fn launch(&self) -> InfinilmSession<M> {
+        InfinilmSession {
+            session: self.service.launch(),
+            runtime: self.runtime.clone(),
+        }
+    }
+}
+
+impl<M: CausalLM> InfinilmSession<M> {
+    /// 对会话推理，`on_text` 返回 `false` 时停止生成。
+    fn decode(&mut self, mut on_text: impl FnMut(&str) -> bool) -> InfinilmFinishReason {
+        let Self { session, runtime } = self;
+        runtime.block_on(async {
+            let mut busy = session.chat();
+            while let Some(chunk) = busy.decode().await {
+                if !chunk.text.is_empty() && !on_text(&chunk.text) {
+                    return InfinilmFinishReason::Cancelled;
+                }
+            }
+            busy.finish_reason()
+                .map_or(InfinilmFinishReason::Cancelled, Into::into)
+        })
+    }
+}
+
+/// 以 NUL 结尾的 UTF-8 字符串参数。
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+/// 返回库的 ABI 版本。
+#[no_mangle]
+pub extern "C" fn infinilm_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+/// 加载模型目录中的模型，失败时返回空指针。
+///
+/// # Safety
+///
+/// `model_dir` 是空指针或以 NUL 结尾的字符串。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_service_load(model_dir: *const c_char) -> *mut InfinilmService {
+    let Some(model_dir) = str_arg(model_dir) else {
+        return null_mut();
+    };
+    InfinilmService::<Model>::load(Path::new(model_dir), Default::default())
+        .map_or(null_mut(), |s| Box::into_raw(Box::new(s)))
+}
+
+/// 销毁服务，已创建的会话仍然可用。
+///
+/// # Safety
+///
+/// `service` 是空指针或 [`infinilm_service_load`] 返回的、未销毁的服务。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_service_destroy(service: *mut InfinilmService) {
+    if !service.is_null() {
+        drop(Box::from_raw(service));
+    }
+}
+
+/// 将文本编码为词，不添加对话模板。向 `tokens` 写入至多 `cap` 个词，返回词的总数，文本过长时返回 0。
+///
+/// # Safety
+///
+/// `service` 是有效的服务，`text` 是以 NUL 结尾的字符串，`tokens` 可以写入 `cap` 个词。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_encode(
+    service: *const InfinilmService,
+    text: *const c_char,
+    tokens: *mut u32,
+    cap: usize,
+) -> usize {
+    let (Some(service), Some(text)) = (service.as_ref(), str_arg(text)) else {
+        return 0;
+    };
+    let Ok(Ok(encoded)) = catch_unwind(AssertUnwindSafe(|| service.service.encode(text))) else {
+        return 0;
+    };
+    if !tokens.is_null() {
+        copy_nonoverlapping(encoded.as_ptr(), tokens, encoded.len().min(cap));
+    }
+    encoded.len()
+}
+
+/// 向 `buf` 写入至多 `cap` 个词对应的字节，返回字节的总数，不写入 NUL。单字节词可能不是完整的 UTF-8 字符。
+/// 词超出词表时返回 0。
+///
+/// # Safety
+///
+/// `service` 是有效的服务，`buf` 可以写入 `cap` 个字节。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_decode(
+    service: *const InfinilmService,
+    token: u32,
+    buf: *mut u8,
+    cap: usize,
+) -> usize {
+    let Some(service) = service.as_ref() else {
+        return 0;
+    };
+    if token as usize >= service.service.vocab_size() {
+        return 0;
+    }
+    let Ok(bytes) = catch_unwind(AssertUnwindSafe(|| service.service.decode(token as utok))) else {
+        return 0;
+    };
+    if !buf.is_null() {
+        copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len().min(cap));
+    }
+    bytes.len()
+}
+
+/// 启动一个会话，参数无效时返回空指针。
+///
+/// # Safety
+///
+/// `service` 是空指针或有效的服务。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_session_create(
+    service: *const InfinilmService,
+) -> *mut InfinilmSession {
+    service
+        .as_ref()
+        .map_or(null_mut(), |s| Box::into_raw(Box::new(s.launch())))
+}
+
+/// 复制会话，缓存无法复制时返回空指针。
+///
+/// # Safety
+///
+/// `session` 是空指针或有效的会话。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_session_fork(
+    session: *const InfinilmSession,
+) -> *mut InfinilmSession {
+    let Some(InfinilmSession { session, runtime }) = session.as_ref() else {
+        return null_mut();
+    };
+    session.fork().map_or(null_mut(), |session| {
+        Box::into_raw(Box::new(InfinilmSession {
+            session,
+            runtime: runtime.clone(),
+        }))
+    })
+}
+
+/// 销毁会话。
+///
+/// # Safety
+///
+/// `session` 是空指针或有效的会话。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_session_destroy(session: *mut InfinilmSession) {
+    if !session.is_null() {
+        drop(Box::from_raw(session));
+    }
+}
+
+/// 向会话连接 `n` 个句子，成功时返回 0；任何句子过长时返回 -1，会话不变。
+///
+/// 用户和助手的句子交替出现，会话的句子数为奇数时可以推理。
+///
+/// # Safety
+///
+/// `session` 是有效的会话，`sentences` 指向 `n` 个以 NUL 结尾的字符串。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_session_extend(
+    session: *mut InfinilmSession,
+    sentences: *const *const c_char,
+    n: usize,
+) -> c_int {
+    let Some(session) = session.as_mut() else {
+        return -1;
+    };
+    if sentences.is_null() && n > 0 {
+        return -1;
+    }
+    let sentences = if n == 0 {
+        Vec::new()
+    } else {
+        match slice::from_raw_parts(sentences, n)
+            .iter()
+            .map(|&s| str_arg(s))
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(sentences) => sentences,
+            None => return -1,
+        }
+    };
+    match catch_unwind(AssertUnwindSafe(|| session.session.extend(sentences))) {
+        Ok(Ok(())) => 0,
+        _ => -1,
+    }
+}
+
+/// 将会话回滚到第 `dialog_pos` 个句子，成功时返回 0。
+///
+/// # Safety
+///
+/// `session` 是有效的会话。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_session_revert(
+    session: *mut InfinilmSession,
+    dialog_pos: usize,
+) -> c_int {
+    match session.as_mut().map(|s| s.session.revert(dialog_pos)) {
+        Some(Ok(())) => 0,
+        _ => -1,
+    }
+}
+
+/// 会话中的句子数。
+///
+/// # Safety
+///
+/// `session` 是有效的会话。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_session_dialog_pos(session: *const InfinilmSession) -> usize {
+    session.as_ref().map_or(0, |s| s.session.dialog_pos())
+}
+
+/// 对会话推理，阻塞到生成结束，生成的文本依次传给 `on_text`。生成的回答追加到会话中。
+///
+/// # Safety
+///
+/// `session` 是有效的会话，不能在其他线程上同时使用。
+#[no_mangle]
+pub unsafe extern "C" fn infinilm_session_decode(
+    session: *mut InfinilmSession,
+    on_text: Option<InfinilmOnText>,
+    user_data: *mut c_void,
+) -> InfinilmFinishReason {
+    let Some(session) = session.as_mut() else {
+        return InfinilmFinishReason::InvalidArgument;
+    };
+    catch_unwind(AssertUnwindSafe(|| {
+        session.decode(|text| match on_text {
+            Some(f) => f(user_data, text.as_ptr().cast(), text.len()) == 0,
+            None => true,
+        })
+    }))
+    .unwrap_or(InfinilmFinishReason::Error)
+}
+
+#[test]
+fn test_session() {
+    use causal_lm::MockModel;
+
+    let model_dir = MockModel::model_dir("ffi-mock");
+
+    let reply = "ok".bytes().map(|b| b as utok + 3);
+    let service = InfinilmService::<MockModel>::load(&model_dir, MockModel::script(reply)).unwrap();
+    let mut session = service.launch();
+    // 销毁服务后会话仍然可用
+    drop(service);
+
+    session.session.extend(["Hi"]).unwrap();
+    let mut text = String::new();
+    let reason = session.decode(|s| {
+        text.push_str(s);
+        true
+    });
+    assert_eq!(reason, InfinilmFinishReason::Stop);
+    assert_eq!(text, "ok");
+    assert_eq!(session.session.dialog_pos(), 2);
+
+    // 回调要求停止
+    session.session.extend(["Hi"]).unwrap();
+    let reason = session.decode(|_| false);
+    assert_eq!(reason, InfinilmFinishReason::Cancelled);
+
+    drop(session);
+    let _ = std::fs::remove_dir_all(model_dir);
+}
@@ -2,7 +2,7 @@
 
 mod resource;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta, ShapeError};
 use common::{upos, utok, FileLoadError};
 use common_cn::Tensor;
 use std::path::Path;
@@ -28,7 +28,7 @@ impl CausalLM for Transformer {
         todo!()
     }
 
-    fn eos_token(&self) -> utok {
+    fn eos_tokens(&self) -> &[utok] {
         todo!()
     }
 
@@ -36,7 +36,11 @@ impl CausalLM for Transformer {
         todo!()
     }
 
-    fn duplicate_cache(&self, _cache: &Tensor<Self::Storage>, _pos: upos) -> Tensor<Self::Storage> {
+    fn duplicate_cache(
+        &self,
+        _cache: &Tensor<Self::Storage>,
+        _pos: upos,
+    ) -> Result<Tensor<Self::Storage>, ShapeError> {
         todo!()
     }
 
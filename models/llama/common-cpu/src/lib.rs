@@ -1,26 +1,147 @@
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{f16, upos, utok, Blob, FileLoadError};
+use causal_lm::{
+    log_softmax, AdapterError, AdapterInfo, CausalLM, DecodingMeta, Model, QueryContext,
+    SampleMeta, ShapeError,
+};
+use common::{bf16, f16, upos, utok, BetweenF32, Blob, FileLoadError};
 use common_cpu::{
-    tensor::{reslice, slice, udim, Tensor},
-    CpuKernels, Kernels, KernelsA, KernelsB, ThisThread,
+    tensor::{reslice, reslice_mut, slice, udim, Tensor},
+    Accumulation, CpuKernels, Kernels, KernelsA, KernelsB, ThisThread,
+};
+use digit_layout::{
+    types::{BF16, F16, F32},
+    DigitLayout,
+};
+use llama::{
+    cast_to, Adapter, ComputeConst, ComputeStream, Handle, LayerStorage, Proj, QueueOf, SliceOn,
+    SoftPrompt, Steering, Storage, Weight,
 };
-use llama::{ComputeConst, ComputeStream, Handle, LayerStorage, QueueOf, SliceOn, Storage, Weight};
-use std::{iter::repeat, ops::Deref, path::Path, slice::from_raw_parts};
+use std::{
+    iter::{repeat, zip},
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    slice::from_raw_parts,
+    sync::{Arc, RwLock},
+};
+
+mod screen;
+mod shard;
 
 pub struct Transformer {
     s: Storage,
     kernels: CpuKernels,
+    lm_head_top: Option<usize>,
+    lm_head_shards: usize,
+    adapters: RwLock<Vec<AdapterSlot>>,
+    steering: Vec<(String, Steering)>,
+    soft_prompts: Vec<(String, SoftPrompt)>,
+}
+
+/// 常驻的适配器和它的名字，卸载后留下空位，其他适配器的序号不变。
+type AdapterSlot = Option<(String, Arc<Adapter>)>;
+
+/// 加载模型的选项。
+#[derive(Clone, Default, Debug)]
+pub struct ModelLoadMeta {
+    /// 加载时预转置投影矩阵和 lm_head，以加载时间和内存换取更快的解码矩阵乘，参见 [`Storage::pretranspose`]。
+    pub pretranspose: bool,
+    /// 预转置的矩阵缓存在这个目录，之后的加载直接映射缓存。
+    pub cache_dir: Option<PathBuf>,
+    /// 只精确计算约这么多个候选词的 logits，其余置为负无穷，以近似的 lm_head 换取更快的解码。
+    ///
+    /// 候选词由部分维度的内积初筛，真正最大的 logit 可能被漏掉，只适合贪心或低温度的采样。
+    /// 只影响 [`CausalLM::decode_sample`]，回显提示词的对数概率和打分仍使用完整的 logits。
+    pub lm_head_top: Option<usize>,
+    /// 大于 1 时按词表分成这么多片并行计算 lm_head，每片只为采样保留必要的候选词，不生成完整的 logits。
+    ///
+    /// 只影响 [`CausalLM::decode_sample`]，与 `lm_head_top` 同时设置时后者优先。
+    pub lm_head_shards: usize,
+    /// 词嵌入的数据类型，`None` 保持文件中的类型。
+    pub embed_dt: Option<DigitLayout>,
+    /// lm_head 的数据类型，`None` 保持文件中的类型。
+    ///
+    /// 主体使用较低的精度时，词嵌入和 lm_head 常保留更高的精度，它们对生成质量的影响更大。
+    pub lm_head_dt: Option<DigitLayout>,
+    /// 各层投影矩阵的数据类型，`None` 保持文件中的类型。
+    ///
+    /// 可以是 8 位浮点数或分块量化的 [`Q8_0`](common::quant::Q8_0)、[`Q4_0`](common::quant::Q4_0)，
+    /// 矩阵乘时再转换为主体的类型，以计算换取内存带宽。
+    /// 4 位的 GPTQ 和 AWQ 权重加载为 [`Q4_1`](common::quant::Q4_1)，指定其他类型时反量化后转换。
+    pub matrix_dt: Option<DigitLayout>,
+    /// 常驻的 LoRA 适配器的名字和目录，查询按名字选择，参见 [`CausalLM::adapter`]。
+    pub adapters: Vec<(String, PathBuf)>,
+    /// 控制向量的名字和 safetensors 文件，查询按名字选择，参见 [`CausalLM::steering`]。
+    pub steering: Vec<(String, PathBuf)>,
+    /// 软提示词的名字和 safetensors 文件，会话按名字选择，参见 [`CausalLM::soft_prompt`]。
+    pub soft_prompts: Vec<(String, PathBuf)>,
+    /// 矩阵乘逐行计算，同一个序列的结果不随批的组成改变，参见 [`CpuKernels::batch_invariant`]。
+    ///
+    /// 不影响 `lm_head_top` 的初筛。
+    pub batch_invariant: bool,
+    /// 以 f32 累加的算子，参见 [`Accumulation`]。
+    pub accumulation: Accumulation,
+    /// 替代模型配置的注意力分数缩放，参见 [`InferenceConfig::attn_scale`](llama::InferenceConfig::attn_scale)。
+    pub attn_scale: Option<f32>,
 }
 
 impl Model for Transformer {
-    type Meta = ();
+    type Meta = ModelLoadMeta;
     type Error = FileLoadError;
 
-    #[inline]
-    fn load(model_dir: impl AsRef<Path>, _meta: Self::Meta) -> Result<Self, Self::Error> {
+    fn load(model_dir: impl AsRef<Path>, meta: Self::Meta) -> Result<Self, Self::Error> {
+        let s = Storage::load(model_dir)?;
+        // 投影矩阵可能与主体的类型不同，转换其他部分时保持
+        let matrix_dt = meta
+            .matrix_dt
+            .or_else(|| s.layers.first().map(|l| l.att_qkv.data_layout()));
+        let s = match (meta.embed_dt, meta.lm_head_dt) {
+            (None, None) => s,
+            (embed, lm_head) => {
+                let embed = embed.unwrap_or(s.embed_tokens.data_layout());
+                let lm_head = lm_head.unwrap_or(s.lm_head.data_layout());
+                let dt = s.config.dt;
+                s.cast_parts(dt, embed, lm_head)
+            }
+        };
+        let s = match matrix_dt {
+            Some(dt) => s.cast_matrices(dt),
+            None => s,
+        };
+        let adapters = meta
+            .adapters
+            .into_iter()
+            .map(|(name, dir)| {
+                let adapter = Adapter::load_safetensors(dir, &s.config)?;
+                Ok(Some((name, Arc::new(adapter))))
+            })
+            .collect::<Result<_, FileLoadError>>()?;
+        let steering = meta
+            .steering
+            .into_iter()
+            .map(|(name, path)| Ok((name, Steering::load_safetensors(path, &s.config)?)))
+            .collect::<Result<_, FileLoadError>>()?;
+        let soft_prompts = meta
+            .soft_prompts
+            .into_iter()
+            .map(|(name, path)| Ok((name, SoftPrompt::load_safetensors(path, &s.config)?)))
+            .collect::<Result<_, FileLoadError>>()?;
+        let mut s = match (meta.pretranspose, meta.cache_dir) {
+            (false, _) => s,
+            (true, None) => s.pretranspose(),
+            (true, Some(dir)) => s.pretranspose_cached(dir)?,
+        };
+        if let Some(scale) = meta.attn_scale {
+            s.config.attn_scale = Some(scale);
+        }
         Ok(Self {
-            s: llama::Storage::load_safetensors(model_dir)?,
-            kernels: Default::default(),
+            s,
+            kernels: CpuKernels::default()
+                .batch_invariant(meta.batch_invariant)
+                .accumulation(meta.accumulation),
+            lm_head_top: meta.lm_head_top,
+            lm_head_shards: meta.lm_head_shards,
+            adapters: RwLock::new(adapters),
+            steering,
+            soft_prompts,
         })
     }
 }
@@ -62,6 +183,7 @@ impl ComputeStream for Transformer {
             di: self.s.config.di,
             epsilon: self.s.config.epsilon,
             theta: self.s.config.theta,
+            attn_scale: self.s.config.attn_scale(),
         }
     }
 
@@ -78,13 +200,89 @@ impl ComputeStream for Transformer {
     ) -> impl Iterator<Item = impl llama::LLamaLayer<Byte = <Self::Handle as Handle>::Byte>> {
         self.s.layers.iter().map(LlamaLayer)
     }
+
+    fn lora<Y, X>(
+        &self,
+        layer: usize,
+        proj: Proj,
+        y: &mut Tensor<Y>,
+        x: &Tensor<X>,
+        adapters: &[(Option<usize>, udim)],
+    ) where
+        Y: DerefMut<Target = SliceOn<Self::Handle>>,
+        X: Deref<Target = SliceOn<Self::Handle>>,
+    {
+        let dt = self.s.config.dt;
+        let mut start = 0;
+        for &(adapter, len) in adapters {
+            let row = start;
+            start += len;
+            let Some(i) = adapter else {
+                continue;
+            };
+            // 推理中卸载的适配器不再生效
+            let Some((_, adapter)) = self.adapters.read().unwrap()[i].clone() else {
+                continue;
+            };
+            let x = x
+                .as_ref()
+                .map_physical(|u| &**u)
+                .slice(&[slice![row =>=> len], slice![=>]]);
+            // 先乘 a 降到秩 r，再乘 b 累加到输出的对应列上
+            for delta in adapter.deltas(layer, proj) {
+                let &[r, out] = delta.b.shape() else {
+                    unreachable!()
+                };
+                let mut tmp = Tensor::alloc(dt, &[len, r], Blob::new);
+                self.kernels
+                    .mat_mul(&mut tmp, 0., &x, &delta.a, 1., &ThisThread);
+                let mut y = y
+                    .as_mut()
+                    .map_physical(|u| &mut **u)
+                    .slice(&[slice![row =>=> len], slice![delta.offset =>=> out]]);
+                self.kernels
+                    .mat_mul(&mut y, 1., &tmp, &delta.b, adapter.scale, &ThisThread);
+            }
+        }
+    }
+
+    fn steer<X>(&self, layer: usize, x: &mut Tensor<X>, steering: &[(Option<(usize, f32)>, udim)])
+    where
+        X: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+        let dt = self.s.config.dt;
+        let mut start = 0;
+        for &(steering, len) in steering {
+            let row = start;
+            start += len;
+            let Some((i, strength)) = steering else {
+                continue;
+            };
+            let Some(v) = &self.steering[i].1.layers[layer] else {
+                continue;
+            };
+            // 以全 1 的列向量乘方向，广播到查询的每个词上
+            let mut ones = Tensor::alloc(F32, &[len, 1], Blob::new);
+            reslice_mut::<u8, f32>(ones.physical_mut()).fill(1.);
+            let ones = cast_to(&ones, dt);
+            let mut x = x
+                .as_mut()
+                .map_physical(|u| &mut **u)
+                .slice(&[slice![row =>=> len], slice![=>]]);
+            self.kernels
+                .mat_mul(&mut x, 1., &ones, v, strength, &ThisThread);
+        }
+    }
 }
 
 struct LlamaLayer<'a>(&'a LayerStorage<Weight>);
 
 impl<'a> llama::LLamaLayer for LlamaLayer<'a> {
     type Byte = u8;
-    type Storage<'m> = Weight where Self: 'm;
+    type Storage<'m>
+        = Weight
+    where
+        Self: 'm;
 
     #[inline]
     fn att_layernorm(&self) -> Tensor<Self::Storage<'_>> {
@@ -120,15 +318,76 @@ impl CausalLM for Transformer {
         self.s.config.max_seq_len
     }
     #[inline]
-    fn eos_token(&self) -> utok {
-        self.s.config.eos_token
+    fn eos_tokens(&self) -> &[utok] {
+        &self.s.config.eos_tokens
+    }
+    fn adapter(&self, name: &str) -> Option<usize> {
+        self.adapters
+            .read()
+            .unwrap()
+            .iter()
+            .position(|a| a.as_ref().is_some_and(|(n, _)| n == name))
+    }
+    fn steering(&self, name: &str) -> Option<usize> {
+        self.steering.iter().position(|(n, _)| n == name)
+    }
+    fn soft_prompt(&self, name: &str) -> Option<Vec<utok>> {
+        // 各软提示词的虚拟词依次排在词表之后
+        let mut start = self.s.config.voc;
+        for (n, prompt) in &self.soft_prompts {
+            let len = prompt.num_tokens();
+            if n == name {
+                return Some((start..start + len).collect());
+            }
+            start += len;
+        }
+        None
+    }
+    fn adapters(&self) -> Vec<AdapterInfo> {
+        self.adapters
+            .read()
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(|(name, adapter)| AdapterInfo {
+                name: name.clone(),
+                bytes: adapter.bytes(),
+            })
+            .collect()
+    }
+    fn load_adapter(&self, name: &str, dir: &Path) -> Result<(), AdapterError> {
+        if self.adapter(name).is_some() {
+            return Err(AdapterError::Duplicate);
+        }
+        // 加载较慢，不持有锁
+        let adapter = Adapter::load_safetensors(dir, &self.s.config)
+            .map_err(|e| AdapterError::Load(format!("{e:?}")))?;
+        let mut adapters = self.adapters.write().unwrap();
+        if adapters.iter().flatten().any(|(n, _)| n == name) {
+            return Err(AdapterError::Duplicate);
+        }
+        adapters.push(Some((name.into(), Arc::new(adapter))));
+        Ok(())
+    }
+    fn unload_adapter(&self, name: &str) -> Result<(), AdapterError> {
+        let mut adapters = self.adapters.write().unwrap();
+        let slot = adapters
+            .iter_mut()
+            .find(|a| a.as_ref().is_some_and(|(n, _)| n == name))
+            .ok_or(AdapterError::NotFound)?;
+        *slot = None;
+        Ok(())
     }
     #[inline]
     fn new_cache(&self) -> Tensor<Self::Storage> {
         self.s.config.new_cache(Blob::new)
     }
     #[inline]
-    fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
+    fn duplicate_cache(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        pos: upos,
+    ) -> Result<Tensor<Self::Storage>, ShapeError> {
         self.s
             .config
             .duplicate_cache(cache, pos, Blob::new, |dst, src| {
@@ -144,9 +403,21 @@ impl CausalLM for Transformer {
         let tokens = queries.into_iter().collect::<Vec<_>>();
         let nt = tokens.len() as udim;
 
-        let mut x = Tensor::alloc(dt, &[nt, d], Blob::new);
-        self.kernels
-            .gather(&mut x, &self.s.embed_tokens, tokens, &ThisThread);
+        let voc = self.s.config.voc;
+        let table = &self.s.embed_tokens;
+        let mut x = Tensor::alloc(table.data_layout(), &[nt, d], Blob::new);
+        // 虚拟词先用 0 号词占位，转换类型后替换为软提示词的嵌入
+        let gathered = tokens.iter().map(|&t| if t < voc { t } else { 0 });
+        self.kernels.gather(&mut x, table, gathered, &ThisThread);
+        let mut x = if x.data_layout() == dt {
+            x
+        } else {
+            cast_to(&x, dt)
+        };
+        let row = d as usize * dt.nbytes();
+        for (i, &t) in tokens.iter().enumerate().filter(|(_, &t)| t >= voc) {
+            x.physical_mut()[i * row..][..row].copy_from_slice(self.virtual_embed(t - voc));
+        }
         x
     }
 
@@ -165,26 +436,19 @@ impl CausalLM for Transformer {
     ) -> Tensor<Self::Storage> {
         let dt = self.s.config.dt;
         let d = self.s.config.d;
-        let epsilon = self.s.config.epsilon;
-
-        let mut x = hidden_state;
-        let range = DecodingMeta::select(&mut x, decoding, |dst, src| dst.copy_from_slice(src));
 
-        if range.is_empty() {
+        let Some(x) = self.lm_input(decoding, hidden_state) else {
             return Tensor::alloc(dt, &[0, d as _], Blob::new);
-        }
+        };
 
-        let lm_layernorm = &self.s.lm_layernorm;
         let lm_head = &self.s.lm_head;
-        let mut x = x.slice(&[slice![range.start => range.end], slice![=>]]);
-        let mut logits = Tensor::alloc(dt, &[x.shape()[0], lm_head.shape()[1]], Blob::new);
-
-        // 复制一个 x 以实现原地归一化
-        let x_ = x
-            .as_ref()
-            .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
-        self.kernels()
-            .rms_norm(&mut x, &x_, lm_layernorm, epsilon, self.queue());
+        let head_dt = lm_head.data_layout();
+        let x = if head_dt == dt {
+            x
+        } else {
+            cast_to(&x, head_dt)
+        };
+        let mut logits = Tensor::alloc(head_dt, &[x.shape()[0], lm_head.shape()[1]], Blob::new);
         self.kernels()
             .mat_mul(&mut logits, 0., &x, lm_head, 1., self.queue());
 
@@ -196,23 +460,165 @@ impl CausalLM for Transformer {
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
-        let &[_, voc] = logits.shape() else { panic!() };
-        let logits: &[f16] = reslice(logits.as_slice());
-        args.into_iter()
+        match logits.data_layout() {
+            F16 => sample_typed::<f16>(args, &logits),
+            BF16 => sample_typed::<bf16>(args, &logits),
+            F32 => sample_typed::<f32>(args, &logits),
+            dt => panic!("unsupported logits type: {dt:?}"),
+        }
+    }
+
+    fn logprobs(
+        &self,
+        logits: &Tensor<Self::Storage>,
+        targets: &[(usize, utok)],
+    ) -> Option<Vec<f32>> {
+        match logits.data_layout() {
+            F16 => Some(logprobs_typed::<f16>(logits, targets)),
+            BF16 => Some(logprobs_typed::<bf16>(logits, targets)),
+            F32 => Some(logprobs_typed::<f32>(logits, targets)),
+            _ => None,
+        }
+    }
+
+    fn decode_sample(
+        &self,
+        decoding: impl IntoIterator<Item = DecodingMeta>,
+        hidden_state: Tensor<Self::Storage>,
+        args: impl IntoIterator<Item = SampleMeta>,
+    ) -> Vec<utok> {
+        let head_dt = self.s.lm_head.data_layout();
+        if head_dt != F16 || (self.lm_head_shards < 2 && self.lm_head_top.is_none()) {
+            let logits = self.decode(decoding, hidden_state);
+            return self.sample(args, logits);
+        }
+        let Some(x) = self.lm_input(decoding, hidden_state) else {
+            return Vec::new();
+        };
+        let x = if x.data_layout() == head_dt {
+            x
+        } else {
+            cast_to(&x, head_dt)
+        };
+        // 近似的 logits 只用于采样，需要精确 logits 的 `decode` 总是完整计算
+        if let Some(top) = self.lm_head_top {
+            let lm_head = &self.s.lm_head;
+            let mut logits = Tensor::alloc(head_dt, &[x.shape()[0], lm_head.shape()[1]], Blob::new);
+            screen::screen(&mut logits, &x, lm_head, top);
+            return self.sample(args, logits);
+        }
+
+        let voc = self.s.lm_head.shape()[1] as usize;
+        let args = args
+            .into_iter()
             .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
-            .enumerate()
-            .map(|(i, args)| args.random(&common_cpu::slice!(logits; voc; [i])))
+            .collect::<Vec<_>>();
+        let keep = args.iter().map(|a| a.partial_top_k()).collect::<Vec<_>>();
+        let candidates = shard::lm_head_sharded(
+            &self.kernels,
+            &x,
+            &self.s.lm_head,
+            self.lm_head_shards,
+            &keep,
+        );
+        zip(args, candidates)
+            .map(|(args, candidates)| {
+                if args.partial_top_k().is_some() {
+                    args.random_partial(candidates)
+                } else {
+                    // 需要完整的 logits
+                    let mut logits = vec![f32::NEG_INFINITY; voc];
+                    for (tok, val) in candidates {
+                        logits[tok as usize] = val;
+                    }
+                    args.random(&logits)
+                }
+            })
             .collect()
     }
 }
 
+fn sample_typed<T: BetweenF32 + PartialOrd>(
+    args: impl IntoIterator<Item = SampleMeta>,
+    logits: &Tensor<Blob>,
+) -> Vec<utok> {
+    let &[_, voc] = logits.shape() else { panic!() };
+    let logits: &[T] = reslice(logits.as_slice());
+    args.into_iter()
+        .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
+        .enumerate()
+        .map(|(i, args)| args.random(&common_cpu::slice!(logits; voc; [i])))
+        .collect()
+}
+
+fn logprobs_typed<T: BetweenF32>(logits: &Tensor<Blob>, targets: &[(usize, utok)]) -> Vec<f32> {
+    let &[_, voc] = logits.shape() else { panic!() };
+    let logits: &[T] = reslice(logits.as_slice());
+    targets
+        .iter()
+        .map(|&(row, token)| {
+            let row = logits[row * voc as usize..][..voc as usize]
+                .iter()
+                .map(T::get)
+                .collect::<Vec<_>>();
+            log_softmax(&row, token)
+        })
+        .collect()
+}
+
+impl Transformer {
+    /// 词表之后第 `i` 个虚拟词的嵌入。
+    fn virtual_embed(&self, mut i: utok) -> &[u8] {
+        for (_, prompt) in &self.soft_prompts {
+            let len = prompt.num_tokens();
+            if i < len {
+                let row = prompt.embeds.bytes_size() / len as usize;
+                return &prompt.embeds.as_slice()[i as usize * row..][..row];
+            }
+            i -= len;
+        }
+        panic!("virtual token out of range")
+    }
+
+    /// 选出需要解码的隐藏状态并归一化，作为 lm_head 的输入。
+    fn lm_input(
+        &self,
+        decoding: impl IntoIterator<Item = DecodingMeta>,
+        hidden_state: Tensor<Blob>,
+    ) -> Option<Tensor<Blob>> {
+        let epsilon = self.s.config.epsilon;
+
+        let mut x = hidden_state;
+        let range = DecodingMeta::select(&mut x, decoding, |dst, src| dst.copy_from_slice(src));
+        if range.is_empty() {
+            return None;
+        }
+
+        let mut x = x.slice(&[slice![range.start => range.end], slice![=>]]);
+        // 复制一个 x 以实现原地归一化
+        let x_ = x
+            .as_ref()
+            .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
+        self.kernels()
+            .rms_norm(&mut x, &x_, &self.s.lm_layernorm, epsilon, self.queue());
+        Some(x)
+    }
+}
+
 #[test]
 fn test_infer() {
     causal_lm::test_impl::<Transformer>(
-        (),
+        Default::default(),
         &[
             29966, 29989, 1792, 29989, 29958, 13, 29903, 388, 376, 18567, 29908, 304, 592, 21106,
             29879, 5299, 29989, 465, 22137, 29989, 29958, 13,
         ],
     );
 }
+
+#[test]
+fn test_golden() {
+    llama::test_golden::<Transformer>(Default::default(), |_, logits| {
+        logits.map_physical(|b| b.to_vec())
+    });
+}
@@ -0,0 +1,124 @@
+//! 近似地计算 lm_head，参见 [`ModelLoadMeta::lm_head_top`](crate::ModelLoadMeta::lm_head_top)。
+
+use common::f16;
+use common_cpu::tensor::{idim, reslice_mut, Tensor};
+use std::{
+    iter::zip,
+    ops::{Deref, DerefMut, Range},
+    thread,
+};
+
+/// 初筛使用隐藏层的前 `1 / SCREEN_DIV` 维。
+const SCREEN_DIV: usize = 4;
+
+/// 计算 `logits = x · w`，但只精确计算 `top` 个候选词的 logits，其余置为负无穷。
+///
+/// 候选词由前 `d / SCREEN_DIV` 维的部分积初筛，再补全剩余维度的内积。
+/// 两步都按词表分段多线程计算。只支持 f16。
+pub(crate) fn screen<T, U, V>(logits: &mut Tensor<T>, x: &Tensor<U>, w: &Tensor<V>, top: usize)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+    V: Deref<Target = [u8]>,
+{
+    let &[n, d] = x.shape() else { panic!() };
+    let &[d_, voc] = w.shape() else { panic!() };
+    assert_eq!(d, d_);
+    assert_eq!(logits.shape(), [n, voc]);
+    let (n, d, voc) = (n as usize, d as usize, voc as usize);
+    let top = top.clamp(1, voc);
+    let screen = (d / SCREEN_DIV).max(1);
+
+    let at = |t: *const f16, strides: &[idim], i: usize, j: usize| unsafe {
+        let off = i as isize * strides[0] as isize + j as isize * strides[1] as isize;
+        t.offset(off).read().to_f32()
+    };
+    // 裸指针不能跨线程，以地址传递；各线程只读取权重
+    let (x_base, w_base) = (x.base().cast::<f16>(), w.base() as usize);
+    let w_strides = [w.strides()[0], w.strides()[1]];
+    let dot = |x: &[f32], j: usize, range: Range<usize>| {
+        range
+            .map(|i| x[i] * at(w_base as *const f16, &w_strides, i, j))
+            .sum::<f32>()
+    };
+
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(voc);
+    let logits = reslice_mut::<u8, f16>(logits.as_mut_slice());
+    let mut scores = vec![(0, 0f32); voc];
+    let mut exact = vec![0f32; top];
+    for (t, row) in logits.chunks_exact_mut(voc).enumerate().take(n) {
+        let x = (0..d)
+            .map(|i| at(x_base, x.strides(), t, i))
+            .collect::<Vec<_>>();
+        let x = &x;
+        thread::scope(|s| {
+            let chunk = voc.div_ceil(threads);
+            for (k, part) in scores.chunks_mut(chunk).enumerate() {
+                s.spawn(move || {
+                    for (j, s) in (k * chunk..).zip(part) {
+                        *s = (j, dot(x, j, 0..screen));
+                    }
+                });
+            }
+        });
+        if top < voc {
+            scores.select_nth_unstable_by(top - 1, |a, b| b.1.total_cmp(&a.1));
+        }
+        thread::scope(|s| {
+            let chunk = top.div_ceil(threads);
+            for (part, exact) in zip(scores[..top].chunks(chunk), exact.chunks_mut(chunk)) {
+                s.spawn(move || {
+                    for (&(j, partial), e) in zip(part, exact) {
+                        *e = partial + dot(x, j, screen..d);
+                    }
+                });
+            }
+        });
+        row.fill(f16::NEG_INFINITY);
+        for (&(j, _), &e) in zip(&scores[..top], &exact) {
+            row[j] = f16::from_f32(e);
+        }
+    }
+}
+
+#[test]
+fn test_screen() {
+    use common_cpu::tensor::reslice;
+    use digit_layout::types::F16;
+
+    let (d, voc) = (16, 32);
+    // 第 7 个词的权重与输入同向，logits 明显最大
+    let x = (0..d)
+        .map(|i| f16::from_f32(i as f32 / 8.))
+        .collect::<Vec<_>>();
+    let w = (0..voc * d)
+        .map(|k| {
+            let (j, i) = (k / d, k % d);
+            f16::from_f32(if j == 7 {
+                i as f32 / 8.
+            } else {
+                (j + i) as f32 / 256.
+            })
+        })
+        .collect::<Vec<_>>();
+    let x = Tensor::new(F16, &[1, d as _], reslice::<f16, u8>(&x));
+    // 按 `[voc, d]` 存储，与加载的 lm_head 相同
+    let w = Tensor::new(F16, &[voc as _, d as _], reslice::<f16, u8>(&w)).transpose(&[1, 0]);
+
+    let mut logits = vec![f16::ZERO; voc];
+    let mut t = Tensor::new(F16, &[1, voc as _], reslice_mut::<f16, u8>(&mut logits));
+    screen(&mut t, &x, &w, 4);
+    let computed = logits.iter().filter(|l| l.is_finite()).count();
+    assert_eq!(computed, 4);
+    let best = (0..voc).max_by(|&a, &b| logits[a].total_cmp(&logits[b]));
+    assert_eq!(best, Some(7));
+
+    // 候选词覆盖整个词表时结果是精确的
+    let mut exact = vec![f16::ZERO; voc];
+    let mut t = Tensor::new(F16, &[1, voc as _], reslice_mut::<f16, u8>(&mut exact));
+    screen(&mut t, &x, &w, voc);
+    assert!(exact.iter().all(|l| l.is_finite()));
+    assert_eq!(exact[7], logits[7]);
+}
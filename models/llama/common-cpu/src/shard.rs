@@ -0,0 +1,117 @@
+//! 按词表分片并行计算 lm_head，参见 [`ModelLoadMeta::lm_head_shards`](crate::ModelLoadMeta::lm_head_shards)。
+
+use common::{f16, utok, Blob};
+use common_cpu::{
+    tensor::{reslice, slice, Tensor},
+    CpuKernels, KernelsA, ThisThread,
+};
+use rayon::prelude::*;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    iter::zip,
+    ops::Deref,
+};
+
+/// 在 rayon 的线程池中计算 `x · w` 的各段词表，只保留各行需要的候选词，不拼接完整的 logits。
+///
+/// 分片的矩阵乘在同一个线程池中执行，不会因分片而额外创建线程。
+/// `keep[i]` 是第 `i` 行在每个分片中保留的 logits 最大的词的数量，`None` 保留整个分片。
+/// 返回每行的候选词及其 logit。
+pub(crate) fn lm_head_sharded<U, V>(
+    kernels: &CpuKernels,
+    x: &Tensor<U>,
+    w: &Tensor<V>,
+    shards: usize,
+    keep: &[Option<usize>],
+) -> Vec<Vec<(utok, f32)>>
+where
+    U: Deref<Target = [u8]> + Sync,
+    V: Deref<Target = [u8]> + Sync,
+{
+    let n = x.shape()[0];
+    let voc = w.shape()[1];
+    assert_eq!(n as usize, keep.len());
+    let shards = shards.clamp(1, voc as usize) as u32;
+
+    let parts = (0..shards)
+        .into_par_iter()
+        .map(|i| {
+            let (start, end) = (voc * i / shards, voc * (i + 1) / shards);
+            let w = w
+                .as_ref()
+                .map_physical(|u| &**u)
+                .slice(&[slice![=>], slice![start => end]]);
+            let len = (end - start) as usize;
+            let mut logits = Tensor::alloc(x.data_layout(), &[n, end - start], Blob::new);
+            kernels.mat_mul(&mut logits, 0., x, &w, 1., &ThisThread);
+
+            let logits: &[f16] = reslice(logits.as_slice());
+            zip(logits.chunks_exact(len), keep)
+                .map(|(row, keep)| match keep.filter(|&k| 0 < k && k < len) {
+                    Some(k) => top_k(row, start, k),
+                    None => row
+                        .iter()
+                        .enumerate()
+                        .map(|(j, l)| (start + j as utok, l.to_f32()))
+                        .collect(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut ans = vec![Vec::new(); n as usize];
+    for part in parts {
+        for (row, candidates) in zip(&mut ans, part) {
+            row.extend(candidates);
+        }
+    }
+    ans
+}
+
+/// 按 logit 比较的候选词。
+struct Candidate(f32, utok);
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// 以 `k` 个元素的小顶堆选出一行中 logits 最大的 `k` 个词，不复制整行。
+fn top_k(row: &[f16], start: utok, k: usize) -> Vec<(utok, f32)> {
+    let mut heap = BinaryHeap::with_capacity(k);
+    for (j, l) in row.iter().enumerate() {
+        let candidate = Reverse(Candidate(l.to_f32(), start + j as utok));
+        if heap.len() < k {
+            heap.push(candidate);
+        } else if heap.peek().is_some_and(|min| candidate < *min) {
+            *heap.peek_mut().unwrap() = candidate;
+        }
+    }
+    heap.into_iter()
+        .map(|Reverse(Candidate(l, tok))| (tok, l))
+        .collect()
+}
+
+#[test]
+fn test_top_k() {
+    let row = [3., 1., 4., 1., 5., 9., 2., 6.].map(f16::from_f32);
+    let mut top = top_k(&row, 10, 3);
+    top.sort_unstable_by_key(|&(tok, _)| tok);
+    assert_eq!(top, [(14, 5.), (15, 9.), (17, 6.)]);
+}
@@ -0,0 +1,221 @@
+//! LoRA 适配器，常驻内存，推理时按查询选择，不合并到模型的权重。
+
+use crate::{
+    cast::cast,
+    load::{concat0, convert},
+    InferenceConfig, Weight,
+};
+use common::{
+    safe_tensors::SafeTensors,
+    FileLoadError::{self, Io, Json},
+};
+use std::{fs::File, path::Path};
+use tensor::{udim, Tensor};
+
+/// 适配器作用的投影。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Proj {
+    Qkv,
+    O,
+    GateUp,
+    Down,
+}
+
+/// 一个投影的低秩增量 `scale * x · a · b`，累加到输出的 `offset..offset + b.shape()[1]` 列上。
+pub struct LoraDelta {
+    /// 形状为 `[输入, r]`。
+    pub a: Tensor<Weight>,
+    /// 形状为 `[r, 输出]`。
+    pub b: Tensor<Weight>,
+    /// 在合并的投影（qkv、gate_up）中输出的起始列。
+    pub offset: udim,
+}
+
+/// 一层中各投影的增量，未训练的投影为空。
+pub struct AdapterLayer {
+    pub att_qkv: Vec<LoraDelta>,
+    pub att_o: Vec<LoraDelta>,
+    pub mlp_gate_up: Vec<LoraDelta>,
+    pub mlp_down: Vec<LoraDelta>,
+}
+
+pub struct Adapter {
+    /// 增量的缩放系数，即 `lora_alpha / r`。
+    pub scale: f32,
+    pub layers: Vec<AdapterLayer>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct AdapterConfig {
+    r: usize,
+    lora_alpha: f32,
+}
+
+impl Adapter {
+    /// 加载 PEFT 格式的适配器（`adapter_config.json` 和 `adapter_model.safetensors`），
+    /// 增量转换为 `config` 的数据类型。
+    pub fn load_safetensors(
+        dir: impl AsRef<Path>,
+        config: &InferenceConfig,
+    ) -> Result<Self, FileLoadError> {
+        let dir = dir.as_ref();
+        let json = File::open(dir.join("adapter_config.json")).map_err(Io)?;
+        let json: AdapterConfig = serde_json::from_reader(&json).map_err(Json)?;
+        let model = SafeTensors::single_file(dir.join("adapter_model.safetensors"))?.share();
+
+        let &InferenceConfig {
+            dt,
+            nlayers,
+            nh,
+            nkvh,
+            d,
+            dkv,
+            di,
+            ..
+        } = config;
+        let dh = d / nh;
+        let layers = (0..nlayers)
+            .map(|l| {
+                // q、k 的输出维度与模型的权重相同地重排，参见 `Storage::load_safetensors`
+                let lora = |name: &str, [out, in_]: [udim; 2], heads: Option<udim>, offset| {
+                    let name =
+                        |ab: &str| format!("base_model.model.model.layers.{l}.{name}.{ab}.weight");
+                    let a = model.share_tensor(&name("lora_A"))?;
+                    let b = model.share_tensor(&name("lora_B"))?;
+                    let r = a.shape()[0] as udim;
+                    assert_eq!(a.shape(), [r as usize, in_ as usize]);
+                    assert_eq!(b.shape(), [out as usize, r as usize]);
+
+                    let a = Tensor::new(convert(a.dtype()), &[r, in_], Weight::SafeTensor(a));
+                    let b = Tensor::new(convert(b.dtype()), &[out, r], Weight::SafeTensor(b));
+                    let b = match heads {
+                        Some(n) => {
+                            concat0(&[b.reshape(&[n, 2, dh / 2, r]).transpose(&[0, 2, 1, 3])])
+                                .reshape(&[out, r])
+                        }
+                        None => b,
+                    };
+                    Some(LoraDelta {
+                        a: cast(a, dt).transpose(&[1, 0]),
+                        b: cast(b, dt).transpose(&[1, 0]),
+                        offset,
+                    })
+                };
+                AdapterLayer {
+                    att_qkv: [
+                        lora("self_attn.q_proj", [d, d], Some(nh), 0),
+                        lora("self_attn.k_proj", [dkv, d], Some(nkvh), d),
+                        lora("self_attn.v_proj", [dkv, d], None, d + dkv),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+                    att_o: lora("self_attn.o_proj", [d, d], None, 0)
+                        .into_iter()
+                        .collect(),
+                    mlp_gate_up: [
+                        lora("mlp.gate_proj", [di, d], None, 0),
+                        lora("mlp.up_proj", [di, d], None, di),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+                    mlp_down: lora("mlp.down_proj", [d, di], None, 0)
+                        .into_iter()
+                        .collect(),
+                }
+            })
+            .collect();
+        Ok(Self {
+            scale: json.lora_alpha / json.r as f32,
+            layers,
+        })
+    }
+
+    /// 所有增量占用的字节数。
+    pub fn bytes(&self) -> usize {
+        self.layers
+            .iter()
+            .flat_map(|l| [&l.att_qkv, &l.att_o, &l.mlp_gate_up, &l.mlp_down])
+            .flatten()
+            .map(|d| d.a.physical().len() + d.b.physical().len())
+            .sum()
+    }
+
+    /// 第 `layer` 层投影 `proj` 的增量。
+    pub fn deltas(&self, layer: usize, proj: Proj) -> &[LoraDelta] {
+        let layer = &self.layers[layer];
+        match proj {
+            Proj::Qkv => &layer.att_qkv,
+            Proj::O => &layer.att_o,
+            Proj::GateUp => &layer.mlp_gate_up,
+            Proj::Down => &layer.mlp_down,
+        }
+    }
+}
+
+#[test]
+fn test_load() {
+    use crate::{save::write_safetensors, tiny_model, Storage};
+    use common::{f16, Blob};
+    use digit_layout::types::F32;
+    use std::fs;
+    use tensor::{reslice, reslice_mut};
+
+    let dir = std::env::temp_dir().join(format!("llama-adapter-test-{}", std::process::id()));
+    tiny_model(&dir, 3).unwrap();
+    let config = Storage::load_safetensors(&dir).unwrap().config;
+    let (d, nh, dkv) = (config.d, config.nh, config.dkv);
+    let dh = d / nh;
+    let r = 2;
+
+    // 元素的值编码了它的下标，便于检查重排
+    let tensor = |shape: [udim; 2]| {
+        let mut t = Tensor::alloc(F32, &shape, Blob::new);
+        let data = reslice_mut::<u8, f32>(t.physical_mut());
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = i as f32;
+        }
+        t.map_physical(|b| b.into())
+    };
+    let name =
+        |module: &str, ab: &str| format!("base_model.model.model.layers.1.{module}.{ab}.weight");
+    let adapter_dir = dir.join("adapter");
+    fs::create_dir_all(&adapter_dir).unwrap();
+    write_safetensors(
+        &adapter_dir.join("adapter_model.safetensors"),
+        &[
+            (name("self_attn.q_proj", "lora_A"), tensor([r, d])),
+            (name("self_attn.q_proj", "lora_B"), tensor([d, r])),
+            (name("self_attn.v_proj", "lora_A"), tensor([r, d])),
+            (name("self_attn.v_proj", "lora_B"), tensor([dkv, r])),
+        ],
+    )
+    .unwrap();
+    fs::write(
+        adapter_dir.join("adapter_config.json"),
+        r#"{"r": 2, "lora_alpha": 8, "target_modules": ["q_proj", "v_proj"]}"#,
+    )
+    .unwrap();
+
+    let adapter = Adapter::load_safetensors(&adapter_dir, &config).unwrap();
+    assert_eq!(adapter.scale, 4.);
+    assert!(adapter.deltas(0, Proj::Qkv).is_empty());
+    let qkv = adapter.deltas(1, Proj::Qkv);
+    assert_eq!(qkv.len(), 2);
+    assert_eq!((qkv[0].offset, qkv[1].offset), (0, d + dkv));
+    assert_eq!(qkv[0].a.shape(), [d, r]);
+    assert_eq!(qkv[0].b.shape(), [r, d]);
+    assert_eq!(qkv[1].b.shape(), [r, dkv]);
+    assert_eq!(qkv[0].b.data_layout(), config.dt);
+    assert_eq!(adapter.bytes(), (2 * r * d + r * d + r * dkv) as usize * 2);
+
+    // q 的第 `i` 个输出来自 HF 布局中一个头内前后两半的交错
+    let b = reslice::<u8, f16>(qkv[0].b.physical());
+    for i in 0..d {
+        let (head, k, half) = (i / dh, i % dh / 2, i % 2);
+        let src = head * dh + half * (dh / 2) + k;
+        assert_eq!(b[(i * r) as usize].to_f32(), (src * r) as f32);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
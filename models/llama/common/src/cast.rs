@@ -1,20 +1,40 @@
 ﻿use crate::{InferenceConfig, LayerStorage, Storage, Weight};
-use common::{bf16, f16, Blob};
+use common::{
+    bf16, f16,
+    fp8::{f8e4m3, f8e5m2, F8E4M3, F8E5M2},
+    quant::{block_bytes, dequantize_row, quantize_row, row_bytes, BLOCK},
+    Blob,
+};
 use digit_layout::{
     types::{BF16, F16, F32},
     AsDigit, DigitLayout,
 };
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use tensor::Tensor;
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+use std::{mem::size_of, ops::Deref};
+use tensor::{reslice, reslice_mut, Tensor};
 
 impl Storage {
     pub fn cast(self, dt: DigitLayout) -> Self {
-        if self.config.dt == dt {
+        if self.config.dt == dt
+            && self.embed_tokens.data_layout() == dt
+            && self.lm_head.data_layout() == dt
+        {
             return self;
         }
+        self.cast_parts(dt, dt, dt)
+    }
+
+    /// 主体、词嵌入和 lm_head 分别转换为各自的类型。
+    ///
+    /// 词嵌入和 lm_head 对精度更敏感，降低主体的精度时常让它们保留更高的精度。
+    pub fn cast_parts(self, body: DigitLayout, embed: DigitLayout, lm_head: DigitLayout) -> Self {
+        let dt = body;
         Self {
             config: InferenceConfig { dt, ..self.config },
-            embed_tokens: cast(self.embed_tokens, dt),
+            embed_tokens: cast(self.embed_tokens, embed),
             layers: self
                 .layers
                 .into_iter()
@@ -28,12 +48,89 @@ impl Storage {
                 })
                 .collect(),
             lm_layernorm: cast(self.lm_layernorm, dt),
-            lm_head: cast(self.lm_head, dt),
+            lm_head: cast(self.lm_head, lm_head),
+        }
+    }
+
+    /// 只转换各层的投影矩阵，归一化的权重和激活仍使用主体的类型。
+    ///
+    /// 用于以 8 位浮点数存储矩阵，矩阵乘时再转换为主体的类型；
+    /// `dt` 也可以是 [`Q8_0`](common::quant::Q8_0) 或 [`Q4_0`](common::quant::Q4_0)，矩阵按行分块量化，
+    /// 输入维度不能分块的矩阵保持原来的类型。
+    /// 已经分块量化的矩阵（如加载的 GPTQ 和 AWQ 权重）先反量化，不支持分块量化的后端以主体的类型调用即可。
+    pub fn cast_matrices(self, dt: DigitLayout) -> Self {
+        Self {
+            layers: self
+                .layers
+                .into_iter()
+                .map(|l| LayerStorage {
+                    att_qkv: cast(l.att_qkv, dt),
+                    att_o: cast(l.att_o, dt),
+                    mlp_gate_up: cast(l.mlp_gate_up, dt),
+                    mlp_down: cast(l.mlp_down, dt),
+                    ..l
+                })
+                .collect(),
+            ..self
         }
     }
 }
 
-fn cast(src: Tensor<Weight>, dt: DigitLayout) -> Tensor<Weight> {
+pub(crate) fn cast(src: Tensor<Weight>, dt: DigitLayout) -> Tensor<Weight> {
+    if src.data_layout() == dt {
+        src
+    } else if block_bytes(src.data_layout()).is_some() {
+        cast(dequantize(&src), dt)
+    } else if block_bytes(dt).is_some() {
+        quantize(&src, dt).unwrap_or(src)
+    } else {
+        cast_to(&src, dt).map_physical(|b| b.into())
+    }
+}
+
+/// 将投影矩阵 `[输入, 输出]` 的视图按原始矩阵的行分块量化，结果仍是 `[每行的字节数, 输出]` 的转置视图。
+///
+/// 输入维度不是 [`BLOCK`](common::quant::BLOCK) 的整数倍时返回 `None`。
+fn quantize(src: &Tensor<Weight>, dt: DigitLayout) -> Option<Tensor<Weight>> {
+    let &[k, n] = src.shape() else {
+        panic!("only matrices can be quantized")
+    };
+    let bytes = row_bytes(dt, k as _)?;
+    // 原始矩阵的每一行是视图的一列，先整理为连续的 f32 行
+    let rows = cast_to(&src.as_ref().map_physical(|w| &**w).transpose(&[1, 0]), F32);
+    let mut x = Tensor::alloc(F32, &[n, k], Blob::new);
+    rows.reform_to(&mut x);
+
+    let mut ans = Blob::new(n as usize * bytes);
+    reslice::<u8, f32>(x.physical())
+        .par_chunks_exact(k as _)
+        .zip(ans.par_chunks_exact_mut(bytes))
+        .for_each(|(x, q)| quantize_row(dt, x, q));
+    Some(
+        Tensor::new(dt, &[n, bytes as _], ans)
+            .map_physical(|b| b.into())
+            .transpose(&[1, 0]),
+    )
+}
+
+/// 将 `[每行的字节数, 输出]` 的分块量化矩阵的视图反量化为 f32 的 `[输入, 输出]` 视图。
+fn dequantize(src: &Tensor<Weight>) -> Tensor<Weight> {
+    let dt = src.data_layout();
+    let &[bytes, n] = src.shape() else {
+        panic!("only matrices can be quantized")
+    };
+    let k = bytes as usize / block_bytes(dt).unwrap() * BLOCK;
+    // 分块量化的矩阵总是原始矩阵连续存储的行
+    let mut ans = Tensor::alloc(F32, &[n, k as _], Blob::new);
+    src.physical()
+        .par_chunks_exact(bytes as _)
+        .zip(reslice_mut::<u8, f32>(ans.physical_mut()).par_chunks_exact_mut(k))
+        .for_each(|(q, y)| dequantize_row(dt, q, y));
+    ans.map_physical(|b| b.into()).transpose(&[1, 0])
+}
+
+/// 逐元素转换张量的数据类型，保持原有的布局。
+pub fn cast_to<T: Deref<Target = [u8]>>(src: &Tensor<T>, dt: DigitLayout) -> Tensor<Blob> {
     match (src.data_layout(), dt) {
         (F16, BF16) => typed(src, |x: &f16| bf16::from_f32(x.to_f32())),
         (F16, F32) => typed(src, |x: &f16| x.to_f32()),
@@ -41,23 +138,109 @@ fn cast(src: Tensor<Weight>, dt: DigitLayout) -> Tensor<Weight> {
         (BF16, F32) => typed(src, |x: &bf16| x.to_f32()),
         (F32, F16) => typed(src, |x: &f32| f16::from_f32(*x)),
         (F32, BF16) => typed(src, |x: &f32| bf16::from_f32(*x)),
+        (F16, F8E4M3) => typed(src, |x: &f16| f8e4m3::from_f32(x.to_f32())),
+        (F16, F8E5M2) => typed(src, |x: &f16| f8e5m2::from_f32(x.to_f32())),
+        (BF16, F8E4M3) => typed(src, |x: &bf16| f8e4m3::from_f32(x.to_f32())),
+        (BF16, F8E5M2) => typed(src, |x: &bf16| f8e5m2::from_f32(x.to_f32())),
+        (F32, F8E4M3) => typed(src, |x: &f32| f8e4m3::from_f32(*x)),
+        (F32, F8E5M2) => typed(src, |x: &f32| f8e5m2::from_f32(*x)),
+        (F8E4M3, F16) => typed(src, |x: &f8e4m3| f16::from_f32(x.to_f32())),
+        (F8E4M3, F32) => typed(src, |x: &f8e4m3| x.to_f32()),
+        (F8E5M2, F16) => typed(src, |x: &f8e5m2| f16::from_f32(x.to_f32())),
+        (F8E5M2, F32) => typed(src, |x: &f8e5m2| x.to_f32()),
+        (a, b) if a == b => {
+            let mut ans = Blob::new(src.physical().len());
+            ans.copy_from_slice(src.physical());
+            unsafe { Tensor::from_raw_parts(dt, src.shape(), src.pattern(), ans) }
+        }
         _ => todo!(),
     }
 }
 
-fn typed<T: AsDigit + Sync, U: AsDigit + Send>(
-    src: Tensor<Weight>,
-    cast: impl Fn(&T) -> U + Sync,
-) -> Tensor<Weight> {
+fn typed<P, T, U>(src: &Tensor<P>, cast: impl Fn(&T) -> U + Sync) -> Tensor<Blob>
+where
+    P: Deref<Target = [u8]>,
+    T: AsDigit + Sync,
+    U: AsDigit + Send,
+{
     use tensor::{reslice, reslice_mut};
 
     assert_eq!(src.data_layout(), T::LAYOUT);
-    let mut ans = Tensor::alloc(U::LAYOUT, src.shape(), Blob::new);
-
-    reslice(src.physical())
-        .par_iter()
-        .zip(reslice_mut(ans.physical_mut()))
+    let src_ = reslice::<u8, T>(src.physical());
+    let mut ans = Blob::new(src_.len() * size_of::<U>());
+    src_.par_iter()
+        .zip(reslice_mut(&mut ans))
         .for_each(|(src, dst)| *dst = cast(src));
+    // 整个存储逐元素转换，转置等视图的步长和偏移仍然有效
+    unsafe { Tensor::from_raw_parts(U::LAYOUT, src.shape(), src.pattern(), ans) }
+}
+
+#[test]
+fn test_cast_parts() {
+    use crate::tiny_model;
+
+    let dir = std::env::temp_dir().join(format!("llama-cast-test-{}", std::process::id()));
+    tiny_model(&dir, 2).unwrap();
+    let storage = Storage::load_safetensors(&dir).unwrap();
+    let lm_head = storage.lm_head.clone();
+    assert_eq!(lm_head.data_layout(), F16);
+
+    let storage = storage.cast_parts(F16, F32, F32);
+    assert_eq!(storage.config.dt, F16);
+    assert_eq!(storage.layers[0].att_qkv.data_layout(), F16);
+    assert_eq!(storage.embed_tokens.data_layout(), F32);
+    // lm_head 是转置的视图，转换后逐个元素仍然对应
+    let cast = &storage.lm_head;
+    assert_eq!(cast.data_layout(), F32);
+    assert_eq!(cast.shape(), lm_head.shape());
+    assert_eq!(cast.pattern(), lm_head.pattern());
+    let src = reslice::<u8, f16>(lm_head.physical());
+    let dst = reslice::<u8, f32>(cast.physical());
+    assert!(src.iter().zip(dst).all(|(a, b)| a.to_f32() == *b));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_quantize() {
+    use crate::tiny_model;
+    use common::quant::Q4_0;
+
+    let dir = std::env::temp_dir().join(format!("llama-quantize-test-{}", std::process::id()));
+    tiny_model(&dir, 3).unwrap();
+    let storage = Storage::load_safetensors(&dir).unwrap();
+    let o = storage.layers[0].att_o.clone();
+    let &[k, n] = o.shape() else { panic!() };
+
+    let storage = storage.cast_matrices(Q4_0).pretranspose();
+    let q = &storage.layers[0].att_o;
+    let bytes = row_bytes(Q4_0, k as _).unwrap();
+    assert_eq!(q.data_layout(), Q4_0);
+    assert_eq!(q.shape(), [bytes as _, n]);
+    // 量化的矩阵不整理，每一列仍是原始矩阵连续的一行
+    assert_eq!(q.strides()[0], 1);
+    assert_eq!(storage.layers[0].att_layernorm.data_layout(), F16);
+    // 输入维度不是块的整数倍的矩阵不量化
+    assert_ne!(storage.layers[0].mlp_down.shape()[0] as usize % BLOCK, 0);
+    assert_eq!(storage.layers[0].mlp_down.data_layout(), F16);
+
+    let src = cast_to(&o.transpose(&[1, 0]), F32);
+    let src = reslice::<u8, f32>(src.physical());
+    let mut row = vec![0.; k as usize];
+    dequantize_row(Q4_0, &q.physical()[..bytes], &mut row);
+    let amax = row.iter().fold(0f32, |m, x| m.max(x.abs()));
+    assert!(src[..k as usize]
+        .iter()
+        .zip(&row)
+        .all(|(x, y)| (x - y).abs() <= amax / 4.));
+
+    // 量化的矩阵转换回浮点数时先反量化
+    let storage = storage.cast_matrices(F32);
+    let o = &storage.layers[0].att_o;
+    assert_eq!(o.data_layout(), F32);
+    assert_eq!(o.shape(), [k, n]);
+    let o = cast_to(&o.clone().transpose(&[1, 0]), F32);
+    assert_eq!(&reslice::<u8, f32>(o.physical())[..k as usize], &*row);
 
-    ans.map_physical(|b| b.into())
+    std::fs::remove_dir_all(dir).unwrap();
 }
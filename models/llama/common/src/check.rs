@@ -0,0 +1,195 @@
+//! 不加载权重地检查模型目录，用于快速验证模型文件。
+
+use crate::{load::read_config, quant::Quantization, InferenceConfig, Storage};
+use common::{
+    safe_tensors::{Dtype, SafeTensors},
+    FileLoadError,
+};
+use digit_layout::DigitLayout;
+use std::path::Path;
+use tensor::udim;
+
+/// 检查模型目录的结果。
+#[derive(Debug)]
+pub struct CheckReport {
+    /// 从配置文件解析的推理配置。
+    pub config: InferenceConfig,
+    /// 权重文件数。
+    pub files: usize,
+    /// 权重文件中的张量数。
+    pub tensors: usize,
+    /// 模型需要但缺少、类型或形状不符的张量。
+    pub problems: Vec<String>,
+}
+
+impl Storage {
+    /// 解析配置并映射权重文件，按加载时的要求检查张量的存在、类型和形状，不复制也不转换任何权重。
+    ///
+    /// 配置文件或权重文件无法解析时返回错误，张量的问题收集在报告中。
+    pub fn check_safetensors(model_dir: impl AsRef<Path>) -> Result<CheckReport, FileLoadError> {
+        let (json, config) = read_config(model_dir.as_ref())?;
+        let model = SafeTensors::load_from_dir(model_dir)?;
+        let quant = json
+            .quantization_config
+            .as_ref()
+            .map(Quantization::new)
+            .transpose()?;
+
+        let &InferenceConfig {
+            dt,
+            voc,
+            nlayers,
+            d,
+            dkv,
+            di,
+            ..
+        } = &config;
+        let mut checker = Checker {
+            model: &model,
+            problems: Vec::new(),
+        };
+        checker.float("model.embed_tokens.weight", &[voc, d]);
+        for l in 0..nlayers {
+            let name = |name: &str| format!("model.layers.{l}.{name}.weight");
+            checker.exact(&name("input_layernorm"), dt, &[d]);
+            checker.exact(&name("post_attention_layernorm"), dt, &[d]);
+            // 投影矩阵可以合并存储，量化的模型只检查量化的权重是否存在
+            let qkv = name("self_attn.qkv_proj");
+            if model.contains(&qkv) {
+                checker.float(&qkv, &[d + dkv + dkv, d]);
+            } else {
+                checker.group(
+                    l,
+                    quant.is_some(),
+                    &[
+                        ("self_attn.q_proj", [d, d]),
+                        ("self_attn.k_proj", [dkv, d]),
+                        ("self_attn.v_proj", [dkv, d]),
+                    ],
+                );
+            }
+            checker.group(l, quant.is_some(), &[("self_attn.o_proj", [d, d])]);
+            let gate_up = name("mlp.gate_up_proj");
+            if model.contains(&gate_up) {
+                checker.float(&gate_up, &[di + di, d]);
+            } else {
+                checker.group(
+                    l,
+                    quant.is_some(),
+                    &[("mlp.gate_proj", [di, d]), ("mlp.up_proj", [di, d])],
+                );
+            }
+            checker.group(l, quant.is_some(), &[("mlp.down_proj", [d, di])]);
+        }
+        checker.exact("model.norm.weight", dt, &[d]);
+        checker.float("lm_head.weight", &[voc, d]);
+
+        Ok(CheckReport {
+            files: model.files_count(),
+            tensors: model.tensors_count(),
+            problems: checker.problems,
+            config,
+        })
+    }
+}
+
+struct Checker<'a> {
+    model: &'a SafeTensors,
+    problems: Vec<String>,
+}
+
+impl Checker<'_> {
+    /// 检查张量存在且形状正确，返回它的类型。
+    fn shape(&mut self, name: &str, shape: &[udim]) -> Option<Dtype> {
+        let Some(tensor) = self.model.get(name) else {
+            self.problems.push(format!("missing tensor: {name}"));
+            return None;
+        };
+        if !tensor
+            .shape
+            .iter()
+            .map(|&d| d as udim)
+            .eq(shape.iter().copied())
+        {
+            self.problems.push(format!(
+                "wrong shape of {name}: {:?}, expected {shape:?}",
+                tensor.shape
+            ));
+        }
+        Some(tensor.dtype)
+    }
+
+    /// 类型必须与模型的类型相同的张量。
+    fn exact(&mut self, name: &str, dt: DigitLayout, shape: &[udim]) {
+        if let Some(dtype) = self.shape(name, shape) {
+            if !same_type(dtype, dt) {
+                self.problems
+                    .push(format!("wrong dtype of {name}: {dtype:?}, expected {dt:?}"));
+            }
+        }
+    }
+
+    /// 可以以任意浮点类型存储的张量。
+    fn float(&mut self, name: &str, shape: &[udim]) -> Option<Dtype> {
+        let dtype = self.shape(name, shape)?;
+        use Dtype::*;
+        if !matches!(dtype, F8_E4M3 | F8_E5M2 | F16 | BF16 | F32) {
+            self.problems
+                .push(format!("unsupported dtype of {name}: {dtype:?}"));
+        }
+        Some(dtype)
+    }
+
+    /// 加载时拼接在一起的一组投影矩阵，类型必须相同；量化的矩阵只检查 `qweight` 存在。
+    fn group(&mut self, layer: udim, quantized: bool, group: &[(&str, [udim; 2])]) {
+        if quantized {
+            for (name, _) in group {
+                let name = format!("model.layers.{layer}.{name}.qweight");
+                if !self.model.contains(&name) {
+                    self.problems.push(format!("missing tensor: {name}"));
+                }
+            }
+            return;
+        }
+        let dtypes = group
+            .iter()
+            .map(|(name, shape)| self.float(&format!("model.layers.{layer}.{name}.weight"), shape))
+            .collect::<Vec<_>>();
+        if let [Some(first), rest @ ..] = &*dtypes {
+            if rest.iter().flatten().any(|dt| dt != first) {
+                self.problems
+                    .push(format!("mixed dtypes in layer {layer}: {dtypes:?}"));
+            }
+        }
+    }
+}
+
+fn same_type(dtype: Dtype, dt: DigitLayout) -> bool {
+    use digit_layout::types as ty;
+    matches!(
+        (dtype, dt),
+        (Dtype::F16, ty::F16) | (Dtype::BF16, ty::BF16) | (Dtype::F32, ty::F32)
+    )
+}
+
+#[test]
+fn test_check() {
+    use std::fs;
+
+    let dir = std::env::temp_dir().join(format!("llama-check-test-{}", std::process::id()));
+    crate::tiny_model(&dir, 1).unwrap();
+    let report = Storage::check_safetensors(&dir).unwrap();
+    assert_eq!(report.config.nlayers, 2);
+    assert_eq!(report.files, 1);
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+    // 声明的层数多于文件中的层
+    let config = fs::read_to_string(dir.join("config.json")).unwrap();
+    let config = config.replace("\"num_hidden_layers\": 2", "\"num_hidden_layers\": 3");
+    fs::write(dir.join("config.json"), config).unwrap();
+    let report = Storage::check_safetensors(&dir).unwrap();
+    assert!(report
+        .problems
+        .contains(&"missing tensor: model.layers.2.input_layernorm.weight".to_string()));
+    fs::remove_dir_all(dir).unwrap();
+}
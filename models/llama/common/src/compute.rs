@@ -1,9 +1,11 @@
-﻿use causal_lm::QueryContext;
+use crate::Proj;
+use causal_lm::QueryContext;
+use common::profiler::Timer;
 use common_devices::{Kernels, KernelsA, SliceOn};
 use itertools::izip;
 use operators::{Handle, QueueOf};
 use std::ops::{Deref, DerefMut};
-use tensor::{slice, split, udim, LocalSplitable, Tensor};
+use tensor::{slice, split, udim, LocalSplitable, Named, Tensor};
 
 pub trait ComputeStream {
     type Handle: Handle;
@@ -31,6 +33,35 @@ pub trait ComputeStream {
         &self,
     ) -> impl Iterator<Item = impl LLamaLayer<Byte = <Self::Handle as Handle>::Byte>>;
 
+    /// 将第 `layer` 层投影 `proj` 的低秩增量 `x · Δw` 累加到 `y` 上，`adapters` 依次为各查询的适配器和 token 数。
+    ///
+    /// 只在有查询使用适配器时调用，默认不支持适配器。
+    fn lora<Y, X>(
+        &self,
+        _layer: usize,
+        _proj: Proj,
+        _y: &mut Tensor<Y>,
+        _x: &Tensor<X>,
+        _adapters: &[(Option<usize>, udim)],
+    ) where
+        Y: DerefMut<Target = SliceOn<Self::Handle>>,
+        X: Deref<Target = SliceOn<Self::Handle>>,
+    {
+    }
+
+    /// 将控制向量按强度累加到第 `layer` 层输出的残差 `x` 上，`steering` 依次为各查询的控制向量和 token 数。
+    ///
+    /// 只在有查询使用控制向量时调用，默认不支持控制向量。
+    fn steer<X>(
+        &self,
+        _layer: usize,
+        _x: &mut Tensor<X>,
+        _steering: &[(Option<(usize, f32)>, udim)],
+    ) where
+        X: DerefMut<Target = SliceOn<Self::Handle>>,
+    {
+    }
+
     fn forward<'q>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'q, Self::Storage>>,
@@ -54,6 +85,32 @@ pub trait ComputeStream {
                 seq
             })
             .collect::<Vec<_>>();
+        // 有 kv cache 的查询占据的 token 区间，相邻的合并；其他查询不参与注意力，不必计算 qkv
+        let mut attended = Vec::<(udim, udim)>::new();
+        let mut start = 0;
+        for query in &queries {
+            let len = query.seq_len();
+            if query.cache.is_some() && len > 0 {
+                match attended.last_mut() {
+                    Some((s, l)) if *s + *l == start => *l += len,
+                    _ => attended.push((start, len)),
+                }
+            }
+            start += len;
+        }
+        // 各查询的适配器和控制向量，都不使用时不必叠加
+        let adapters = queries
+            .iter()
+            .map(|q| (q.adapter, q.seq_len()))
+            .collect::<Vec<_>>();
+        let adapted = adapters.iter().any(|(a, _)| a.is_some());
+        let steering = queries
+            .iter()
+            .map(|q| (q.steering, q.seq_len()))
+            .collect::<Vec<_>>();
+        let steered = steering.iter().any(|(s, _)| s.is_some());
+        // 稳定的解码阶段每个查询只有一个 token
+        let decoding = seq_len.iter().all(|&len| len == 1);
 
         let ComputeConst {
             nh,
@@ -61,13 +118,13 @@ pub trait ComputeStream {
             di,
             epsilon,
             theta,
+            attn_scale,
         } = self.constant();
         let dt = token_embedded.data_layout();
         let d = token_embedded.shape()[1];
         let dh = d / nh;
         let dkv = nkvh * dh;
         let head_group = nh / nkvh;
-        let head_div = (dh as f32).sqrt().recip();
         let queue = self.queue();
 
         let mut x = token_embedded
@@ -82,87 +139,225 @@ pub trait ComputeStream {
         let pos = pos.as_ref().map_physical(|u| self.map_pos(u));
 
         for (layer, params) in self.layers().enumerate() {
+            let mut timer = Timer::start(layer);
             let (mut x1, qkv) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing);
             let mut qkv = qkv.slice(&[slice![=>], slice![=> d + dkv + dkv]]);
 
             self.kernels()
                 .rms_norm(&mut x1, &x, &params.att_layernorm(), epsilon, queue);
-            self.kernels()
-                .mat_mul(&mut qkv, 0., &x1, &params.att_qkv(), 1., queue);
+            let w = params.att_qkv();
+            for &(start, len) in &attended {
+                let rows = &[slice![start =>=> len], slice![=>]];
+                let mut qkv = qkv.as_mut().map_physical(|u| &mut **u).slice(rows);
+                let x1 = x1.as_ref().map_physical(|u| &**u).slice(rows);
+                self.kernels().mat_mul(&mut qkv, 0., &x1, &w, 1., queue);
+            }
+            if adapted {
+                self.lora(layer, Proj::Qkv, &mut qkv, &x1, &adapters);
+            }
+            timer.lap("qkv");
 
             let (q, k, v) = split!(qkv; [1]: d, dkv, dkv);
-            let mut q = q.reshape(&[nt, nh, dh]);
-            let mut k = k.reshape(&[nt, nkvh, dh]);
-            let v = v.reshape(&[nt, nkvh, dh]);
-            let o = x1.reshape(&[nt, nh, dh]);
-
-            self.kernels().rope(&mut q, &pos, theta, queue);
-            self.kernels().rope(&mut k, &pos, theta, queue);
-
-            let q = q.transpose(&[1, 0, 2]).split(1, &seq_len);
-            let k = k.transpose(&[1, 0, 2]).split(1, &seq_len);
-            let v = v.transpose(&[1, 0, 2]).split(1, &seq_len);
-            let o = o.transpose(&[1, 0, 2]).split(1, &seq_len);
-
-            for (query, q, k, v, mut o) in izip!(&mut queries, q, k, v, o) {
-                let pos = query.pos();
-                let seq_len = query.seq_len();
-                let att_len = query.att_len();
-                let mut cache = query
-                    .cache
-                    .as_mut()
-                    .map(|t| t.as_mut().map_physical(|u| self.map_storage(u)));
-                let mut query = QueryContext {
-                    cache: cache.as_mut(),
-                    range: query.range.clone(),
-                };
-                let Some((mut k_cache, mut v_cache)) = query.cache(layer as _) else {
-                    continue;
-                };
-
-                let slice_cat = &[slice![=>], slice![pos =>=> seq_len], slice![=>]];
-                let slice_att = &[slice![=>], slice![      => att_len], slice![=>]];
-                let shape_q0 = &[nkvh * head_group, seq_len, dh];
-                let shape_q1 = &[nkvh, head_group * seq_len, dh];
-                let shape_att0 = &[nkvh, head_group * seq_len, att_len];
-                let shape_att1 = &[nkvh * head_group, seq_len, att_len];
-
-                let mut q_att = Tensor::new(dt, shape_q0, &mut q_buf[..]);
-                let mut k_cat = k_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
-                let mut v_cat = v_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
-                self.kernels().reform(&mut q_att, &q, queue);
-                self.kernels().reform(&mut k_cat, &k, queue);
-                self.kernels().reform(&mut v_cat, &v, queue);
-
-                let q_att = q_att.reshape(shape_q1);
-                let k_att = k_cache.slice(slice_att).transpose(&[0, 2, 1]);
-                let v_att = v_cache.slice(slice_att);
-
-                let mut att = Tensor::new(dt, shape_att0, &mut att_buf[..]);
-                self.kernels()
-                    .mat_mul(&mut att, 0., &q_att, &k_att, head_div, queue);
-                let mut att = att.reshape(shape_att1);
-                self.kernels().softmax(&mut att, queue);
-                let mut x2 = q_att;
-                self.kernels()
-                    .mat_mul(&mut x2, 0., &att.reshape(shape_att0), &v_att, 1., queue);
-
-                self.kernels().reform(&mut o, &x2.reshape(shape_q0), queue);
+            let q = q
+                .named(&["nt", "d"])
+                .reshape(&[("nt", nt), ("nh", nh), ("dh", dh)]);
+            let k = k
+                .named(&["nt", "dkv"])
+                .reshape(&[("nt", nt), ("nkvh", nkvh), ("dh", dh)]);
+            let v = v
+                .named(&["nt", "dkv"])
+                .reshape(&[("nt", nt), ("nkvh", nkvh), ("dh", dh)]);
+            let o = x1
+                .named(&["nt", "d"])
+                .reshape(&[("nt", nt), ("nh", nh), ("dh", dh)]);
+
+            if decoding {
+                // 每个查询只有一个 token：q 原地旋转后直接参与注意力，不复制到注意力的缓冲区，也不按查询切分
+                let (mut q, mut o) = (q, o);
+                self.kernels().rope(&mut q, &pos, theta, queue);
+                for (i, query) in queries.iter_mut().enumerate() {
+                    let i = i as udim;
+                    let row = &[slice![i =>=> 1], slice![=>], slice![=>]];
+                    let pos_q = pos
+                        .as_ref()
+                        .map_physical(|u| &**u)
+                        .slice(&[slice![i =>=> 1]]);
+
+                    let pos = query.pos();
+                    let att_len = query.att_len();
+                    let mut cache = query
+                        .cache
+                        .as_mut()
+                        .map(|t| t.as_mut().map_physical(|u| self.map_storage(u)));
+                    let mut query = QueryContext {
+                        cache: cache.as_mut(),
+                        range: query.range.clone(),
+                        adapter: query.adapter,
+                        steering: query.steering,
+                    };
+                    let Some((mut k_cache, mut v_cache)) = query.cache(layer as _) else {
+                        continue;
+                    };
+
+                    let slice_cat = &[slice![=>], slice![pos =>=> 1], slice![=>]];
+                    let slice_att = &[slice![=>], slice![  => att_len], slice![=>]];
+                    let shape_q = [nkvh, head_group, dh];
+                    let shape_att0 = [("nkvh", nkvh), ("g", head_group), ("att", att_len)];
+                    let shape_att1 = [("nh", nh), ("nt", 1), ("att", att_len)];
+
+                    let q = q.as_ref().map_physical(|u| &**u).slice(row);
+                    let k = k.as_ref().map_physical(|u| &**u).slice(row);
+                    let v = v.as_ref().map_physical(|u| &**u).slice(row);
+                    let o = o.as_mut().map_physical(|u| &mut **u).slice(row);
+                    let mut k_cat = k_cache
+                        .as_mut()
+                        .slice(slice_cat)
+                        .map_physical(|u| &mut **u)
+                        .transpose(&[1, 0, 2]);
+                    let mut v_cat = v_cache
+                        .as_mut()
+                        .slice(slice_cat)
+                        .map_physical(|u| &mut **u)
+                        .transpose(&[1, 0, 2]);
+                    self.kernels().rope_to(&mut k_cat, &k, &pos_q, theta, queue);
+                    self.kernels().reform(&mut v_cat, &v, queue);
+
+                    let k_att = k_cache
+                        .slice(slice_att)
+                        .named(&["nkvh", "att", "dh"])
+                        .transpose(&["nkvh", "dh", "att"]);
+                    let v_att = v_cache.slice(slice_att);
+
+                    let mut att = Named::new(dt, &shape_att0, &mut att_buf[..]);
+                    self.kernels().attention_mat_mul(
+                        &mut att,
+                        0.,
+                        &q.reshape(&shape_q),
+                        &k_att,
+                        attn_scale,
+                        queue,
+                    );
+                    let mut att = att.reshape(&shape_att1);
+                    self.kernels().softmax(&mut att, queue);
+                    let att = att.reshape(&shape_att0);
+                    let mut o = o.reshape(&shape_q);
+                    self.kernels()
+                        .attention_mat_mul(&mut o, 0., &att, &v_att, 1., queue);
+                }
+            } else {
+                // q、k 在写入注意力的缓冲区和 kv cache 时旋转，不单独遍历
+                let q = q.split("nt", &seq_len);
+                let k = k.split("nt", &seq_len);
+                let v = v.transpose(&["nkvh", "nt", "dh"]).split("nt", &seq_len);
+                let o = o.transpose(&["nh", "nt", "dh"]).split("nt", &seq_len);
+
+                let mut start = 0;
+                for (query, q, k, v, mut o) in izip!(&mut queries, q, k, v, o) {
+                    let seq_len = query.seq_len();
+                    let pos_q = pos
+                        .as_ref()
+                        .map_physical(|u| &**u)
+                        .slice(&[slice![start =>=> seq_len]]);
+                    start += seq_len;
+
+                    let pos = query.pos();
+                    let att_len = query.att_len();
+                    let mut cache = query
+                        .cache
+                        .as_mut()
+                        .map(|t| t.as_mut().map_physical(|u| self.map_storage(u)));
+                    let mut query = QueryContext {
+                        cache: cache.as_mut(),
+                        range: query.range.clone(),
+                        adapter: query.adapter,
+                        steering: query.steering,
+                    };
+                    let Some((mut k_cache, mut v_cache)) = query.cache(layer as _) else {
+                        continue;
+                    };
+
+                    let slice_cat = &[slice![=>], slice![pos =>=> seq_len], slice![=>]];
+                    let slice_att = &[slice![=>], slice![      => att_len], slice![=>]];
+                    let shape_q0 = [("nh", nh), ("nt", seq_len), ("dh", dh)];
+                    let shape_q1 = [("nkvh", nkvh), ("g*nt", head_group * seq_len), ("dh", dh)];
+                    let shape_att0 = [
+                        ("nkvh", nkvh),
+                        ("g*nt", head_group * seq_len),
+                        ("att", att_len),
+                    ];
+                    let shape_att1 = [("nh", nh), ("nt", seq_len), ("att", att_len)];
+
+                    let mut q_att = Named::new(dt, &shape_q0, &mut q_buf[..]);
+                    let mut k_cat = k_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
+                    let mut v_cat = v_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
+                    // 按 [nt, nh, dh] 的顺序写入
+                    let mut q_rot = q_att
+                        .as_mut()
+                        .map_physical(|u| &mut **u)
+                        .transpose(&[1, 0, 2]);
+                    let mut k_rot = k_cat
+                        .as_mut()
+                        .map_physical(|u| &mut **u)
+                        .transpose(&[1, 0, 2]);
+                    self.kernels().rope_to(&mut q_rot, &q, &pos_q, theta, queue);
+                    self.kernels().rope_to(&mut k_rot, &k, &pos_q, theta, queue);
+                    self.kernels().reform(&mut v_cat, &v, queue);
+
+                    let q_att = q_att.reshape(&shape_q1);
+                    let k_att = k_cache
+                        .slice(slice_att)
+                        .named(&["nkvh", "att", "dh"])
+                        .transpose(&["nkvh", "dh", "att"]);
+                    let v_att = v_cache.slice(slice_att);
+
+                    let mut att = Named::new(dt, &shape_att0, &mut att_buf[..]);
+                    self.kernels()
+                        .attention_mat_mul(&mut att, 0., &q_att, &k_att, attn_scale, queue);
+                    let mut att = att.reshape(&shape_att1);
+                    self.kernels().softmax(&mut att, queue);
+                    let att = att.reshape(&shape_att0);
+                    if seq_len == 1 {
+                        // 解码时 o 的各头可以按 q 的形状排列，注意力的结果直接写入 o，o 投影再累加到 x 上
+                        let mut o = o.reshape(&shape_q1);
+                        self.kernels()
+                            .attention_mat_mul(&mut o, 0., &att, &v_att, 1., queue);
+                    } else {
+                        let mut x2 = q_att;
+                        self.kernels()
+                            .attention_mat_mul(&mut x2, 0., &att, &v_att, 1., queue);
+                        self.kernels().reform(&mut o, &x2.reshape(&shape_q0), queue);
+                    }
+                }
             }
+            timer.lap("attention");
 
             let (mut x1, gate_up) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing);
             let mut gate_up = gate_up.slice(&[slice![=>], slice![=> di + di]]);
 
             self.kernels()
                 .mat_mul(&mut x, 1., &x1, &params.att_o(), 1., queue);
+            if adapted {
+                self.lora(layer, Proj::O, &mut x, &x1, &adapters);
+            }
+            timer.lap("o");
             self.kernels()
                 .rms_norm(&mut x1, &x, &params.mlp_layernorm(), epsilon, queue);
             self.kernels()
                 .mat_mul(&mut gate_up, 0., &x1, &params.mlp_gate_up(), 1., queue);
+            if adapted {
+                self.lora(layer, Proj::GateUp, &mut gate_up, &x1, &adapters);
+            }
             let (mut gate, up) = split!(gate_up; [1]: di, di);
             self.kernels().swiglu(&mut gate, &up, queue);
             self.kernels()
                 .mat_mul(&mut x, 1., &gate, &params.mlp_down(), 1., queue);
+            if adapted {
+                self.lora(layer, Proj::Down, &mut x, &gate, &adapters);
+            }
+            if steered {
+                self.steer(layer, &mut x, &steering);
+            }
+            timer.lap("mlp");
         }
         self.free_pos(pos.take_physical());
         self.free(state_buf.take_physical());
@@ -179,6 +374,8 @@ pub struct ComputeConst {
     pub di: udim,
     pub epsilon: f32,
     pub theta: f32,
+    /// 注意力分数的缩放，参见 [`InferenceConfig::attn_scale`](crate::InferenceConfig::attn_scale)。
+    pub attn_scale: f32,
 }
 
 pub trait LLamaLayer {
@@ -0,0 +1,316 @@
+//! 从 GGUF 文件加载模型，配置从文件的元数据读取，不需要 `config.json`。
+
+use crate::{cast_to, load::concat0, InferenceConfig, LayerStorage, Storage, Weight};
+use common::{
+    gguf::{self, GGmlType, GGuf, MetaValue},
+    Architecture, Blob,
+    FileLoadError::{self, Io},
+    GenerationConfig,
+};
+use digit_layout::{
+    types::{BF16, F16, F32},
+    DigitLayout,
+};
+use std::{
+    io::{Error, ErrorKind::InvalidData},
+    path::Path,
+};
+use tensor::{reslice_mut, udim, Tensor};
+
+impl Storage {
+    /// 从模型目录加载模型：有 `config.json` 时加载 safetensors，否则加载目录中的 GGUF 文件。
+    ///
+    /// `model_dir` 也可以直接是一个 GGUF 文件。
+    pub fn load(model_dir: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        let model_dir = model_dir.as_ref();
+        if model_dir.join("config.json").is_file() {
+            return Self::load_safetensors(model_dir);
+        }
+        match gguf::find(model_dir)? {
+            Some(path) => Self::load_gguf(path),
+            None => Self::load_safetensors(model_dir),
+        }
+    }
+
+    /// 加载 GGUF 文件，量化的权重反量化为模型的类型。
+    ///
+    /// 模型的类型取词嵌入的类型，词嵌入量化时使用 `f16`。
+    /// GGUF 中的 q、k 投影已经按交错的旋转位置编码排列，不再重排。
+    pub fn load_gguf(path: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        let path = path.as_ref();
+        let model = GGuf::load(path)?;
+        let config = read_config(&model, path.parent().unwrap_or(Path::new(".")))?;
+
+        let &InferenceConfig {
+            dt,
+            voc,
+            nlayers,
+            d,
+            dkv,
+            di,
+            ..
+        } = &config;
+        let tensor = |name: &str, shape: &[udim]| tensor(&model, name, dt, shape);
+        // 投影矩阵的形状是 [输出, 输入]，与 safetensors 相同
+        let embed_tokens = tensor("token_embd.weight", &[voc, d]);
+        let lm_head = if model.tensor("output.weight").is_some() {
+            tensor("output.weight", &[voc, d])
+        } else {
+            // 共享词嵌入和 lm_head 的模型没有 output
+            tensor("token_embd.weight", &[voc, d])
+        };
+
+        Ok(Self {
+            config,
+            embed_tokens,
+            layers: (0..nlayers)
+                .map(|l| {
+                    let name = |name: &str| format!("blk.{l}.{name}.weight");
+                    let matrix = |name_: &str, shape: &[udim]| tensor(&name(name_), shape);
+                    LayerStorage {
+                        att_layernorm: matrix("attn_norm", &[d]),
+                        att_qkv: concat0(&[
+                            matrix("attn_q", &[d, d]),
+                            matrix("attn_k", &[dkv, d]),
+                            matrix("attn_v", &[dkv, d]),
+                        ])
+                        .transpose(&[1, 0]),
+                        att_o: matrix("attn_output", &[d, d]).transpose(&[1, 0]),
+                        mlp_layernorm: matrix("ffn_norm", &[d]),
+                        mlp_gate_up: concat0(&[
+                            matrix("ffn_gate", &[di, d]),
+                            matrix("ffn_up", &[di, d]),
+                        ])
+                        .transpose(&[1, 0]),
+                        mlp_down: matrix("ffn_down", &[d, di]).transpose(&[1, 0]),
+                    }
+                })
+                .collect(),
+            lm_layernorm: tensor("output_norm.weight", &[d]),
+            lm_head: lm_head.transpose(&[1, 0]),
+        })
+    }
+}
+
+/// 从 GGUF 的元数据读取推理配置，结束符与模型目录中 `generation_config.json` 的合并。
+fn read_config(model: &GGuf, model_dir: &Path) -> Result<InferenceConfig, FileLoadError> {
+    let invalid = |msg: String| Io(Error::new(InvalidData, msg));
+    let arch = model
+        .get("general.architecture")
+        .and_then(MetaValue::as_str)
+        .unwrap_or("");
+    match Architecture::from_gguf(model)? {
+        Architecture::Llama => {}
+        arch => {
+            let msg = format!("{arch:?} model can't be loaded with the llama layout");
+            return Err(invalid(msg));
+        }
+    }
+    let get = |key: &str| model.get(&format!("{arch}.{key}"));
+    let uint = |key: &str| {
+        get(key)
+            .and_then(MetaValue::as_u64)
+            .map(|v| v as udim)
+            .ok_or_else(|| invalid(format!("missing metadata: {arch}.{key}")))
+    };
+    let float = |key: &str, default: f32| {
+        get(key)
+            .and_then(MetaValue::as_f64)
+            .map_or(default, |v| v as _)
+    };
+    let token = |key: &str, default: u32| {
+        model
+            .get(key)
+            .and_then(MetaValue::as_u64)
+            .map_or(default, |v| v as _)
+    };
+
+    let d = uint("embedding_length")?;
+    let nh = uint("attention.head_count")?;
+    let nkvh = uint("attention.head_count_kv").unwrap_or(nh);
+    let (embd, _) = model
+        .tensor("token_embd.weight")
+        .ok_or_else(|| invalid("missing tensor: token_embd.weight".into()))?;
+    // 词表大小可能不在元数据中，按词表或词嵌入的形状确定
+    let voc = uint("vocab_size").unwrap_or_else(|_| {
+        model
+            .get("tokenizer.ggml.tokens")
+            .and_then(MetaValue::as_array)
+            .map_or(embd.shape()[0] as _, |tokens| tokens.len() as _)
+    });
+    let generation = GenerationConfig::load(model_dir)?;
+    Ok(InferenceConfig {
+        dt: match embd.ty {
+            GGmlType::F32 => F32,
+            GGmlType::BF16 => BF16,
+            _ => F16,
+        },
+        voc,
+        nlayers: uint("block_count")?,
+        nh,
+        nkvh,
+        d,
+        dkv: d / nh * nkvh,
+        di: uint("feed_forward_length")?,
+        max_seq_len: uint("context_length")?,
+        bos_token: token("tokenizer.ggml.bos_token_id", 1),
+        eos_tokens: generation.eos_tokens(token("tokenizer.ggml.eos_token_id", 2)),
+        epsilon: float("attention.layer_norm_rms_epsilon", 1e-5),
+        theta: float("rope.freq_base", 1e4),
+        attn_scale: None,
+    })
+}
+
+/// 读取张量并转换为 `dt`，量化的张量先反量化为 `f32`。
+fn tensor(model: &GGuf, name: &str, dt: DigitLayout, shape: &[udim]) -> Tensor<Weight> {
+    let (info, data) = model
+        .tensor(name)
+        .unwrap_or_else(|| panic!("missing tensor: {name}"));
+    assert!(
+        info.shape()
+            .iter()
+            .map(|&d| d as udim)
+            .eq(shape.iter().copied()),
+        "wrong shape of {name}: {:?}, expected {shape:?}",
+        info.shape()
+    );
+    assert!(
+        info.ty.block().is_some(),
+        "unsupported ggml type of {name}: {}",
+        info.ty.0
+    );
+    let mut ans = Tensor::alloc(F32, shape, Blob::new);
+    info.ty.dequantize(data, reslice_mut(ans.physical_mut()));
+    cast_to(&ans, dt).map_physical(|b| b.into())
+}
+
+#[test]
+fn test_load_gguf() {
+    use crate::tiny::tiny_config;
+    use std::fs;
+    use tensor::reslice;
+
+    // 将随机权重按 llama.cpp 的命名写成 f32 的 GGUF 文件
+    fn string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+    fn uint(buf: &mut Vec<u8>, key: &str, v: u32) {
+        string(buf, key);
+        buf.extend_from_slice(&4u32.to_le_bytes());
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+    let config = InferenceConfig {
+        dt: F32,
+        ..tiny_config(64)
+    };
+    let storage = Storage::random(config.clone(), 1);
+    // 矩阵以转置的视图存储，按行读出原始的 [输出, 输入] 矩阵
+    let rows = |t: &Tensor<Weight>, start: udim, len: udim| {
+        let t = t.as_ref().transpose(&[1, 0]);
+        let cols = t.shape()[1] as usize;
+        reslice::<u8, f32>(t.physical())[start as usize * cols..][..len as usize * cols].to_vec()
+    };
+    let all = |t: &Tensor<Weight>| reslice::<u8, f32>(t.physical()).to_vec();
+    let InferenceConfig { d, dkv, di, .. } = config;
+    let mut tensors = vec![
+        (
+            "token_embd".into(),
+            vec![config.voc, d],
+            all(&storage.embed_tokens),
+        ),
+        ("output_norm".into(), vec![d], all(&storage.lm_layernorm)),
+        (
+            "output".into(),
+            vec![config.voc, d],
+            rows(&storage.lm_head, 0, config.voc),
+        ),
+    ];
+    for (l, layer) in storage.layers.iter().enumerate() {
+        let name = |name: &str| format!("blk.{l}.{name}");
+        tensors.extend([
+            (name("attn_norm"), vec![d], all(&layer.att_layernorm)),
+            (name("attn_q"), vec![d, d], rows(&layer.att_qkv, 0, d)),
+            (name("attn_k"), vec![dkv, d], rows(&layer.att_qkv, d, dkv)),
+            (
+                name("attn_v"),
+                vec![dkv, d],
+                rows(&layer.att_qkv, d + dkv, dkv),
+            ),
+            (name("attn_output"), vec![d, d], rows(&layer.att_o, 0, d)),
+            (name("ffn_norm"), vec![d], all(&layer.mlp_layernorm)),
+            (
+                name("ffn_gate"),
+                vec![di, d],
+                rows(&layer.mlp_gate_up, 0, di),
+            ),
+            (
+                name("ffn_up"),
+                vec![di, d],
+                rows(&layer.mlp_gate_up, di, di),
+            ),
+            (name("ffn_down"), vec![d, di], rows(&layer.mlp_down, 0, d)),
+        ]);
+    }
+
+    let mut buf = b"GGUF".to_vec();
+    buf.extend_from_slice(&3u32.to_le_bytes());
+    buf.extend_from_slice(&(tensors.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&8u64.to_le_bytes());
+    string(&mut buf, "general.architecture");
+    buf.extend_from_slice(&8u32.to_le_bytes());
+    string(&mut buf, "llama");
+    uint(&mut buf, "llama.context_length", config.max_seq_len);
+    uint(&mut buf, "llama.embedding_length", d);
+    uint(&mut buf, "llama.block_count", config.nlayers);
+    uint(&mut buf, "llama.feed_forward_length", di);
+    uint(&mut buf, "llama.attention.head_count", config.nh);
+    uint(&mut buf, "llama.attention.head_count_kv", config.nkvh);
+    uint(&mut buf, "tokenizer.ggml.eos_token_id", 2);
+    let mut offset = 0u64;
+    for (name, shape, data) in &tensors {
+        string(&mut buf, &format!("{name}.weight"));
+        buf.extend_from_slice(&(shape.len() as u32).to_le_bytes());
+        for &d in shape.iter().rev() {
+            buf.extend_from_slice(&(d as u64).to_le_bytes());
+        }
+        buf.extend_from_slice(&GGmlType::F32.0.to_le_bytes());
+        buf.extend_from_slice(&offset.to_le_bytes());
+        offset += (data.len() * 4).div_ceil(32) as u64 * 32;
+    }
+    for (_, _, data) in &tensors {
+        buf.resize(buf.len().div_ceil(32) * 32, 0);
+        buf.extend(data.iter().flat_map(|x| x.to_le_bytes()));
+    }
+
+    let dir = std::env::temp_dir().join(format!("llama-gguf-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("tiny.gguf"), buf).unwrap();
+    let loaded = Storage::load(&dir).unwrap();
+    assert_eq!(Architecture::detect(&dir).unwrap(), Architecture::Llama);
+    fs::remove_dir_all(dir).unwrap();
+
+    let c = &loaded.config;
+    assert_eq!(c.dt, F32);
+    assert_eq!((c.voc, c.nlayers, c.nh, c.nkvh), (64, 2, 4, 2));
+    assert_eq!((c.d, c.dkv, c.di, c.max_seq_len), (d, dkv, di, 128));
+    assert_eq!((c.bos_token, &*c.eos_tokens), (1, &[2][..]));
+    assert_eq!(c.theta, 1e4);
+    assert_eq!(all(&loaded.embed_tokens), all(&storage.embed_tokens));
+    assert_eq!(
+        rows(&loaded.lm_head, 0, c.voc),
+        rows(&storage.lm_head, 0, c.voc)
+    );
+    for (a, b) in loaded.layers.iter().zip(&storage.layers) {
+        assert_eq!(
+            rows(&a.att_qkv, 0, d + dkv + dkv),
+            rows(&b.att_qkv, 0, d + dkv + dkv)
+        );
+        assert_eq!(
+            rows(&a.mlp_gate_up, 0, di + di),
+            rows(&b.mlp_gate_up, 0, di + di)
+        );
+        assert_eq!(rows(&a.mlp_down, 0, d), rows(&b.mlp_down, 0, d));
+        assert_eq!(all(&a.mlp_layernorm), all(&b.mlp_layernorm));
+    }
+}
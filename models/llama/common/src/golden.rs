@@ -0,0 +1,25 @@
+use crate::tiny_model;
+use causal_lm::CausalLM;
+use common::utok;
+use std::{fmt::Debug, path::Path};
+use tensor::Tensor;
+
+/// 用随机权重的小模型检查模型实现的 logits，参考值保存在 `golden/tiny.txt`，
+/// 以 `UPDATE_GOLDEN=1 cargo test -p llama-cpu test_golden` 重新记录。
+///
+/// 参见 [`causal_lm::test_golden`]。
+pub fn test_golden<M>(meta: M::Meta, to_host: impl Fn(&M, Tensor<M::Storage>) -> Tensor<Vec<u8>>)
+where
+    M: CausalLM,
+    M::Error: Debug,
+{
+    const PROMPT: [utok; 8] = [1, 7, 42, 13, 25, 60, 3, 19];
+
+    let dir = std::env::temp_dir().join(format!("llama-tiny-{}", std::process::id()));
+    tiny_model(&dir, 42).unwrap();
+    let model = M::load(&dir, meta).unwrap();
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("golden/tiny.txt");
+    causal_lm::test_golden(&model, &PROMPT, golden, to_host);
+    drop(model);
+    std::fs::remove_dir_all(dir).unwrap();
+}
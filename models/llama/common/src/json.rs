@@ -6,6 +6,10 @@ use digit_layout::{
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub(crate) struct ConfigJson {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub architectures: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_type: Option<String>,
     pub bos_token_id: utok,
     pub eos_token_id: utok,
     pub hidden_size: usize,
@@ -19,7 +23,28 @@ pub(crate) struct ConfigJson {
     pub rms_norm_eps: f32,
     #[serde(default = "default_rope_theta")]
     pub rope_theta: f32,
+    /// 注意力分数的缩放，未指定时为 `1/sqrt(head_dim)`。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attn_scale: Option<f32>,
     pub torch_dtype: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantization_config: Option<QuantizationConfig>,
+}
+
+/// 量化模型的 `quantization_config`。
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct QuantizationConfig {
+    pub quant_method: String,
+    pub bits: u32,
+    /// 每组的输入维度数，-1 表示不分组。
+    #[serde(default = "default_group_size")]
+    pub group_size: i64,
+    /// GPTQ 的存储格式。
+    #[serde(default)]
+    pub checkpoint_format: Option<String>,
+    /// AWQ 的打包方式。
+    #[serde(default)]
+    pub version: Option<String>,
 }
 
 impl ConfigJson {
@@ -51,3 +76,8 @@ const fn default_rms_norm_eps() -> f32 {
 const fn default_rope_theta() -> f32 {
     1e4
 }
+
+#[inline(always)]
+const fn default_group_size() -> i64 {
+    -1
+}
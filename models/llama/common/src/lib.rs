@@ -1,17 +1,33 @@
+mod adapter;
 mod cast;
+mod check;
 mod compute;
+mod golden;
+mod gguf;
 mod json;
 mod load;
+mod quant;
+mod repack;
 mod save;
+mod soft_prompt;
+mod steering;
+mod tiny;
 
 use common::{safe_tensors::SharedTensor, upos, utok, Blob};
 use digit_layout::DigitLayout;
 use std::{ops::Deref, sync::Arc};
-use tensor::{slice, udim, Tensor};
+use tensor::{slice, udim, ShapeError, Tensor};
 
+pub use adapter::{Adapter, AdapterLayer, LoraDelta, Proj};
+pub use cast::cast_to;
+pub use check::CheckReport;
 pub use common_devices::SliceOn;
 pub use compute::{ComputeConst, ComputeStream, LLamaLayer};
+pub use golden::test_golden;
 pub use operators::{Handle, QueueOf};
+pub use soft_prompt::SoftPrompt;
+pub use steering::Steering;
+pub use tiny::tiny_model;
 
 pub struct Storage {
     pub config: InferenceConfig,
@@ -63,12 +79,21 @@ pub struct InferenceConfig {
     pub di: udim,
     pub max_seq_len: udim,
     pub bos_token: utok,
-    pub eos_token: utok,
+    pub eos_tokens: Vec<utok>,
     pub epsilon: f32,
     pub theta: f32,
+    /// 注意力分数的缩放，`None` 表示 `1/sqrt(dh)`，参见 [`attn_scale`](Self::attn_scale)。
+    pub attn_scale: Option<f32>,
 }
 
 impl InferenceConfig {
+    /// 注意力分数的缩放，即 q·k 矩阵乘的系数。
+    #[inline]
+    pub fn attn_scale(&self) -> f32 {
+        self.attn_scale
+            .unwrap_or_else(|| ((self.d / self.nh) as f32).sqrt().recip())
+    }
+
     pub fn new_cache<S>(&self, f: impl FnOnce(usize) -> S) -> Tensor<S> {
         Tensor::alloc(
             self.dt,
@@ -89,23 +114,21 @@ impl InferenceConfig {
         pos: upos,
         malloc: impl FnOnce(usize) -> S,
         reform: impl FnOnce(Tensor<&mut S>, Tensor<&S>),
-    ) -> Tensor<S> {
+    ) -> Result<Tensor<S>, ShapeError> {
+        let slice = [
+            slice![=>],
+            slice![=>],
+            slice![=>],
+            slice![=>pos],
+            slice![=>],
+        ];
+        // 先检查位置再分配
+        let src = cache.as_ref().try_slice(&slice)?;
         let mut ans = Tensor::alloc(cache.data_layout(), cache.shape(), malloc);
         if pos > 0 {
-            let &[_nlayers, 2, _nkvh, max_seq_len, _dh] = cache.shape() else {
-                panic!()
-            };
-            assert!(pos <= max_seq_len);
-            let slice = [
-                slice![=>],
-                slice![=>],
-                slice![=>],
-                slice![=>pos],
-                slice![=>],
-            ];
-            reform(ans.as_mut().slice(&slice), cache.as_ref().slice(&slice));
+            reform(ans.as_mut().slice(&slice), src);
         }
-        ans
+        Ok(ans)
     }
 }
 
@@ -1,98 +1,154 @@
-﻿use crate::{json::ConfigJson, InferenceConfig, LayerStorage, Storage, Weight};
+use crate::{
+    json::ConfigJson, quant::Quantization, InferenceConfig, LayerStorage, Storage, Weight,
+};
 use common::{
     safe_tensors::{Dtype, SafeTensors},
-    Blob,
+    Architecture, Blob,
     FileLoadError::{self, Io, Json},
+    GenerationConfig,
 };
 use digit_layout::DigitLayout;
-use std::{fs::File, path::Path, pin::Pin, sync::Arc};
+use std::{
+    fs::File,
+    io::{Error, ErrorKind::InvalidData},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+};
 use tensor::{udim, Shape, Tensor};
 
 impl Storage {
     pub fn load_safetensors(model_dir: impl AsRef<Path>) -> Result<Self, FileLoadError> {
-        let config = File::open(model_dir.as_ref().join("config.json")).map_err(Io)?;
-        let config: ConfigJson = serde_json::from_reader(&config).map_err(Json)?;
+        let (json, config) = read_config(model_dir.as_ref())?;
         let model = SafeTensors::load_from_dir(model_dir)?.share();
+        let quant = json
+            .quantization_config
+            .as_ref()
+            .map(Quantization::new)
+            .transpose()?;
 
-        let dt = config.data_layout();
-        let voc = config.vocab_size as udim;
-        let d = config.hidden_size as udim;
-        let nh = config.num_attention_heads as udim;
-        let nkvh = config.num_key_value_heads as udim;
+        let &InferenceConfig {
+            dt,
+            voc,
+            nlayers,
+            nh,
+            nkvh,
+            d,
+            dkv,
+            di,
+            ..
+        } = &config;
         let dh = d / nh;
-        let dkv = dh * nkvh;
-        let di = config.intermediate_size as udim;
 
         Ok(Self {
-            config: InferenceConfig {
-                dt,
-                voc,
-                nlayers: config.num_hidden_layers as _,
-                nh,
-                nkvh,
-                d,
-                dkv,
-                di,
-                max_seq_len: config.max_position_embeddings as _,
-                bos_token: config.bos_token_id,
-                eos_token: config.eos_token_id,
-                epsilon: config.rms_norm_eps,
-                theta: config.rope_theta,
-            },
+            config,
 
-            embed_tokens: tensor(&model, "model.embed_tokens.weight", dt, [voc, d]),
-            layers: (0..config.num_hidden_layers)
+            // 词嵌入和 lm_head 可以保留与主体不同的精度
+            embed_tokens: {
+                let name = "model.embed_tokens.weight";
+                tensor(&model, name, file_dt(&model, name), [voc, d])
+            },
+            layers: (0..nlayers)
                 .map(|l| {
                     let name = |name: &str| format!("model.layers.{l}.{name}.weight");
-                    LayerStorage {
+                    // 投影矩阵可以使用与主体不同的类型存储，量化的模型只量化投影矩阵
+                    let matrix =
+                        |name: &str, shape| tensor(&model, name, file_dt(&model, name), shape);
+                    let linear = |name_: &str, shape: [udim; 2]| match &quant {
+                        Some(quant) => {
+                            quant.load(&model, &format!("model.layers.{l}.{name_}"), dt, shape)
+                        }
+                        None => Ok(matrix(&name(name_), shape)),
+                    };
+                    Ok(LayerStorage {
                         att_layernorm: tensor(&model, &name("input_layernorm"), dt, [d]),
                         att_qkv: {
                             let qkv = name("self_attn.qkv_proj");
                             if model.contains(&qkv) {
-                                tensor(&model, &qkv, dt, [d + dkv + dkv, d])
+                                matrix(&qkv, [d + dkv + dkv, d])
                             } else {
-                                let sq = &[nh, 2, dh / 2, d];
-                                let skv = &[nkvh, 2, dh / 2, d];
+                                let q = linear("self_attn.q_proj", [d, d])?;
+                                let k = linear("self_attn.k_proj", [dkv, d])?;
+                                let v = linear("self_attn.v_proj", [dkv, d])?;
+                                // 打包的量化矩阵的列数是每行的字节数，只重排行
+                                let cols = q.shape()[1];
+                                let sq = &[nh, 2, dh / 2, cols];
+                                let skv = &[nkvh, 2, dh / 2, cols];
                                 let perm = &[0, 2, 1, 3];
 
-                                let q = tensor(&model, &name("self_attn.q_proj"), dt, [d, d])
-                                    .reshape(sq)
-                                    .transpose(perm);
-                                let k = tensor(&model, &name("self_attn.k_proj"), dt, [dkv, d])
-                                    .reshape(skv)
-                                    .transpose(perm);
-                                let v = tensor(&model, &name("self_attn.v_proj"), dt, [dkv, d])
-                                    .reshape(skv);
-                                concat0(&[q, k, v]).reshape(&[d + dkv + dkv, d])
+                                let q = q.reshape(sq).transpose(perm);
+                                let k = k.reshape(skv).transpose(perm);
+                                let v = v.reshape(skv);
+                                concat0(&[q, k, v]).reshape(&[d + dkv + dkv, cols])
                             }
                         }
                         .transpose(&[1, 0]),
-                        att_o: tensor(&model, &name("self_attn.o_proj"), dt, [d, d])
-                            .transpose(&[1, 0]),
+                        att_o: linear("self_attn.o_proj", [d, d])?.transpose(&[1, 0]),
                         mlp_layernorm: tensor(&model, &name("post_attention_layernorm"), dt, [d]),
                         mlp_gate_up: {
                             let gate_up = name("mlp.gate_up_proj");
                             if model.contains(&gate_up) {
-                                tensor(&model, &gate_up, dt, [di + di, d])
+                                matrix(&gate_up, [di + di, d])
                             } else {
                                 concat0(&[
-                                    tensor(&model, &name("mlp.gate_proj"), dt, [di, d]),
-                                    tensor(&model, &name("mlp.up_proj"), dt, [di, d]),
+                                    linear("mlp.gate_proj", [di, d])?,
+                                    linear("mlp.up_proj", [di, d])?,
                                 ])
                             }
                         }
                         .transpose(&[1, 0]),
-                        mlp_down: tensor(&model, &name("mlp.down_proj"), dt, [d, di])
-                            .transpose(&[1, 0]),
-                    }
+                        mlp_down: linear("mlp.down_proj", [d, di])?.transpose(&[1, 0]),
+                    })
                 })
-                .collect(),
+                .collect::<Result<_, FileLoadError>>()?,
             lm_layernorm: tensor(&model, "model.norm.weight", dt, [d]),
-            lm_head: tensor(&model, "lm_head.weight", dt, [voc, d]).transpose(&[1, 0]),
+            lm_head: {
+                let name = "lm_head.weight";
+                tensor(&model, name, file_dt(&model, name), [voc, d]).transpose(&[1, 0])
+            },
         })
     }
 }
 
+/// 读取 `config.json` 和 `generation_config.json`，返回原始的配置和推理配置。
+///
+/// `config.json` 中的架构必须能以 llama 的布局加载。
+pub(crate) fn read_config(
+    model_dir: &Path,
+) -> Result<(ConfigJson, InferenceConfig), FileLoadError> {
+    let config = File::open(model_dir.join("config.json")).map_err(Io)?;
+    let config: ConfigJson = serde_json::from_reader(&config).map_err(Json)?;
+    match Architecture::from_config(&config.architectures, config.model_type.as_deref())? {
+        Architecture::Llama => {}
+        arch => {
+            let msg = format!("{arch:?} model can't be loaded with the llama layout");
+            return Err(Io(Error::new(InvalidData, msg)));
+        }
+    }
+    let generation = GenerationConfig::load(model_dir)?;
+
+    let d = config.hidden_size as udim;
+    let nh = config.num_attention_heads as udim;
+    let nkvh = config.num_key_value_heads as udim;
+    let inference = InferenceConfig {
+        dt: config.data_layout(),
+        voc: config.vocab_size as _,
+        nlayers: config.num_hidden_layers as _,
+        nh,
+        nkvh,
+        d,
+        dkv: d / nh * nkvh,
+        di: config.intermediate_size as _,
+        max_seq_len: config.max_position_embeddings as _,
+        bos_token: config.bos_token_id,
+        eos_tokens: generation.eos_tokens(config.eos_token_id),
+        epsilon: config.rms_norm_eps,
+        theta: config.rope_theta,
+        attn_scale: config.attn_scale,
+    };
+    Ok((config, inference))
+}
+
 fn tensor<const N: usize>(
     model: &Pin<Arc<SafeTensors>>,
     name: &str,
@@ -110,7 +166,14 @@ fn tensor<const N: usize>(
     Tensor::new(dt, &shape, Weight::SafeTensor(shared))
 }
 
-fn concat0(tensors: &[Tensor<Weight>]) -> Tensor<Weight> {
+fn file_dt(model: &Pin<Arc<SafeTensors>>, name: &str) -> DigitLayout {
+    let shared = model
+        .share_tensor(name)
+        .unwrap_or_else(|| panic!("missing tensor: {name}"));
+    convert(shared.dtype())
+}
+
+pub(crate) fn concat0(tensors: &[Tensor<Weight>]) -> Tensor<Weight> {
     assert!(tensors
         .windows(2)
         .all(|t| t[0].data_layout() == t[1].data_layout()));
@@ -130,14 +193,15 @@ fn concat0(tensors: &[Tensor<Weight>]) -> Tensor<Weight> {
     ans.map_physical(|b| b.into())
 }
 
-fn convert(dtype: Dtype) -> DigitLayout {
+pub(crate) fn convert(dtype: Dtype) -> DigitLayout {
+    use common::fp8::{F8E4M3, F8E5M2};
     use digit_layout::types::*;
     match dtype {
         Dtype::BOOL => BOOL,
         Dtype::U8 => U8,
         Dtype::I8 => I8,
-        Dtype::F8_E5M2 => DigitLayout::new(1, true, 5, 2),
-        Dtype::F8_E4M3 => DigitLayout::new(1, true, 4, 3),
+        Dtype::F8_E5M2 => F8E5M2,
+        Dtype::F8_E4M3 => F8E4M3,
         Dtype::I16 => I16,
         Dtype::U16 => U16,
         Dtype::F16 => F16,
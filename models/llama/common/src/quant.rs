@@ -0,0 +1,396 @@
+//! 加载 GPTQ 或 AWQ 量化的权重，4 位的权重保持打包，其他的加载时反量化为模型的数据类型。
+
+use crate::{cast::cast_to, json::QuantizationConfig, load::convert, Weight};
+use common::{
+    bf16, f16,
+    quant::{block_bytes, row_bytes, BLOCK, Q4_1},
+    safe_tensors::{SafeTensors, SharedTensor},
+    Blob,
+    FileLoadError::{self, Io},
+};
+use digit_layout::{
+    types::{BF16, F16, F32},
+    DigitLayout,
+};
+use std::{
+    io::{Error, ErrorKind::InvalidData},
+    pin::Pin,
+    sync::Arc,
+};
+use tensor::{reslice_mut, udim, Tensor};
+
+/// AWQ 打包 4 位整数时，第 `k` 个元素存放在字中的第 `AWQ_ORDER[k]` 个位置。
+const AWQ_ORDER: [u32; 8] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+/// 量化权重的打包方式。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Method {
+    /// `qweight` 沿输入维度打包为 `[输入 / pack, 输出]`，`g_idx` 可以打乱分组。
+    Gptq {
+        /// 存储的零点与实际零点的差，v1 格式的零点少存了 1。
+        zero_offset: i32,
+    },
+    /// `qweight` 沿输出维度交错打包为 `[输入, 输出 / pack]`。
+    Awq,
+}
+
+/// 量化参数。
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Quantization {
+    method: Method,
+    bits: u32,
+    /// 每组的输入维度数，`None` 表示整个输入维度共用一组。
+    group_size: Option<usize>,
+}
+
+impl Quantization {
+    pub fn new(config: &QuantizationConfig) -> Result<Self, FileLoadError> {
+        let unsupported = |what: String| Err(invalid(what));
+        let method = match config.quant_method.as_str() {
+            "gptq" => Method::Gptq {
+                zero_offset: match config.checkpoint_format.as_deref() {
+                    Some("gptq_v2") => 0,
+                    _ => 1,
+                },
+            },
+            "awq" if matches!(config.version.as_deref(), None | Some("gemm")) => Method::Awq,
+            method => return unsupported(format!("unsupported quantization method: {method}")),
+        };
+        let bits = config.bits;
+        let supported = match method {
+            Method::Gptq { .. } => matches!(bits, 2 | 4 | 8),
+            Method::Awq => bits == 4,
+        };
+        if !supported {
+            return unsupported(format!("unsupported {} bits: {bits}", config.quant_method));
+        }
+        Ok(Self {
+            method,
+            bits,
+            group_size: usize::try_from(config.group_size).ok().filter(|&g| g > 0),
+        })
+    }
+
+    /// 加载 `{prefix}.qweight` 等张量表示的形状为 `[输出, 输入]` 的权重，张量缺失或形状、类型不符时返回错误。
+    ///
+    /// 4 位量化、每组的输入维度数是 [`BLOCK`] 的整数倍且没有用 `g_idx` 打乱分组时保持 4 位存储：
+    /// 每个输出的一行重新打包为 [`Q4_1`] 的块，矩阵乘时逐行反量化，返回 `[输出, 每行的字节数]`；
+    /// 其他的量化方式反量化为 `dt`，内存占用与未量化的模型相同。
+    pub fn load(
+        &self,
+        model: &Pin<Arc<SafeTensors>>,
+        prefix: &str,
+        dt: DigitLayout,
+        shape: [udim; 2],
+    ) -> Result<Tensor<Weight>, FileLoadError> {
+        let packed = Packed::read(self, model, prefix, shape)?;
+        Ok(match packed.repack() {
+            Some(t) => t,
+            None => packed.dequantize(dt),
+        })
+    }
+}
+
+/// 从文件中读取的一个量化的矩阵。
+struct Packed {
+    quant: Quantization,
+    out: usize,
+    in_: usize,
+    qweight: Vec<u32>,
+    qzeros: Vec<u32>,
+    scales: Vec<f32>,
+    g_idx: Option<Vec<u32>>,
+}
+
+impl Packed {
+    fn read(
+        quant: &Quantization,
+        model: &Pin<Arc<SafeTensors>>,
+        prefix: &str,
+        [out, in_]: [udim; 2],
+    ) -> Result<Self, FileLoadError> {
+        let name = |name: &str| format!("{prefix}.{name}");
+        let (out, in_) = (out as usize, in_ as usize);
+        let pack = (32 / quant.bits) as usize;
+        let groups = quant.group_size.map_or(1, |g| in_.div_ceil(g));
+        // GPTQ 按激活值排序量化的模型用 g_idx 记录每个输入维度所在的组
+        let g_idx = name("g_idx");
+        Ok(Self {
+            quant: *quant,
+            out,
+            in_,
+            qweight: match quant.method {
+                Method::Gptq { .. } => words(model, &name("qweight"), [in_ / pack, out])?,
+                Method::Awq => words(model, &name("qweight"), [in_, out / pack])?,
+            },
+            qzeros: words(model, &name("qzeros"), [groups, out / pack])?,
+            scales: floats(model, &name("scales"), [groups, out])?,
+            g_idx: if model.contains(&g_idx) {
+                Some(words(model, &g_idx, [in_])?)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// 打包在同一个字中的第 `k` 个元素的位移。
+    fn lane(&self, k: usize) -> u32 {
+        match self.quant.method {
+            Method::Gptq { .. } => k as u32 * self.quant.bits,
+            Method::Awq => AWQ_ORDER[k] * self.quant.bits,
+        }
+    }
+
+    /// 第 `i` 个输入维度所在的组。
+    fn group(&self, i: usize) -> usize {
+        match &self.g_idx {
+            Some(g_idx) => g_idx[i] as usize,
+            None => self.quant.group_size.map_or(0, |g| i / g),
+        }
+    }
+
+    /// 第 `i` 个输入维度、第 `o` 个输出的量化值和它所在组的零点、缩放。
+    fn get(&self, i: usize, o: usize) -> (i32, i32, f32) {
+        let (out, bits) = (self.out, self.quant.bits);
+        let pack = (32 / bits) as usize;
+        let mask = (1u32 << bits) - 1;
+        let g = self.group(i);
+        let q = match self.quant.method {
+            Method::Gptq { .. } => self.qweight[i / pack * out + o] >> self.lane(i % pack),
+            Method::Awq => self.qweight[i * (out / pack) + o / pack] >> self.lane(o % pack),
+        };
+        let z = self.qzeros[g * (out / pack) + o / pack] >> self.lane(o % pack);
+        let zero_offset = match self.quant.method {
+            Method::Gptq { zero_offset } => zero_offset,
+            Method::Awq => 0,
+        };
+        (
+            (q & mask) as i32,
+            (z & mask) as i32 + zero_offset,
+            self.scales[g * out + o],
+        )
+    }
+
+    /// 反量化为 `dt`，返回形状为 `[输出, 输入]` 的视图。
+    fn dequantize(&self, dt: DigitLayout) -> Tensor<Weight> {
+        let (out, in_) = (self.out, self.in_);
+        // 逐行输入维度解包，沿输出维度连续写入
+        let mut ans = Tensor::alloc(F32, &[in_ as _, out as _], Blob::new);
+        let data = reslice_mut::<u8, f32>(ans.physical_mut());
+        for (i, row) in data.chunks_exact_mut(out).enumerate() {
+            for (o, w) in row.iter_mut().enumerate() {
+                let (q, z, s) = self.get(i, o);
+                *w = (q - z) as f32 * s;
+            }
+        }
+        cast_to(&ans, dt)
+            .map_physical(|b| b.into())
+            .transpose(&[1, 0])
+    }
+
+    /// 4 位的权重逐个输出重新打包为 [`Q4_1`]：每块的缩放是所在组的缩放，最小值是缩放与零点的积的相反数。
+    ///
+    /// 不能无损地打包时返回 `None`。
+    fn repack(&self) -> Option<Tensor<Weight>> {
+        let (out, in_) = (self.out, self.in_);
+        let group = self.quant.group_size.unwrap_or(in_);
+        let ordered = self.g_idx.as_ref().is_none_or(|g_idx| {
+            g_idx
+                .iter()
+                .enumerate()
+                .all(|(i, &g)| g as usize == i / group)
+        });
+        if self.quant.bits != 4 || !group.is_multiple_of(BLOCK) || !ordered {
+            return None;
+        }
+        let bytes = row_bytes(Q4_1, in_)?;
+        let block = block_bytes(Q4_1)?;
+        let mut ans = Blob::new(out * bytes);
+        for (o, row) in ans.chunks_exact_mut(bytes).enumerate() {
+            for (b, dst) in row.chunks_exact_mut(block).enumerate() {
+                let start = b * BLOCK;
+                let q = |j: usize| self.get(start + j, o).0 as u8;
+                let (_, z, s) = self.get(start, o);
+                dst[..2].copy_from_slice(&f16::from_f32(s).to_le_bytes());
+                dst[2..4].copy_from_slice(&f16::from_f32(-s * z as f32).to_le_bytes());
+                for (j, q_) in dst[4..].iter_mut().enumerate() {
+                    *q_ = q(j) | q(j + BLOCK / 2) << 4;
+                }
+            }
+        }
+        Some(Tensor::new(Q4_1, &[out as _, bytes as _], ans).map_physical(|b| b.into()))
+    }
+}
+
+fn invalid(msg: String) -> FileLoadError {
+    Io(Error::new(InvalidData, msg))
+}
+
+/// 取出形状为 `shape` 的张量 `name`。
+fn shared(
+    model: &Pin<Arc<SafeTensors>>,
+    name: &str,
+    shape: &[usize],
+) -> Result<SharedTensor, FileLoadError> {
+    let t = model
+        .share_tensor(name)
+        .ok_or_else(|| invalid(format!("missing tensor: {name}")))?;
+    if t.shape() != shape {
+        let msg = format!("{name}: shape {:?}, expected {shape:?}", t.shape());
+        return Err(invalid(msg));
+    }
+    Ok(t)
+}
+
+/// 读取打包的 32 位整数，safetensors 中的数据不保证对齐。
+fn words<const N: usize>(
+    model: &Pin<Arc<SafeTensors>>,
+    name: &str,
+    shape: [usize; N],
+) -> Result<Vec<u32>, FileLoadError> {
+    let t = shared(model, name, &shape)?;
+    let dt = convert(t.dtype());
+    if dt.nbytes() != 4 {
+        return Err(invalid(format!("{name}: {dt:?} is not a 32-bit integer")));
+    }
+    Ok(t.data()
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .collect())
+}
+
+fn floats<const N: usize>(
+    model: &Pin<Arc<SafeTensors>>,
+    name: &str,
+    shape: [usize; N],
+) -> Result<Vec<f32>, FileLoadError> {
+    let t = shared(model, name, &shape)?;
+    let data = t.data();
+    let ans = match convert(t.dtype()) {
+        F16 => data
+            .chunks_exact(2)
+            .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        BF16 => data
+            .chunks_exact(2)
+            .map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        F32 => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect(),
+        dt => return Err(invalid(format!("{name}: unsupported scale type {dt:?}"))),
+    };
+    Ok(ans)
+}
+
+#[test]
+fn test_dequantize() {
+    use crate::save::write_safetensors;
+    use common::quant::dequantize_row;
+    use digit_layout::types::I32;
+    use tensor::reslice;
+
+    // 4 位量化，64 个输入维度分 2 组，每组 32 个
+    let (in_, out) = (64usize, 8usize);
+    let q = |i: usize, o: usize| ((i * 3 + o) % 16) as u32;
+    let z = |g: usize, o: usize| ((g + o) % 7 + 4) as u32;
+    let s = |g: usize, o: usize| (g + 1) as f32 / 16. + o as f32 / 64.;
+
+    let words = |shape: [udim; 2], words: Vec<u32>| -> Tensor<Weight> {
+        let mut t = Tensor::alloc(I32, &shape, Blob::new);
+        reslice_mut::<u8, u32>(t.physical_mut()).copy_from_slice(&words);
+        t.map_physical(|b| b.into())
+    };
+    let scales = || {
+        let mut t = Tensor::alloc(F16, &[2, out as _], Blob::new);
+        let data = reslice_mut::<u8, f16>(t.physical_mut());
+        for (k, x) in data.iter_mut().enumerate() {
+            *x = f16::from_f32(s(k / out, k % out));
+        }
+        t.map_physical(|b| b.into())
+    };
+
+    let dir = std::env::temp_dir().join(format!("llama-quant-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for method in [Method::Gptq { zero_offset: 1 }, Method::Awq] {
+        let lane = |k: usize| match method {
+            Method::Gptq { .. } => k * 4,
+            Method::Awq => AWQ_ORDER[k] as usize * 4,
+        };
+        // GPTQ 的 qweight 沿输入维度打包，AWQ 的沿输出维度交错打包；v1 格式的零点少存 1
+        let (qweight, zero_offset) = match method {
+            Method::Gptq { zero_offset } => {
+                let mut qweight = vec![0u32; in_ / 8 * out];
+                for i in 0..in_ {
+                    for o in 0..out {
+                        qweight[i / 8 * out + o] |= q(i, o) << lane(i % 8);
+                    }
+                }
+                (words([(in_ / 8) as _, out as _], qweight), zero_offset)
+            }
+            Method::Awq => {
+                let mut qweight = vec![0u32; in_ * out / 8];
+                for i in 0..in_ {
+                    for o in 0..out {
+                        qweight[i * out / 8 + o / 8] |= q(i, o) << lane(o % 8);
+                    }
+                }
+                (words([in_ as _, (out / 8) as _], qweight), 0)
+            }
+        };
+        let mut qzeros = vec![0u32; 2 * out / 8];
+        for g in 0..2 {
+            for o in 0..out {
+                qzeros[g * out / 8 + o / 8] |= (z(g, o) - zero_offset as u32) << lane(o % 8);
+            }
+        }
+        let qzeros = words([2, (out / 8) as _], qzeros);
+
+        let path = dir.join(format!("{method:?}.safetensors"));
+        write_safetensors(
+            &path,
+            &[
+                ("proj.qweight".into(), qweight),
+                ("proj.qzeros".into(), qzeros),
+                ("proj.scales".into(), scales()),
+            ],
+        )
+        .unwrap();
+        let model = SafeTensors::single_file(&path).unwrap().share();
+
+        let quant = Quantization {
+            method,
+            bits: 4,
+            group_size: Some(32),
+        };
+        // 张量缺失或形状不符时返回错误
+        assert!(Packed::read(&quant, &model, "none", [out as _, in_ as _]).is_err());
+        assert!(Packed::read(&quant, &model, "proj", [out as _, (in_ * 2) as _]).is_err());
+        let packed = Packed::read(&quant, &model, "proj", [out as _, in_ as _]).unwrap();
+        let w = packed.dequantize(F32);
+        assert_eq!(w.shape(), [out as udim, in_ as udim]);
+        let data = reslice::<u8, f32>(w.physical());
+        // 打包为 Q4_1 的每一行反量化后与直接反量化的结果相同
+        let q4 = packed.repack().unwrap();
+        let bytes = row_bytes(Q4_1, in_).unwrap();
+        assert_eq!(q4.shape(), [out as udim, bytes as udim]);
+        let mut row = vec![0.; in_];
+        for o in 0..out {
+            dequantize_row(Q4_1, &q4.physical()[o * bytes..][..bytes], &mut row);
+            for i in 0..in_ {
+                let g = i / 32;
+                let expected = (q(i, o) as f32 - z(g, o) as f32) * s(g, o);
+                // 物理上按 `[输入, 输出]` 存储
+                let actual = data[i * out + o];
+                assert!((actual - expected).abs() < 1e-3, "{method:?} ({o}, {i})");
+                assert!(
+                    (row[i] - expected).abs() < 1e-2,
+                    "{method:?} Q4_1 ({o}, {i})"
+                );
+            }
+        }
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,155 @@
+use crate::{load::convert, save::write_safetensors, LayerStorage, Storage, Weight};
+use common::{
+    quant::block_bytes,
+    safe_tensors::SafeTensors,
+    Blob,
+    FileLoadError::{self, Io},
+};
+use std::{
+    fs,
+    io::{Error, ErrorKind::InvalidData},
+    path::Path,
+};
+use tensor::Tensor;
+
+/// 缓存预转置的矩阵的文件名。
+const CACHE_FILE: &str = "pretransposed.safetensors";
+
+impl Storage {
+    /// 将各层的投影矩阵和 lm_head 整理为 `[输入, 输出]` 的连续布局，矩阵乘沿输出维度连续地读取权重。
+    ///
+    /// 整理需要复制这些矩阵，加载时间和内存占用都会增加。分块量化的矩阵按行读取，不整理。
+    pub fn pretranspose(self) -> Self {
+        self.map_matrices(|_, t| Some(contiguous(t))).unwrap()
+    }
+
+    /// 同 [`Storage::pretranspose`]，但将整理后的矩阵缓存到 `cache_dir`，之后的加载直接映射缓存文件。
+    ///
+    /// 缓存只按名字、类型和形状校验，不同的模型应当使用不同的目录。
+    pub fn pretranspose_cached(self, cache_dir: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        let cache_dir = cache_dir.as_ref();
+        let path = cache_dir.join(CACHE_FILE);
+        if !path.is_file() {
+            // 先写入临时文件，避免中断的写入留下不完整的缓存
+            let tmp = path.with_extension("tmp");
+            fs::create_dir_all(cache_dir).map_err(Io)?;
+            write_safetensors(&tmp, &self.matrices()).map_err(Io)?;
+            fs::rename(tmp, &path).map_err(Io)?;
+        }
+
+        let cache = SafeTensors::single_file(&path)?.share();
+        self.map_matrices(|name, t| {
+            if is_quantized(&t) {
+                return Some(t);
+            }
+            let shared = cache.share_tensor(name)?;
+            let dt = t.data_layout();
+            let shape = shared.shape().iter().map(|&d| d as _).collect::<Vec<_>>();
+            (convert(shared.dtype()) == dt && shape == t.shape())
+                .then(|| Tensor::new(dt, &shape, Weight::SafeTensor(shared)))
+        })
+        .ok_or_else(|| {
+            let msg = format!("{} does not match the model", path.display());
+            Io(Error::new(InvalidData, msg))
+        })
+    }
+
+    /// 需要预转置的矩阵及其在缓存中的名字。
+    fn matrices(&self) -> Vec<(String, Tensor<Weight>)> {
+        let mut ans = Vec::with_capacity(self.layers.len() * 4 + 1);
+        for (i, l) in self.layers.iter().enumerate() {
+            ans.extend([
+                (format!("layers.{i}.att_qkv"), l.att_qkv.clone()),
+                (format!("layers.{i}.att_o"), l.att_o.clone()),
+                (format!("layers.{i}.mlp_gate_up"), l.mlp_gate_up.clone()),
+                (format!("layers.{i}.mlp_down"), l.mlp_down.clone()),
+            ]);
+        }
+        ans.push(("lm_head".into(), self.lm_head.clone()));
+        ans.retain(|(_, t)| !is_quantized(t));
+        ans
+    }
+
+    /// 替换需要预转置的矩阵，`f` 返回 `None` 时放弃替换。
+    fn map_matrices(
+        self,
+        mut f: impl FnMut(&str, Tensor<Weight>) -> Option<Tensor<Weight>>,
+    ) -> Option<Self> {
+        let layers = self
+            .layers
+            .into_iter()
+            .enumerate()
+            .map(|(i, l)| {
+                Some(LayerStorage {
+                    att_layernorm: l.att_layernorm,
+                    att_qkv: f(&format!("layers.{i}.att_qkv"), l.att_qkv)?,
+                    att_o: f(&format!("layers.{i}.att_o"), l.att_o)?,
+                    mlp_layernorm: l.mlp_layernorm,
+                    mlp_gate_up: f(&format!("layers.{i}.mlp_gate_up"), l.mlp_gate_up)?,
+                    mlp_down: f(&format!("layers.{i}.mlp_down"), l.mlp_down)?,
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            config: self.config,
+            embed_tokens: self.embed_tokens,
+            layers,
+            lm_layernorm: self.lm_layernorm,
+            lm_head: f("lm_head", self.lm_head)?,
+        })
+    }
+}
+
+fn is_quantized(t: &Tensor<Weight>) -> bool {
+    block_bytes(t.data_layout()).is_some()
+}
+
+fn contiguous(t: Tensor<Weight>) -> Tensor<Weight> {
+    if t.is_contiguous() || is_quantized(&t) {
+        return t;
+    }
+    let mut ans = Tensor::alloc(t.data_layout(), t.shape(), Blob::new);
+    t.reform_to(&mut ans);
+    ans.map_physical(|b| b.into())
+}
+
+#[test]
+fn test_pretranspose() {
+    use crate::tiny_model;
+    use tensor::udim;
+
+    let dir = std::env::temp_dir().join(format!("llama-repack-test-{}", std::process::id()));
+    tiny_model(&dir, 1).unwrap();
+    let storage = Storage::load_safetensors(&dir).unwrap();
+    let qkv = storage.layers[0].att_qkv.clone();
+    let &[d, n] = qkv.shape() else { panic!() };
+    let elem = qkv.data_layout().nbytes() as isize;
+    // 逻辑上的第 (i, j) 个元素
+    let at = |t: &Tensor<Weight>, i: udim, j: udim| {
+        let &[s0, s1] = t.strides() else { panic!() };
+        let off = t.bytes_offset() + (i as isize * s0 as isize + j as isize * s1 as isize) * elem;
+        t.physical()[off as usize..][..elem as usize].to_vec()
+    };
+
+    let packed = Storage::load_safetensors(&dir).unwrap().pretranspose();
+    assert!(packed.layers[0].att_qkv.is_contiguous());
+    assert!(packed.lm_head.is_contiguous());
+    assert_eq!(packed.layers[0].att_qkv.shape(), [d, n]);
+
+    // 第一次加载写入缓存，第二次映射缓存，结果都与直接整理的一致
+    let cache = dir.join("cache");
+    for _ in 0..2 {
+        let cached = Storage::load_safetensors(&dir)
+            .unwrap()
+            .pretranspose_cached(&cache)
+            .unwrap();
+        let t = &cached.layers[0].att_qkv;
+        assert!(t.is_contiguous());
+        for (i, j) in [(0, 0), (1, 2), (d - 1, n - 1)] {
+            assert_eq!(at(t, i, j), at(&qkv, i, j));
+            assert_eq!(at(t, i, j), at(&packed.layers[0].att_qkv, i, j));
+        }
+        assert_eq!(cached.lm_head.as_slice(), packed.lm_head.as_slice());
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
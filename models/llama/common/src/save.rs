@@ -1,11 +1,13 @@
-﻿use crate::{
+use crate::{
     json::{data_layout_name, ConfigJson},
     Storage, Weight,
 };
-use common::safe_tensors::{Dtype, SafeTensorsHeader, SafeTensorsHeaderMetadata, TensorInfo};
+use common::{
+    safe_tensors::{Dtype, SafeTensorsHeader, SafeTensorsHeaderMetadata, TensorInfo},
+    Blob,
+};
 use digit_layout::DigitLayout;
 use std::{
-    collections::HashMap,
     fs,
     io::{self, BufWriter, Write},
     path::Path,
@@ -17,8 +19,10 @@ impl Storage {
         let dir = dir.as_ref();
         fs::create_dir_all(dir)?;
         let config = serde_json::to_string_pretty(&ConfigJson {
+            architectures: vec!["LlamaForCausalLM".into()],
+            model_type: Some("llama".into()),
             bos_token_id: self.config.bos_token,
-            eos_token_id: self.config.eos_token,
+            eos_token_id: self.config.eos_tokens[0],
             hidden_size: self.config.d as _,
             intermediate_size: self.config.di as _,
             max_position_embeddings: self.config.max_seq_len as _,
@@ -28,93 +32,109 @@ impl Storage {
             vocab_size: self.config.voc as _,
             rms_norm_eps: self.config.epsilon,
             rope_theta: self.config.theta,
+            attn_scale: self.config.attn_scale,
             torch_dtype: data_layout_name(self.config.dt).to_string(),
+            // 量化的权重在加载时已经反量化
+            quantization_config: None,
         })?;
         fs::write(dir.join("config.json"), config)?;
 
-        let mut offset = 0usize;
-        let mut header = SafeTensorsHeader {
-            tensors: HashMap::new(),
-            metadata: SafeTensorsHeaderMetadata {
-                format: "rs".into(),
-            },
-        };
-
-        let mut t = |tensor: &Tensor<Weight>| TensorInfo {
-            dtype: convert(tensor.data_layout()),
-            shape: tensor.shape().iter().map(|&d| d as _).collect(),
-            data_offsets: {
-                let start = offset;
-                offset += tensor.bytes_size();
-                (start, offset)
-            },
-        };
-
-        header
-            .tensors
-            .insert("model.embed_tokens.weight".into(), t(&self.embed_tokens));
+        let mut tensors = vec![(
+            "model.embed_tokens.weight".into(),
+            self.embed_tokens.clone(),
+        )];
         for (i, l) in self.layers.iter().enumerate() {
             #[rustfmt::skip]
             let iter = [
-                ("input_layernorm"         , &l.att_layernorm),
-                ("self_attn.qkv_proj"      , &l.att_qkv    .clone().transpose(&[1, 0])),
-                ("self_attn.o_proj"        , &l.att_o      .clone().transpose(&[1, 0])),
-                ("post_attention_layernorm", &l.mlp_layernorm),
-                ("mlp.gate_up_proj"        , &l.mlp_gate_up.clone().transpose(&[1, 0])),
-                ("mlp.down_proj"           , &l.mlp_down   .clone().transpose(&[1, 0])),
+                ("input_layernorm"         , l.att_layernorm.clone()),
+                ("self_attn.qkv_proj"      , l.att_qkv    .clone().transpose(&[1, 0])),
+                ("self_attn.o_proj"        , l.att_o      .clone().transpose(&[1, 0])),
+                ("post_attention_layernorm", l.mlp_layernorm.clone()),
+                ("mlp.gate_up_proj"        , l.mlp_gate_up.clone().transpose(&[1, 0])),
+                ("mlp.down_proj"           , l.mlp_down   .clone().transpose(&[1, 0])),
             ];
-            header.tensors.extend(
-                iter.map(|(name, tensor)| (format!("model.layers.{i}.{name}.weight"), t(tensor))),
+            tensors.extend(
+                iter.map(|(name, tensor)| (format!("model.layers.{i}.{name}.weight"), tensor)),
             );
         }
-        header.tensors.extend([
-            ("model.norm.weight".into(), t(&self.lm_layernorm)),
+        tensors.extend([
+            ("model.norm.weight".into(), self.lm_layernorm.clone()),
             (
                 "lm_head.weight".into(),
-                t(&self.lm_head.clone().transpose(&[1, 0])),
+                self.lm_head.clone().transpose(&[1, 0]),
             ),
         ]);
+        write_safetensors(&dir.join("model.safetensors"), &tensors)
+    }
+}
+
+/// 按顺序将张量写入 safetensors 文件，不连续的张量整理为连续的再写入。
+pub(crate) fn write_safetensors(
+    path: &Path,
+    tensors: &[(String, Tensor<Weight>)],
+) -> io::Result<()> {
+    let mut offset = 0usize;
+    let header = SafeTensorsHeader {
+        tensors: tensors
+            .iter()
+            .map(|(name, tensor)| {
+                let info = TensorInfo {
+                    dtype: convert(tensor.data_layout()),
+                    shape: tensor.shape().iter().map(|&d| d as _).collect(),
+                    data_offsets: {
+                        let start = offset;
+                        offset += tensor.bytes_size();
+                        (start, offset)
+                    },
+                };
+                (name.clone(), info)
+            })
+            .collect(),
+        metadata: SafeTensorsHeaderMetadata {
+            format: "rs".into(),
+        },
+    };
 
-        let header = {
-            let str = serde_json::to_string(&header)?;
-            let len = str.len();
-            const ALIGN: usize = std::mem::size_of::<usize>();
-            let aligned = (len + ALIGN - 1) & !(ALIGN - 1);
+    let header = {
+        let str = serde_json::to_string(&header)?;
+        let len = str.len();
+        const ALIGN: usize = std::mem::size_of::<usize>();
+        let aligned = (len + ALIGN - 1) & !(ALIGN - 1);
 
-            let mut buffer = Vec::with_capacity(aligned);
-            let mut write = BufWriter::new(&mut buffer);
-            write.write_all(&(aligned as u64).to_le_bytes())?;
-            write.write_all(str.as_bytes())?;
-            for _ in len..aligned {
-                write.write_all(&[32])?;
-            }
-            drop(write);
-            buffer
-        };
+        let mut buffer = Vec::with_capacity(aligned);
+        let mut write = BufWriter::new(&mut buffer);
+        write.write_all(&(aligned as u64).to_le_bytes())?;
+        write.write_all(str.as_bytes())?;
+        for _ in len..aligned {
+            write.write_all(&[32])?;
+        }
+        drop(write);
+        buffer
+    };
 
-        let mut file = fs::File::create(dir.join("model.safetensors"))?;
-        file.write_all(&header)?;
-        file.write_all(self.embed_tokens.physical())?;
-        for l in self.layers.iter() {
-            file.write_all(l.att_layernorm.physical())?;
-            file.write_all(l.att_qkv.physical())?;
-            file.write_all(l.att_o.physical())?;
-            file.write_all(l.mlp_layernorm.physical())?;
-            file.write_all(l.mlp_gate_up.physical())?;
-            file.write_all(l.mlp_down.physical())?;
+    let mut file = fs::File::create(path)?;
+    file.write_all(&header)?;
+    for (_, tensor) in tensors {
+        if tensor.is_contiguous() {
+            file.write_all(tensor.as_slice())?;
+        } else {
+            let mut blob = Blob::new(tensor.bytes_size());
+            unsafe { tensor.reform_to_raw(&mut blob) };
+            file.write_all(&blob)?;
         }
-        file.write_all(self.lm_layernorm.physical())?;
-        file.write_all(self.lm_head.physical())?;
-        Ok(())
     }
+    Ok(())
 }
 
 fn convert(dtype: DigitLayout) -> Dtype {
+    use common::fp8::{F8E4M3, F8E5M2};
     use digit_layout::types::*;
     match dtype {
         BOOL => Dtype::BOOL,
         U8 => Dtype::U8,
         I8 => Dtype::I8,
+        F8E5M2 => Dtype::F8_E5M2,
+        F8E4M3 => Dtype::F8_E4M3,
         I16 => Dtype::I16,
         U16 => Dtype::U16,
         F16 => Dtype::F16,
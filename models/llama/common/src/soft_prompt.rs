@@ -0,0 +1,95 @@
+//! 软提示词（prompt tuning），推理时作为虚拟的词接在会话的开头，不修改模型的权重。
+
+use crate::{cast::cast, load::convert, InferenceConfig, Weight};
+use common::{
+    safe_tensors::SafeTensors,
+    FileLoadError::{self, Io},
+};
+use std::{
+    io::{Error, ErrorKind::InvalidData},
+    path::Path,
+};
+use tensor::{udim, Tensor};
+
+pub struct SoftPrompt {
+    /// 各虚拟词的嵌入，形状为 `[n, d]`。
+    pub embeds: Tensor<Weight>,
+}
+
+impl SoftPrompt {
+    /// 加载 safetensors 格式的软提示词，即 PEFT 保存的 `prompt_embeddings`，转换为 `config` 的数据类型。
+    pub fn load_safetensors(
+        path: impl AsRef<Path>,
+        config: &InferenceConfig,
+    ) -> Result<Self, FileLoadError> {
+        let file = SafeTensors::single_file(path)?.share();
+        let &InferenceConfig { dt, d, .. } = config;
+        let invalid = |msg: String| Io(Error::new(InvalidData, msg));
+        let t = file
+            .share_tensor("prompt_embeddings")
+            .ok_or_else(|| invalid("prompt_embeddings not found".into()))?;
+        let n = match *t.shape() {
+            [n, d_] if n > 0 && d_ == d as usize => n as udim,
+            _ => {
+                let msg = format!(
+                    "prompt_embeddings has shape {:?}, expected [_, {d}]",
+                    t.shape()
+                );
+                return Err(invalid(msg));
+            }
+        };
+        let t = Tensor::new(convert(t.dtype()), &[n, d], Weight::SafeTensor(t));
+        Ok(Self {
+            embeds: cast(t, dt),
+        })
+    }
+
+    /// 虚拟词的数量。
+    #[inline]
+    pub fn num_tokens(&self) -> udim {
+        self.embeds.shape()[0]
+    }
+}
+
+#[test]
+fn test_load() {
+    use crate::{save::write_safetensors, tiny_model, Storage};
+    use common::{f16, Blob};
+    use digit_layout::types::F32;
+    use std::fs;
+    use tensor::{reslice, reslice_mut};
+
+    let dir = std::env::temp_dir().join(format!("llama-soft-prompt-test-{}", std::process::id()));
+    tiny_model(&dir, 5).unwrap();
+    let config = Storage::load_safetensors(&dir).unwrap().config;
+    let d = config.d;
+
+    let embeddings = |shape: &[udim]| {
+        let mut t = Tensor::alloc(F32, shape, Blob::new);
+        let data = reslice_mut::<u8, f32>(t.physical_mut());
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = i as f32 / 8.;
+        }
+        t.map_physical(|b| b.into())
+    };
+    let path = dir.join("soft-prompt.safetensors");
+    write_safetensors(&path, &[("prompt_embeddings".into(), embeddings(&[3, d]))]).unwrap();
+    let prompt = SoftPrompt::load_safetensors(&path, &config).unwrap();
+    assert_eq!(prompt.num_tokens(), 3);
+    assert_eq!(prompt.embeds.data_layout(), config.dt);
+    let v = reslice::<u8, f16>(prompt.embeds.physical());
+    assert!(v
+        .iter()
+        .enumerate()
+        .all(|(i, x)| x.to_f32() == i as f32 / 8.));
+
+    // 维度不符时报错
+    let path = dir.join("wrong.safetensors");
+    write_safetensors(
+        &path,
+        &[("prompt_embeddings".into(), embeddings(&[3, d + 1]))],
+    )
+    .unwrap();
+    assert!(SoftPrompt::load_safetensors(&path, &config).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,84 @@
+//! 控制向量，推理时按查询和强度叠加到每层输出的残差上，不修改模型的权重。
+
+use crate::{cast::cast, load::convert, InferenceConfig, Weight};
+use common::{
+    safe_tensors::SafeTensors,
+    FileLoadError::{self, Io},
+};
+use std::{
+    io::{Error, ErrorKind::InvalidData},
+    path::Path,
+};
+use tensor::Tensor;
+
+pub struct Steering {
+    /// 各层之后叠加的方向，形状为 `[1, d]`；没有方向的层为 `None`。
+    pub layers: Vec<Option<Tensor<Weight>>>,
+}
+
+impl Steering {
+    /// 加载 safetensors 格式的控制向量，叠加在第 `l` 层（从 0 开始）之后的方向名为 `direction.{l}`，
+    /// 转换为 `config` 的数据类型。
+    pub fn load_safetensors(
+        path: impl AsRef<Path>,
+        config: &InferenceConfig,
+    ) -> Result<Self, FileLoadError> {
+        let file = SafeTensors::single_file(path)?.share();
+        let &InferenceConfig { dt, nlayers, d, .. } = config;
+        let layers = (0..nlayers)
+            .map(|l| {
+                let Some(t) = file.share_tensor(&format!("direction.{l}")) else {
+                    return Ok(None);
+                };
+                if t.shape() != [d as usize] {
+                    let msg = format!("direction.{l} has shape {:?}, expected [{d}]", t.shape());
+                    return Err(Io(Error::new(InvalidData, msg)));
+                }
+                let t = Tensor::new(convert(t.dtype()), &[1, d], Weight::SafeTensor(t));
+                Ok(Some(cast(t, dt)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { layers })
+    }
+}
+
+#[test]
+fn test_load() {
+    use crate::{save::write_safetensors, tiny_model, Storage};
+    use common::{f16, Blob};
+    use digit_layout::types::F32;
+    use std::fs;
+    use tensor::{reslice, reslice_mut};
+
+    let dir = std::env::temp_dir().join(format!("llama-steering-test-{}", std::process::id()));
+    tiny_model(&dir, 4).unwrap();
+    let config = Storage::load_safetensors(&dir).unwrap().config;
+    let d = config.d;
+
+    let direction = |len| {
+        let mut t = Tensor::alloc(F32, &[len], Blob::new);
+        let data = reslice_mut::<u8, f32>(t.physical_mut());
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = i as f32 / 4.;
+        }
+        t.map_physical(|b| b.into())
+    };
+    let path = dir.join("steering.safetensors");
+    write_safetensors(&path, &[("direction.1".into(), direction(d))]).unwrap();
+    let steering = Steering::load_safetensors(&path, &config).unwrap();
+    assert!(steering.layers[0].is_none());
+    let v = steering.layers[1].as_ref().unwrap();
+    assert_eq!(v.shape(), [1, d]);
+    assert_eq!(v.data_layout(), config.dt);
+    let v = reslice::<u8, f16>(v.physical());
+    assert!(v
+        .iter()
+        .enumerate()
+        .all(|(i, x)| x.to_f32() == i as f32 / 4.));
+
+    // 维度不符时报错
+    let path = dir.join("wrong.safetensors");
+    write_safetensors(&path, &[("direction.0".into(), direction(d + 1))]).unwrap();
+    assert!(Steering::load_safetensors(&path, &config).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
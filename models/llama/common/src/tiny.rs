@@ -0,0 +1,126 @@
+use crate::{InferenceConfig, LayerStorage, Storage, Weight};
+use common::{bf16, f16, Blob};
+use digit_layout::types::{BF16, F16, F32};
+use std::{fs, io, path::Path};
+use tensor::{reslice_mut, udim, Tensor};
+
+impl Storage {
+    /// 由 `seed` 确定地生成随机权重，用于测试。
+    pub fn random(config: InferenceConfig, seed: u64) -> Self {
+        let InferenceConfig {
+            dt,
+            voc,
+            nlayers,
+            d,
+            dkv,
+            di,
+            ..
+        } = config;
+        // xorshift64*，生成 [-1, 1) 的均匀分布
+        let mut state = seed | 1;
+        let mut next = move || {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            let x = state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 40;
+            x as f32 / (1 << 23) as f32 - 1.
+        };
+        let mut tensor = |shape: &[udim], scale: f32, bias: f32| -> Tensor<Weight> {
+            let mut ans = Tensor::alloc(dt, shape, Blob::new);
+            let data = ans.physical_mut();
+            let mut x = || bias + scale * next();
+            match dt {
+                F16 => reslice_mut(data).fill_with(|| f16::from_f32(x())),
+                BF16 => reslice_mut(data).fill_with(|| bf16::from_f32(x())),
+                F32 => reslice_mut(data).fill_with(x),
+                _ => todo!(),
+            }
+            ans.map_physical(|b| b.into())
+        };
+        // 矩阵按输入维度缩放，保持各层输出的量级
+        let w = |fan_in: udim| (fan_in as f32).sqrt().recip();
+
+        let embed_tokens = tensor(&[voc, d], 1., 0.);
+        let layers = (0..nlayers)
+            .map(|_| LayerStorage {
+                att_layernorm: tensor(&[d], 0.1, 1.),
+                att_qkv: tensor(&[d + dkv + dkv, d], w(d), 0.).transpose(&[1, 0]),
+                att_o: tensor(&[d, d], w(d), 0.).transpose(&[1, 0]),
+                mlp_layernorm: tensor(&[d], 0.1, 1.),
+                mlp_gate_up: tensor(&[di + di, d], w(d), 0.).transpose(&[1, 0]),
+                mlp_down: tensor(&[d, di], w(di), 0.).transpose(&[1, 0]),
+            })
+            .collect();
+        let lm_layernorm = tensor(&[d], 0.1, 1.);
+        let lm_head = tensor(&[voc, d], w(d), 0.).transpose(&[1, 0]);
+        Self {
+            config,
+            embed_tokens,
+            layers,
+            lm_layernorm,
+            lm_head,
+        }
+    }
+}
+
+/// 在 `model_dir` 生成随机权重的小模型，包括 config.json、model.safetensors 和 vocabs.txt，测试不必下载真实的模型。
+///
+/// 词表由特殊词、单字节词和可打印的 ascii 字符组成，相同的 `seed` 生成相同的权重。
+pub fn tiny_model(model_dir: impl AsRef<Path>, seed: u64) -> io::Result<()> {
+    let model_dir = model_dir.as_ref();
+    let pieces = ["<unk>", "<s>", "</s>"]
+        .into_iter()
+        .map(String::from)
+        .chain((0..=255u8).map(|b| format!("<0x{b:02X}>")))
+        .chain((b' '..=b'~').map(|b| char::from(b).to_string()))
+        .collect::<Vec<_>>();
+
+    Storage::random(tiny_config(pieces.len() as _), seed).save(model_dir)?;
+    let text = pieces
+        .iter()
+        .map(|p| format!("\"{p}\"\n"))
+        .collect::<String>();
+    fs::write(model_dir.join("vocabs.txt"), text)
+}
+
+pub(crate) fn tiny_config(voc: udim) -> InferenceConfig {
+    InferenceConfig {
+        dt: F16,
+        voc,
+        nlayers: 2,
+        nh: 4,
+        nkvh: 2,
+        d: 32,
+        dkv: 16,
+        di: 48,
+        max_seq_len: 128,
+        bos_token: 1,
+        eos_tokens: vec![2],
+        epsilon: 1e-5,
+        theta: 1e4,
+        attn_scale: None,
+    }
+}
+
+#[test]
+fn test_tiny_model() {
+    let dir = std::env::temp_dir().join(format!("llama-tiny-test-{}", std::process::id()));
+    tiny_model(&dir, 1).unwrap();
+    let storage = Storage::load_safetensors(&dir).unwrap();
+    assert_eq!(storage.config.voc, 3 + 256 + 95);
+    assert_eq!(storage.layers.len(), 2);
+    // 未指定缩放时不写入配置，以 1/sqrt(dh) 缩放
+    assert_eq!(storage.config.attn_scale, None);
+    assert_eq!(storage.config.attn_scale(), (8f32).sqrt().recip());
+    let json = fs::read_to_string(dir.join("config.json")).unwrap();
+    assert!(!json.contains("attn_scale"));
+
+    let random = Storage::random(storage.config.clone(), 1);
+    assert_eq!(
+        storage.embed_tokens.physical()[..],
+        random.embed_tokens.physical()[..]
+    );
+    let vocabs = fs::read_to_string(dir.join("vocabs.txt")).unwrap();
+    assert_eq!(vocabs.lines().count(), storage.config.voc as usize);
+    fs::remove_dir_all(dir).unwrap();
+}
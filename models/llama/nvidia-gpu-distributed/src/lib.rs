@@ -6,7 +6,7 @@ mod parameters;
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta, ShapeError};
 use common::{upos, utok, FileLoadError};
 use common_nv::{
     cuda::{
@@ -51,7 +51,10 @@ impl Model for Transformer {
     #[inline]
     fn load(model_dir: impl AsRef<Path>, meta: Self::Meta) -> Result<Self, Self::Error> {
         let time = Instant::now();
-        let host = llama::Storage::load_safetensors(model_dir)?;
+        let host = llama::Storage::load(model_dir)?;
+        // GPU 的矩阵乘不支持分块量化的矩阵
+        let dt = host.config.dt;
+        let host = host.cast_matrices(dt);
         info!("load host: {:?}", time.elapsed());
 
         let kernels = NvidiaKernels::new(&meta, host.config.d as _);
@@ -110,8 +113,8 @@ impl CausalLM for Transformer {
         self.config.max_seq_len
     }
     #[inline]
-    fn eos_token(&self) -> utok {
-        self.config.eos_token
+    fn eos_tokens(&self) -> &[utok] {
+        &self.config.eos_tokens
     }
 
     fn new_cache(&self) -> Tensor<Self::Storage> {
@@ -131,7 +134,11 @@ impl CausalLM for Transformer {
         })
     }
 
-    fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
+    fn duplicate_cache(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        pos: upos,
+    ) -> Result<Tensor<Self::Storage>, ShapeError> {
         let contexts = Arc::new(self.comms.contexts().collect::<Vec<_>>());
         self.config.duplicate_cache(
             cache,
@@ -276,6 +283,8 @@ impl CausalLM for Transformer {
                                 .map(|(cache, range)| QueryContext {
                                     cache: cache.as_mut(),
                                     range: range.clone(),
+                                    adapter: None,
+                                    steering: None,
                                 })
                                 .collect::<Vec<_>>();
 
@@ -454,7 +463,7 @@ impl Transformer {
         let dkv = nkvh * dh;
         let di = self.config.di;
         let head_group = nh / nkvh;
-        let head_div = (dh as f32).sqrt().recip();
+        let head_div = self.config.attn_scale();
         let theta = self.config.theta;
         let epsilon = self.config.epsilon;
 
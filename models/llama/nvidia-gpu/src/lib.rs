@@ -5,11 +5,11 @@ mod resource;
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta, ShapeError};
 use common::{upos, utok, Blob, FileLoadError};
 use common_nv::{
     cuda::memcpy_d2h, sample_nv, slice, udim, Gpu, Kernels, KernelsA, KernelsB, NvidiaKernels,
-    Tensor,
+    StreamTransfer, Tensor,
 };
 use cuda::{
     ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore, Device, EventSpore, HostMemSpore,
@@ -79,7 +79,10 @@ impl Model for Transformer {
         }: Self::Meta,
     ) -> Result<Self, Self::Error> {
         let time = Instant::now();
-        let host = llama::Storage::load_safetensors(model_dir)?;
+        let host = llama::Storage::load(model_dir)?;
+        // GPU 的矩阵乘不支持分块量化的矩阵
+        let dt = host.config.dt;
+        let host = host.cast_matrices(dt);
         info!("load host: {:?}", time.elapsed());
         let load_layers = (load_layers as udim).min(host.config.nlayers);
 
@@ -113,10 +116,12 @@ impl Model for Transformer {
                 layers,
                 lm_layernorm: host
                     .lm_layernorm
-                    .map_physical(|u| transfer.from_host(&u).sporulate()),
+                    .to_device(&StreamTransfer(&transfer))
+                    .map_physical(|u| u.sporulate()),
                 lm_head: host
                     .lm_head
-                    .map_physical(|u| transfer.from_host(&u).sporulate()),
+                    .to_device(&StreamTransfer(&transfer))
+                    .map_physical(|u| u.sporulate()),
                 pool: Mutex::new(pool),
 
                 config: host.config,
@@ -147,15 +152,19 @@ impl CausalLM for Transformer {
         self.0.config.max_seq_len
     }
     #[inline]
-    fn eos_token(&self) -> utok {
-        self.0.config.eos_token
+    fn eos_tokens(&self) -> &[utok] {
+        &self.0.config.eos_tokens
     }
 
     fn new_cache(&self) -> Tensor<Self::Storage> {
         self.0.config.new_cache(|len| self.cache(len))
     }
 
-    fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
+    fn duplicate_cache(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        pos: upos,
+    ) -> Result<Tensor<Self::Storage>, ShapeError> {
         self.0.config.duplicate_cache(
             cache,
             pos,
@@ -209,6 +218,7 @@ impl CausalLM for Transformer {
                 di: self.0.config.di,
                 epsilon: self.0.config.epsilon,
                 theta: self.0.config.theta,
+                attn_scale: self.0.config.attn_scale(),
                 kernels: &self.0.kernels,
                 compute,
                 transfer,
@@ -342,6 +352,7 @@ struct ComputeStream<'a> {
     di: udim,
     epsilon: f32,
     theta: f32,
+    attn_scale: f32,
     kernels: &'a NvidiaKernels,
     compute: &'a Stream<'a>,
     transfer: &'a Stream<'a>,
@@ -397,6 +408,7 @@ impl<'a> llama::ComputeStream for ComputeStream<'a> {
             di: self.di,
             epsilon: self.epsilon,
             theta: self.theta,
+            attn_scale: self.attn_scale,
         }
     }
 
@@ -564,3 +576,27 @@ fn test_infer() {
         );
     };
 }
+
+#[test]
+fn test_golden() {
+    cuda::init();
+    if let Some(device) = cuda::Device::fetch() {
+        llama::test_golden::<Transformer>(
+            ModelLoadMeta {
+                device,
+                load_layers: 20,
+            },
+            |model, logits| {
+                model.0.resource.apply(|compute| {
+                    compute.synchronize();
+                    logits.map_physical(|cache| {
+                        let mem = cache.mem.sprout_ref(compute.ctx());
+                        let mut host = vec![0; mem.len()];
+                        memcpy_d2h(&mut host, mem);
+                        host
+                    })
+                })
+            },
+        );
+    };
+}
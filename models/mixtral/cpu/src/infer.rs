@@ -1,5 +1,5 @@
 use super::MixtralCPU;
-use causal_lm::{CausalLM, DecodingMeta, QueryContext, SampleMeta};
+use causal_lm::{CausalLM, DecodingMeta, QueryContext, SampleMeta, ShapeError};
 use common::{f16, upos, utok, Blob};
 use common_cpu::{KernelsA, KernelsB, ThisThread};
 use digit_layout::{types::U32, DigitLayout};
@@ -11,8 +11,8 @@ impl CausalLM for MixtralCPU {
     type Storage = Blob;
 
     #[inline]
-    fn eos_token(&self) -> utok {
-        self.eos_token
+    fn eos_tokens(&self) -> &[utok] {
+        &self.eos_tokens
     }
     #[inline]
     fn max_seq_len(&self) -> upos {
@@ -29,11 +29,11 @@ impl CausalLM for MixtralCPU {
         Tensor::alloc(dt, &[nlayers, 2, nkvh, max_seq_len, d / nh], Blob::new)
     }
 
-    fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
-        let &[_nlayers, 2, _nkvh, max_seq_len, _dh] = cache.shape() else {
-            panic!()
-        };
-        assert!(pos <= max_seq_len);
+    fn duplicate_cache(
+        &self,
+        cache: &Tensor<Self::Storage>,
+        pos: upos,
+    ) -> Result<Tensor<Self::Storage>, ShapeError> {
         let slice = [
             slice![=>],
             slice![=>],
@@ -42,13 +42,11 @@ impl CausalLM for MixtralCPU {
             slice![=>],
         ];
 
+        let src = cache.as_ref().try_slice(&slice)?;
         let mut ans = Tensor::alloc(cache.data_layout(), cache.shape(), Blob::new);
-        cache
-            .as_ref()
-            .slice(&slice)
-            .map_physical(|u| &**u)
+        src.map_physical(|u| &**u)
             .reform_to(&mut ans.as_mut().slice(&slice).map_physical(|u| &mut **u));
-        ans
+        Ok(ans)
     }
 
     fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
@@ -1,7 +1,7 @@
 mod infer;
 
 use causal_lm::Model;
-use common::{safe_tensors::SafeTensors, utok, FileLoadError};
+use common::{safe_tensors::SafeTensors, utok, FileLoadError, GenerationConfig};
 use common_cpu::CpuKernels;
 use digit_layout::DigitLayout;
 use mixtral::{ConfigJson, MixtralParams};
@@ -9,7 +9,7 @@ use std::path::Path;
 use tensor::udim;
 
 pub struct MixtralCPU {
-    eos_token: utok,
+    eos_tokens: Vec<utok>,
     data_type: DigitLayout,
     nlayers: udim,
     nh: udim,
@@ -33,7 +33,7 @@ impl Model for MixtralCPU {
     fn load(model_dir: impl AsRef<Path>, _: Self::Meta) -> Result<Self, Self::Error> {
         let config = ConfigJson::load(&model_dir)?;
         Ok(Self {
-            eos_token: config.eos_token_id,
+            eos_tokens: GenerationConfig::load(&model_dir)?.eos_tokens(config.eos_token_id),
             data_type: config.data_layout(),
             nlayers: config.num_hidden_layers as _,
             nh: config.num_attention_heads as _,
@@ -1,6 +1,13 @@
 #![deny(warnings)]
 
+mod processor;
 mod sample;
+mod stage;
+
+use common::utok;
+
+pub use processor::{LogitProcessor, LogitProcessors};
+pub use stage::{ParseStageError, SampleStage};
 
 /// 采样参数。
 #[derive(Clone, PartialEq, Debug)]
@@ -11,6 +18,16 @@ pub struct SampleArgs {
     pub top_k: usize,
     /// 软阈值，(0, 1] 区间有效，不大于 0 使用贪心采样。
     pub top_p: f32,
+    /// XTC 阈值，概率不小于阈值的词视为“最可能的选择”，(0, 0.5] 区间有效。
+    pub xtc_threshold: f32,
+    /// XTC 触发概率，(0, 1] 区间有效，不大于 0 不启用 XTC。
+    pub xtc_probability: f32,
+    /// 采样流程中各阶段的顺序，未列出的阶段不生效。
+    pub order: Vec<SampleStage>,
+    /// 不参与采样的词。
+    pub suppressed: Vec<utok>,
+    /// 采样之前依次修改 logits 的处理器。
+    pub processors: LogitProcessors,
 }
 
 impl Default for SampleArgs {
@@ -21,6 +38,11 @@ impl Default for SampleArgs {
             temperature: 0.,
             top_k: usize::MAX,
             top_p: 1.,
+            xtc_threshold: 0.1,
+            xtc_probability: 0.,
+            order: SampleStage::DEFAULT_ORDER.to_vec(),
+            suppressed: Vec::new(),
+            processors: Default::default(),
         }
     }
 }
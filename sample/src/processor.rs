@@ -0,0 +1,65 @@
+use common::utok;
+use std::{fmt, sync::Arc};
+
+/// 在采样之前修改 logits 的处理器。
+///
+/// 下游可以实现这个特性并注册到 [`SampleArgs`](crate::SampleArgs)，在不修改采样器的情况下施加自定义的约束。
+pub trait LogitProcessor: Send + Sync {
+    /// 根据已有的词序列 `history` 修改下一个词的 `logits`。
+    fn process(&self, history: &[utok], logits: &mut [f32]);
+}
+
+/// 依次执行的 logits 处理器，以及执行时可见的词序列。
+#[derive(Clone, Default)]
+pub struct LogitProcessors {
+    list: Vec<Arc<dyn LogitProcessor>>,
+    history: Arc<[utok]>,
+}
+
+impl LogitProcessors {
+    /// 注册一个处理器，处理器按注册顺序执行。
+    #[inline]
+    pub fn push(&mut self, processor: Arc<dyn LogitProcessor>) {
+        self.list.push(processor);
+    }
+
+    /// 是否没有注册任何处理器。
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// 设置处理器可见的词序列。
+    #[inline]
+    pub fn set_history(&mut self, history: &[utok]) {
+        self.history = history.into();
+    }
+
+    /// 依次执行所有处理器。
+    pub fn process(&self, logits: &mut [f32]) {
+        for p in &self.list {
+            p.process(&self.history, logits);
+        }
+    }
+}
+
+impl PartialEq for LogitProcessors {
+    fn eq(&self, other: &Self) -> bool {
+        self.list.len() == other.list.len()
+            && self
+                .list
+                .iter()
+                .zip(&other.list)
+                .all(|(a, b)| Arc::ptr_eq(a, b))
+            && self.history == other.history
+    }
+}
+
+impl fmt::Debug for LogitProcessors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LogitProcessors")
+            .field("len", &self.list.len())
+            .field("history", &self.history.len())
+            .finish()
+    }
+}
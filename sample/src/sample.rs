@@ -1,5 +1,6 @@
-﻿use common::{utok, BetweenF32};
-use std::{cmp::Ordering, mem::replace};
+﻿use crate::SampleStage;
+use common::{utok, BetweenF32};
+use std::cmp::Ordering;
 
 impl crate::SampleArgs {
     #[inline]
@@ -11,63 +12,301 @@ impl crate::SampleArgs {
     where
         T: BetweenF32 + PartialOrd,
     {
+        if !self.processors.is_empty() {
+            let mut logits = logits.iter().map(T::get).collect::<Vec<_>>();
+            self.processors.process(&mut logits);
+            let args = Self {
+                processors: Default::default(),
+                ..self.clone()
+            };
+            return args.random(&logits);
+        }
+
         if self.is_argmax() {
             return logits
                 .iter()
                 .enumerate()
+                .filter(|(i, _)| !self.suppressed.contains(&(*i as _)))
                 .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
                 .unwrap()
                 .0 as _;
         }
 
-        #[derive(Clone, Copy, PartialEq, Debug)]
-        struct Probability {
-            val: f32,
-            tok: utok,
-        }
-        impl Eq for Probability {}
-        impl PartialOrd for Probability {
-            #[inline]
-            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-                Some(self.cmp(other))
-            }
-        }
-        impl Ord for Probability {
-            #[inline]
-            fn cmp(&self, other: &Self) -> Ordering {
-                match self.val.total_cmp(&other.val) {
-                    Ordering::Equal => self.tok.cmp(&other.tok),
-                    ord => ord.reverse(),
-                }
-            }
-        }
-        impl<T: BetweenF32> From<(usize, &T)> for Probability {
-            #[inline]
-            fn from((i, p): (usize, &T)) -> Self {
-                Self {
-                    val: p.get(),
-                    tok: i as _,
-                }
-            }
-        }
-
         // sort
         let mut logits = logits
             .iter()
             .enumerate()
             .map(Probability::from)
+            .filter(|p| !self.suppressed.contains(&p.tok))
+            .collect::<Vec<_>>();
+        logits.sort_unstable();
+        self.random_sorted(&logits)
+    }
+
+    /// 部分采样时每个词表分片需要保留的候选词数量，`None` 表示需要完整的 logits。
+    ///
+    /// 贪心采样只需要各分片的最大值；默认流程（[`is_basic`](Self::is_basic)）的 top-p 只在 top-k 的范围内计算，
+    /// 所以全局的候选词一定在各分片的前 k 个中。
+    pub fn partial_top_k(&self) -> Option<usize> {
+        if !self.suppressed.is_empty() || !self.processors.is_empty() {
+            None
+        } else if self.is_argmax() {
+            Some(1)
+        } else {
+            (self.is_basic() && self.top_k <= MAX_PARTIAL_TOP_K).then_some(self.top_k)
+        }
+    }
+
+    /// 从各分片的候选词中采样，每个分片至少提供 [`partial_top_k`](Self::partial_top_k) 个 logits 最大的词。
+    pub fn random_partial(&self, candidates: impl IntoIterator<Item = (utok, f32)>) -> utok {
+        let mut logits = candidates
+            .into_iter()
+            .map(|(tok, val)| Probability { val, tok })
             .collect::<Vec<_>>();
         logits.sort_unstable();
-        let max = replace(&mut logits[0].val, 1.);
-        // softmax & sum
-        for i in 1..logits.len() {
-            logits[i].val = logits[i - 1].val + ((logits[i].val - max) / self.temperature).exp();
+        if self.is_argmax() {
+            logits[0].tok
+        } else {
+            self.random_sorted(&logits)
         }
-        // topk & topp & random
-        let pk = logits[self.top_k.min(logits.len()) - 1].val;
-        let pp = logits[logits.len() - 1].val * self.top_p;
-        let plimit = rand::random::<f32>() * f32::min(pk, pp);
+    }
+
+    /// 在已排序的 logits 上按顺序执行各阶段并采样。
+    fn random_sorted(&self, logits: &[Probability]) -> utok {
+        let mut candidates = logits;
+        let mut temperature = 1.;
+        // 候选词的累积概率，截断候选词时一并截断，改变温度时重新计算
+        let mut sums: Option<Vec<f32>> = None;
+        for stage in &self.order {
+            match stage {
+                SampleStage::Temperature => {
+                    temperature = self.temperature;
+                    sums = None;
+                }
+                SampleStage::TopK => {
+                    let len = self.top_k.min(candidates.len());
+                    candidates = &candidates[..len];
+                    if let Some(sums) = &mut sums {
+                        sums.truncate(len);
+                    }
+                }
+                SampleStage::TopP => {
+                    let sums = sums.get_or_insert_with(|| cumulative(candidates, temperature));
+                    let plimit = sums[sums.len() - 1] * self.top_p;
+                    let len = sums.iter().take_while(|&&p| p < plimit).count() + 1;
+                    let len = len.min(candidates.len());
+                    candidates = &candidates[..len];
+                    sums.truncate(len);
+                }
+                SampleStage::Xtc if self.is_xtc() => {
+                    let sums = sums.get_or_insert_with(|| cumulative(candidates, temperature));
+                    let skip = self.xtc_skip(&*sums);
+                    if skip > 0 {
+                        candidates = &candidates[skip..];
+                        let base = sums[skip - 1];
+                        sums.drain(..skip);
+                        sums.iter_mut().for_each(|p| *p -= base);
+                    }
+                }
+                SampleStage::Xtc => {}
+            }
+        }
+        // random
+        let cumulative = sums.unwrap_or_else(|| cumulative(candidates, temperature));
+        let plimit = rand::random::<f32>() * cumulative[cumulative.len() - 1];
         // sample
-        logits.iter().find(|p| p.val >= plimit).unwrap().tok
+        let i = cumulative.iter().take_while(|&&p| p < plimit).count();
+        candidates[i.min(candidates.len() - 1)].tok
+    }
+
+    /// 是否启用 XTC（exclude top choices）采样。
+    #[inline]
+    pub fn is_xtc(&self) -> bool {
+        self.order.contains(&SampleStage::Xtc)
+            && self.xtc_probability > 0.
+            && self.xtc_threshold > 0.
+            && self.xtc_threshold <= 0.5
+    }
+
+    /// 是否只包含默认顺序的温度、top-k、top-p 阶段，且没有屏蔽任何词或处理 logits。
+    #[inline]
+    pub fn is_basic(&self) -> bool {
+        !self.is_xtc()
+            && self.suppressed.is_empty()
+            && self.processors.is_empty()
+            && self.order == SampleStage::DEFAULT_ORDER
+    }
+
+    /// 在已排序的累积概率上执行 XTC，返回需要跳过的最可能的词的数量。
+    ///
+    /// 概率不小于阈值的词中，只保留概率最小的一个，其余全部排除。
+    fn xtc_skip(&self, cumulative: impl AsRef<[f32]>) -> usize {
+        if !self.is_xtc() || rand::random::<f32>() >= self.xtc_probability {
+            return 0;
+        }
+        let cumulative = cumulative.as_ref();
+        let threshold = cumulative[cumulative.len() - 1] * self.xtc_threshold;
+        let mut last = 0.;
+        let mut num_top = 0usize;
+        for &val in cumulative {
+            if val - last < threshold {
+                break;
+            }
+            last = val;
+            num_top += 1;
+        }
+        num_top.saturating_sub(1)
+    }
+}
+
+/// top-k 超过这个值时部分采样不划算，使用完整的 logits。
+const MAX_PARTIAL_TOP_K: usize = 1024;
+
+/// 以 `temperature` 计算已排序的候选词的未归一化累积概率。
+fn cumulative(candidates: &[Probability], temperature: f32) -> Vec<f32> {
+    let max = candidates[0].val;
+    candidates
+        .iter()
+        .scan(0., |sum, p| {
+            *sum += ((p.val - max) / temperature).exp();
+            Some(*sum)
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Probability {
+    val: f32,
+    tok: utok,
+}
+impl Eq for Probability {}
+impl PartialOrd for Probability {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Probability {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.val.total_cmp(&other.val) {
+            Ordering::Equal => self.tok.cmp(&other.tok),
+            ord => ord.reverse(),
+        }
+    }
+}
+impl<T: BetweenF32> From<(usize, &T)> for Probability {
+    #[inline]
+    fn from((i, p): (usize, &T)) -> Self {
+        Self {
+            val: p.get(),
+            tok: i as _,
+        }
+    }
+}
+
+#[test]
+fn test_xtc() {
+    let args = crate::SampleArgs {
+        temperature: 1.,
+        xtc_threshold: 0.2,
+        xtc_probability: 1.,
+        ..Default::default()
+    };
+    // 前三个词的概率都不小于阈值，只保留其中最小的一个
+    assert_eq!(args.xtc_skip([0.4, 0.7, 0.95, 0.97, 1.]), 2);
+    // 只有一个词的概率不小于阈值，不排除任何词
+    assert_eq!(args.xtc_skip([0.9, 0.95, 1.]), 0);
+
+    // 前三个词概率相同，按词序排列，保留最后一个
+    let logits = [1f32, 1., 1., 0., 0., 0., -10.];
+    for _ in 0..64 {
+        assert!(args.random(&logits) >= 2);
+    }
+}
+
+#[test]
+fn test_order() {
+    use SampleStage::*;
+
+    let logits = [2f32, 0., 0., 0.];
+    let mut args = crate::SampleArgs {
+        temperature: 100.,
+        top_p: 0.5,
+        order: vec![TopP, Temperature],
+        ..Default::default()
+    };
+    // 先截断，未缩放的分布中第一个词的概率已超过 top-p
+    for _ in 0..64 {
+        assert_eq!(args.random(&logits), 0);
+    }
+    // 先缩放，分布接近均匀，top-p 保留多个词
+    args.order = vec![Temperature, TopP];
+    assert!((0..256).any(|_| args.random(&logits) != 0));
+}
+
+#[test]
+fn test_suppressed() {
+    let logits = [3f32, 2., 1., 0.];
+    let mut args = crate::SampleArgs {
+        suppressed: vec![0],
+        ..Default::default()
+    };
+    assert_eq!(args.random(&logits), 1);
+
+    args.temperature = 1.;
+    args.suppressed = vec![0, 1, 2];
+    for _ in 0..64 {
+        assert_eq!(args.random(&logits), 3);
+    }
+}
+
+#[test]
+fn test_processor() {
+    use crate::LogitProcessor;
+    use std::sync::Arc;
+
+    /// 禁止重复上一个词。
+    struct NoRepeat;
+    impl LogitProcessor for NoRepeat {
+        fn process(&self, history: &[utok], logits: &mut [f32]) {
+            if let Some(&last) = history.last() {
+                logits[last as usize] = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    let mut args = crate::SampleArgs::default();
+    args.processors.push(Arc::new(NoRepeat));
+    args.processors.set_history(&[0]);
+    assert_eq!(args.random(&[3f32, 2., 1.]), 1);
+}
+
+#[test]
+fn test_partial() {
+    let logits = [0f32, 5., 1., 4., 3., 2.];
+    // 分成两片，各自保留前 k 个
+    let partial = |k: usize| {
+        [0..3, 3..6].into_iter().flat_map(move |range| {
+            let mut shard = range.map(|i| (i as utok, logits[i])).collect::<Vec<_>>();
+            shard.sort_by(|a, b| b.1.total_cmp(&a.1));
+            shard.truncate(k);
+            shard
+        })
+    };
+
+    let mut args = crate::SampleArgs::default();
+    assert_eq!(args.partial_top_k(), Some(1));
+    assert_eq!(args.random_partial(partial(1)), 1);
+
+    args.temperature = 1.;
+    assert_eq!(args.partial_top_k(), None);
+    args.top_k = 2;
+    assert_eq!(args.partial_top_k(), Some(2));
+    for _ in 0..64 {
+        assert!([1, 3].contains(&args.random_partial(partial(2))));
     }
+
+    args.suppressed = vec![1];
+    assert_eq!(args.partial_top_k(), None);
 }
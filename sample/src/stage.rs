@@ -0,0 +1,62 @@
+use std::{error, fmt, str::FromStr};
+
+/// 采样流程中的阶段。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SampleStage {
+    /// 以温度缩放 logits，影响之后的阶段看到的概率分布。
+    Temperature,
+    /// 保留概率最大的 `top_k` 个词。
+    TopK,
+    /// 保留累积概率达到 `top_p` 的最少的词。
+    TopP,
+    /// 以一定概率排除最可能的选择。
+    Xtc,
+}
+
+impl SampleStage {
+    /// 默认的采样流程。
+    pub const DEFAULT_ORDER: [Self; 4] = [Self::Temperature, Self::TopK, Self::TopP, Self::Xtc];
+
+    /// 阶段的名字。
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::TopK => "top_k",
+            Self::TopP => "top_p",
+            Self::Xtc => "xtc",
+        }
+    }
+}
+
+impl fmt::Display for SampleStage {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// 采样阶段名字解析错误。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ParseStageError(pub String);
+
+impl error::Error for ParseStageError {}
+impl fmt::Display for ParseStageError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown sample stage: {}", self.0)
+    }
+}
+
+impl FromStr for SampleStage {
+    type Err = ParseStageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "temperature" | "temp" => Ok(Self::Temperature),
+            "top_k" => Ok(Self::TopK),
+            "top_p" => Ok(Self::TopP),
+            "xtc" => Ok(Self::Xtc),
+            _ => Err(ParseStageError(s.into())),
+        }
+    }
+}
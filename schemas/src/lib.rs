@@ -0,0 +1,50 @@
+#![doc = include_str!("../README.md")]
+#![deny(warnings)]
+
+pub mod v1;
+
+#[cfg(test)]
+mod tests;
+
+use std::{fmt, str::FromStr};
+
+/// 服务在每个响应中用这个头标明格式的版本。
+pub const VERSION_HEADER: &str = "x-api-version";
+
+/// 请求和响应格式的版本。
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[non_exhaustive]
+pub enum Version {
+    /// 见 [`v1`]。
+    V1,
+}
+
+impl Version {
+    /// 服务当前使用的版本。
+    pub const CURRENT: Self = Self::V1;
+
+    #[inline]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::V1 => "1",
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().trim_start_matches(['v', 'V']) {
+            "1" => Ok(Self::V1),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,504 @@
+//! 第 1 版格式的序列化测试，每个字段的名字和格式都是兼容性的一部分。
+
+use crate::v1::*;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// 序列化的结果与 `expected` 相同，且能反序列化回原值。
+#[track_caller]
+fn round_trip<T>(value: T, expected: Value)
+where
+    T: Serialize + DeserializeOwned + PartialEq + fmt::Debug,
+{
+    assert_eq!(serde_json::to_value(&value).unwrap(), expected);
+    assert_eq!(serde_json::from_value::<T>(expected).unwrap(), value);
+}
+
+#[test]
+fn test_v1_infer() {
+    round_trip(
+        InferRequest::new([Message::user("Hi")]),
+        json!({
+            "inputs": [{ "role": "user", "content": "Hi" }],
+            "encoding": "text",
+        }),
+    );
+    round_trip(
+        InferRequest {
+            inputs: vec![Message::user("Hi"), Message::assistant("Hello")],
+            encoding: Some("base64".into()),
+            session_id: Some("a".into()),
+            dialog_pos: Some(2),
+            resume_from: Some(3),
+            preset: Some("precise".into()),
+            document_id: Some("manual".into()),
+            return_ids: Some(true),
+            generation: Generation {
+                stop_token_ids: Some(vec![2]),
+                min_new_tokens: Some(1),
+                timeout: Some(1.5),
+                token_timeout: Some(0.5),
+                skip_special_tokens: Some(true),
+                clean_up_tokenization_spaces: Some(false),
+                strip_leading_space: Some(true),
+                temperature: Some(0.5),
+                top_k: Some(5),
+                top_p: Some(0.75),
+                xtc_threshold: Some(0.25),
+                xtc_probability: Some(0.5),
+                sample_order: Some(vec![SampleStage::TopK, SampleStage::Xtc]),
+                choices: Some(vec!["yes".into(), "no".into()]),
+                adapter: Some("sql".into()),
+                steering: Some("calm".into()),
+                steering_strength: Some(0.5),
+                soft_prompt: Some("tutor".into()),
+                chat_template: Some("Q: {prompt}\nA: ".into()),
+            },
+            user: Some("u".into()),
+            metadata: Some("m".into()),
+            idempotency_key: None,
+        },
+        json!({
+            "inputs": [
+                { "role": "user", "content": "Hi" },
+                { "role": "assistant", "content": "Hello" },
+            ],
+            "encoding": "base64",
+            "session_id": "a",
+            "dialog_pos": 2,
+            "resume_from": 3,
+            "preset": "precise",
+            "document_id": "manual",
+            "return_ids": true,
+            "stop_token_ids": [2],
+            "min_new_tokens": 1,
+            "timeout": 1.5,
+            "token_timeout": 0.5,
+            "skip_special_tokens": true,
+            "clean_up_tokenization_spaces": false,
+            "strip_leading_space": true,
+            "temperature": 0.5,
+            "top_k": 5,
+            "top_p": 0.75,
+            "xtc_threshold": 0.25,
+            "xtc_probability": 0.5,
+            "sample_order": ["top_k", "xtc"],
+            "choices": ["yes", "no"],
+            "adapter": "sql",
+            "steering": "calm",
+            "steering_strength": 0.5,
+            "soft_prompt": "tutor",
+            "chat_template": "Q: {prompt}\nA: ",
+            "user": "u",
+            "metadata": "m",
+        }),
+    );
+    // 请求头不属于请求体
+    let mut req = InferRequest::new([]);
+    req.idempotency_key = Some("k".into());
+    assert_eq!(
+        serde_json::to_value(&req).unwrap(),
+        json!({ "inputs": [], "encoding": "text" })
+    );
+    // 忽略不认识的字段
+    let req = serde_json::from_value::<InferRequest>(json!({ "inputs": [], "unknown": 1 }));
+    assert_eq!(req.unwrap(), InferRequest::default());
+}
+
+#[test]
+fn test_v1_sessions() {
+    round_trip(
+        Fork {
+            session_id: "a".into(),
+            new_session_id: "b".into(),
+        },
+        json!({ "session_id": "a", "new_session_id": "b" }),
+    );
+    round_trip(
+        Drop {
+            session_id: "a".into(),
+        },
+        json!({ "session_id": "a" }),
+    );
+    round_trip(
+        Success {
+            message: "fork success".into(),
+        },
+        json!({ "message": "fork success" }),
+    );
+}
+
+#[test]
+fn test_v1_adapters() {
+    round_trip(
+        LoadAdapter {
+            name: "sql".into(),
+            path: "/lora/sql".into(),
+        },
+        json!({ "name": "sql", "path": "/lora/sql" }),
+    );
+    round_trip(
+        UnloadAdapter { name: "sql".into() },
+        json!({ "name": "sql" }),
+    );
+    round_trip(
+        AdapterReport {
+            used_bytes: 6,
+            adapters: vec![AdapterStatus {
+                name: "sql".into(),
+                bytes: 6,
+            }],
+        },
+        json!({ "used_bytes": 6, "adapters": [{ "name": "sql", "bytes": 6 }] }),
+    );
+}
+
+#[test]
+fn test_v1_message() {
+    round_trip(
+        Message::tokens("user", vec![1, 2]),
+        json!({ "role": "user", "content": "", "input_ids": [1, 2] }),
+    );
+    // 只有词的句子可以省略 `content`
+    let message = serde_json::from_value::<Message>(json!({ "role": "user", "input_ids": [1] }));
+    assert_eq!(message.unwrap(), Message::tokens("user", vec![1]));
+}
+
+#[test]
+fn test_v1_complete() {
+    round_trip(
+        CompleteRequest::new("def add(a, b):"),
+        json!({ "prompt": "def add(a, b):", "encoding": "text" }),
+    );
+    round_trip(
+        CompleteRequest {
+            input_ids: Some(vec![1, 2, 3]),
+            preset: Some("precise".into()),
+            prompt_logprobs: Some(true),
+            generation: Generation {
+                temperature: Some(0.),
+                ..Default::default()
+            },
+            user: Some("u".into()),
+            ..Default::default()
+        },
+        json!({
+            "prompt": "",
+            "input_ids": [1, 2, 3],
+            "preset": "precise",
+            "prompt_logprobs": true,
+            "temperature": 0.0,
+            "user": "u",
+        }),
+    );
+    // 只有词的请求可以省略 `prompt`
+    let req = serde_json::from_value::<CompleteRequest>(json!({ "input_ids": [1] })).unwrap();
+    assert!(req.prompt.is_empty());
+}
+
+#[test]
+fn test_v1_score() {
+    round_trip(
+        ScoreRequest::new("1 + 1 =", [" 2", " 3"]),
+        json!({ "prompt": "1 + 1 =", "encoding": "text", "candidates": [" 2", " 3"] }),
+    );
+    round_trip(
+        ScoreReport {
+            best: 0,
+            scores: vec![
+                CandidateScore {
+                    logprob: -0.5,
+                    tokens: 1,
+                },
+                CandidateScore {
+                    logprob: -4.,
+                    tokens: 2,
+                },
+            ],
+        },
+        json!({
+            "best": 0,
+            "scores": [
+                { "logprob": -0.5, "tokens": 1 },
+                { "logprob": -4.0, "tokens": 2 },
+            ],
+        }),
+    );
+}
+
+#[test]
+fn test_v1_documents() {
+    round_trip(
+        RegisterDocument {
+            document_id: "manual".into(),
+            content: "Press the button.".into(),
+            encoding: Some("text".into()),
+        },
+        json!({ "document_id": "manual", "content": "Press the button.", "encoding": "text" }),
+    );
+    round_trip(
+        DropDocument {
+            document_id: "manual".into(),
+        },
+        json!({ "document_id": "manual" }),
+    );
+    round_trip(
+        DocumentReport {
+            used_bytes: 8,
+            documents: vec![DocumentStatus {
+                document_id: "manual".into(),
+                tokens: 2,
+                used_bytes: 8,
+            }],
+        },
+        json!({
+            "used_bytes": 8,
+            "documents": [{ "document_id": "manual", "tokens": 2, "used_bytes": 8 }],
+        }),
+    );
+}
+
+#[test]
+fn test_v1_reports() {
+    round_trip(
+        CacheReport {
+            budget: None,
+            used_bytes: 3,
+            allocated_bytes: 4,
+            sessions: vec![SessionCache {
+                session_id: "#0".into(),
+                busy: false,
+                tokens: 1,
+                used_bytes: 3,
+                allocated_bytes: 4,
+            }],
+            memory_limit: Some(1 << 30),
+            resident_bytes: Some(1 << 29),
+            evicted_sessions: 2,
+            rejected_sessions: 1,
+        },
+        json!({
+            "budget": null,
+            "used_bytes": 3,
+            "allocated_bytes": 4,
+            "sessions": [{
+                "session_id": "#0",
+                "busy": false,
+                "tokens": 1,
+                "used_bytes": 3,
+                "allocated_bytes": 4,
+            }],
+            "memory_limit": 1 << 30,
+            "resident_bytes": 1 << 29,
+            "evicted_sessions": 2,
+            "rejected_sessions": 1,
+        }),
+    );
+    // 旧版的服务没有内存的字段
+    let report = serde_json::from_value::<CacheReport>(json!({
+        "budget": 8,
+        "used_bytes": 0,
+        "allocated_bytes": 0,
+        "sessions": [],
+    }))
+    .unwrap();
+    assert_eq!((report.memory_limit, report.evicted_sessions), (None, 0));
+    round_trip(
+        ThroughputReport {
+            decode_tokens_per_sec: 1.5,
+            prefill_tokens_per_sec: 2.5,
+            batch_occupancy: 0.5,
+            decode_batch: 0.25,
+            peak_batch: 4,
+            cache_hit_rate: 0.75,
+            deferred_tasks: 0.,
+            queue_latency_ms: 1.25,
+            peak_queue_latency_ms: 3.,
+            scheduler: "fifo".into(),
+            timeouts: 1,
+            by_metadata: Default::default(),
+        },
+        json!({
+            "decode_tokens_per_sec": 1.5,
+            "prefill_tokens_per_sec": 2.5,
+            "batch_occupancy": 0.5,
+            "decode_batch": 0.25,
+            "peak_batch": 4,
+            "cache_hit_rate": 0.75,
+            "deferred_tasks": 0.,
+            "queue_latency_ms": 1.25,
+            "peak_queue_latency_ms": 3.,
+            "scheduler": "fifo",
+            "timeouts": 1,
+        }),
+    );
+    let report = serde_json::from_value::<ThroughputReport>(json!({
+        "decode_tokens_per_sec": 0.,
+        "prefill_tokens_per_sec": 0.,
+        "batch_occupancy": 0.,
+        "decode_batch": 0.,
+        "peak_batch": 0,
+        "cache_hit_rate": 0.,
+        "deferred_tasks": 0.,
+        "queue_latency_ms": 0.,
+        "peak_queue_latency_ms": 0.,
+        "scheduler": "fifo",
+        "timeouts": 0,
+        "by_metadata": {"other": {"requests": 2, "prompt_tokens": 5, "completion_tokens": 7}},
+    }))
+    .unwrap();
+    assert_eq!(
+        report.by_metadata["other"],
+        MetadataUsage {
+            requests: 2,
+            prompt_tokens: 5,
+            completion_tokens: 7,
+        }
+    );
+}
+
+#[test]
+fn test_v1_errors() {
+    let error = |status, detail| ErrorBody {
+        status,
+        code: 0,
+        message: "m".into(),
+        kind: ErrorKind::Unknown,
+        param: None,
+        detail,
+    };
+    let mut not_found = error(404, None);
+    not_found.kind = ErrorKind::SessionNotFound;
+    round_trip(
+        not_found,
+        json!({ "status": 404, "code": 0, "message": "m", "type": "session_not_found" }),
+    );
+    let mut recoverable = error(
+        410,
+        Some(ErrorDetail::DialogPos {
+            current_dialog_pos: 4,
+        }),
+    );
+    recoverable.kind = ErrorKind::SessionRecoverable;
+    round_trip(
+        recoverable,
+        json!({ "status": 410, "code": 0, "message": "m", "type": "session_recoverable", "current_dialog_pos": 4 }),
+    );
+    let mut invalid = error(400, None);
+    invalid.code = 1;
+    invalid.kind = ErrorKind::InvalidParam;
+    invalid.param = Some("adapter".into());
+    round_trip(
+        invalid,
+        json!({ "status": 400, "code": 1, "message": "m", "type": "invalid_param", "param": "adapter" }),
+    );
+    // 旧版的服务不返回种类，新版的服务可能返回这个版本不认识的种类
+    for body in [
+        json!({ "status": 404, "code": 0, "message": "m" }),
+        json!({ "status": 404, "code": 0, "message": "m", "type": "rate_limited" }),
+    ] {
+        let body = serde_json::from_value::<ErrorBody>(body).unwrap();
+        assert_eq!(body.kind, ErrorKind::Unknown);
+    }
+    round_trip(
+        error(
+            416,
+            Some(ErrorDetail::DialogPos {
+                current_dialog_pos: 2,
+            }),
+        ),
+        json!({ "status": 416, "code": 0, "message": "m", "type": "unknown", "current_dialog_pos": 2 }),
+    );
+    round_trip(
+        error(
+            413,
+            Some(ErrorDetail::Limit {
+                limit: "body".into(),
+                max: 4,
+                actual: None,
+            }),
+        ),
+        json!({ "status": 413, "code": 0, "message": "m", "type": "unknown", "limit": "body", "max": 4 }),
+    );
+    round_trip(
+        error(
+            422,
+            Some(ErrorDetail::Limit {
+                limit: "prompt_tokens".into(),
+                max: 4,
+                actual: Some(5),
+            }),
+        ),
+        json!({
+            "status": 422,
+            "code": 0,
+            "message": "m",
+            "type": "unknown",
+            "limit": "prompt_tokens",
+            "max": 4,
+            "actual": 5,
+        }),
+    );
+}
+
+#[test]
+fn test_v1_stream_events() {
+    round_trip(
+        StreamEvent {
+            text: "Hi".into(),
+            ..Default::default()
+        },
+        json!({ "text": "Hi" }),
+    );
+    round_trip(
+        StreamEvent {
+            text: String::new(),
+            finish_reason: Some(FinishReason::Stop),
+            output_ids: Some(vec![1, 2]),
+            usage: Some(Usage {
+                prompt_tokens: 3,
+                completion_tokens: 2,
+                first_token_ms: None,
+                total_ms: 40,
+            }),
+        },
+        json!({
+            "text": "",
+            "finish_reason": "stop",
+            "output_ids": [1, 2],
+            "usage": { "prompt_tokens": 3, "completion_tokens": 2, "total_ms": 40 },
+        }),
+    );
+}
+
+#[test]
+fn test_v1_enums() {
+    for (stage, name) in [
+        (SampleStage::Temperature, "temperature"),
+        (SampleStage::TopK, "top_k"),
+        (SampleStage::TopP, "top_p"),
+        (SampleStage::Xtc, "xtc"),
+    ] {
+        round_trip(stage, json!(name));
+    }
+    for reason in [
+        FinishReason::Stop,
+        FinishReason::ContentFilter,
+        FinishReason::Timeout,
+        FinishReason::Error,
+    ] {
+        round_trip(reason, json!(reason.as_str()));
+        assert_eq!(reason.as_str().parse(), Ok(reason));
+    }
+    assert!(serde_json::from_value::<SampleStage>(json!("top-k")).is_err());
+}
+
+#[test]
+fn test_version() {
+    use crate::Version;
+
+    assert_eq!(Version::CURRENT.as_str().parse(), Ok(Version::CURRENT));
+    assert_eq!("v1".parse(), Ok(Version::V1));
+    assert!("2".parse::<Version>().is_err());
+}
@@ -0,0 +1,501 @@
+//! 第 1 版的请求和响应格式。
+//!
+//! 枚举都标记为 `#[non_exhaustive]`，服务可以在本版本内增加取值；结构体的字段都是公开的，
+//! 新增的字段总是可选的，旧的客户端反序列化时忽略不认识的字段。
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+/// 对话中的一个句子。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Message {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+    /// 代替 `content` 直接指定句子的词，不经过服务端的分词和对话模板。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_ids: Option<Vec<u32>>,
+}
+
+impl Message {
+    #[inline]
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: "user".into(),
+            content: content.into(),
+            input_ids: None,
+        }
+    }
+
+    #[inline]
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self {
+            role: "assistant".into(),
+            content: content.into(),
+            input_ids: None,
+        }
+    }
+
+    /// 以词代替文本的句子。
+    #[inline]
+    pub fn tokens(role: impl Into<String>, input_ids: Vec<u32>) -> Self {
+        Self {
+            role: role.into(),
+            content: String::new(),
+            input_ids: Some(input_ids),
+        }
+    }
+}
+
+/// `POST /infer` 的请求体。
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct InferRequest {
+    pub inputs: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dialog_pos: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_from: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document_id: Option<String>,
+    /// 在 `X-Output-Ids` trailer 中返回生成的词。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_ids: Option<bool>,
+    #[serde(flatten)]
+    pub generation: Generation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+    /// 放在 `Idempotency-Key` 请求头中，不属于请求体。
+    #[serde(skip)]
+    pub idempotency_key: Option<String>,
+}
+
+impl InferRequest {
+    /// 以明文发送 `inputs` 的请求。
+    pub fn new(inputs: impl IntoIterator<Item = Message>) -> Self {
+        Self {
+            inputs: inputs.into_iter().collect(),
+            encoding: Some("text".into()),
+            ..Default::default()
+        }
+    }
+}
+
+/// `POST /completions` 的请求体。
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct CompleteRequest {
+    /// 续写的原始文本，不应用对话模板。
+    #[serde(default)]
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// 代替 `prompt` 直接指定提示词的词。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_ids: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// 在 `X-Output-Ids` trailer 中返回生成的词。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_ids: Option<bool>,
+    /// 在 `X-Prompt-Logprobs` 响应头中返回提示词的对数概率。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_logprobs: Option<bool>,
+    #[serde(flatten)]
+    pub generation: Generation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+}
+
+impl CompleteRequest {
+    /// 以明文发送 `prompt` 的请求。
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            encoding: Some("text".into()),
+            ..Default::default()
+        }
+    }
+}
+
+/// `POST /score` 的请求体。
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct ScoreRequest {
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// 接在 `prompt` 之后的候选续写。
+    pub candidates: Vec<String>,
+}
+
+impl ScoreRequest {
+    /// 以明文发送 `prompt` 和 `candidates` 的请求。
+    pub fn new(
+        prompt: impl Into<String>,
+        candidates: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        Self {
+            prompt: prompt.into(),
+            encoding: Some("text".into()),
+            candidates: candidates.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// `POST /score` 的响应体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ScoreReport {
+    /// 对数似然最大的候选的下标。
+    pub best: usize,
+    /// 与请求中的候选一一对应。
+    pub scores: Vec<CandidateScore>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CandidateScore {
+    /// 候选中所有词的对数概率之和。
+    pub logprob: f32,
+    pub tokens: usize,
+}
+
+/// 请求中的生成参数，未指定的参数沿用会话中的值。
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+#[serde(default)]
+pub struct Generation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_token_ids: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_new_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_timeout: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_special_tokens: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clean_up_tokenization_spaces: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_leading_space: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xtc_threshold: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xtc_probability: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_order: Option<Vec<SampleStage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub choices: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steering: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steering_strength: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soft_prompt: Option<String>,
+    /// 替代模型默认模板的对话模板，`{prompt}` 处替换为提示词。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_template: Option<String>,
+}
+
+/// 采样流程中的阶段。
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum SampleStage {
+    Temperature,
+    TopK,
+    TopP,
+    Xtc,
+}
+
+/// `POST /fork` 的请求体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Fork {
+    pub session_id: String,
+    pub new_session_id: String,
+}
+
+/// `POST /drop` 的请求体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Drop {
+    pub session_id: String,
+}
+
+/// `POST /fork`、`POST /drop` 等操作成功时的响应体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Success {
+    pub message: String,
+}
+
+/// `POST /admin/adapters/load` 的请求体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LoadAdapter {
+    pub name: String,
+    /// 服务所在机器上的适配器目录。
+    pub path: String,
+}
+
+/// `POST /admin/adapters/unload` 的请求体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct UnloadAdapter {
+    pub name: String,
+}
+
+/// `GET /admin/adapters` 的响应体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AdapterReport {
+    pub used_bytes: usize,
+    pub adapters: Vec<AdapterStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AdapterStatus {
+    pub name: String,
+    pub bytes: usize,
+}
+
+/// `POST /documents/register` 的请求体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct RegisterDocument {
+    pub document_id: String,
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+/// `POST /documents/drop` 的请求体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct DropDocument {
+    pub document_id: String,
+}
+
+/// `GET /documents` 的响应体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct DocumentReport {
+    pub used_bytes: usize,
+    pub documents: Vec<DocumentStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct DocumentStatus {
+    pub document_id: String,
+    pub tokens: usize,
+    pub used_bytes: usize,
+}
+
+/// `GET /cache` 的响应体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CacheReport {
+    pub budget: Option<usize>,
+    pub used_bytes: usize,
+    pub allocated_bytes: usize,
+    pub sessions: Vec<SessionCache>,
+    /// 进程常驻内存的软上限。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_limit: Option<usize>,
+    /// 进程当前的常驻内存字节数，服务无法读取时没有。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resident_bytes: Option<usize>,
+    /// 因内存超限清除的空闲会话数。
+    #[serde(default)]
+    pub evicted_sessions: usize,
+    /// 因内存超限拒绝的新会话数。
+    #[serde(default)]
+    pub rejected_sessions: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SessionCache {
+    pub session_id: String,
+    pub busy: bool,
+    pub tokens: usize,
+    pub used_bytes: usize,
+    pub allocated_bytes: usize,
+}
+
+/// `GET /throughput` 的响应体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ThroughputReport {
+    pub decode_tokens_per_sec: f64,
+    pub prefill_tokens_per_sec: f64,
+    pub batch_occupancy: f64,
+    pub decode_batch: f64,
+    pub peak_batch: usize,
+    pub cache_hit_rate: f64,
+    pub deferred_tasks: f64,
+    pub queue_latency_ms: f64,
+    pub peak_queue_latency_ms: f64,
+    pub scheduler: String,
+    pub timeouts: usize,
+    /// 启动以来按请求的 `metadata` 分组的用量，只包含启动服务时允许的取值和 `"other"`。
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub by_metadata: BTreeMap<String, MetadataUsage>,
+}
+
+/// 一组请求累计的用量。
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct MetadataUsage {
+    pub requests: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+/// 错误的响应体。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ErrorBody {
+    pub status: u16,
+    pub code: u16,
+    /// 给人看的说明，可能随版本变化，客户端应按 `kind` 区分错误。
+    pub message: String,
+    /// 错误的种类，序列化为 `type`。
+    #[serde(rename = "type", default)]
+    pub kind: ErrorKind,
+    /// 导致错误的请求参数。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub param: Option<String>,
+    /// 部分错误附加的字段。
+    #[serde(flatten)]
+    pub detail: Option<ErrorDetail>,
+}
+
+/// 错误的种类，每种错误一个稳定的名字。
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorKind {
+    InvalidJson,
+    InvalidMsgPack,
+    InvalidContent,
+    InvalidParam,
+    SessionNotFound,
+    /// 服务重启前存在的会话，需要从头预填充恢复。
+    SessionRecoverable,
+    SessionBusy,
+    DuplicateSession,
+    InvalidDialogPos,
+    StreamNotFound,
+    /// 同一个幂等键的请求内容不同。
+    IdempotencyMismatch,
+    CacheExhausted,
+    MemoryLimitExceeded,
+    InferenceFailed,
+    InvalidCache,
+    TooLarge,
+    AdaptersUnsupported,
+    DuplicateAdapter,
+    AdapterNotFound,
+    AdapterLoadFailed,
+    DocumentNotFound,
+    DuplicateDocument,
+    TooManyTokens,
+    RouteNotFound,
+    /// 旧版的服务没有返回种类，或者是这个版本不认识的种类。
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+/// 错误附加的字段，与 [`ErrorBody`] 的字段平级。
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+#[non_exhaustive]
+pub enum ErrorDetail {
+    /// 非法对话位置：会话当前的句子数。
+    DialogPos { current_dialog_pos: usize },
+    /// 超出限制：限制的名字、上限和实际的大小。
+    Limit {
+        limit: String,
+        max: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        actual: Option<usize>,
+    },
+}
+
+/// 请求头 `Accept: text/event-stream` 时推理流中每个事件的 `data`。
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct StreamEvent {
+    /// 生成的文本，最后一个事件为空。
+    #[serde(default)]
+    pub text: String,
+    /// 生成结束的原因，只有最后一个事件带有。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<FinishReason>,
+    /// 请求 `return_ids` 时，最后一个事件带有生成的所有词。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_ids: Option<Vec<u32>>,
+    /// 推理的用量，只有最后一个事件带有。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+/// 一次推理的用量。
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Usage {
+    /// 需要计算的提示词数量，不包括已缓存的部分。
+    pub prompt_tokens: usize,
+    /// 生成的词数。
+    pub completion_tokens: usize,
+    /// 从请求到达到生成第一段文本的毫秒数。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_token_ms: Option<u64>,
+    /// 从请求到达到生成结束的毫秒数。
+    pub total_ms: u64,
+}
+
+/// 生成结束的原因，来自 `X-Finish-Reason` trailer 或最后一个 [`StreamEvent`]。
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum FinishReason {
+    Stop,
+    ContentFilter,
+    Timeout,
+    Error,
+}
+
+impl FinishReason {
+    #[inline]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Stop => "stop",
+            Self::ContentFilter => "content_filter",
+            Self::Timeout => "timeout",
+            Self::Error => "error",
+        }
+    }
+}
+
+impl FromStr for FinishReason {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(Self::Stop),
+            "content_filter" => Ok(Self::ContentFilter),
+            "timeout" => Ok(Self::Timeout),
+            "error" => Ok(Self::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for FinishReason {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,86 @@
+use causal_lm::LogitProcessor;
+use common::utok;
+use std::collections::BTreeMap;
+
+/// 把回答限制为给定选项之一的 logits 处理器。
+///
+/// 选项的词序列组成前缀树，按已生成的词在树上行进，只允许树上的下一个词；
+/// 生成完一个选项时允许结束符，经过的节点是选项的结尾且没有子节点时只允许结束符。
+pub(crate) struct Choices {
+    /// 前缀树的节点，第 0 个是根节点。
+    nodes: Vec<Node>,
+    eos: utok,
+    /// 回答在词序列中的起始位置。
+    start: usize,
+}
+
+#[derive(Default)]
+struct Node {
+    children: BTreeMap<utok, usize>,
+    is_end: bool,
+}
+
+impl Choices {
+    /// 由选项的词序列建立前缀树，`start` 之后的词是生成的回答。
+    pub fn new(choices: impl IntoIterator<Item = Vec<utok>>, eos: utok, start: usize) -> Self {
+        let mut nodes = vec![Node::default()];
+        for choice in choices {
+            let mut i = 0;
+            for t in choice {
+                let len = nodes.len();
+                i = *nodes[i].children.entry(t).or_insert(len);
+                if i == len {
+                    nodes.push(Node::default());
+                }
+            }
+            nodes[i].is_end = true;
+        }
+        Self { nodes, eos, start }
+    }
+
+    /// 已生成 `generated` 之后允许的下一个词。
+    fn allowed(&self, generated: &[utok]) -> Vec<utok> {
+        let mut i = 0;
+        for t in generated {
+            match self.nodes[i].children.get(t) {
+                Some(&next) => i = next,
+                // 回答已经偏离了所有选项，只能结束
+                None => return vec![self.eos],
+            }
+        }
+        let node = &self.nodes[i];
+        let mut ans = node.children.keys().copied().collect::<Vec<_>>();
+        if node.is_end || ans.is_empty() {
+            ans.push(self.eos);
+        }
+        ans
+    }
+}
+
+impl LogitProcessor for Choices {
+    fn process(&self, history: &[utok], logits: &mut [f32]) {
+        let generated = history.get(self.start..).unwrap_or(&[]);
+        let allowed = self.allowed(generated);
+        for (t, x) in logits.iter_mut().enumerate() {
+            if !allowed.contains(&(t as utok)) {
+                *x = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_choices() {
+    // yes: [1, 2]，no: [3]，yesno: [1, 2, 3]
+    let choices = Choices::new([vec![1, 2], vec![3], vec![1, 2, 3]], 0, 2);
+    assert_eq!(choices.allowed(&[]), [1, 3]);
+    assert_eq!(choices.allowed(&[1]), [2]);
+    assert_eq!(choices.allowed(&[1, 2]), [3, 0]);
+    assert_eq!(choices.allowed(&[3]), [0]);
+    assert_eq!(choices.allowed(&[4]), [0]);
+
+    let mut logits = [1., 0., 5., 4., 3.];
+    choices.process(&[9, 9, 1], &mut logits);
+    assert_eq!(logits[2], 5.);
+    assert_eq!(logits.iter().filter(|x| x.is_finite()).count(), 1);
+}
@@ -0,0 +1,138 @@
+use common::utok;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, RwLock},
+};
+
+/// 生成结束的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum FinishReason {
+    /// 生成了结束符。
+    Stop,
+    /// 接收方在生成结束前放弃了推理任务。
+    Cancelled,
+    /// 生成的内容未通过审核。
+    ContentFilter,
+    /// 生成超过了时限。
+    Timeout,
+    /// 推理过程中出错。
+    Error,
+}
+
+impl FinishReason {
+    /// 结束原因的名字。
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stop => "stop",
+            Self::Cancelled => "cancelled",
+            Self::ContentFilter => "content_filter",
+            Self::Timeout => "timeout",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// 生成过程中的事件钩子。
+///
+/// 所有方法都有空的默认实现，只需实现关心的事件。
+/// `id` 是服务为每个推理任务分配的唯一序号，用于关联同一个任务的事件。
+pub trait GenerationHook: Send + Sync {
+    /// 推理任务提交，`num_prompt` 是需要计算的词数。
+    fn on_request(&self, _id: usize, _num_prompt: usize) {}
+    /// 推理任务完成首次前向传播，在推理线程上调用。
+    fn on_prefill_done(&self, _id: usize, _num_prompt: usize) {}
+    /// 接收到一个生成的词，`text` 是这个词带来的完整 utf-8 文本，可能为空。
+    fn on_token(&self, _id: usize, _token: utok, _text: &str) {}
+    /// 推理任务结束，`num_generated` 是接收到的词数。
+    fn on_finish(&self, _id: usize, _reason: FinishReason, _num_generated: usize) {}
+}
+
+/// 审核的结论。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Verdict {
+    /// 通过。
+    Pass,
+    /// 用给定的文本替换本次生成的文本，会话中保存客户端实际收到的回答。
+    Redact(String),
+    /// 终止生成，已生成的部分不进入会话的对话。
+    Abort,
+}
+
+/// 审核的文本窗口的最大字节数。
+pub const MODERATION_WINDOW: usize = 1024;
+
+/// 流式生成内容的审核器。
+pub trait Moderator: Send + Sync {
+    /// 审核最近生成的文本 `window`，`window` 以本次生成的文本结尾，不超过 [`MODERATION_WINDOW`] 字节。
+    fn moderate<'a>(
+        &'a self,
+        window: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Verdict> + Send + 'a>>;
+}
+
+/// 注册到服务的所有钩子。
+#[derive(Default)]
+pub(crate) struct Hooks {
+    hooks: RwLock<Vec<Arc<dyn GenerationHook>>>,
+    moderators: RwLock<Vec<Arc<dyn Moderator>>>,
+}
+
+impl Hooks {
+    #[inline]
+    pub fn register(&self, hook: Arc<dyn GenerationHook>) {
+        self.hooks.write().unwrap().push(hook);
+    }
+
+    #[inline]
+    pub fn register_moderator(&self, moderator: Arc<dyn Moderator>) {
+        self.moderators.write().unwrap().push(moderator);
+    }
+
+    #[inline]
+    pub fn emit(&self, f: impl Fn(&dyn GenerationHook)) {
+        for hook in self.hooks.read().unwrap().iter() {
+            f(&**hook);
+        }
+    }
+
+    /// 依次询问所有审核器，返回第一个不通过的结论。
+    pub async fn moderate(&self, window: &str) -> Verdict {
+        let moderators = self.moderators.read().unwrap().clone();
+        for m in moderators {
+            match m.moderate(window).await {
+                Verdict::Pass => {}
+                verdict => return verdict,
+            }
+        }
+        Verdict::Pass
+    }
+}
+
+/// 保留最近生成的文本用于审核。
+#[derive(Clone, Default, Debug)]
+pub(crate) struct TextWindow(String);
+
+impl TextWindow {
+    pub fn push(&mut self, s: &str) -> &str {
+        self.0.push_str(s);
+        if self.0.len() > MODERATION_WINDOW {
+            let mut start = self.0.len() - MODERATION_WINDOW;
+            while !self.0.is_char_boundary(start) {
+                start += 1;
+            }
+            self.0.drain(..start);
+        }
+        &self.0
+    }
+}
+
+#[test]
+fn test_text_window() {
+    let mut window = TextWindow::default();
+    assert_eq!(window.push("abc"), "abc");
+    let long = "中".repeat(MODERATION_WINDOW);
+    let s = window.push(&long);
+    assert!(s.len() <= MODERATION_WINDOW);
+    assert!(s.chars().all(|c| c == '中'));
+}
@@ -1,23 +1,52 @@
 #![deny(warnings)]
 
+mod choice;
+mod hooks;
+mod metrics;
+mod scheduler;
+mod self_test;
 mod session;
 mod session_manager;
 mod template;
 
 use causal_lm::{CausalLM, SampleArgs};
+use common::{utok, GenerationConfig};
 use session::{Dispatcher, Generator};
-use std::{fmt::Debug, path::Path, sync::Arc};
+use std::{
+    borrow::Cow,
+    fmt::Debug,
+    future::Future,
+    path::Path,
+    str,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 use template::Template;
-use tokenizer::{BPECommonNormalizer, Normalizer, Tokenizer, VocabTxt, BPE};
-use tokio::task::JoinHandle;
+use tokenizer::{Normalizer, Tokenizer};
+use tokio::runtime::Handle;
 
-pub use session::{BusySession, ChatError, Session};
-pub use session_manager::{SessionError, SessionManager};
+pub use causal_lm::{AdapterError, AdapterInfo, LogitProcessor, ShapeError};
+pub use hooks::{FinishReason, GenerationHook, Moderator, Verdict, MODERATION_WINDOW};
+pub use metrics::Throughput;
+pub use scheduler::{FairShare, Fcfs, Scheduler, SharedPrefix, ShortestFirst, TaskInfo};
+pub use self_test::SelfTestError;
+pub use session::{
+    BusySession, CacheUsage, ChatError, Chunk, DetokenizeArgs, Prompts, Session, StopArgs,
+    Truncation,
+};
+pub use session_manager::{MemoryUsage, SessionError, SessionManager};
+pub use template::ChatTemplate;
+pub use tokenizer::{EncodeError, TokenizerFormat, MAX_TEXT_LEN};
 
 /// 对话服务。
 pub struct Service<M: CausalLM> {
     component: Arc<ServiceComponent<M>>,
     pub default_sample: SampleArgs,
+    pub default_truncation: Truncation,
+    pub default_detokenize: DetokenizeArgs,
+    pub default_cache_budget: Option<usize>,
+    pub default_prompts: Arc<Prompts>,
 }
 
 /// 服务中不变的组件，将在所有会话之间共享。
@@ -38,44 +67,264 @@ impl<M: CausalLM> Drop for ServiceComponent<M> {
     }
 }
 
+impl<M: CausalLM> ServiceComponent<M> {
+    /// 规范化后分词，文本过长时返回错误。
+    fn encode(&self, text: &str) -> Result<Vec<utok>, EncodeError> {
+        self.tokenizer.try_encode(&self.normalizer.encode(text))
+    }
+}
+
 impl<M> Service<M>
 where
     M: CausalLM + Send + Sync + 'static,
     M::Storage: Send,
     M::Error: Debug,
 {
+    /// 加载模型目录中的模型，自动识别分词器文件的类型。
+    #[inline]
     pub fn load(model_dir: impl AsRef<Path>, meta: M::Meta) -> (Self, JoinHandle<()>) {
+        Self::load_with_tokenizer(model_dir, meta, None)
+    }
+
+    /// 加载模型目录中的模型，指定分词器文件的类型，`None` 表示自动识别。
+    ///
+    /// 前向传播在名为 `infer` 的专用线程中执行，不占用异步运行时的线程，必须在 tokio 运行时中调用。
+    pub fn load_with_tokenizer(
+        model_dir: impl AsRef<Path>,
+        meta: M::Meta,
+        tokenizer: Option<TokenizerFormat>,
+    ) -> (Self, JoinHandle<()>) {
+        let (tokenizer, normalizer) = TokenizerFormat::load_from(&model_dir, tokenizer).unwrap();
         let handle = Arc::new(Dispatcher::from(M::load(&model_dir, meta).unwrap()));
         (
             Self {
                 component: Arc::new(ServiceComponent {
                     handle: handle.clone(),
-                    tokenizer: tokenizer(&model_dir),
-                    normalizer: normalizer(&model_dir),
-                    template: template(model_dir),
+                    tokenizer,
+                    normalizer,
+                    template: template(&model_dir),
                 }),
-                default_sample: Default::default(),
+                default_sample: default_sample(&model_dir),
+                default_truncation: Default::default(),
+                default_detokenize: Default::default(),
+                default_cache_budget: None,
+                default_prompts: Default::default(),
+            },
+            {
+                // 推理线程发射结果时仍需在运行时中启动异步任务
+                let runtime = Handle::current();
+                thread::Builder::new()
+                    .name("infer".into())
+                    .spawn(move || {
+                        let _rt = runtime.enter();
+                        handle.run()
+                    })
+                    .unwrap()
             },
-            tokio::task::spawn_blocking(move || handle.run()),
         )
     }
 }
 
 impl<M: CausalLM> Service<M> {
+    /// 注册一个 logits 处理器，之后启动的会话和生成器都会使用。
+    #[inline]
+    pub fn register_logit_processor(&mut self, processor: Arc<dyn LogitProcessor>) {
+        self.default_sample.processors.push(processor);
+    }
+
+    /// 注册一个生成事件钩子，对所有会话和生成器生效。
+    #[inline]
+    pub fn register_hook(&self, hook: Arc<dyn GenerationHook>) {
+        self.component.handle.hooks.register(hook);
+    }
+
+    /// 注册一个内容审核器，对所有会话和生成器生效。
+    #[inline]
+    pub fn register_moderator(&self, moderator: Arc<dyn Moderator>) {
+        self.component.handle.hooks.register_moderator(moderator);
+    }
+
+    /// 获取推理吞吐量的滑动平均。
+    #[inline]
+    pub fn throughput(&self) -> Throughput {
+        self.component.handle.metrics.throughput()
+    }
+
+    /// 设置预填充的批处理窗口。
+    ///
+    /// 推理线程空闲时收到新的推理任务，将等待这段时间，使同时到达的任务（例如无状态请求的匿名会话）在一次前向传播中预填充。
+    /// 默认为 0，不等待。
+    #[inline]
+    pub fn set_prefill_window(&self, window: Duration) {
+        self.component.handle.set_prefill_window(window);
+    }
+
+    /// 设置推理线程的调度策略，默认为 [`Fcfs`]。切换策略时重新开始统计吞吐量。
+    #[inline]
+    pub fn set_scheduler(&self, scheduler: Arc<dyn Scheduler>) {
+        self.component.handle.set_scheduler(scheduler);
+    }
+
+    /// 设置每轮前向传播最多执行的任务数，超出的任务由调度策略选择推迟，0 表示不限制。
+    #[inline]
+    pub fn set_max_batch(&self, max_batch: usize) {
+        self.component.handle.set_max_batch(max_batch);
+    }
+
+    /// 设置每轮前向传播最多解码的任务数，超出的解码任务由调度策略选择推迟，预填充的任务不受限制，0 表示不限制。
+    #[inline]
+    pub fn set_max_decode_batch(&self, max_decode: usize) {
+        self.component.handle.set_max_decode(max_decode);
+    }
+
+    /// 设置相邻两轮前向传播开始的最小间隔。
+    ///
+    /// 推理线程在间隔内等待，使期间到达的任务加入同一批，以单个请求的延迟换取总吞吐量。
+    /// 默认为 0，不等待。
+    #[inline]
+    pub fn set_step_pacing(&self, pacing: Duration) {
+        self.component.handle.set_step_pacing(pacing);
+    }
+
+    /// 模型的最大序列长度。
+    #[inline]
+    pub fn max_seq_len(&self) -> usize {
+        self.component.handle.model.max_seq_len() as _
+    }
+
+    /// 词表的大小，合法的词都小于这个值。
+    #[inline]
+    pub fn vocab_size(&self) -> usize {
+        self.component.tokenizer.vocab_size()
+    }
+
+    /// 文本编码后的词数，不包括对话模板添加的词。
+    #[inline]
+    pub fn num_tokens(&self, text: &str) -> Result<usize, EncodeError> {
+        self.encode(text).map(|tokens| tokens.len())
+    }
+
+    /// 名为 `name` 的常驻适配器的序号，模型没有这个适配器时返回 `None`。
+    #[inline]
+    pub fn adapter(&self, name: &str) -> Option<usize> {
+        self.component.handle.model.adapter(name)
+    }
+
+    /// 名为 `name` 的控制向量的序号，模型没有这个控制向量时返回 `None`。
+    #[inline]
+    pub fn steering(&self, name: &str) -> Option<usize> {
+        self.component.handle.model.steering(name)
+    }
+
+    /// 名为 `name` 的软提示词的虚拟词数，模型没有这个软提示词时返回 `None`。
+    #[inline]
+    pub fn soft_prompt(&self, name: &str) -> Option<usize> {
+        self.component
+            .handle
+            .model
+            .soft_prompt(name)
+            .map(|tokens| tokens.len())
+    }
+
+    /// 所有常驻的适配器及其占用的字节数。
+    #[inline]
+    pub fn adapters(&self) -> Vec<AdapterInfo> {
+        self.component.handle.model.adapters()
+    }
+
+    /// 运行时从 `dir` 加载名为 `name` 的适配器，不影响正在进行的推理。
+    #[inline]
+    pub fn load_adapter(&self, name: &str, dir: impl AsRef<Path>) -> Result<(), AdapterError> {
+        self.component.handle.model.load_adapter(name, dir.as_ref())
+    }
+
+    /// 卸载名为 `name` 的适配器，选择它的会话之后按基础模型推理。
+    #[inline]
+    pub fn unload_adapter(&self, name: &str) -> Result<(), AdapterError> {
+        self.component.handle.model.unload_adapter(name)
+    }
+
+    /// 将文本编码为词，不添加对话模板。文本超过 [`MAX_TEXT_LEN`] 字节时返回错误。
+    #[inline]
+    pub fn encode(&self, text: &str) -> Result<Vec<utok>, EncodeError> {
+        self.component.encode(text)
+    }
+
+    /// 词对应的字节，单字节词可能不是完整的 utf-8 字符。
+    pub fn decode(&self, token: utok) -> Cow<'_, [u8]> {
+        let ServiceComponent {
+            tokenizer,
+            normalizer,
+            ..
+        } = &*self.component;
+        let bytes = tokenizer.decode_bytes(token);
+        match str::from_utf8(bytes) {
+            Ok(s) => match normalizer.decode(s) {
+                Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+                Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+            },
+            Err(_) => Cow::Borrowed(bytes),
+        }
+    }
+
     /// 从对话服务启动一个会话。
     #[inline]
     pub fn launch(&self) -> Session<M> {
         let mut session: Session<M> = self.component.clone().into();
         session.sample = self.default_sample.clone();
+        session.truncation = self.default_truncation;
+        session.detokenize = self.default_detokenize;
+        session.cache_budget = self.default_cache_budget;
+        session.prompts = self.default_prompts.clone();
         session
     }
 
-    /// 从对话服务启动一个文本生成器。
+    /// 从对话服务启动一个文本生成器，提示词过长时返回错误。
     #[inline]
-    pub fn generate(&self, prompt: impl AsRef<str>, sample: Option<SampleArgs>) -> Generator<M> {
+    pub fn generate(
+        &self,
+        prompt: impl AsRef<str>,
+        sample: Option<SampleArgs>,
+    ) -> Result<Generator<M>, EncodeError> {
         let sample = sample.unwrap_or_else(|| self.default_sample.clone());
         Generator::new(self.component.clone(), prompt, sample)
     }
+
+    /// 续写原始的词，不应用对话模板，也不记录对话位置，适用于基础模型和中间填充（FIM）等提示词。
+    ///
+    /// `prompt` 不能为空，其中的词都必须小于 [`vocab_size`](Self::vocab_size)。
+    /// `echo` 时在预填充中计算提示词的对数概率，从生成器的 `prompt_logprobs` 取得。
+    pub fn complete(
+        &self,
+        prompt: Vec<utok>,
+        sample: SampleArgs,
+        stop: StopArgs,
+        detokenize: DetokenizeArgs,
+        echo: bool,
+    ) -> Generator<M> {
+        assert!(!prompt.is_empty());
+        Generator::with_tokens(
+            self.component.clone(),
+            prompt,
+            sample,
+            stop,
+            detokenize,
+            echo,
+        )
+    }
+
+    /// 计算 `prompt` 中除第一个词以外每个词的对数概率，不生成新的词。
+    ///
+    /// 任务在调用时立即进入批，同时发起的多个打分一起预填充。
+    /// `prompt` 不能为空；模型不支持或推理出错时返回 `None`。
+    pub fn score(&self, prompt: Vec<utok>) -> impl Future<Output = Option<Vec<f32>>> {
+        assert!(!prompt.is_empty());
+        let mut generator = Generator::scoring(self.component.clone(), prompt);
+        async move {
+            while generator.decode().await.is_some() {}
+            generator.prompt_logprobs()
+        }
+    }
 }
 
 #[test]
@@ -92,7 +341,7 @@ fn test() {
     let runtime = Builder::new_current_thread().build().unwrap();
     let _rt = runtime.enter();
 
-    let (service, _handle) = Service::<llama_cpu::Transformer>::load(model_dir, ());
+    let (service, _handle) = Service::<llama_cpu::Transformer>::load(model_dir, Default::default());
 
     let mut set = JoinSet::new();
     let tasks = vec![
@@ -105,10 +354,10 @@ fn test() {
 
     for ((prompt, color), mut session) in zip(tasks, sessions) {
         set.spawn(async move {
-            session.extend([prompt]);
+            session.extend([prompt]).unwrap();
             let mut busy = session.chat();
-            while let Some(s) = busy.decode().await {
-                print!("{}", s.color(color));
+            while let Some(chunk) = busy.decode().await {
+                print!("{}", chunk.text.color(color));
                 std::io::stdout().flush().unwrap();
             }
         });
@@ -118,42 +367,274 @@ fn test() {
     runtime.shutdown_background();
 }
 
-fn template(model_dir: impl AsRef<Path>) -> Box<dyn Template + Send + Sync> {
-    let path: String = model_dir.as_ref().display().to_string();
-    let path = path.to_ascii_lowercase();
-    if path.contains("tinyllama") {
-        Box::new(template::ChatTinyLlama)
-    } else {
-        Box::new(template::ChatCPM)
+#[test]
+fn test_tiny() {
+    use tokio::runtime::Builder;
+
+    let model_dir = std::env::temp_dir().join(format!("service-tiny-{}", std::process::id()));
+    llama::tiny_model(&model_dir, 0).unwrap();
+
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let _rt = runtime.enter();
+
+    let (service, _handle) =
+        Service::<llama_cpu::Transformer>::load(&model_dir, Default::default());
+    let mut session = service.launch();
+    // 随机的模型可能立即生成结束符
+    session.stop.min_new_tokens = 4;
+    // 两轮对话，第二轮复用第一轮的缓存
+    for prompt in ["Hi", "Hello"] {
+        session.extend([prompt]).unwrap();
+        runtime.block_on(async {
+            let mut busy = session.chat();
+            for _ in 0..8 {
+                if busy.decode().await.is_none() {
+                    break;
+                }
+            }
+            assert!(busy.num_generated_tokens() > 0);
+        });
     }
+    // 每轮对话包括提问和回答两个句子
+    assert_eq!(session.dialog_pos(), 4);
+
+    drop(session);
+    runtime.shutdown_background();
+    let _ = std::fs::remove_dir_all(model_dir);
 }
 
-fn normalizer(model_dir: impl AsRef<Path>) -> Box<dyn Normalizer + Send + Sync> {
-    use std::io::ErrorKind::NotFound;
-    match BPE::from_model_file(model_dir.as_ref().join("tokenizer.model")) {
-        Ok(_) => return Box::new(BPECommonNormalizer {}),
-        Err(e) if e.kind() == NotFound => {}
-        Err(e) => panic!("{e:?}"),
+#[test]
+fn test_mock() {
+    use causal_lm::MockModel;
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use tokio::runtime::Builder;
+
+    let model_dir = MockModel::model_dir("service-mock");
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    let _rt = runtime.enter();
+
+    let reply = "ok".bytes().map(|b| b as utok + 3);
+    let model = MockModel::script(reply.clone());
+    let (service, _handle) = Service::<MockModel>::load(&model_dir, model);
+    assert!(service.encode("ok").unwrap().into_iter().eq(reply.clone()));
+    assert_eq!(&*service.decode(b'o' as utok + 3), b"o");
+    let mut session = service.launch();
+    // 过长的文本无法分词，会话不变
+    let long = "a".repeat(MAX_TEXT_LEN + 1);
+    assert_eq!(
+        service.encode(&long),
+        Err(EncodeError::TooLong(MAX_TEXT_LEN + 1))
+    );
+    assert!(session.extend(["Hi", &long]).is_err());
+    assert_eq!(session.dialog_pos(), 0);
+    session.extend(["Hi"]).unwrap();
+    runtime.block_on(async {
+        let mut busy = session.chat();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = busy.decode().await {
+            chunks.push(chunk);
+        }
+        let text = chunks.iter().map(Chunk::as_str).collect::<String>();
+        let tokens = chunks.iter().flat_map(|c| &c.tokens).copied();
+        assert_eq!(text, "ok");
+        assert!(tokens.eq(reply.clone()));
+        // 只有最后一段带有结束的原因
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|c| !c.is_last()));
+        assert_eq!(last.finish_reason, Some(FinishReason::Stop));
+        assert_eq!(busy.finish_reason(), Some(FinishReason::Stop));
+    });
+
+    // 回答限制为选项之一
+    session.choices = vec!["yes".into(), "no".into()];
+    session.extend(["Yes or no?"]).unwrap();
+    runtime.block_on(async {
+        let mut busy = session.chat();
+        let mut text = String::new();
+        while let Some(chunk) = busy.decode().await {
+            text.push_str(&chunk.text);
+        }
+        assert!(["yes", "no"].contains(&&*text));
+    });
+
+    // 总结超过词数上限时放弃，会话不变
+    session.choices.clear();
+    let num_tokens = session.num_tokens();
+    assert!(!runtime.block_on(session.summarize(1, 1)));
+    assert_eq!(session.num_tokens(), num_tokens);
+    assert!(runtime.block_on(session.summarize(1, 8)));
+    assert_eq!(session.dialog_pos(), 4);
+
+    // 预先计算文档的缓存，复制的会话只计算新的提问
+    let mut document = service.launch();
+    document.ground("Doc").unwrap();
+    assert_eq!(document.dialog_pos(), 2);
+    runtime.block_on(document.prefill());
+    assert_eq!(
+        document.cache_usage().unwrap().tokens,
+        document.num_tokens()
+    );
+    let mut session = document.fork().unwrap();
+    session.extend(["Hi"]).unwrap();
+    let num_prompt = session.num_tokens() - document.num_tokens();
+    runtime.block_on(async {
+        let mut busy = session.chat();
+        assert_eq!(busy.num_prompt_tokens(), num_prompt);
+        let mut text = String::new();
+        while let Some(chunk) = busy.decode().await {
+            text.push_str(&chunk.text);
+        }
+        assert_eq!(text, "ok");
+    });
+
+    // 已编码的句子原样进入对话
+    let mut session = service.launch();
+    session.extend_tokens([service.encode("Hi").unwrap()]);
+    assert_eq!(session.num_tokens(), 2);
+    runtime.block_on(async {
+        let mut busy = session.chat();
+        assert_eq!(busy.num_prompt_tokens(), 2);
+        while busy.decode().await.is_some() {}
+    });
+    assert_eq!(session.dialog_pos(), 2);
+
+    // 续写不添加对话模板
+    let mut generator = service.complete(
+        service.encode("Hi").unwrap(),
+        service.default_sample.clone(),
+        Default::default(),
+        Default::default(),
+        true,
+    );
+    assert_eq!(generator.num_prompt_tokens(), 2);
+    runtime.block_on(async {
+        let mut text = String::new();
+        while let Some(chunk) = generator.decode().await {
+            text.push_str(&chunk.text);
+        }
+        assert_eq!(text, "ok");
+        assert_eq!(generator.finish_reason(), Some(FinishReason::Stop));
+    });
+    // 模型在 "H" 之后预测 "o" 而不是 "i"，"i" 的概率低于均匀分布
+    let logprobs = generator.prompt_logprobs().unwrap();
+    assert_eq!(logprobs.len(), 1);
+    assert!(logprobs[0] < -(512f32).ln());
+
+    // 同时发起的打分一起预填充，预测的续写得分更高
+    let scores = [
+        service.score(service.encode("Ho").unwrap()),
+        service.score(service.encode("Hi").unwrap()),
+    ];
+    let [ho, hi] = scores.map(|score| runtime.block_on(score).unwrap());
+    assert_eq!((ho.len(), hi.len()), (1, 1));
+    assert!(ho[0] > hi[0]);
+
+    // 不接收输出时暂停生成，接收后继续
+    let steps = Arc::new(AtomicUsize::new(0));
+    let steps_ = steps.clone();
+    let model = MockModel::new(move |_| {
+        steps_.fetch_add(1, Relaxed);
+        Some(b'a' as utok + 3)
+    });
+    let (service, _handle) = Service::<MockModel>::load(&model_dir, model);
+    let mut session = service.launch();
+    session.extend(["Hi"]).unwrap();
+    runtime.block_on(async {
+        let mut busy = session.chat();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let paused = steps.load(Relaxed);
+        assert!(paused <= 32, "{paused} steps without receiving");
+        for _ in 0..64 {
+            busy.decode().await.unwrap();
+        }
+        assert!(steps.load(Relaxed) > 64);
+    });
+
+    // 审核的结论同样作用于会话中保存的回答
+    struct Fixed(Verdict);
+    impl Moderator for Fixed {
+        fn moderate<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Verdict> + Send + 'a>> {
+            Box::pin(std::future::ready(self.0.clone()))
+        }
     }
-    match VocabTxt::from_txt_file(model_dir.as_ref().join("vocabs.txt")) {
-        Ok(_) => return Box::new(()),
-        Err(e) if e.kind() == NotFound => {}
-        Err(e) => panic!("{e:?}"),
+    for (verdict, dialog_pos, reply) in [
+        (Verdict::Redact("x".into()), 2, "xx"),
+        (Verdict::Abort, 1, ""),
+    ] {
+        let model = MockModel::script("ok".bytes().map(|b| b as utok + 3));
+        let (service, _handle) = Service::<MockModel>::load(&model_dir, model);
+        service.register_moderator(Arc::new(Fixed(verdict)));
+        let mut session = service.launch();
+        session.extend(["Hi"]).unwrap();
+        let num_prompt = session.num_tokens();
+        runtime.block_on(async {
+            let mut busy = session.chat();
+            let mut text = String::new();
+            while let Some(chunk) = busy.decode().await {
+                text.push_str(&chunk.text);
+            }
+            assert_eq!(text, reply);
+        });
+        assert_eq!(session.dialog_pos(), dialog_pos);
+        // 替换的文本加上结束符
+        let stored = if reply.is_empty() { 0 } else { reply.len() + 1 };
+        assert_eq!(session.num_tokens(), num_prompt + stored);
+        // 替换后的回答在下次推理时计算缓存
+        if dialog_pos % 2 == 0 {
+            session.extend(["Again"]).unwrap();
+        }
+        runtime.block_on(async {
+            let mut busy = session.chat();
+            while busy.decode().await.is_some() {}
+        });
+        assert_eq!(session.num_tokens(), session.cache_usage().unwrap().tokens);
     }
-    panic!("Tokenizer file not found");
+
+    // 模型比时限慢
+    let model = MockModel::echo().with_delay(Duration::from_millis(50));
+    let (service, _handle) = Service::<MockModel>::load(&model_dir, model);
+    let mut session = service.launch();
+    session.stop.token_timeout = Some(Duration::from_millis(5));
+    session.extend(["Hi"]).unwrap();
+    runtime.block_on(async {
+        let mut busy = session.chat();
+        let mut last = None;
+        while let Some(chunk) = busy.decode().await {
+            last = Some(chunk);
+        }
+        assert_eq!(last.unwrap().finish_reason, Some(FinishReason::Timeout));
+        assert_eq!(busy.finish_reason(), Some(FinishReason::Timeout));
+    });
+
+    runtime.shutdown_background();
+    let _ = std::fs::remove_dir_all(model_dir);
 }
 
-fn tokenizer(model_dir: impl AsRef<Path>) -> Box<dyn Tokenizer + Send + Sync> {
-    use std::io::ErrorKind::NotFound;
-    match BPE::from_model_file(model_dir.as_ref().join("tokenizer.model")) {
-        Ok(bpe) => return Box::new(bpe),
-        Err(e) if e.kind() == NotFound => {}
-        Err(e) => panic!("{e:?}"),
+/// 以 `generation_config.json` 中的采样参数作为默认值，与 transformers 一样仅在 `do_sample` 为真时随机采样。
+fn default_sample(model_dir: impl AsRef<Path>) -> SampleArgs {
+    let mut args = SampleArgs::default();
+    match GenerationConfig::load(model_dir) {
+        Ok(config) if config.do_sample => {
+            args.temperature = config.temperature.unwrap_or(1.);
+            args.top_k = config.top_k.unwrap_or(50);
+            args.top_p = config.top_p.unwrap_or(1.);
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Failed to load generation config: {e:?}"),
     }
-    match VocabTxt::from_txt_file(model_dir.as_ref().join("vocabs.txt")) {
-        Ok(voc) => return Box::new(voc),
-        Err(e) if e.kind() == NotFound => {}
-        Err(e) => panic!("{e:?}"),
+    args
+}
+
+fn template(model_dir: impl AsRef<Path>) -> Box<dyn Template + Send + Sync> {
+    let path: String = model_dir.as_ref().display().to_string();
+    let path = path.to_ascii_lowercase();
+    if path.contains("tinyllama") {
+        Box::new(template::ChatTinyLlama)
+    } else {
+        Box::new(template::ChatCPM)
     }
-    panic!("Tokenizer file not found");
 }
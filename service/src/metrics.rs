@@ -0,0 +1,256 @@
+use crate::scheduler::{Fcfs, Scheduler};
+use log::info;
+use std::{
+    fmt,
+    mem::take,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// 统计周期，每个周期结束时更新滑动平均并输出日志。
+const INTERVAL: Duration = Duration::from_secs(10);
+/// 滑动平均中一个周期的权重。
+const ALPHA: f64 = 0.3;
+
+/// 推理吞吐量的滑动平均。
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Throughput {
+    /// 每秒解码的词数。
+    pub decode_tokens_per_sec: f64,
+    /// 每秒预填充的词数。
+    pub prefill_tokens_per_sec: f64,
+    /// 平均每次前向传播包含的推理任务数。
+    pub batch_occupancy: f64,
+    /// 平均每次前向传播解码的任务数。
+    pub decode_batch: f64,
+    /// 最近一个统计周期内单次前向传播包含的最多任务数。
+    pub peak_batch: usize,
+    /// 提交的推理任务中命中缓存的词占上下文的比例。
+    pub cache_hit_rate: f64,
+    /// 平均每次前向传播推迟的推理任务数。
+    pub deferred_tasks: f64,
+    /// 推理任务提交到推理线程取出之间的平均等待毫秒数。
+    pub queue_latency_ms: f64,
+    /// 最近一个统计周期内推理任务最长的等待毫秒数。
+    pub peak_queue_latency_ms: f64,
+    /// 统计期间使用的调度策略。
+    pub scheduler: &'static str,
+    /// 统计开始以来超时的推理任务数。
+    pub timeouts: usize,
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "decode {:.2} tok/s, prefill {:.2} tok/s, batch {:.2} (decode {:.2}, peak {}), cache hit {:.1}%, deferred {:.2}, queue {:.2} ms (peak {:.2}), timeouts {} ({})",
+            self.decode_tokens_per_sec,
+            self.prefill_tokens_per_sec,
+            self.batch_occupancy,
+            self.decode_batch,
+            self.peak_batch,
+            self.cache_hit_rate * 100.,
+            self.deferred_tasks,
+            self.queue_latency_ms,
+            self.peak_queue_latency_ms,
+            self.timeouts,
+            self.scheduler,
+        )
+    }
+}
+
+/// 一个统计周期内的计数。
+#[derive(Default)]
+struct Counter {
+    prefill: usize,
+    decode: usize,
+    steps: usize,
+    tasks: usize,
+    peak: usize,
+    deferred: usize,
+    cached: usize,
+    computed: usize,
+    queued: usize,
+    waited: Duration,
+    peak_wait: Duration,
+}
+
+struct State {
+    start: Instant,
+    counter: Counter,
+    average: Throughput,
+    /// 是否已经有过推理，此前的滑动平均直接取第一个有推理的周期。
+    warm: bool,
+}
+
+/// 推理线程与会话共享的吞吐量统计。
+pub(crate) struct Metrics(Mutex<State>);
+
+impl Default for Metrics {
+    #[inline]
+    fn default() -> Self {
+        Self(Mutex::new(State::new(Instant::now(), Fcfs.name())))
+    }
+}
+
+impl Metrics {
+    /// 提交一个推理任务，上下文中 `cached` 个词已缓存，`computed` 个词需要计算。
+    #[inline]
+    pub fn request(&self, cached: usize, computed: usize) {
+        let mut state = self.0.lock().unwrap();
+        state.counter.cached += cached;
+        state.counter.computed += computed;
+    }
+
+    /// 推理线程从队列中取出任务，`waited` 是每个任务排队的时间。
+    pub fn queued(&self, waited: impl IntoIterator<Item = Duration>) {
+        let mut state = self.0.lock().unwrap();
+        let counter = &mut state.counter;
+        for waited in waited {
+            counter.queued += 1;
+            counter.waited += waited;
+            counter.peak_wait = counter.peak_wait.max(waited);
+        }
+    }
+
+    /// 一个推理任务超时。
+    #[inline]
+    pub fn timeout(&self) {
+        self.0.lock().unwrap().average.timeouts += 1;
+    }
+
+    /// 切换调度策略，重新开始统计。
+    #[inline]
+    pub fn reset(&self, scheduler: &'static str) {
+        *self.0.lock().unwrap() = State::new(Instant::now(), scheduler);
+    }
+
+    /// 完成一次包含 `tasks` 个任务、推迟了 `deferred` 个任务的前向传播，周期结束时输出日志。
+    pub fn step(&self, tasks: usize, prefill: usize, decode: usize, deferred: usize) {
+        let mut state = self.0.lock().unwrap();
+        state.counter.steps += 1;
+        state.counter.tasks += tasks;
+        state.counter.peak = state.counter.peak.max(tasks);
+        state.counter.deferred += deferred;
+        state.counter.prefill += prefill;
+        state.counter.decode += decode;
+        if state.roll(Instant::now()) {
+            info!("Throughput: {}", state.average);
+        }
+    }
+
+    /// 获取当前的滑动平均。
+    #[inline]
+    pub fn throughput(&self) -> Throughput {
+        let mut state = self.0.lock().unwrap();
+        state.roll(Instant::now());
+        state.average
+    }
+}
+
+impl State {
+    #[inline]
+    fn new(start: Instant, scheduler: &'static str) -> Self {
+        Self {
+            start,
+            counter: Default::default(),
+            average: Throughput {
+                scheduler,
+                ..Default::default()
+            },
+            warm: false,
+        }
+    }
+
+    /// 结束已经过去的统计周期并更新滑动平均，返回是否结束了周期。
+    fn roll(&mut self, now: Instant) -> bool {
+        let elapsed = now - self.start;
+        if elapsed < INTERVAL {
+            return false;
+        }
+        self.start = now;
+        let counter = take(&mut self.counter);
+        // 跨越多个周期时按周期数增加权重，使空闲期间的速率衰减
+        let periods = elapsed.as_secs_f64() / INTERVAL.as_secs_f64();
+        let weight = if self.warm {
+            1. - (1. - ALPHA).powf(periods)
+        } else {
+            1.
+        };
+        self.warm |= counter.steps > 0;
+        let mix = |avg: &mut f64, val: f64| *avg += (val - *avg) * weight;
+
+        let secs = elapsed.as_secs_f64();
+        let avg = &mut self.average;
+        mix(&mut avg.decode_tokens_per_sec, counter.decode as f64 / secs);
+        mix(
+            &mut avg.prefill_tokens_per_sec,
+            counter.prefill as f64 / secs,
+        );
+        // 没有样本的比例保持不变
+        if counter.steps > 0 {
+            let occupancy = counter.tasks as f64 / counter.steps as f64;
+            mix(&mut avg.batch_occupancy, occupancy);
+            let decode = counter.decode as f64 / counter.steps as f64;
+            mix(&mut avg.decode_batch, decode);
+            let deferred = counter.deferred as f64 / counter.steps as f64;
+            mix(&mut avg.deferred_tasks, deferred);
+        }
+        avg.peak_batch = counter.peak;
+        if counter.queued > 0 {
+            let latency = counter.waited.as_secs_f64() * 1e3 / counter.queued as f64;
+            mix(&mut avg.queue_latency_ms, latency);
+        }
+        avg.peak_queue_latency_ms = counter.peak_wait.as_secs_f64() * 1e3;
+        let total = counter.cached + counter.computed;
+        if total > 0 {
+            mix(
+                &mut avg.cache_hit_rate,
+                counter.cached as f64 / total as f64,
+            );
+        }
+        true
+    }
+}
+
+#[test]
+fn test_roll() {
+    let start = Instant::now();
+    let mut state = State::new(start, "fcfs");
+    state.counter = Counter {
+        prefill: 1000,
+        decode: 100,
+        steps: 50,
+        tasks: 100,
+        peak: 4,
+        deferred: 25,
+        cached: 30,
+        computed: 70,
+        queued: 4,
+        waited: Duration::from_millis(10),
+        peak_wait: Duration::from_millis(6),
+    };
+    assert!(!state.roll(start + INTERVAL / 2));
+    assert!(state.roll(start + INTERVAL));
+
+    let avg = state.average;
+    let eq = |a: f64, b: f64| (a - b).abs() < 1e-9;
+    assert!(eq(avg.decode_tokens_per_sec, 10.));
+    assert!(eq(avg.prefill_tokens_per_sec, 100.));
+    assert!(eq(avg.batch_occupancy, 2.));
+    assert!(eq(avg.decode_batch, 2.));
+    assert_eq!(avg.peak_batch, 4);
+    assert!(eq(avg.cache_hit_rate, 0.3));
+    assert!(eq(avg.deferred_tasks, 0.5));
+    assert!(eq(avg.queue_latency_ms, 2.5));
+    assert!(eq(avg.peak_queue_latency_ms, 6.));
+    assert_eq!(avg.scheduler, "fcfs");
+
+    // 空闲的周期只衰减速率
+    assert!(state.roll(start + INTERVAL * 2));
+    assert!(eq(state.average.decode_tokens_per_sec, 10. * (1. - ALPHA)));
+    assert!(eq(state.average.batch_occupancy, 2.));
+    assert_eq!(state.average.peak_batch, 0);
+    assert!(eq(state.average.queue_latency_ms, 2.5));
+    assert_eq!(state.average.peak_queue_latency_ms, 0.);
+}
@@ -0,0 +1,115 @@
+use common::utok;
+
+/// 调度器看到的推理任务。
+#[derive(Clone, Copy, Debug)]
+pub struct TaskInfo<'a> {
+    /// 服务为推理任务分配的唯一序号。
+    pub id: usize,
+    /// 任务缓存窗口中的词。
+    pub tokens: &'a [utok],
+    /// 本轮需要计算的词数。
+    pub num_query: usize,
+    /// 已经生成的词数。
+    pub num_generated: usize,
+}
+
+/// 决定每轮前向传播执行哪些任务、以什么顺序执行的调度策略。
+pub trait Scheduler: Send + Sync {
+    /// 策略的名字。
+    fn name(&self) -> &'static str;
+    /// 返回本轮执行的任务在 `tasks` 中的序号，按优先级排列。
+    ///
+    /// 未列出的任务以及超出批大小限制的任务推迟到下一轮；没有列出任何任务时按原顺序执行所有任务。
+    fn schedule(&self, tasks: &[TaskInfo]) -> Vec<usize>;
+}
+
+/// 按到达顺序执行任务。
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Fcfs;
+
+impl Scheduler for Fcfs {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "fcfs"
+    }
+
+    #[inline]
+    fn schedule(&self, tasks: &[TaskInfo]) -> Vec<usize> {
+        sort_by_key(tasks, |t| t.id)
+    }
+}
+
+/// 优先执行本轮需要计算的词最少的任务，降低解码的延迟。
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ShortestFirst;
+
+impl Scheduler for ShortestFirst {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "shortest-first"
+    }
+
+    #[inline]
+    fn schedule(&self, tasks: &[TaskInfo]) -> Vec<usize> {
+        sort_by_key(tasks, |t| (t.num_query, t.id))
+    }
+}
+
+/// 优先执行已生成的词最少的任务，使各任务的进度接近。
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FairShare;
+
+impl Scheduler for FairShare {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "fair-share"
+    }
+
+    #[inline]
+    fn schedule(&self, tasks: &[TaskInfo]) -> Vec<usize> {
+        sort_by_key(tasks, |t| (t.num_generated, t.id))
+    }
+}
+
+/// 将共享前缀的任务（例如分叉的会话）相邻执行，使注意力读取的缓存保持在热的内存中。
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SharedPrefix;
+
+impl Scheduler for SharedPrefix {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "shared-prefix"
+    }
+
+    #[inline]
+    fn schedule(&self, tasks: &[TaskInfo]) -> Vec<usize> {
+        // 按词序列的字典序排列，前缀相同的任务相邻
+        sort_by_key(tasks, |t| (t.tokens, t.id))
+    }
+}
+
+fn sort_by_key<'a, K: Ord>(tasks: &[TaskInfo<'a>], f: impl Fn(&TaskInfo<'a>) -> K) -> Vec<usize> {
+    let mut order = (0..tasks.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| f(&tasks[i]));
+    order
+}
+
+#[test]
+fn test_schedule() {
+    let task = |id, tokens, num_query, num_generated| TaskInfo {
+        id,
+        tokens,
+        num_query,
+        num_generated,
+    };
+    let tasks = [
+        task(3, &[1, 2, 3], 1, 5),
+        task(1, &[4, 5], 8, 0),
+        task(2, &[1, 2, 4], 1, 2),
+        task(0, &[4, 5, 6], 3, 2),
+    ];
+    assert_eq!(Fcfs.schedule(&tasks), [3, 1, 2, 0]);
+    assert_eq!(ShortestFirst.schedule(&tasks), [2, 0, 3, 1]);
+    assert_eq!(FairShare.schedule(&tasks), [1, 3, 2, 0]);
+    assert_eq!(SharedPrefix.schedule(&tasks), [0, 2, 1, 3]);
+}
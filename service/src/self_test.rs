@@ -0,0 +1,101 @@
+//! 启动时的自检，用固定的提示词确认模型与分词器匹配。
+
+use crate::{FinishReason, Service};
+use causal_lm::CausalLM;
+use std::fmt;
+use tokenizer::EncodeError;
+
+/// 自检失败的原因。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SelfTestError {
+    /// 没有生成任何文本。
+    Empty,
+    /// 生成的字节不是合法的 UTF-8，文本中有替换字符。
+    InvalidUtf8(String),
+    /// 生成的词数超过上限仍未结束。
+    TooLong(String),
+    /// 生成以结束符以外的原因结束。
+    Unfinished(Option<FinishReason>),
+    /// 提示词过长，无法分词。
+    Encode(EncodeError),
+}
+
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty output"),
+            Self::InvalidUtf8(text) => write!(f, "invalid UTF-8 in output {text:?}"),
+            Self::TooLong(text) => write!(f, "no end of sentence after {text:?}"),
+            Self::Unfinished(Some(reason)) => {
+                write!(f, "generation stopped by {}", reason.as_str())
+            }
+            Self::Unfinished(None) => write!(f, "generation interrupted"),
+            Self::Encode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for SelfTestError {}
+
+impl<M: CausalLM> Service<M> {
+    /// 以贪心采样回答 `prompt`，回答不为空、是合法的 UTF-8 且在 `max_tokens` 个词以内以结束符结束时返回回答。
+    ///
+    /// 分词器与模型不匹配时，回答通常是乱码或停不下来。
+    pub async fn self_test(
+        &self,
+        prompt: &str,
+        max_tokens: usize,
+    ) -> Result<String, SelfTestError> {
+        let mut session = self.launch();
+        session.sample = Default::default();
+        session.extend([prompt]).map_err(SelfTestError::Encode)?;
+        let mut busy = session.chat();
+        let mut text = String::new();
+        while let Some(chunk) = busy.decode().await {
+            text.push_str(&chunk.text);
+            if busy.num_generated_tokens() > max_tokens {
+                return Err(SelfTestError::TooLong(text));
+            }
+        }
+        match busy.finish_reason() {
+            Some(FinishReason::Stop) => {}
+            reason => return Err(SelfTestError::Unfinished(reason)),
+        }
+        if text.trim().is_empty() {
+            Err(SelfTestError::Empty)
+        } else if text.contains(char::REPLACEMENT_CHARACTER) {
+            Err(SelfTestError::InvalidUtf8(text))
+        } else {
+            Ok(text)
+        }
+    }
+}
+
+#[test]
+fn test_self_test() {
+    use causal_lm::MockModel;
+    use common::utok;
+    use tokio::runtime::Builder;
+
+    let model_dir = MockModel::model_dir("service-self-test");
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    let _rt = runtime.enter();
+
+    // 自检要求回答在限定的词数内结束
+    let model = MockModel::script("ok".bytes().map(|b| b as utok + 3));
+    let (service, _handle) = Service::<MockModel>::load(&model_dir, model);
+    assert_eq!(runtime.block_on(service.self_test("Hi", 2)).unwrap(), "ok");
+    assert_eq!(
+        runtime.block_on(service.self_test("Hi", 1)),
+        Err(SelfTestError::TooLong("ok".into()))
+    );
+    let model = MockModel::script([0xff + 3]);
+    let (garbled, _handle) = Service::<MockModel>::load(&model_dir, model);
+    assert!(matches!(
+        runtime.block_on(garbled.self_test("Hi", 8)),
+        Err(SelfTestError::InvalidUtf8(_))
+    ));
+
+    runtime.shutdown_background();
+    let _ = std::fs::remove_dir_all(model_dir);
+}
@@ -1,7 +1,11 @@
-﻿use std::sync::{Condvar, Mutex};
+﻿use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
 
+/// 推理线程的任务队列，记录每个元素入队的时刻以统计排队时间。
 pub struct Batcher<T> {
-    queue: Mutex<(Vec<T>, bool)>,
+    queue: Mutex<(Vec<(T, Instant)>, bool)>,
     condvar: Condvar,
 }
 
@@ -19,20 +23,27 @@ impl<T> Batcher<T> {
         let mut lock = self.queue.lock().unwrap();
         let (queue, alive) = &mut *lock;
         if *alive {
-            queue.push(val);
+            queue.push((val, Instant::now()));
         }
         self.condvar.notify_one();
     }
 
+    /// 取走队列中的所有元素及其排队的时间，队列为空时等待。
     #[inline]
-    pub fn deq(&self) -> Vec<T> {
-        std::mem::take(
+    pub fn deq(&self) -> Vec<(T, Duration)> {
+        waited(std::mem::take(
             &mut self
                 .condvar
                 .wait_while(self.queue.lock().unwrap(), |(q, a)| q.is_empty() && *a)
                 .unwrap()
                 .0,
-        )
+        ))
+    }
+
+    /// 取走队列中的所有元素及其排队的时间，不等待。
+    #[inline]
+    pub fn take(&self) -> Vec<(T, Duration)> {
+        waited(std::mem::take(&mut self.queue.lock().unwrap().0))
     }
 
     #[inline]
@@ -44,3 +55,8 @@ impl<T> Batcher<T> {
         self.condvar.notify_all();
     }
 }
+
+fn waited<T>(queue: Vec<(T, Instant)>) -> Vec<(T, Duration)> {
+    let now = Instant::now();
+    queue.into_iter().map(|(t, time)| (t, now - time)).collect()
+}
@@ -1,8 +1,13 @@
-﻿use causal_lm::{CausalLM, QueryContext};
+﻿use causal_lm::{CausalLM, QueryContext, ShapeError};
 use common::{upos, utok};
 use log::{debug, info};
 use rangemap::{range_set, RangeSet};
-use std::{cmp::min, ops::Range};
+use std::{
+    cmp::min,
+    iter::{zip, Chain},
+    ops::Range,
+    slice,
+};
 use tensor::Tensor;
 
 pub(super) struct Cache<Storage> {
@@ -14,25 +19,42 @@ pub(super) struct Cache<Storage> {
     cached: RangeSet<usize>,
     /// 已缓存的 token 在 cached_range 中的范围
     to_be_cached: RangeSet<usize>,
+    /// 上次回滚丢弃的词，它们紧接在缓存的末尾，计算缓存仍然有效。
+    reverted: Vec<utok>,
     /// 计算缓存。
     cache: Tensor<Storage>,
+    /// 计算缓存时使用的适配器。
+    adapter: Option<usize>,
+    /// 计算缓存时使用的控制向量和强度。
+    steering: Option<(usize, f32)>,
+    /// 软提示词的虚拟词，占据计算缓存开头的位置，不属于 token 序列。
+    soft_prompt: Vec<utok>,
+    /// 软提示词是否已缓存。
+    soft_prompt_cached: bool,
 }
 
 pub struct CacheQuery<'a> {
+    prefix: &'a [utok],
     tokens: &'a [utok],
     to_be_cached: &'a RangeSet<usize>,
 }
 
 impl<'a> CacheQuery<'a> {
-    fn new(tokens: &'a [utok], to_be_cached: &'a RangeSet<usize>) -> Self {
+    fn new(prefix: &'a [utok], tokens: &'a [utok], to_be_cached: &'a RangeSet<usize>) -> Self {
         Self {
+            prefix,
             tokens,
             to_be_cached,
         }
     }
 
     pub fn len(&self) -> usize {
-        self.to_be_cached.iter().map(|range| range.len()).sum()
+        self.prefix.len()
+            + self
+                .to_be_cached
+                .iter()
+                .map(|range| range.len())
+                .sum::<usize>()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -43,10 +65,12 @@ impl<'a> CacheQuery<'a> {
 impl<'a> IntoIterator for CacheQuery<'a> {
     type Item = &'a utok;
 
-    type IntoIter = CacheQueryIter<'a>;
+    type IntoIter = Chain<slice::Iter<'a, utok>, CacheQueryIter<'a>>;
 
     fn into_iter(self) -> Self::IntoIter {
-        CacheQueryIter::new(self.tokens, self.to_be_cached)
+        self.prefix
+            .iter()
+            .chain(CacheQueryIter::new(self.tokens, self.to_be_cached))
     }
 }
 
@@ -101,22 +125,62 @@ impl<Storage> Cache<Storage> {
             } else {
                 RangeSet::new()
             },
+            reverted: Vec::new(),
             cache: t.new_cache(),
+            adapter: None,
+            steering: None,
+            soft_prompt: Vec::new(),
+            soft_prompt_cached: false,
         }
     }
 
     /// 复制缓存结构。
     #[inline]
-    pub fn duplicate(&self, t: &impl CausalLM<Storage = Storage>) -> Self {
+    pub fn duplicate(&self, t: &impl CausalLM<Storage = Storage>) -> Result<Self, ShapeError> {
         debug!("call duplicate");
-        Self {
+        Ok(Self {
             tokens: self.tokens.clone(),
             pos: self.pos,
             cached: self.cached.clone(),
             to_be_cached: self.to_be_cached.clone(),
-            cache: t.duplicate_cache(&self.cache, self.cached_len() as _),
+            reverted: Vec::new(),
+            cache: t.duplicate_cache(&self.cache, self.cached_pos() as _)?,
+            adapter: self.adapter,
+            steering: self.steering,
+            soft_prompt: self.soft_prompt.clone(),
+            soft_prompt_cached: self.soft_prompt_cached,
+        })
+    }
+    /// 切换适配器，已有的计算缓存随之失效，所有的词需要重新计算。
+    pub fn set_adapter(&mut self, adapter: Option<usize>) {
+        if adapter != self.adapter {
+            self.adapter = adapter;
+            self.invalidate();
+        }
+    }
+    /// 切换控制向量或强度，已有的计算缓存随之失效，所有的词需要重新计算。
+    pub fn set_steering(&mut self, steering: Option<(usize, f32)>) {
+        if steering != self.steering {
+            self.steering = steering;
+            self.invalidate();
+        }
+    }
+    /// 切换软提示词，已有的计算缓存随之失效，所有的词需要重新计算。
+    pub fn set_soft_prompt(&mut self, soft_prompt: Vec<utok>) {
+        if soft_prompt != self.soft_prompt {
+            self.soft_prompt = soft_prompt;
+            self.invalidate();
         }
     }
+    /// 所有已缓存的词重新加入待缓存的部分。
+    fn invalidate(&mut self) {
+        self.soft_prompt_cached = false;
+        for range in self.cached.iter() {
+            self.to_be_cached.insert(range.clone());
+        }
+        self.cached.clear();
+        self.reverted.clear();
+    }
     /// 回滚缓存到 `pos`，并返回剩余的有效缓存长度。
     pub fn revert(&mut self, pos: usize) -> Option<usize> {
         debug!("call revert");
@@ -126,7 +190,16 @@ impl<Storage> Cache<Storage> {
         // 2. cached.end 不大于 pos；
         if len != 0 && self.cached.contains(&(len - 1)) {
             self.to_be_cached.clear();
-            self.cached.remove(len..self.cached.last().unwrap().end);
+            let last = self.cached.last().unwrap().clone();
+            // 在最后一段缓存中丢弃的词，其计算缓存紧接在保留部分之后，可供再次填充时复用
+            self.reverted.clear();
+            if last.start < len {
+                self.reverted.extend_from_slice(&self.tokens[len..last.end]);
+            }
+            // 回滚到缓存的末尾时没有可丢弃的缓存
+            if len < last.end {
+                self.cached.remove(len..last.end);
+            }
         } else {
             return None;
         }
@@ -141,12 +214,34 @@ impl<Storage> Cache<Storage> {
         debug!("call extend tokens is : {:?}", tokens);
         let before_len = self.tokens.len();
         self.tokens.extend_from_slice(tokens);
-        self.to_be_cached.insert(before_len..self.tokens.len());
+        // 与上次回滚丢弃的词相同的开头不必重新计算，但至少计算最后一个词以得到 logits
+        let reused = if self.to_be_cached.is_empty() {
+            zip(tokens, &self.reverted)
+                .take_while(|(a, b)| a == b)
+                .count()
+                .min(tokens.len().saturating_sub(1))
+        } else {
+            0
+        };
+        self.reverted.clear();
+        if reused > 0 {
+            info!("{reused} reverted tokens reused");
+            self.cached.insert(before_len..before_len + reused);
+        }
+        if before_len + reused < self.tokens.len() {
+            self.to_be_cached
+                .insert(before_len + reused..self.tokens.len());
+        }
     }
     /// 所有 token 中还没有加入缓存的部分就是这次的查询。
     #[inline]
     pub fn query(&self) -> CacheQuery {
-        CacheQuery::new(&self.tokens, &self.to_be_cached)
+        let prefix = if self.soft_prompt_cached {
+            &[]
+        } else {
+            &*self.soft_prompt
+        };
+        CacheQuery::new(prefix, &self.tokens, &self.to_be_cached)
     }
     /// 生成对应的查询上下文。
     #[inline]
@@ -157,8 +252,10 @@ impl<Storage> Cache<Storage> {
             self.cached, self.to_be_cached
         );
         QueryContext {
-            range: self.cached_len() as upos..(self.cached_len() + self.to_be_cached_len()) as upos,
+            range: self.cached_pos() as upos..self.context_len() as upos,
             cache: Some(&mut (self.cache)),
+            adapter: self.adapter,
+            steering: self.steering,
         }
     }
 
@@ -168,16 +265,42 @@ impl<Storage> Cache<Storage> {
         debug!("call push");
         assert!(self.is_continue());
 
+        self.reverted.clear();
+        self.commit();
+        //插入新的需要缓存的token
+        self.to_be_cached
+            .insert(self.tokens.len()..self.tokens.len() + 1);
+        //插入token
+        self.tokens.push(token);
+    }
+    /// 待缓存的部分已完成计算，全部加入缓存。
+    pub fn commit(&mut self) {
+        self.soft_prompt_cached = true;
         //to_be_cached 全部变为cached
         self.to_be_cached
             .iter()
             .for_each(|range| self.cached.insert(range.clone()));
-        //清空to_be_cached 并插入新的需要缓存的token
         self.to_be_cached.clear();
-        self.to_be_cached
-            .insert(self.tokens.len()..self.tokens.len() + 1);
-        //插入token
-        self.tokens.push(token);
+    }
+    /// 缓存窗口中的所有词。
+    #[inline]
+    pub fn tokens(&self) -> &[utok] {
+        &self.tokens
+    }
+    /// 缓存窗口的起始位置。
+    #[inline]
+    pub fn start(&self) -> usize {
+        self.pos
+    }
+    /// 缓存窗口中参与推理的词数，包括软提示词。
+    #[inline]
+    pub fn context_len(&self) -> usize {
+        self.soft_prompt.len() + self.cached_len() + self.to_be_cached_len()
+    }
+    /// 计算缓存占用的字节数。
+    #[inline]
+    pub fn allocated_bytes(&self) -> usize {
+        self.cache.bytes_size()
     }
     /// 已采样的最后一个词在对话中的位置。
     #[inline]
@@ -195,8 +318,9 @@ impl<Storage> Cache<Storage> {
     #[allow(unused)]
     pub fn reset_within_one_range(&mut self, min: usize, max: usize) {
         assert!(min != 0 && max != 0);
-        if self.cached_len() + self.to_be_cached_len() >= max {
+        if self.context_len() >= max {
             self.cached.clear();
+            self.reverted.clear();
             self.to_be_cached = range_set![(self.tokens.len() - min..self.tokens.len())];
         }
     }
@@ -208,7 +332,8 @@ impl<Storage> Cache<Storage> {
         max: usize,
     ) {
         assert!(start_size + end_size <= max);
-        if self.cached_len() + self.to_be_cached_len() >= max {
+        if self.context_len() >= max {
+            self.reverted.clear();
             let mut uncached_start: usize = 0;
             // 为cached 赋值
             if let Some(mut first_range) = self.cached.first().cloned() {
@@ -243,6 +368,7 @@ impl<Storage> Cache<Storage> {
         self.tokens = tokens;
         self.pos = pos;
         self.cached.clear();
+        self.reverted.clear();
         let tokens_len = self.tokens.len();
         self.to_be_cached = if tokens_len > 0 {
             range_set![0..tokens_len]
@@ -252,7 +378,10 @@ impl<Storage> Cache<Storage> {
     }
     /// 清理缓存中在缓存窗口之前的部分。
     pub fn cleanup_before_start(&mut self) {
-        let to_remove = self.cached.first().unwrap().start;
+        // 第一次推理完成前结束的任务没有缓存任何词
+        let Some(to_remove) = self.cached.first().map(|r| r.start) else {
+            return;
+        };
         if to_remove > 0 {
             self.tokens.copy_within(to_remove.., 0);
             self.pos += to_remove;
@@ -289,6 +418,17 @@ impl<Storage> Cache<Storage> {
         }
     }
 
+    /// 计算缓存中有效的长度，即下一个查询的起始位置
+    #[inline]
+    fn cached_pos(&self) -> usize {
+        let prefix = if self.soft_prompt_cached {
+            self.soft_prompt.len()
+        } else {
+            0
+        };
+        prefix + self.cached_len()
+    }
+
     /// 获取cached 总长度
     #[inline]
     fn cached_len(&self) -> usize {
@@ -306,9 +446,80 @@ impl<Storage> Cache<Storage> {
 fn test_cache_query() {
     let v: Vec<u32> = (0..100).into_iter().collect();
     CacheQuery::new(
+        &[],
         &v,
         &range_set![10 as usize..20 as usize, 40 as usize..50 as usize],
     )
     .into_iter()
     .for_each(|a| println!("{:?}", a));
 }
+
+#[test]
+fn test_revert_reuse() {
+    use causal_lm::MockModel;
+
+    let model = MockModel::echo();
+    // 填充 [1, 2, 3] 并生成 [4, 5]，回滚到 [1, 2]
+    let reverted = || {
+        let mut cache = Cache::new(&model, vec![1, 2, 3]);
+        cache.push(4);
+        cache.push(5);
+        assert_eq!(cache.revert(2), Some(2));
+        cache
+    };
+
+    // 再次填充相同的词，只计算不同的部分
+    let mut cache = reverted();
+    cache.extend(&[3, 4, 6]);
+    assert_eq!(cache.query().into_iter().copied().collect::<Vec<_>>(), [6]);
+    assert_eq!(cache.context_len(), 5);
+    // 完全相同时也要计算最后一个词
+    let mut cache = reverted();
+    cache.extend(&[3, 4]);
+    assert_eq!(cache.query().into_iter().copied().collect::<Vec<_>>(), [4]);
+    // 不同的词不复用
+    let mut cache = reverted();
+    cache.extend(&[7, 4]);
+    assert_eq!(cache.query().len(), 2);
+    // 只复用一次
+    let mut cache = reverted();
+    cache.extend(&[7]);
+    cache.extend(&[3, 4]);
+    assert_eq!(cache.query().len(), 3);
+    // 切换适配器或控制向量后全部重新计算
+    let mut cache = reverted();
+    cache.set_adapter(Some(0));
+    cache.extend(&[3, 4]);
+    assert_eq!(cache.query().len(), 4);
+    let mut cache = reverted();
+    cache.set_steering(Some((0, 2.)));
+    cache.extend(&[3, 4]);
+    assert_eq!(cache.query().len(), 4);
+}
+
+#[test]
+fn test_soft_prompt() {
+    use causal_lm::MockModel;
+
+    let model = MockModel::echo();
+    let query = |cache: &Cache<_>| cache.query().into_iter().copied().collect::<Vec<_>>();
+    let mut cache = Cache::new(&model, vec![1, 2, 3]);
+    cache.set_soft_prompt(vec![100, 101]);
+    // 软提示词在第一次查询的开头，占据计算缓存开头的位置
+    assert_eq!(query(&cache), [100, 101, 1, 2, 3]);
+    assert_eq!(cache.as_ctx().range, 0..5);
+    cache.push(4);
+    assert_eq!(query(&cache), [4]);
+    assert_eq!(cache.as_ctx().range, 5..6);
+    assert_eq!(cache.tokens(), [1, 2, 3, 4]);
+    assert_eq!(cache.context_len(), 6);
+    // 回滚不影响软提示词的缓存
+    assert_eq!(cache.revert(2), Some(2));
+    cache.extend(&[7]);
+    assert_eq!(query(&cache), [7]);
+    assert_eq!(cache.as_ctx().range, 4..5);
+    // 切换后全部重新计算
+    cache.set_soft_prompt(vec![]);
+    assert_eq!(query(&cache), [1, 2, 7]);
+    assert_eq!(cache.as_ctx().range, 0..3);
+}
@@ -0,0 +1,41 @@
+use crate::FinishReason;
+use common::utok;
+use std::time::Duration;
+
+/// 推理任务流式输出的一段结果。
+///
+/// 流的最后一段带有生成结束的原因，其文本和词可能为空。
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Chunk {
+    /// 还原的文本。
+    pub text: String,
+    /// 产生这段文本的词，包括跳过的特殊词和尚未凑成字符的字节词。
+    pub tokens: Vec<utok>,
+    /// 每个词的对数概率，模型不提供时为 `None`。
+    pub logprobs: Option<Vec<f32>>,
+    /// 从推理任务开始到产生这段结果经过的时间。
+    pub elapsed: Duration,
+    /// 生成结束的原因，只在最后一段中出现。
+    pub finish_reason: Option<FinishReason>,
+}
+
+impl Chunk {
+    /// 这段结果的文本。
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// 这是流的最后一段。
+    #[inline]
+    pub fn is_last(&self) -> bool {
+        self.finish_reason.is_some()
+    }
+}
+
+impl From<Chunk> for String {
+    #[inline]
+    fn from(chunk: Chunk) -> Self {
+        chunk.text
+    }
+}
@@ -0,0 +1,161 @@
+use super::DetokenizeArgs;
+use std::str;
+
+/// transformers 的 `clean_up_tokenization` 依次进行的替换。
+const CLEAN_UP: [(&str, &str); 10] = [
+    (" .", "."),
+    (" ?", "?"),
+    (" !", "!"),
+    (" ,", ","),
+    (" ' ", "'"),
+    (" n't", "n't"),
+    (" 'm", "'m"),
+    (" 's", "'s"),
+    (" 've", "'ve"),
+    (" 're", "'re"),
+];
+
+/// 把逐个生成的词的字节还原为流式输出的文本。
+///
+/// 单字节词可能只是一个字符的一部分，凑成完整的字符后才输出，不能构成字符的字节输出为替换字符；
+/// 清理空格时，可能是替换模式开头的结尾文本暂不输出，等待后续的词。
+#[derive(Clone, Default, Debug)]
+pub(super) struct Detokenizer {
+    args: DetokenizeArgs,
+    /// 尚未凑成完整字符的字节。
+    bytes: Vec<u8>,
+    /// 等待后续文本才能清理空格的文本。
+    pending: String,
+    /// 已经产生过文本。
+    started: bool,
+}
+
+impl Detokenizer {
+    #[inline]
+    pub fn new(args: DetokenizeArgs) -> Self {
+        Self {
+            args,
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    pub fn args(&self) -> &DetokenizeArgs {
+        &self.args
+    }
+
+    /// 加入一个词的字节，返回可以输出的文本。
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.bytes.extend_from_slice(bytes);
+        let mut text = String::new();
+        let mut rest = &self.bytes[..];
+        while !rest.is_empty() {
+            match str::from_utf8(rest) {
+                Ok(s) => {
+                    text.push_str(s);
+                    rest = &[];
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    text.push_str(unsafe { str::from_utf8_unchecked(valid) });
+                    // 不完整的字符留到下次，非法的字节逐个替换
+                    let Some(len) = e.error_len() else {
+                        rest = invalid;
+                        break;
+                    };
+                    text.extend((0..len).map(|_| char::REPLACEMENT_CHARACTER));
+                    rest = &invalid[len..];
+                }
+            }
+        }
+        self.bytes = rest.to_vec();
+        self.text(text, false)
+    }
+
+    /// 生成结束时输出剩余的文本。
+    pub fn flush(&mut self) -> String {
+        let text = self
+            .bytes
+            .drain(..)
+            .map(|_| char::REPLACEMENT_CHARACTER)
+            .collect();
+        self.text(text, true)
+    }
+
+    fn text(&mut self, mut text: String, last: bool) -> String {
+        if !self.started && !text.is_empty() {
+            self.started = true;
+            if self.args.strip_leading_space && text.starts_with(' ') {
+                text.remove(0);
+            }
+        }
+        if !self.args.clean_up_tokenization_spaces {
+            return text;
+        }
+        self.pending.push_str(&text);
+        // 保留可能是替换模式开头的结尾
+        let keep = if last {
+            self.pending.len()
+        } else {
+            self.pending
+                .char_indices()
+                .rev()
+                .take(3)
+                .filter(|&(_, c)| c == ' ')
+                .map(|(i, _)| i)
+                .filter(|&i| {
+                    let tail = &self.pending[i..];
+                    CLEAN_UP
+                        .iter()
+                        .any(|(from, _)| from.len() > tail.len() && from.starts_with(tail))
+                })
+                .last()
+                .unwrap_or(self.pending.len())
+        };
+        let rest = self.pending.split_off(keep);
+        let text = std::mem::replace(&mut self.pending, rest);
+        CLEAN_UP
+            .iter()
+            .fold(text, |text, (from, to)| text.replace(from, to))
+    }
+}
+
+#[test]
+fn test_detokenize() {
+    fn collect(args: DetokenizeArgs, pieces: &[&[u8]]) -> String {
+        let mut detokenizer = Detokenizer::new(args);
+        let mut ans = pieces
+            .iter()
+            .map(|p| detokenizer.push(p))
+            .collect::<String>();
+        ans.push_str(&detokenizer.flush());
+        ans
+    }
+
+    let pieces: &[&[u8]] = &[
+        b" Hello", b" ", b".", b" I", b" do", b" n", b"'", b"t", b" know",
+    ];
+    assert_eq!(
+        collect(Default::default(), pieces),
+        " Hello . I do n't know"
+    );
+    let args = DetokenizeArgs {
+        strip_leading_space: true,
+        clean_up_tokenization_spaces: true,
+        ..Default::default()
+    };
+    let text = pieces.concat();
+    let text = str::from_utf8(&text).unwrap().trim_start();
+    let expected = CLEAN_UP
+        .iter()
+        .fold(text.to_string(), |text, (from, to)| text.replace(from, to));
+    assert_eq!(collect(args, pieces), expected);
+    assert_eq!(expected, "Hello. I don't know");
+
+    // 单字节词拼成完整字符，非法字节和结尾不完整的字符输出为替换字符
+    let ni = "你".as_bytes();
+    assert_eq!(
+        collect(Default::default(), &[&ni[..1], &ni[1..], b"\xff", &ni[..2]]),
+        "你\u{FFFD}\u{FFFD}\u{FFFD}"
+    );
+}
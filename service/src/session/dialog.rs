@@ -15,6 +15,18 @@ impl Dialog {
         self.0.last().map_or(0, |s| s.1)
     }
 
+    /// 第 `i` 个句子的词序列。
+    #[inline]
+    pub fn sentence(&self, i: usize) -> &[utok] {
+        &self.0[i].0
+    }
+
+    /// 第 `i` 个句子在对话中的起始位置。
+    #[inline]
+    pub fn sentence_start(&self, i: usize) -> usize {
+        i.checked_sub(1).map_or(0, |i| self.0[i].1)
+    }
+
     #[inline]
     pub fn revert(&mut self, len: usize) {
         self.0.truncate(len);
@@ -1,19 +1,57 @@
-﻿use super::{batcher::Batcher, cache::Cache, task::Task};
-use crate::ServiceComponent;
+use super::{
+    batcher::Batcher, cache::Cache, chunk::Chunk, detokenizer::Detokenizer, task::Task,
+    DetokenizeArgs, StopArgs,
+};
+use crate::{
+    hooks::{FinishReason, Hooks, TextWindow, Verdict},
+    metrics::Metrics,
+    scheduler::{Fcfs, Scheduler, TaskInfo},
+    ServiceComponent,
+};
 use causal_lm::{CausalLM, DecodingMeta, SampleArgs, SampleMeta};
 use common::utok;
+use log::{error, warn};
 use std::{
+    any::Any,
     iter::zip,
-    mem::{replace, size_of},
+    mem::take,
+    panic::{catch_unwind, AssertUnwindSafe},
     str,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{channel, Receiver},
+    time::{timeout_at, Instant},
 };
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// 每个任务已生成但尚未被接收的词数上限，达到上限时暂停这个任务的生成。
+const TOKEN_BUFFER: usize = 16;
 
 pub(super) struct TaskHandle<M: CausalLM> {
-    receiver: Option<UnboundedReceiver<utok>>,
+    id: usize,
+    receiver: Option<Receiver<Option<utok>>>,
     cache: Arc<Mutex<Option<Cache<M::Storage>>>>,
-    buffer: Utf8Buffer,
+    prompt_logprobs: Arc<Mutex<Option<Vec<f32>>>>,
+    detokenizer: Detokenizer,
+    hooks: Arc<Hooks>,
+    window: TextWindow,
+    /// 已输出的全部文本，审核替换过文本时代替生成的词进入对话。
+    emitted: String,
+    /// 审核替换过输出的文本。
+    redacted: bool,
+    /// 尚未随文本输出的词。
+    pending: Vec<utok>,
+    start: Instant,
+    num_prompt: usize,
+    num_generated: usize,
+    finish: Option<FinishReason>,
+    deadline: Option<Instant>,
+    token_timeout: Option<Duration>,
 }
 
 impl<M: CausalLM> TaskHandle<M> {
@@ -21,42 +59,245 @@ impl<M: CausalLM> TaskHandle<M> {
     pub fn take(&mut self) -> Cache<M::Storage> {
         // 停止响应接收
         let _ = self.receiver.take();
+        let reason = *self.finish.get_or_insert(FinishReason::Cancelled);
+        let (id, n) = (self.id, self.num_generated);
+        self.hooks.emit(|h| h.on_finish(id, reason, n));
         // 取走 cache
         self.cache.lock().unwrap().take().unwrap()
     }
+
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish
+    }
+
+    /// 审核对本次生成的修改，没有修改时为 `None`。
+    pub fn moderated(&mut self) -> Option<Moderated> {
+        match self.finish {
+            Some(FinishReason::ContentFilter) => Some(Moderated::Aborted),
+            _ if self.redacted => Some(Moderated::Redacted(take(&mut self.emitted))),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn num_prompt(&self) -> usize {
+        self.num_prompt
+    }
+
+    #[inline]
+    pub fn num_generated(&self) -> usize {
+        self.num_generated
+    }
+
+    /// 提示词中除第一个词以外每个词的对数概率，首次前向传播之前或模型不支持时为 `None`。
+    #[inline]
+    pub fn prompt_logprobs(&self) -> Option<Vec<f32>> {
+        self.prompt_logprobs.lock().unwrap().clone()
+    }
+
+    /// 带上暂存的词，生成一段输出。
+    fn chunk(&mut self, text: String) -> Chunk {
+        self.emitted.push_str(&text);
+        Chunk {
+            text,
+            tokens: take(&mut self.pending),
+            logprobs: None,
+            elapsed: self.start.elapsed(),
+            finish_reason: self.finish,
+        }
+    }
+
+    /// 结束生成，输出最后一段。
+    fn finish(&mut self, reason: FinishReason, text: String) -> Chunk {
+        self.finish = Some(reason);
+        self.chunk(text)
+    }
+
+    /// 输出审核替换的文本。
+    fn redact(&mut self, text: String) -> Chunk {
+        self.redacted = true;
+        self.chunk(text)
+    }
+}
+
+/// 审核对生成的修改，决定会话中保存的回答。
+pub(super) enum Moderated {
+    /// 生成被终止，回答不进入对话。
+    Aborted,
+    /// 输出的文本被替换，对话中保存实际输出的全部文本。
+    Redacted(String),
 }
 
 impl<M: CausalLM> ServiceComponent<M> {
-    pub(super) fn infer(&self, sample: SampleArgs, mut cache: Cache<M::Storage>) -> TaskHandle<M> {
-        let max = self.handle.model.max_seq_len() as usize;
+    pub(super) fn infer(
+        &self,
+        sample: SampleArgs,
+        stop: StopArgs,
+        detokenize: DetokenizeArgs,
+        max: usize,
+        cache: Cache<M::Storage>,
+    ) -> TaskHandle<M> {
+        self.launch(sample, stop, detokenize, max, cache, |task| task)
+    }
+
+    /// 与 [`infer`](Self::infer) 相同，并在首次前向传播时计算提示词的对数概率。
+    pub(super) fn infer_echo(
+        &self,
+        sample: SampleArgs,
+        stop: StopArgs,
+        detokenize: DetokenizeArgs,
+        max: usize,
+        cache: Cache<M::Storage>,
+    ) -> TaskHandle<M> {
+        self.launch(sample, stop, detokenize, max, cache, Task::echo)
+    }
+
+    /// 只计算缓存中待缓存的部分，不生成新的词；任务结束时缓存已完成计算。
+    pub(super) fn prefill(&self, max: usize, cache: Cache<M::Storage>) -> TaskHandle<M> {
+        self.launch(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            max,
+            cache,
+            Task::prefill_only,
+        )
+    }
+
+    /// 只计算提示词的对数概率，不生成新的词。
+    pub(super) fn score(&self, max: usize, cache: Cache<M::Storage>) -> TaskHandle<M> {
+        self.launch(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            max,
+            cache,
+            |task| task.prefill_only().echo(),
+        )
+    }
+
+    fn launch(
+        &self,
+        sample: SampleArgs,
+        stop: StopArgs,
+        detokenize: DetokenizeArgs,
+        max: usize,
+        mut cache: Cache<M::Storage>,
+        configure: impl FnOnce(Task<M::Storage>) -> Task<M::Storage>,
+    ) -> TaskHandle<M> {
         cache.reset_within_start_and_end_range(max / 4, max / 4, max / 4 * 3);
         // 生成推理任务与会话的交互管道
-        let cache = Arc::new(Mutex::new(Some(cache)));
-        let (sender, receiver) = unbounded_channel();
+        let id = self.handle.next_id.fetch_add(1, Relaxed);
+        let num_prompt = cache.query().len();
         self.handle
-            .batcher
-            .enq(Task::new(cache.clone(), sample, sender));
+            .metrics
+            .request(cache.context_len() - num_prompt, num_prompt);
+        let hooks = self.handle.hooks.clone();
+        hooks.emit(|h| h.on_request(id, num_prompt));
+
+        let deadline = stop.timeout.map(|t| Instant::now() + t);
+        let token_timeout = stop.token_timeout;
+        let cache = Arc::new(Mutex::new(Some(cache)));
+        let (sender, receiver) = channel(TOKEN_BUFFER);
+        let task = configure(Task::new(id, cache.clone(), sample, stop, max, sender));
+        let prompt_logprobs = task.prompt_logprobs();
+        self.handle.batcher.enq(task);
         TaskHandle {
+            id,
             receiver: Some(receiver),
             cache,
-            buffer: Default::default(),
+            prompt_logprobs,
+            detokenizer: Detokenizer::new(detokenize),
+            hooks,
+            window: Default::default(),
+            emitted: String::new(),
+            redacted: false,
+            pending: Vec::new(),
+            start: Instant::now(),
+            num_prompt,
+            num_generated: 0,
+            finish: None,
+            deadline,
+            token_timeout,
         }
     }
 
-    pub(super) async fn decode(&self, x: &mut TaskHandle<M>) -> Option<String> {
+    pub(super) async fn decode(&self, x: &mut TaskHandle<M>) -> Option<Chunk> {
+        // 结束的原因已随最后一段输出
+        if x.finish.is_some() {
+            return None;
+        }
         loop {
-            let s = x.receiver.as_mut().unwrap().recv().await.map(|token| {
-                // detokenize and denormalize the token
-                let ServiceComponent {
-                    normalizer,
-                    tokenizer,
-                    ..
-                } = self;
-                normalizer.decode(tokenizer.decode(token))
-            })?;
-            let s = x.buffer.push(s.as_bytes());
+            // 等待下一个词，不超过总时限和单个词的时限
+            let limit = match (x.deadline, x.token_timeout) {
+                (Some(d), Some(t)) => Some(d.min(Instant::now() + t)),
+                (d, t) => d.or_else(|| t.map(|t| Instant::now() + t)),
+            };
+            let recv = x.receiver.as_mut()?.recv();
+            let token = match limit {
+                Some(limit) => match timeout_at(limit, recv).await {
+                    Ok(token) => token,
+                    Err(_) => {
+                        // 丢弃接收端以终止推理任务，释放其在批中的位置
+                        let _ = x.receiver.take();
+                        self.handle.metrics.timeout();
+                        return Some(x.finish(FinishReason::Timeout, String::new()));
+                    }
+                },
+                None => recv.await,
+            };
+            let token = match token {
+                Some(Some(token)) => token,
+                Some(None) => return Some(x.finish(FinishReason::Error, String::new())),
+                None => {
+                    // 输出还原文本时暂存的剩余文本
+                    let s = x.detokenizer.flush();
+                    if s.is_empty() {
+                        return Some(x.finish(FinishReason::Stop, s));
+                    }
+                    let hooks = x.hooks.clone();
+                    return Some(match hooks.moderate(x.window.push(&s)).await {
+                        Verdict::Pass => x.finish(FinishReason::Stop, s),
+                        Verdict::Redact(s) => {
+                            x.finish = Some(FinishReason::Stop);
+                            x.redact(s)
+                        }
+                        Verdict::Abort => x.finish(FinishReason::ContentFilter, String::new()),
+                    });
+                }
+            };
+            // detokenize and denormalize the token
+            let ServiceComponent {
+                normalizer,
+                tokenizer,
+                ..
+            } = self;
+            let s = if x.detokenizer.args().skip_special_tokens && tokenizer.is_special(token) {
+                String::new()
+            } else {
+                // 单字节词可能不是完整的 utf-8 字符，由还原器拼接
+                let bytes = tokenizer.decode_bytes(token);
+                match str::from_utf8(bytes) {
+                    Ok(s) => x.detokenizer.push(normalizer.decode(s).as_bytes()),
+                    Err(_) => x.detokenizer.push(bytes),
+                }
+            };
+            x.num_generated += 1;
+            x.pending.push(token);
+            x.hooks.emit(|h| h.on_token(x.id, token, &s));
             if !s.is_empty() {
-                return Some(s);
+                // 审核最近生成的文本
+                let hooks = x.hooks.clone();
+                return Some(match hooks.moderate(x.window.push(&s)).await {
+                    Verdict::Pass => x.chunk(s),
+                    Verdict::Redact(s) => x.redact(s),
+                    Verdict::Abort => {
+                        // 丢弃接收端以终止推理任务
+                        let _ = x.receiver.take();
+                        x.finish(FinishReason::ContentFilter, String::new())
+                    }
+                });
             }
         }
     }
@@ -64,7 +305,19 @@ impl<M: CausalLM> ServiceComponent<M> {
 
 pub(crate) struct Dispatcher<M: CausalLM> {
     pub model: M,
+    pub hooks: Arc<Hooks>,
+    pub metrics: Metrics,
+    pub scheduler: RwLock<Arc<dyn Scheduler>>,
     pub(super) batcher: Batcher<Task<M::Storage>>,
+    next_id: AtomicUsize,
+    /// 预填充批处理窗口的微秒数。
+    prefill_window: AtomicU64,
+    /// 每轮前向传播最多执行的任务数，0 表示不限制。
+    max_batch: AtomicUsize,
+    /// 每轮前向传播最多解码的任务数，0 表示不限制。
+    max_decode: AtomicUsize,
+    /// 相邻两轮前向传播开始的最小间隔微秒数。
+    step_pacing: AtomicU64,
 }
 
 impl<M: CausalLM> From<M> for Dispatcher<M> {
@@ -72,7 +325,15 @@ impl<M: CausalLM> From<M> for Dispatcher<M> {
     fn from(model: M) -> Self {
         Self {
             model,
+            hooks: Default::default(),
+            metrics: Default::default(),
+            scheduler: RwLock::new(Arc::new(Fcfs)),
             batcher: Batcher::new(),
+            next_id: AtomicUsize::new(0),
+            prefill_window: AtomicU64::new(0),
+            max_batch: AtomicUsize::new(0),
+            max_decode: AtomicUsize::new(0),
+            step_pacing: AtomicU64::new(0),
         }
     }
 }
@@ -83,6 +344,32 @@ impl<M: CausalLM> Dispatcher<M> {
     pub fn stop(&self) {
         self.batcher.shutdown();
     }
+
+    #[inline]
+    pub fn set_prefill_window(&self, window: Duration) {
+        self.prefill_window.store(window.as_micros() as _, Relaxed);
+    }
+
+    #[inline]
+    pub fn set_max_batch(&self, max_batch: usize) {
+        self.max_batch.store(max_batch, Relaxed);
+    }
+
+    #[inline]
+    pub fn set_max_decode(&self, max_decode: usize) {
+        self.max_decode.store(max_decode, Relaxed);
+    }
+
+    #[inline]
+    pub fn set_step_pacing(&self, pacing: Duration) {
+        self.step_pacing.store(pacing.as_micros() as _, Relaxed);
+    }
+
+    #[inline]
+    pub fn set_scheduler(&self, scheduler: Arc<dyn Scheduler>) {
+        self.metrics.reset(scheduler.name());
+        *self.scheduler.write().unwrap() = scheduler;
+    }
 }
 
 impl<M> Dispatcher<M>
@@ -91,7 +378,35 @@ where
     M::Storage: Send,
 {
     pub fn run(self: Arc<Self>) {
-        while let Some(tasks) = Some(self.batcher.deq()).filter(|t| !t.is_empty()) {
+        let mut last_step = Instant::now();
+        while let Some(mut tasks) =
+            Some(self.dequeued(self.batcher.deq())).filter(|t| !t.is_empty())
+        {
+            // 空闲时收到的新任务等待同时到达的任务，一起预填充
+            let window = Duration::from_micros(self.prefill_window.load(Relaxed));
+            if !window.is_zero() && tasks.iter().all(Task::is_fresh) {
+                thread::sleep(window);
+                tasks.extend(self.dequeued(self.batcher.take()));
+            }
+            // 两轮前向传播间隔过短时等待，期间到达的任务加入本轮
+            let pacing = Duration::from_micros(self.step_pacing.load(Relaxed));
+            let wait = pacing.saturating_sub(last_step.elapsed());
+            if !wait.is_zero() {
+                thread::sleep(wait);
+                tasks.extend(self.dequeued(self.batcher.take()));
+            }
+            last_step = Instant::now();
+            let (mut tasks, deferred) = self.schedule(tasks);
+            // 采样参数需要读取缓存，在锁定缓存之前生成
+            let eos = self.model.eos_tokens();
+            let num_decode = tasks.iter().map(Task::num_decode).collect::<Vec<_>>();
+            let echo = tasks.iter().map(Task::is_echoing).collect::<Vec<_>>();
+            let args = zip(&tasks, &num_decode)
+                .map(|(t, &num_decode)| SampleMeta {
+                    num_decode,
+                    args: t.sample(eos),
+                })
+                .collect::<Vec<_>>();
             // 锁定所有请求的缓存
             let mut caches = tasks.iter().map(Task::lock_cache).collect::<Vec<_>>();
             // 统计每个任务的查询长度
@@ -102,51 +417,115 @@ where
             if num_query.iter().all(|&n| n == 0) {
                 continue;
             }
-            // 词嵌入
-            let queries = caches
-                .iter()
-                .filter_map(|c| c.as_ref().map(Cache::query).filter(|q| !q.is_empty()))
-                .flatten()
-                .copied();
-            let token_embedded = self.model.token_embed(queries);
-            // 推理
-            let queries = caches
-                .iter_mut()
-                .filter_map(|c| c.as_mut().map(Cache::as_ctx).filter(|q| q.seq_len() > 0));
-            let hidden_state = self.model.forward(queries, token_embedded);
+            // 解码整个查询的任务计算每个词作为下一个词的对数概率
+            let mut targets = Vec::new();
+            let mut row = 0;
+            for ((cache, &n), &echoing) in zip(zip(&caches, &num_decode), &echo) {
+                if let Some(cache) = cache.as_ref().filter(|_| echoing && n > 0) {
+                    let next = cache.query().into_iter().skip(1).copied();
+                    targets.extend(next.enumerate().map(|(i, t)| (row + i, t)));
+                }
+                row += n;
+            }
+            // 后端 panic 时只影响本批任务，推理线程继续运行
+            let tokens = catch_unwind(AssertUnwindSafe(|| {
+                // 词嵌入
+                let queries = caches
+                    .iter()
+                    .filter_map(|c| c.as_ref().map(Cache::query).filter(|q| !q.is_empty()))
+                    .flatten()
+                    .copied();
+                let token_embedded = self.model.token_embed(queries);
+                // 推理
+                let queries = caches
+                    .iter_mut()
+                    .filter_map(|c| c.as_mut().map(Cache::as_ctx).filter(|q| q.seq_len() > 0));
+                let hidden_state = self.model.forward(queries, token_embedded);
+                // 解码并采样
+                let decoding =
+                    zip(&num_query, &num_decode).map(|(&num_query, &num_decode)| DecodingMeta {
+                        num_query,
+                        num_decode,
+                    });
+                if !echo.contains(&true) {
+                    (self.model.decode_sample(decoding, hidden_state, args), None)
+                } else {
+                    let logits = self.model.decode(decoding, hidden_state);
+                    let logprobs = self.model.logprobs(&logits, &targets);
+                    (self.model.sample(args, logits), logprobs)
+                }
+            }));
             drop(caches);
-            // 采样
-            let num_decode = tasks
-                .iter()
-                .map(|t| if t.is_alive() { 1 } else { 0 })
+            let (tokens, logprobs) = match tokens {
+                Ok(ans) => ans,
+                Err(payload) => {
+                    self.recover(tasks, payload);
+                    continue;
+                }
+            };
+            // 通知首次完成前向传播的任务，并统计吞吐量
+            let (mut batch, mut prefilled, mut decoded) = (0, 0, 0);
+            for (task, &n) in zip(&mut tasks, &num_query) {
+                // 单独执行成功的任务不再需要单独执行
+                task.set_suspect(false);
+                if n == 0 {
+                    continue;
+                }
+                batch += 1;
+                let first = task.prefill_done();
+                if first {
+                    let id = task.id();
+                    self.hooks.emit(|h| h.on_prefill_done(id, n));
+                }
+                if first || n > 1 {
+                    prefilled += n;
+                } else {
+                    decoded += n;
+                }
+            }
+            self.metrics.step(batch, prefilled, decoded, deferred);
+            // 分发提示词的对数概率，只保留每个任务最后一行采样的词
+            let mut logprobs = logprobs.map(Vec::into_iter);
+            let mut tokens = tokens.into_iter();
+            let tokens = zip(zip(&tasks, &num_decode), &echo)
+                .filter(|((_, &n), _)| n > 0)
+                .map(|((task, &n), &echoing)| {
+                    if echoing {
+                        if let Some(logprobs) = logprobs.as_mut() {
+                            let prompt = logprobs.by_ref().take(n - 1).collect();
+                            *task.prompt_logprobs().lock().unwrap() = Some(prompt);
+                        }
+                    }
+                    tokens.by_ref().take(n).last().unwrap()
+                })
                 .collect::<Vec<_>>();
-            let decoding =
-                zip(num_query, &num_decode).map(|(num_query, &num_decode)| DecodingMeta {
-                    num_query,
-                    num_decode,
-                });
-            let logits = self.model.decode(decoding, hidden_state);
-            // 采样
-            let args = zip(&tasks, &num_decode).map(|(t, &num_decode)| SampleMeta {
-                num_decode,
-                args: t.sample().clone(),
-            });
-            let tokens = self.model.sample(args, logits);
+            // 只计算缓存的任务不解码，在此结束
+            for task in tasks.iter().filter(|t| t.is_prefill_only()) {
+                if let Some(cache) = task.lock_cache().as_mut() {
+                    cache.commit();
+                }
+            }
             // 为每次推理启动一个任务执行发射
             let self_ = self.clone();
             tokio::task::spawn_blocking(move || {
-                let eos = self_.model.eos_token();
-                let max = self_.model.max_seq_len() as usize;
-                let end_size = max / 4;
-                let start_size = max / 4;
+                let eos = self_.model.eos_tokens();
                 zip(tasks, num_decode)
                     .filter(|(_, n)| *n > 0)
                     .map(|(t, _)| t)
                     .zip(tokens)
-                    .filter(|(_, token)| *token != eos)
-                    .for_each(|(mut task, token)| {
-                        if task.push(token, start_size, end_size, max) {
-                            self_.batcher.enq(task);
+                    .filter(|(task, _)| !task.is_prefill_only())
+                    .filter(|(task, token)| !eos.contains(token) && !task.is_stop(*token))
+                    .for_each(|(mut task, token)| match task.try_push(token) {
+                        Ok(true) => self_.batcher.enq(task),
+                        Ok(false) => {}
+                        // 接收方来不及接收，暂停这个任务直到缓冲区有空位
+                        Err(token) => {
+                            let self_ = self_.clone();
+                            tokio::spawn(async move {
+                                if task.push(token).await {
+                                    self_.batcher.enq(task);
+                                }
+                            });
                         }
                     });
             });
@@ -154,25 +533,92 @@ where
     }
 }
 
-#[derive(Clone, Default, Debug)]
-struct Utf8Buffer(Vec<u8>);
+impl<M: CausalLM> Dispatcher<M> {
+    /// 统计取出的任务在队列中等待的时间。
+    fn dequeued(&self, queued: Vec<(Task<M::Storage>, Duration)>) -> Vec<Task<M::Storage>> {
+        self.metrics
+            .queued(queued.iter().map(|(_, waited)| *waited));
+        queued.into_iter().map(|(task, _)| task).collect()
+    }
+
+    /// 按调度策略选出本轮执行的任务，推迟的任务放回队列，返回本轮执行的任务和推迟的任务数。
+    fn schedule(&self, tasks: Vec<Task<M::Storage>>) -> (Vec<Task<M::Storage>>, usize) {
+        let mut order = {
+            let caches = tasks.iter().map(Task::lock_cache).collect::<Vec<_>>();
+            let infos = zip(&tasks, &caches)
+                .map(|(task, cache)| TaskInfo {
+                    id: task.id(),
+                    tokens: cache.as_ref().map_or(&[], |c| c.tokens()),
+                    num_query: cache.as_ref().map_or(0, |c| c.query().len()),
+                    num_generated: task.num_generated(),
+                })
+                .collect::<Vec<_>>();
+            self.scheduler.read().unwrap().schedule(&infos)
+        };
+        match self.max_decode.load(Relaxed) {
+            0 => {}
+            n => {
+                // 超出限制的解码任务推迟，不影响预填充的任务
+                let mut decoding = 0;
+                order.retain(|&i| match tasks.get(i) {
+                    Some(task) if !task.is_fresh() => {
+                        decoding += 1;
+                        decoding <= n
+                    }
+                    _ => true,
+                });
+            }
+        }
+        match self.max_batch.load(Relaxed) {
+            0 => {}
+            n => order.truncate(n),
+        }
 
-impl Utf8Buffer {
-    fn push(&mut self, bytes: impl AsRef<[u8]>) -> String {
-        self.0.extend_from_slice(bytes.as_ref());
-        let mut len = match str::from_utf8(&self.0) {
-            Ok(_) => self.0.len(),
-            Err(e) => e.valid_up_to(),
+        let mut tasks = tasks.into_iter().map(Some).collect::<Vec<_>>();
+        let scheduled = order
+            .into_iter()
+            .filter_map(|i| tasks.get_mut(i)?.take())
+            .collect::<Vec<_>>();
+        if scheduled.is_empty() {
+            return (tasks.into_iter().flatten().collect(), 0);
+        }
+        // 曾在出错的批中的任务单独执行，以找出导致出错的任务
+        let (scheduled, isolated) = match scheduled.iter().position(Task::is_suspect) {
+            Some(i) if scheduled.len() > 1 => {
+                let mut rest = scheduled;
+                let task = rest.swap_remove(i);
+                (vec![task], rest)
+            }
+            _ => (scheduled, vec![]),
         };
-        while len + size_of::<char>() <= self.0.len() {
-            len += 1;
-            match str::from_utf8(&self.0[len..]) {
-                Ok(s) => len += s.as_bytes().len(),
-                Err(e) => len += e.valid_up_to(),
+        let mut deferred = 0;
+        for task in tasks.into_iter().flatten().chain(isolated) {
+            self.batcher.enq(task);
+            deferred += 1;
+        }
+        (scheduled, deferred)
+    }
+
+    /// 处理后端的 panic：单独执行的任务以错误结束，否则本批任务都放回队列逐个重试。
+    fn recover(&self, mut tasks: Vec<Task<M::Storage>>, payload: Box<dyn Any + Send>) {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        if tasks.len() == 1 {
+            let task = tasks.pop().unwrap();
+            error!("Task {} failed: {msg}", task.id());
+            task.fail();
+        } else {
+            warn!(
+                "Batch of {} tasks failed: {msg}, retrying one by one",
+                tasks.len()
+            );
+            for mut task in tasks {
+                task.set_suspect(true);
+                self.batcher.enq(task);
             }
         }
-        let s = self.0.split_off(len);
-        let s = replace(&mut self.0, s);
-        unsafe { String::from_utf8_unchecked(s) }
     }
 }
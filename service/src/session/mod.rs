@@ -1,21 +1,33 @@
-﻿mod batcher;
+mod batcher;
 mod cache;
+mod chunk;
+mod detokenizer;
 mod dialog;
 mod dispatch;
 mod task;
 
-use crate::ServiceComponent;
+use crate::{
+    choice::Choices,
+    template::{ChatTemplate, Template},
+    FinishReason, ServiceComponent,
+};
 use cache::Cache;
-use causal_lm::{CausalLM, SampleArgs};
+use causal_lm::{CausalLM, SampleArgs, ShapeError};
+use common::utok;
 use dialog::Dialog;
-use dispatch::TaskHandle;
-use log::info;
+use dispatch::{Moderated, TaskHandle};
+use log::{info, warn};
 use std::{
+    borrow::Cow,
     cmp::Ordering::{Equal, Greater, Less},
     error, fmt,
     sync::Arc,
+    time::Duration,
     vec,
 };
+use tokenizer::EncodeError;
+
+pub use chunk::Chunk;
 
 pub(crate) use dispatch::Dispatcher;
 
@@ -23,11 +35,100 @@ pub(crate) use dispatch::Dispatcher;
 pub struct Session<M: CausalLM> {
     component: Arc<ServiceComponent<M>>,
     pub sample: SampleArgs,
+    pub stop: StopArgs,
+    pub detokenize: DetokenizeArgs,
+    pub truncation: Truncation,
+    /// 会话的缓存中参与推理的部分最多占用的字节数，超过时按截断策略丢弃较早的对话。
+    pub cache_budget: Option<usize>,
+    /// 非空时，回答只能是其中的一个选项。
+    pub choices: Vec<String>,
+    /// 推理使用的适配器的名字，参见 [`Service::adapter`](crate::Service::adapter)；切换时会话的缓存需要重新计算。
+    pub adapter: Option<String>,
+    /// 推理使用的控制向量的名字和强度，参见 [`Service::steering`](crate::Service::steering)；切换时会话的缓存需要重新计算。
+    pub steering: Option<(String, f32)>,
+    /// 接在会话开头的软提示词的名字，参见 [`Service::soft_prompt`](crate::Service::soft_prompt)；切换时会话的缓存需要重新计算。
+    pub soft_prompt: Option<String>,
+    /// 替代模型默认模板的对话模板，只影响之后填充的提示词。
+    pub template: Option<ChatTemplate>,
+    /// 总结对话和引入文档时插入的文本。
+    pub prompts: Arc<Prompts>,
 
     dialog: Dialog,
     cache: Option<Cache<M::Storage>>,
 }
 
+/// 会话的缓存占用。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct CacheUsage {
+    /// 缓存窗口中参与推理的词数。
+    pub tokens: usize,
+    /// 参与推理的词占用的字节数。
+    pub used_bytes: usize,
+    /// 为缓存分配的字节数。
+    pub allocated_bytes: usize,
+}
+
+/// 结束生成的条件。
+#[derive(Clone, Default, Debug)]
+pub struct StopArgs {
+    /// 除模型定义的结束符以外，额外结束生成的词。
+    pub tokens: Vec<utok>,
+    /// 生成的词数达到这个值之前，屏蔽所有结束符。
+    pub min_new_tokens: usize,
+    /// 从开始推理到生成结束的时限。
+    pub timeout: Option<Duration>,
+    /// 等待每个词的时限。
+    pub token_timeout: Option<Duration>,
+}
+
+/// 把生成的词还原为文本的方式，默认逐词拼接，不做任何处理。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct DetokenizeArgs {
+    /// 不输出特殊词（未知词、开始符和结束符）。
+    pub skip_special_tokens: bool,
+    /// 去掉标点和缩写前的空格，与 transformers 的 `clean_up_tokenization_spaces` 相同。
+    pub clean_up_tokenization_spaces: bool,
+    /// 去掉生成的文本开头的一个空格，即 sentencepiece 为第一个词添加的 `▁`。
+    pub strip_leading_space: bool,
+}
+
+/// 会话总结对话和引入文档时插入的固定文本，应与模型使用的语言一致。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Prompts {
+    /// 要求模型总结对话的提示词。
+    pub summarize: String,
+    /// 以总结替换对话时，总结前的说明。
+    pub summary_prefix: String,
+    /// 以总结替换对话时，模型对总结的回答。
+    pub summary_reply: String,
+    /// 以文档开始对话时，文档前的说明。
+    pub document_prefix: String,
+    /// 以文档开始对话时，模型对文档的回答。
+    pub document_reply: String,
+}
+
+impl Default for Prompts {
+    fn default() -> Self {
+        Self {
+            summarize: "请用简洁的语言总结以上对话的要点。".into(),
+            summary_prefix: "以下是之前对话的摘要：\n".into(),
+            summary_reply: "好的。".into(),
+            document_prefix: "请参考以下文档回答之后的问题：\n".into(),
+            document_reply: "好的，我已阅读这份文档。".into(),
+        }
+    }
+}
+
+/// 上下文将要溢出时截断对话历史的策略。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum Truncation {
+    /// 保留开头和结尾固定数量的词，可能截断在句子中间。
+    #[default]
+    Window,
+    /// 丢弃最早的整轮对话，`preserve_first` 时保留第一个句子（通常是系统提示词）。
+    DropOldestTurns { preserve_first: bool },
+}
+
 /// 对话错误类型。
 ///
 /// 目前唯一可能的对话错误是增量对话中句子位置异常。
@@ -48,6 +149,16 @@ impl<M: CausalLM> From<Arc<ServiceComponent<M>>> for Session<M> {
         Self {
             component,
             sample: Default::default(),
+            stop: Default::default(),
+            detokenize: Default::default(),
+            truncation: Default::default(),
+            cache_budget: None,
+            choices: Vec::new(),
+            adapter: None,
+            steering: None,
+            soft_prompt: None,
+            template: None,
+            prompts: Default::default(),
 
             dialog: Default::default(),
             cache: Default::default(),
@@ -61,23 +172,66 @@ impl<M: CausalLM> Session<M> {
         self.dialog.num_sentences()
     }
 
-    /// 复制当前会话。
-    pub fn fork(&self) -> Self {
-        Self {
+    /// 对话中的总词数。
+    #[inline]
+    pub fn num_tokens(&self) -> usize {
+        self.dialog.num_tokens()
+    }
+
+    /// 会话的缓存占用，会话从未填充对话时返回 `None`。
+    pub fn cache_usage(&self) -> Option<CacheUsage> {
+        let cache = self.cache.as_ref()?;
+        let max = self.component.handle.model.max_seq_len() as usize;
+        let allocated_bytes = cache.allocated_bytes();
+        let tokens = cache.context_len();
+        Some(CacheUsage {
+            tokens,
+            used_bytes: allocated_bytes / max * tokens,
+            allocated_bytes,
+        })
+    }
+
+    /// 缓存预算允许的最大上下文长度。
+    fn max_context(&self) -> usize {
+        let max = self.component.handle.model.max_seq_len() as usize;
+        match (self.cache_budget, &self.cache) {
+            (Some(budget), Some(cache)) => {
+                let per_token = (cache.allocated_bytes() / max).max(1);
+                (budget / per_token).clamp(max.min(16), max)
+            }
+            _ => max,
+        }
+    }
+
+    /// 复制当前会话，缓存的有效长度超出模型的最大长度时返回错误。
+    pub fn fork(&self) -> Result<Self, ShapeError> {
+        Ok(Self {
             component: self.component.clone(),
             sample: self.sample.clone(),
+            stop: self.stop.clone(),
+            detokenize: self.detokenize,
+            truncation: self.truncation,
+            cache_budget: self.cache_budget,
+            choices: self.choices.clone(),
+            adapter: self.adapter.clone(),
+            steering: self.steering.clone(),
+            soft_prompt: self.soft_prompt.clone(),
+            template: self.template.clone(),
+            prompts: self.prompts.clone(),
             dialog: self.dialog.clone(),
             cache: self
                 .cache
                 .as_ref()
-                .map(|cache| cache.duplicate(&self.component.handle.model)),
-        }
+                .map(|cache| cache.duplicate(&self.component.handle.model))
+                .transpose()?,
+        })
     }
 
     /// 回滚对话到第 `dialog_pos` 个句子。
     pub fn revert(&mut self, dialog_pos: usize) -> Result<(), ChatError> {
         match dialog_pos.cmp(&self.dialog.num_sentences()) {
             Less => {
+                let len = self.max_context();
                 let cache = self.cache.as_mut().unwrap();
 
                 self.dialog.revert(dialog_pos);
@@ -85,7 +239,6 @@ impl<M: CausalLM> Session<M> {
                 if cache.revert(self.dialog.num_tokens()).is_none()
                     || cache.get_last_cached_range_len() < last_prompt
                 {
-                    let len = self.component.handle.model.max_seq_len() as usize;
                     let (tokens, pos) = self.dialog.window(len);
                     cache.reset_with(tokens, pos);
                 }
@@ -96,51 +249,247 @@ impl<M: CausalLM> Session<M> {
         }
     }
 
-    /// 用 dialog 填充会话。
-    pub fn extend<'a>(&mut self, dialog: impl IntoIterator<Item = &'a str>) {
-        let eos = self.component.handle.model.eos_token();
-        let cache = self
-            .cache
-            .get_or_insert_with(|| Cache::new(&self.component.handle.model, vec![]));
-        // 填充对话
+    /// 用 dialog 填充会话。任何句子过长时返回错误，会话不变。
+    pub fn extend<'a>(
+        &mut self,
+        dialog: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), EncodeError> {
+        let eos = self.component.handle.model.eos_tokens()[0];
+        // 全部编码成功后再填充对话
+        let mut prompt = self.dialog.num_sentences() % 2 == 0;
+        let mut sentences = Vec::new();
         for s in dialog {
-            let prompt = self.dialog.num_sentences() % 2 == 0;
-
             let s = if prompt {
-                self.component.template.apply_chat(s)
+                apply_chat(&self.component, self.template.as_ref(), s)
             } else {
                 s.into()
             };
-            let s = self.component.normalizer.encode(&s);
-            let mut s = self.component.tokenizer.encode(&s);
+            let mut s = self.component.encode(&s)?;
             if !prompt {
                 s.push(eos);
             }
+            sentences.push(s);
+            prompt = !prompt;
+        }
+        for s in sentences {
+            self.push_sentence(s);
+        }
+        Ok(())
+    }
+
+    /// 用已编码的句子填充会话，不应用模板，回答也不补充结束符。
+    pub fn extend_tokens(&mut self, dialog: impl IntoIterator<Item = Vec<utok>>) {
+        for s in dialog {
+            self.push_sentence(s);
+        }
+    }
+
+    fn push_sentence(&mut self, s: Vec<utok>) {
+        let cache = self
+            .cache
+            .get_or_insert_with(|| Cache::new(&self.component.handle.model, vec![]));
+        cache.extend(&s);
+        self.dialog.push(s);
+        assert_eq!(cache.end(), self.dialog.num_tokens());
+    }
+
+    /// 以一轮对话引入参考文档：文档经对话模板放入提示词，回答是固定的确认。
+    ///
+    /// 只能在对话以回答结尾（或为空）时引入，文档过长时返回错误。
+    pub fn ground(&mut self, document: &str) -> Result<(), EncodeError> {
+        assert_eq!(self.dialog.num_sentences() % 2, 0);
+        let prompts = self.prompts.clone();
+        let document = format!("{}{document}", prompts.document_prefix);
+        self.extend([&*document, &*prompts.document_reply])
+    }
+
+    /// 计算对话中所有词的缓存而不生成回答，之后复制的会话直接复用。
+    pub async fn prefill(&mut self) {
+        let max = self.max_context();
+        let Some(mut cache) = self.cache.take() else {
+            return;
+        };
+        self.select(&mut cache);
+        if !cache.query().is_empty() {
+            let mut handle = self.component.prefill(max, cache);
+            while self.component.decode(&mut handle).await.is_some() {}
+            cache = handle.take();
+        }
+        self.cache = Some(cache);
+    }
 
-            cache.extend(&s);
-            self.dialog.push(s);
-            assert_eq!(cache.end(), self.dialog.num_tokens());
+    /// 用模型总结最早的 `turns` 轮对话，并以一轮包含总结的对话替换它们。
+    ///
+    /// 替换后对话的句子数会改变，且需要重新计算整个缓存。
+    /// 只能在对话以回答结尾时总结，并且至少保留最后一轮对话。
+    ///
+    /// 总结超过 `max_tokens` 个词仍未结束或为空时放弃总结，会话保持不变，返回 `false`。
+    pub async fn summarize(&mut self, turns: usize, max_tokens: usize) -> bool {
+        let n = turns * 2;
+        let num_sentences = self.dialog.num_sentences();
+        if n == 0 || n >= num_sentences || num_sentences % 2 != 0 || self.cache.is_none() {
+            return false;
+        }
+        let prompts = self.prompts.clone();
+        let component = self.component.clone();
+        let encode = |s: &str| component.tokenizer.encode(&component.normalizer.encode(s));
+        // 以同一个模型生成总结
+        let mut prompt = (0..n)
+            .flat_map(|i| self.dialog.sentence(i))
+            .copied()
+            .collect::<Vec<_>>();
+        let template = self.template.as_ref();
+        prompt.extend(encode(&apply_chat(
+            &component,
+            template,
+            &prompts.summarize,
+        )));
+        let cache = Cache::new(&component.handle.model, prompt);
+        let max = component.handle.model.max_seq_len() as usize;
+        let mut handle = component.infer(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            max,
+            cache,
+        );
+        let mut summary = String::new();
+        while let Some(chunk) = component.decode(&mut handle).await {
+            summary.push_str(&chunk.text);
+            if handle.num_generated() > max_tokens {
+                break;
+            }
+        }
+        let finished = handle.finish_reason() == Some(FinishReason::Stop);
+        let _ = handle.take();
+        if !finished || summary.trim().is_empty() {
+            warn!("Summary of {turns} turns abandoned");
+            return false;
         }
+        // 重建对话
+        let eos = component.handle.model.eos_tokens()[0];
+        let mut dialog = Dialog::default();
+        dialog.push(encode(&apply_chat(
+            &component,
+            template,
+            &format!("{}{}", prompts.summary_prefix, summary.trim()),
+        )));
+        let mut reply = encode(&prompts.summary_reply);
+        reply.push(eos);
+        dialog.push(reply);
+        for i in n..num_sentences {
+            dialog.push(self.dialog.sentence(i).to_vec());
+        }
+        info!(
+            "Summarized {turns} turns, {} tokens -> {} tokens",
+            self.dialog.num_tokens(),
+            dialog.num_tokens()
+        );
+        self.dialog = dialog;
+        // 重建缓存
+        let (tokens, pos) = self.dialog.window(self.max_context());
+        self.cache.as_mut().unwrap().reset_with(tokens, pos);
+        true
     }
 
     /// 启动推理任务，返回忙会话。
     pub fn chat(&mut self) -> BusySession<M> {
-        let sample = self.sample.clone();
-        let cache = self.cache.take().unwrap();
-        let handle = self.component.infer(sample, cache);
+        let mut sample = self.sample.clone();
+        let stop = self.stop.clone();
+        let max = self.max_context();
+        let mut cache = self.cache.take().unwrap();
+        self.select(&mut cache);
+        self.truncate(&mut cache, max);
+        if !self.choices.is_empty() {
+            // 选项与回答一样编码，回答从缓存中现有的词之后开始
+            let component = &self.component;
+            // 过长的选项不可能是回答，直接忽略
+            let choices = self.choices.iter().filter_map(|s| component.encode(s).ok());
+            let eos = component.handle.model.eos_tokens()[0];
+            let choices = Choices::new(choices, eos, cache.tokens().len());
+            sample.processors.push(Arc::new(choices));
+        }
+        let handle = self
+            .component
+            .infer(sample, stop, self.detokenize, max, cache);
         BusySession {
             session: self,
             handle,
         }
     }
 
-    fn restore_cache(&mut self, mut cache: Cache<M::Storage>) {
+    /// 按名字为缓存选择适配器、控制向量和软提示词。
+    fn select(&self, cache: &mut Cache<M::Storage>) {
+        let model = &self.component.handle.model;
+        cache.set_adapter(self.adapter.as_deref().and_then(|name| model.adapter(name)));
+        cache.set_steering(
+            self.steering
+                .as_ref()
+                .and_then(|(name, strength)| Some((model.steering(name)?, *strength))),
+        );
+        cache.set_soft_prompt(
+            self.soft_prompt
+                .as_deref()
+                .and_then(|name| model.soft_prompt(name))
+                .unwrap_or_default(),
+        );
+    }
+
+    /// 上下文将要溢出时按截断策略丢弃整轮对话，只重新计算保留的尾部。
+    fn truncate(&self, cache: &mut Cache<M::Storage>, max: usize) {
+        let Truncation::DropOldestTurns { preserve_first } = self.truncation else {
+            return;
+        };
+        if cache.context_len() < max / 4 * 3 {
+            return;
+        }
+        let end = cache.end();
+        // 保留的开头必须仍在缓存窗口中
+        let head = if preserve_first && cache.start() == 0 {
+            self.dialog.sentence_start(1)
+        } else {
+            0
+        };
+        // 从一轮对话的开头截断，保留的部分为生成留出一半上下文
+        let Some(tail_start) = (2..self.dialog.num_sentences())
+            .step_by(2)
+            .map(|i| self.dialog.sentence_start(i))
+            .find(|&start| start >= cache.start() && head + end - start <= max / 2)
+        else {
+            return;
+        };
+        let tail = end - tail_start;
+        info!("Drop turns in {head}..{tail_start}, {tail} tokens to prefill");
+        cache.reset_within_start_and_end_range(head, tail, head + tail);
+    }
+
+    fn restore_cache(&mut self, mut cache: Cache<M::Storage>, moderated: Option<Moderated>) {
         let end = self.dialog.num_tokens();
+        let eos = self.component.handle.model.eos_tokens()[0];
         if cache.end() > end {
-            // 无论忙会话为何丢弃，只要生成了新句子，就补充一个结束符
-            cache.push(self.component.handle.model.eos_token());
-            // 只要忙会话收集到任何 token，就生成一个新的句子
-            self.dialog.push(cache.slice_tail(end).to_vec());
+            match moderated {
+                None => {
+                    // 无论忙会话为何丢弃，只要生成了新句子，就补充一个结束符
+                    cache.push(eos);
+                    // 只要忙会话收集到任何 token，就生成一个新的句子
+                    self.dialog.push(cache.slice_tail(end).to_vec());
+                }
+                Some(moderated) => {
+                    // 审核修改过的回答不保留生成的词，对话与客户端收到的一致
+                    if cache.revert(end).is_none() {
+                        let (tokens, pos) = self.dialog.window(self.max_context());
+                        cache.reset_with(tokens, pos);
+                    }
+                    if let Moderated::Redacted(text) = moderated {
+                        // 替换的文本作为回答，下次推理时计算缓存
+                        // 过长的替换文本视为空回答
+                        let mut s = self.component.encode(&text).unwrap_or_default();
+                        s.push(eos);
+                        cache.extend(&s);
+                        self.dialog.push(s);
+                    }
+                }
+            }
         }
         cache.cleanup_before_start();
         info!("Cache restored at {} tokens", cache.end());
@@ -155,17 +504,36 @@ pub struct BusySession<'a, M: CausalLM> {
 }
 
 impl<M: CausalLM> BusySession<'_, M> {
-    /// 接收模型解码产生的文本。
+    /// 接收模型解码产生的一段结果，最后一段带有结束的原因。
     #[inline]
-    pub async fn decode(&mut self) -> Option<String> {
+    pub async fn decode(&mut self) -> Option<Chunk> {
         self.session.component.decode(&mut self.handle).await
     }
+
+    /// 生成结束的原因，生成未结束时返回 `None`。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.handle.finish_reason()
+    }
+
+    /// 本次推理需要计算的提示词数量，不包括已缓存的部分。
+    #[inline]
+    pub fn num_prompt_tokens(&self) -> usize {
+        self.handle.num_prompt()
+    }
+
+    /// 已生成的词数。
+    #[inline]
+    pub fn num_generated_tokens(&self) -> usize {
+        self.handle.num_generated()
+    }
 }
 
 impl<M: CausalLM> Drop for BusySession<'_, M> {
     #[inline]
     fn drop(&mut self) {
-        self.session.restore_cache(self.handle.take());
+        let moderated = self.handle.moderated();
+        self.session.restore_cache(self.handle.take(), moderated);
     }
 }
 
@@ -180,19 +548,77 @@ impl<M: CausalLM> Generator<M> {
         component: Arc<ServiceComponent<M>>,
         prompt: impl AsRef<str>,
         sample: SampleArgs,
-    ) -> Self {
+    ) -> Result<Self, EncodeError> {
         let prompt = component.template.normalize(prompt.as_ref());
-        let prompt = component.normalizer.encode(&prompt);
-        let tokens = component.tokenizer.encode(&prompt);
-        let handle = component.infer(sample, Cache::new(&component.handle.model, tokens));
+        let tokens = component.encode(&prompt)?;
+        Ok(Self::with_tokens(
+            component,
+            tokens,
+            sample,
+            Default::default(),
+            Default::default(),
+            false,
+        ))
+    }
+
+    /// 只计算 `tokens` 的对数概率的生成器，不产生输出。
+    pub(crate) fn scoring(component: Arc<ServiceComponent<M>>, tokens: Vec<utok>) -> Self {
+        let cache = Cache::new(&component.handle.model, tokens);
+        let max = component.handle.model.max_seq_len() as usize;
+        let handle = component.score(max, cache);
+        Self { handle, component }
+    }
+
+    /// 直接续写 `tokens`，不经过模板和分词器；`echo` 时计算提示词的对数概率。
+    pub(crate) fn with_tokens(
+        component: Arc<ServiceComponent<M>>,
+        tokens: Vec<utok>,
+        sample: SampleArgs,
+        stop: StopArgs,
+        detokenize: DetokenizeArgs,
+        echo: bool,
+    ) -> Self {
+        let cache = Cache::new(&component.handle.model, tokens);
+        let max = component.handle.model.max_seq_len() as usize;
+        let handle = if echo {
+            component.infer_echo(sample, stop, detokenize, max, cache)
+        } else {
+            component.infer(sample, stop, detokenize, max, cache)
+        };
         Self { handle, component }
     }
 
-    /// 接收模型解码产生的文本。
+    /// 接收模型解码产生的一段结果，最后一段带有结束的原因。
     #[inline]
-    pub async fn decode(&mut self) -> Option<String> {
+    pub async fn decode(&mut self) -> Option<Chunk> {
         self.component.decode(&mut self.handle).await
     }
+
+    /// 生成结束的原因，生成未结束时返回 `None`。
+    #[inline]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.handle.finish_reason()
+    }
+
+    /// 需要计算的提示词数量。
+    #[inline]
+    pub fn num_prompt_tokens(&self) -> usize {
+        self.handle.num_prompt()
+    }
+
+    /// 已生成的词数。
+    #[inline]
+    pub fn num_generated_tokens(&self) -> usize {
+        self.handle.num_generated()
+    }
+
+    /// 提示词中除第一个词以外每个词的对数概率。
+    ///
+    /// 只在启动时要求计算，且收到第一段结果之后可用；模型不支持时为 `None`。
+    #[inline]
+    pub fn prompt_logprobs(&self) -> Option<Vec<f32>> {
+        self.handle.prompt_logprobs()
+    }
 }
 
 impl<M: CausalLM> Drop for Generator<M> {
@@ -201,3 +627,15 @@ impl<M: CausalLM> Drop for Generator<M> {
         let _ = self.handle.take();
     }
 }
+
+/// 以会话指定的模板或模型默认的模板包装提示词。
+fn apply_chat<'a, M: CausalLM>(
+    component: &ServiceComponent<M>,
+    template: Option<&ChatTemplate>,
+    prompt: &'a str,
+) -> Cow<'a, str> {
+    match template {
+        Some(template) => template.apply_chat(prompt),
+        None => component.template.apply_chat(prompt),
+    }
+}
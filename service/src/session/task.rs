@@ -1,12 +1,25 @@
-﻿use super::cache::Cache;
+﻿use super::{cache::Cache, StopArgs};
 use causal_lm::SampleArgs;
 use common::utok;
 use std::sync::{Arc, Mutex, MutexGuard};
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
 
 pub(super) struct Task<Storage> {
+    id: usize,
+    prefilled: bool,
     sample: SampleArgs,
-    sender: UnboundedSender<utok>,
+    stop: StopArgs,
+    max_len: usize,
+    num_generated: usize,
+    /// 曾在推理出错的批中，需要单独执行。
+    suspect: bool,
+    /// 只计算缓存，不生成新的词。
+    prefill_only: bool,
+    /// 首次前向传播时计算提示词的对数概率。
+    echo: bool,
+    prompt_logprobs: Arc<Mutex<Option<Vec<f32>>>>,
+    /// 生成的词，`None` 表示推理出错。
+    sender: Sender<Option<utok>>,
 
     cache: Arc<Mutex<Option<Cache<Storage>>>>,
 }
@@ -14,20 +27,125 @@ pub(super) struct Task<Storage> {
 impl<Storage> Task<Storage> {
     #[inline]
     pub fn new(
+        id: usize,
         cache: Arc<Mutex<Option<Cache<Storage>>>>,
         sample: SampleArgs,
-        sender: UnboundedSender<utok>,
+        stop: StopArgs,
+        max_len: usize,
+        sender: Sender<Option<utok>>,
     ) -> Self {
         Self {
+            id,
+            prefilled: false,
             sample,
+            stop,
+            max_len,
+            num_generated: 0,
+            suspect: false,
+            prefill_only: false,
+            echo: false,
+            prompt_logprobs: Default::default(),
             sender,
             cache,
         }
     }
 
+    /// 任务只计算缓存，第一次前向传播之后结束。
     #[inline]
-    pub fn sample(&self) -> &SampleArgs {
-        &self.sample
+    pub fn prefill_only(mut self) -> Self {
+        self.prefill_only = true;
+        self
+    }
+
+    /// 任务在首次前向传播时计算提示词的对数概率。
+    #[inline]
+    pub fn echo(mut self) -> Self {
+        self.echo = true;
+        self
+    }
+
+    /// 提示词的对数概率，计算后写入其中。
+    #[inline]
+    pub fn prompt_logprobs(&self) -> Arc<Mutex<Option<Vec<f32>>>> {
+        self.prompt_logprobs.clone()
+    }
+
+    /// 本轮需要计算提示词的对数概率。
+    #[inline]
+    pub fn is_echoing(&self) -> bool {
+        self.echo && !self.prefilled && self.is_alive()
+    }
+
+    /// 本轮解码的行数：需要提示词的对数概率时首次前向传播解码整个查询，只计算缓存时不解码，否则只解码最后一个词。
+    pub fn num_decode(&self) -> usize {
+        if self.is_echoing() {
+            let cache = self.cache.lock().unwrap();
+            cache.as_ref().map_or(1, |c| c.query().len().max(1))
+        } else if !self.is_alive() || self.prefill_only {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    #[inline]
+    pub fn num_generated(&self) -> usize {
+        self.num_generated
+    }
+    /// 任务还没有进行过前向传播。
+    #[inline]
+    pub fn is_fresh(&self) -> bool {
+        !self.prefilled
+    }
+    /// 标记任务完成了首次前向传播，返回这是否是首次。
+    #[inline]
+    pub fn prefill_done(&mut self) -> bool {
+        !std::mem::replace(&mut self.prefilled, true)
+    }
+    #[inline]
+    pub fn is_prefill_only(&self) -> bool {
+        self.prefill_only
+    }
+    /// 任务需要单独执行。
+    #[inline]
+    pub fn is_suspect(&self) -> bool {
+        self.suspect
+    }
+    /// 标记任务是否需要单独执行。
+    #[inline]
+    pub fn set_suspect(&mut self, suspect: bool) {
+        self.suspect = suspect;
+    }
+    /// 通知接收方推理出错，并结束任务。
+    pub fn fail(self) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(None) {
+            // 接收方的缓冲区已满时等待空位，保证错误送达
+            tokio::spawn(async move {
+                let _ = self.sender.send(None).await;
+            });
+        }
+    }
+    /// 生成本次采样的参数，生成的词数不足时屏蔽所有结束符。
+    pub fn sample(&self, eos: &[utok]) -> SampleArgs {
+        let mut args = self.sample.clone();
+        if !args.processors.is_empty() {
+            if let Some(cache) = self.cache.lock().unwrap().as_ref() {
+                args.processors.set_history(cache.tokens());
+            }
+        }
+        if self.num_generated < self.stop.min_new_tokens {
+            args.suppressed.extend_from_slice(eos);
+            args.suppressed.extend_from_slice(&self.stop.tokens);
+        }
+        args
+    }
+    #[inline]
+    pub fn is_stop(&self, token: utok) -> bool {
+        self.stop.tokens.contains(&token)
     }
     #[inline]
     pub fn is_alive(&self) -> bool {
@@ -38,15 +156,30 @@ impl<Storage> Task<Storage> {
         self.cache.lock().unwrap()
     }
 
-    #[inline]
-    pub fn push(&mut self, token: utok, start_size: usize, end_size: usize, max: usize) -> bool {
-        if self.sender.send(token).is_ok() {
-            if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+    /// 发送生成的词并加入缓存，返回任务是否继续；接收方的缓冲区已满时返回 `Err`，词未发送。
+    pub fn try_push(&mut self, token: utok) -> Result<bool, utok> {
+        match self.sender.try_send(Some(token)) {
+            Ok(()) => Ok(self.accept(token)),
+            Err(TrySendError::Full(_)) => Err(token),
+            Err(TrySendError::Closed(_)) => Ok(false),
+        }
+    }
+
+    /// 等待接收方的缓冲区有空位后发送生成的词并加入缓存，返回任务是否继续。
+    pub async fn push(&mut self, token: utok) -> bool {
+        self.sender.send(Some(token)).await.is_ok() && self.accept(token)
+    }
+
+    fn accept(&mut self, token: utok) -> bool {
+        self.num_generated += 1;
+        match self.cache.lock().unwrap().as_mut() {
+            Some(cache) => {
+                let max = self.max_len;
                 cache.push(token);
-                cache.reset_within_start_and_end_range(start_size, end_size, max);
-                return true;
+                cache.reset_within_start_and_end_range(max / 4, max / 4, max);
+                true
             }
+            None => false,
         }
-        false
     }
 }
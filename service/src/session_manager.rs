@@ -1,11 +1,41 @@
-use crate::Session;
+use crate::{CacheUsage, Session};
 use causal_lm::CausalLM;
-use log::warn;
+use log::{error, warn};
 use lru::LruCache;
-use std::{fmt::Debug, hash::Hash, num::NonZeroUsize, sync::Mutex};
+use std::{
+    fmt::Debug,
+    fs,
+    hash::Hash,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+};
 
 pub struct SessionManager<SessionId, M: CausalLM> {
     pending: Mutex<LruCache<SessionId, Option<Session<M>>>>,
+    /// 所有会话的缓存最多占用的字节数。
+    budget: Option<usize>,
+    /// 进程常驻内存的软上限。
+    memory_limit: Option<usize>,
+    /// 因内存超限清除的空闲会话数。
+    evicted: AtomicUsize,
+    /// 因内存超限拒绝的新会话数。
+    rejected: AtomicUsize,
+}
+
+/// 进程的内存占用和超限时的处理。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct MemoryUsage {
+    /// 常驻内存的软上限。
+    pub limit: Option<usize>,
+    /// 当前的常驻内存字节数，无法读取时为 `None`。
+    pub resident_bytes: Option<usize>,
+    /// 因内存超限清除的空闲会话数。
+    pub evicted_sessions: usize,
+    /// 因内存超限拒绝的新会话数。
+    pub rejected_sessions: usize,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
@@ -13,9 +43,15 @@ pub enum SessionError {
     Busy,
     Duplicate,
     NotFound,
+    /// 缓存预算已满且没有可以清除的空闲会话。
+    OutOfMemory,
+    /// 常驻内存超出软上限且没有可以清除的空闲会话。
+    MemoryLimit,
+    /// 会话的缓存与模型的形状不符，不能复制。
+    InvalidCache,
 }
 
-impl<SessionId: Eq + Hash + Debug, M: CausalLM> SessionManager<SessionId, M> {
+impl<SessionId: Eq + Hash + Clone + Debug, M: CausalLM> SessionManager<SessionId, M> {
     pub fn new(capacity: Option<usize>) -> Self {
         let cache = capacity
             .map(|c| NonZeroUsize::new(c).expect("Session capacity must be non-zero"))
@@ -23,7 +59,124 @@ impl<SessionId: Eq + Hash + Debug, M: CausalLM> SessionManager<SessionId, M> {
             .unwrap_or_else(LruCache::unbounded);
         Self {
             pending: Mutex::new(cache),
+            budget: None,
+            memory_limit: None,
+            evicted: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+        }
+    }
+
+    /// 设置所有会话的缓存预算，新建会话超出预算时清除最久未使用的空闲会话。
+    #[inline]
+    pub fn with_budget(mut self, budget: Option<usize>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// 设置进程常驻内存的软上限，超出时清除最久未使用的空闲会话，没有可以清除的会话时拒绝新会话。
+    ///
+    /// 只在能读取常驻内存的系统（Linux）上生效。
+    #[inline]
+    pub fn with_memory_limit(mut self, limit: Option<usize>) -> Self {
+        if limit.is_some() && resident_bytes().is_none() {
+            warn!("Memory limit ignored because resident memory is unavailable");
+        }
+        self.memory_limit = limit;
+        self
+    }
+
+    /// 进程的内存占用和超限时清除、拒绝的会话数。
+    pub fn memory(&self) -> MemoryUsage {
+        MemoryUsage {
+            limit: self.memory_limit,
+            resident_bytes: resident_bytes(),
+            evicted_sessions: self.evicted.load(Relaxed),
+            rejected_sessions: self.rejected.load(Relaxed),
+        }
+    }
+
+    /// 常驻内存超出软上限时清除空闲会话，用于定期检查。
+    pub fn shed(&self) {
+        let mut sessions = self.pending.lock().unwrap();
+        let _ = self.shed_idle(&mut sessions, 0);
+    }
+
+    /// 所有会话的缓存占用，忙会话的占用为 `None`。
+    pub fn usage(&self) -> Vec<(SessionId, Option<CacheUsage>)> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, s)| {
+                let usage = s.as_ref().map(|s| s.cache_usage().unwrap_or_default());
+                (k.clone(), usage)
+            })
+            .collect()
+    }
+
+    /// 为一个新会话腾出缓存预算。
+    fn make_room(
+        &self,
+        sessions: &mut LruCache<SessionId, Option<Session<M>>>,
+    ) -> Result<(), SessionError> {
+        let Some(budget) = self.budget else {
+            return Ok(());
+        };
+        let per_session = per_session(sessions);
+        while per_session * (sessions.len() + 1) > budget {
+            let Some(k) = sessions
+                .iter()
+                .rev()
+                .find(|(_, s)| s.is_some())
+                .map(|(k, _)| k.clone())
+            else {
+                return Err(SessionError::OutOfMemory);
+            };
+            sessions.pop(&k);
+            warn!("{k:?} dropped because cache budget is full");
         }
+        Ok(())
+    }
+
+    /// 为一个新会话腾出缓存预算和内存。
+    fn admit(
+        &self,
+        sessions: &mut LruCache<SessionId, Option<Session<M>>>,
+    ) -> Result<(), SessionError> {
+        self.make_room(sessions)?;
+        let reserve = per_session(sessions);
+        self.shed_idle(sessions, reserve).inspect_err(|_| {
+            self.rejected.fetch_add(1, Relaxed);
+            warn!("New session rejected because memory is over the limit");
+        })
+    }
+
+    /// 常驻内存加上 `reserve` 超出软上限时，清除最久未使用的空闲会话，
+    /// 按清除的会话分配的缓存估计释放的内存。
+    fn shed_idle(
+        &self,
+        sessions: &mut LruCache<SessionId, Option<Session<M>>>,
+        reserve: usize,
+    ) -> Result<(), SessionError> {
+        let Some(limit) = self.memory_limit else {
+            return Ok(());
+        };
+        let Some(mut resident) = resident_bytes() else {
+            return Ok(());
+        };
+        while resident + reserve > limit {
+            let Some((k, freed)) = sessions.iter().rev().find_map(|(k, s)| {
+                let freed = s.as_ref()?.cache_usage().map_or(0, |u| u.allocated_bytes);
+                Some((k.clone(), freed))
+            }) else {
+                return Err(SessionError::MemoryLimit);
+            };
+            sessions.pop(&k);
+            self.evicted.fetch_add(1, Relaxed);
+            warn!("{k:?} dropped because resident memory {resident} is over the limit {limit}");
+            resident = resident.saturating_sub(freed);
+        }
+        Ok(())
     }
 
     pub fn take(&self, k: &SessionId) -> Result<Session<M>, SessionError> {
@@ -41,9 +194,11 @@ impl<SessionId: Eq + Hash + Debug, M: CausalLM> SessionManager<SessionId, M> {
         session_id: SessionId,
         f: impl FnOnce() -> Session<M>,
     ) -> Result<Session<M>, SessionError> {
-        self.pending
-            .lock()
-            .unwrap()
+        let mut sessions = self.pending.lock().unwrap();
+        if !sessions.contains(&session_id) {
+            self.admit(&mut sessions)?;
+        }
+        sessions
             .get_or_insert_mut(session_id, || Some(f()))
             .take()
             .ok_or(SessionError::Busy)
@@ -65,12 +220,17 @@ impl<SessionId: Eq + Hash + Debug, M: CausalLM> SessionManager<SessionId, M> {
         let mut sessions = self.pending.lock().unwrap();
 
         if !sessions.contains(&new_session_id) {
+            self.admit(&mut sessions)?;
             let new = sessions
                 .get_mut(&session_id)
                 .ok_or(SessionError::NotFound)?
                 .as_ref()
                 .ok_or(SessionError::Busy)?
-                .fork();
+                .fork()
+                .map_err(|e| {
+                    error!("Failed to fork {session_id:?}: {e}");
+                    SessionError::InvalidCache
+                })?;
             if let Some((out, _)) = sessions.push(new_session_id, Some(new)) {
                 warn!("{out:?} dropped because LRU cache is full");
             }
@@ -86,3 +246,39 @@ impl<SessionId: Eq + Hash + Debug, M: CausalLM> SessionManager<SessionId, M> {
         }
     }
 }
+
+/// 每个会话的缓存大小相同，取空闲会话中最大的。
+fn per_session<K: Hash + Eq, M: CausalLM>(sessions: &LruCache<K, Option<Session<M>>>) -> usize {
+    sessions
+        .iter()
+        .filter_map(|(_, s)| s.as_ref()?.cache_usage())
+        .map(|u| u.allocated_bytes)
+        .max()
+        .unwrap_or(0)
+}
+
+/// 进程的常驻内存字节数，只支持 Linux。
+fn resident_bytes() -> Option<usize> {
+    parse_vm_rss(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+fn parse_vm_rss(status: &str) -> Option<usize> {
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    Some(kb << 10)
+}
+
+#[test]
+fn test_vm_rss() {
+    let status = "Name:\tinfer\nVmPeak:\t  204800 kB\nVmRSS:\t  102400 kB\nThreads:\t8\n";
+    assert_eq!(parse_vm_rss(status), Some(100 << 20));
+    assert_eq!(parse_vm_rss("Name:\tinfer\n"), None);
+    #[cfg(target_os = "linux")]
+    assert!(resident_bytes().is_some_and(|n| n > 0));
+}
@@ -11,6 +11,23 @@ pub struct ChatCPM;
 
 pub struct ChatTinyLlama;
 
+/// 以 `{prompt}` 为占位符的自定义对话模板，用于替代模型的默认模板。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ChatTemplate(String);
+
+impl ChatTemplate {
+    /// 占位符，应用模板时替换为提示词。
+    pub const PLACEHOLDER: &'static str = "{prompt}";
+
+    /// 模板中不含占位符时返回 `None`。
+    pub fn new(template: impl Into<String>) -> Option<Self> {
+        let template = template.into();
+        template
+            .contains(Self::PLACEHOLDER)
+            .then_some(Self(template))
+    }
+}
+
 impl Template for ChatCPM {
     #[inline]
     fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
@@ -34,3 +51,26 @@ impl Template for ChatTinyLlama {
         Cow::Owned(format!("<|user|>\n{prompt}</s><|assistant|>\n"))
     }
 }
+
+impl Template for ChatTemplate {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+
+    #[inline]
+    fn apply_chat<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Owned(self.0.replace(Self::PLACEHOLDER, prompt))
+    }
+}
+
+#[test]
+fn test_chat_template() {
+    assert!(ChatTemplate::new("### Question").is_none());
+    let template = ChatTemplate::new("### Question\n{prompt}\n### Answer\n").unwrap();
+    assert_eq!(
+        template.apply_chat("1 + 1 = ?"),
+        "### Question\n1 + 1 = ?\n### Answer\n"
+    );
+    assert_eq!(template.normalize("raw"), "raw");
+}
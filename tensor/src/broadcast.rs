@@ -1,5 +1,5 @@
 ﻿use crate::{pattern::Pattern, udim, Affine, Shape, Tensor};
-use std::iter::zip;
+use core::iter::zip;
 
 impl<Physical> Tensor<Physical> {
     pub fn broadcast(self, shape: &[udim]) -> Self {
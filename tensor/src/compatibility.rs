@@ -1,5 +1,5 @@
 ﻿use crate::Tensor;
-use std::iter::zip;
+use core::iter::zip;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u8)]
@@ -0,0 +1,180 @@
+use crate::{idim, pattern::Pattern, udim, Shape, ShapeError, Tensor};
+use alloc::{vec, vec::Vec};
+use core::{
+    iter::zip,
+    ops::{Deref, DerefMut},
+};
+
+impl<P: DerefMut<Target = [u8]>> Tensor<P> {
+    /// 沿 `axis` 拼接张量，除 `axis` 以外各张量的形状必须相同，结果是由 `alloc` 分配的连续张量。
+    pub fn concat<U>(
+        axis: usize,
+        tensors: &[Tensor<U>],
+        alloc: impl FnOnce(usize) -> P,
+    ) -> Result<Self, ShapeError>
+    where
+        U: Deref<Target = [u8]>,
+    {
+        let (first, rest) = tensors.split_first().expect("nothing to concat");
+        let dt = first.layout;
+        let ndim = first.shape.len();
+        if axis >= ndim {
+            return Err(ShapeError::Rank {
+                expected: ndim,
+                actual: axis + 1,
+            });
+        }
+        let mut shape = first.shape.clone();
+        for t in rest {
+            assert_eq!(t.layout, dt);
+            if t.shape.len() != ndim {
+                return Err(ShapeError::Rank {
+                    expected: ndim,
+                    actual: t.shape.len(),
+                });
+            }
+            for (i, (&expected, &actual)) in zip(&first.shape, &t.shape).enumerate() {
+                if i != axis && expected != actual {
+                    return Err(ShapeError::Mismatch {
+                        axis: i,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+            shape[axis] += t.shape[axis];
+        }
+
+        let mut ans = Self::alloc(dt, &shape, alloc);
+        let mut start = 0;
+        for t in tensors {
+            let len = t.shape[axis];
+            let mut dst = ans.as_mut().map_physical(|u| &mut **u);
+            // 在 `axis` 上偏移到这个张量的位置
+            dst.shape[axis] = len;
+            let offset = dst.pattern.0[ndim] + start as idim * dst.pattern.0[axis];
+            dst.pattern.0[ndim] = offset;
+            strided_copy(&mut dst, &t.as_ref().map_physical(|u| &**u));
+            start += len;
+        }
+        Ok(ans)
+    }
+
+    /// 在 `axis` 处插入新的维度并拼接形状相同的张量。
+    pub fn stack<U>(
+        axis: usize,
+        tensors: &[Tensor<U>],
+        alloc: impl FnOnce(usize) -> P,
+    ) -> Result<Self, ShapeError>
+    where
+        U: Deref<Target = [u8]>,
+    {
+        let tensors = tensors
+            .iter()
+            .map(|t| {
+                let ndim = t.shape.len();
+                if axis > ndim {
+                    return Err(ShapeError::Rank {
+                        expected: ndim + 1,
+                        actual: axis + 1,
+                    });
+                }
+                let mut shape = t.shape.clone();
+                shape.insert(axis, 1);
+                let mut pattern = t.pattern.0.as_slice().to_vec();
+                pattern.insert(axis, 0);
+                Ok(Tensor {
+                    layout: t.layout,
+                    shape,
+                    pattern: Pattern(pattern.into()),
+                    physical: &*t.physical,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::concat(axis, &tensors, alloc)
+    }
+}
+
+/// 在形状相同的张量之间复制数据，连续的低维合并为一次复制。
+fn strided_copy(dst: &mut Tensor<&mut [u8]>, src: &Tensor<&[u8]>) {
+    debug_assert_eq!(dst.shape, src.shape);
+    let shape: &[udim] = &dst.shape;
+    if shape.contains(&0) {
+        return;
+    }
+    let unit = dst.layout.nbytes() as isize;
+
+    // 两侧都连续的低维
+    let mut block = 1 as idim;
+    let mut outer = shape.len();
+    while let Some(i) = outer.checked_sub(1) {
+        if dst.strides()[i] != block || src.strides()[i] != block {
+            break;
+        }
+        block *= shape[i] as idim;
+        outer = i;
+    }
+    let block = block as usize * unit as usize;
+    let shape: Shape = shape[..outer].into();
+
+    let mut index = vec![0 as udim; outer];
+    loop {
+        let offset = |t: &[idim], base: isize| {
+            zip(&index, t).fold(base, |acc, (&i, &s)| acc + i as isize * s as isize * unit)
+        };
+        let d = offset(dst.strides(), dst.bytes_offset()) as usize;
+        let s = offset(src.strides(), src.bytes_offset()) as usize;
+        dst.physical[d..][..block].copy_from_slice(&src.physical[s..][..block]);
+        // 按行优先的顺序推进下标
+        let Some(i) = (0..outer).rev().find(|&i| index[i] + 1 < shape[i]) else {
+            break;
+        };
+        index[i] += 1;
+        index[i + 1..].fill(0);
+    }
+}
+
+#[test]
+fn test_concat() {
+    use crate::slice;
+    use digit_layout::types::U8;
+
+    let a = Tensor::new(U8, &[2, 3], (0..6).collect::<Vec<u8>>());
+    let b = Tensor::new(U8, &[2, 2], (6..10).collect::<Vec<u8>>());
+    let c = Tensor::<Vec<u8>>::concat(1, &[a.clone(), b], |len| vec![0; len]).unwrap();
+    assert_eq!(c.shape(), &[2, 5]);
+    assert_eq!(c.physical(), &[0, 1, 2, 6, 7, 3, 4, 5, 8, 9]);
+
+    // 转置和反向的张量按逻辑顺序复制
+    let t = a.clone().transpose(&[1, 0]);
+    let r = a.clone().slice(&[slice![<-], slice![=>]]);
+    let c = Tensor::<Vec<u8>>::concat(0, &[t], |len| vec![0; len]).unwrap();
+    assert_eq!(c.physical(), &[0, 3, 1, 4, 2, 5]);
+    let c = Tensor::<Vec<u8>>::concat(0, &[r, a.clone()], |len| vec![0; len]).unwrap();
+    assert_eq!(c.shape(), &[4, 3]);
+    assert_eq!(c.physical(), &[3, 4, 5, 0, 1, 2, 0, 1, 2, 3, 4, 5]);
+
+    let s = Tensor::<Vec<u8>>::stack(1, &[a.clone(), a.clone()], |len| vec![0; len]).unwrap();
+    assert_eq!(s.shape(), &[2, 2, 3]);
+    assert_eq!(s.physical(), &[0, 1, 2, 0, 1, 2, 3, 4, 5, 3, 4, 5]);
+    let s = Tensor::<Vec<u8>>::stack(2, std::slice::from_ref(&a), |len| vec![0; len]).unwrap();
+    assert_eq!(s.shape(), &[2, 3, 1]);
+
+    let b = Tensor::new(U8, &[3, 2], (0..6).collect::<Vec<u8>>());
+    assert_eq!(
+        Tensor::<Vec<u8>>::concat(1, &[a.clone(), b.clone()], |len| vec![0; len]).unwrap_err(),
+        ShapeError::Mismatch {
+            axis: 0,
+            expected: 2,
+            actual: 3
+        }
+    );
+    assert_eq!(
+        Tensor::<Vec<u8>>::concat(2, &[a.clone(), b], |len| vec![0; len]).unwrap_err(),
+        ShapeError::Rank {
+            expected: 2,
+            actual: 3
+        }
+    );
+    assert!(Tensor::<Vec<u8>>::stack(3, &[a], |len| vec![0; len]).is_err());
+}
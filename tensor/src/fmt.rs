@@ -1,6 +1,7 @@
-﻿use crate::{expand_indices, idim, idx_strides, udim, Tensor};
+use crate::{expand_indices, idim, idx_strides, udim, Tensor};
+use alloc::{string::ToString, vec::Vec};
+use core::{fmt, ops::Deref};
 use half::{bf16, f16};
-use std::{fmt, ops::Deref};
 
 pub trait DataFmt {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
@@ -136,6 +137,7 @@ fn write_matrix<T: DataFmt>(
     Ok(())
 }
 
+#[cfg(feature = "operators")]
 #[test]
 fn test_fmt() {
     use crate::{reslice, slice, Tensor};
@@ -1,10 +1,17 @@
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
 mod broadcast;
+mod concat;
 mod fmt;
+mod named;
 mod pattern;
 mod reshape;
 mod slice;
 mod split;
 mod tensor;
+mod transfer;
 mod transpose;
 
 #[allow(non_camel_case_types)]
@@ -14,19 +21,59 @@ pub type udim = u32;
 pub type idim = i32;
 
 pub use nalgebra::DVector;
+pub use named::{Axis, Named};
 pub use pattern::{expand_indices, idx_strides, Affine, Shape};
 pub use slice::SliceDim;
 pub use split::{LocalSplitable, Splitable};
 pub use tensor::Tensor;
+pub use transfer::Transfer;
+
+use core::{
+    error::Error,
+    fmt::{Display, Formatter},
+    mem::{align_of, size_of, size_of_val},
+};
 
-use std::mem::{align_of, size_of, size_of_val};
+/// 切片、切分或拼接的参数与张量的形状不符。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ShapeError {
+    /// 参数的维数与张量的维数不同。
+    Rank { expected: usize, actual: usize },
+    /// 第 `axis` 维的切片或切分超出了这一维的长度 `len`。
+    OutOfRange { axis: usize, len: udim },
+    /// 拼接的张量第 `axis` 维的长度不同。
+    Mismatch {
+        axis: usize,
+        expected: udim,
+        actual: udim,
+    },
+}
+
+impl Error for ShapeError {}
+impl Display for ShapeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Rank { expected, actual } => {
+                write!(f, "expected {expected} dimensions, got {actual}")
+            }
+            Self::OutOfRange { axis, len } => {
+                write!(f, "out of range on axis {axis} of length {len}")
+            }
+            Self::Mismatch {
+                axis,
+                expected,
+                actual,
+            } => write!(f, "expected length {expected} on axis {axis}, got {actual}"),
+        }
+    }
+}
 
 pub fn reslice<T, U>(src: &[T]) -> &[U] {
     let ptr = src.as_ptr_range();
     let align = align_of::<U>();
     assert_eq!(ptr.start.align_offset(align), 0);
     assert_eq!(ptr.end.align_offset(align), 0);
-    unsafe { std::slice::from_raw_parts(ptr.start.cast(), size_of_val(src) / size_of::<U>()) }
+    unsafe { core::slice::from_raw_parts(ptr.start.cast(), size_of_val(src) / size_of::<U>()) }
 }
 
 pub fn reslice_mut<T, U>(src: &mut [T]) -> &mut [U] {
@@ -34,5 +81,5 @@ pub fn reslice_mut<T, U>(src: &mut [T]) -> &mut [U] {
     let align = align_of::<U>();
     assert_eq!(ptr.start.align_offset(align), 0);
     assert_eq!(ptr.end.align_offset(align), 0);
-    unsafe { std::slice::from_raw_parts_mut(ptr.start.cast(), size_of_val(src) / size_of::<U>()) }
+    unsafe { core::slice::from_raw_parts_mut(ptr.start.cast(), size_of_val(src) / size_of::<U>()) }
 }
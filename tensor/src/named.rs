@@ -0,0 +1,176 @@
+use crate::{udim, Splitable, Tensor};
+use alloc::{collections::VecDeque, vec::Vec};
+use core::ops::{Deref, DerefMut};
+use digit_layout::DigitLayout;
+use smallvec::SmallVec;
+
+/// 维度名，例如 `nt`、`nh`、`dh`。
+pub type Axis = &'static str;
+
+type Names = SmallVec<[Axis; 4]>;
+
+/// 带有维度名的张量。
+///
+/// 变换按名字指定维度并检查名字和长度，维度顺序写错时在构造处 panic，而不是算出错误的数值。
+/// 解引用为张量，可以直接传给算子。
+#[derive(Clone, Debug)]
+pub struct Named<Physical> {
+    tensor: Tensor<Physical>,
+    names: Names,
+}
+
+impl<Physical> Tensor<Physical> {
+    /// 按顺序为各维命名。
+    pub fn named(self, names: &[Axis]) -> Named<Physical> {
+        assert_eq!(
+            names.len(),
+            self.shape.len(),
+            "names {names:?} do not match shape {:?}",
+            self.shape,
+        );
+        for (i, name) in names.iter().enumerate() {
+            assert!(!names[..i].contains(name), "duplicate axis `{name}`");
+        }
+        Named {
+            tensor: self,
+            names: Names::from_slice(names),
+        }
+    }
+}
+
+impl<Physical> Named<Physical> {
+    /// 按维度名和长度构造连续的张量。
+    pub fn new(layout: DigitLayout, dims: &[(Axis, udim)], physical: Physical) -> Self {
+        let (names, shape): (Vec<_>, Vec<_>) = dims.iter().copied().unzip();
+        Tensor::new(layout, &shape, physical).named(&names)
+    }
+
+    #[inline]
+    pub fn names(&self) -> &[Axis] {
+        &self.names
+    }
+
+    /// 名为 `name` 的维度的序号。
+    pub fn axis(&self, name: &str) -> usize {
+        self.names
+            .iter()
+            .position(|&n| n == name)
+            .unwrap_or_else(|| panic!("no axis `{name}` in {:?}", self.names))
+    }
+
+    /// 名为 `name` 的维度的长度。
+    #[inline]
+    pub fn dim(&self, name: &str) -> udim {
+        self.tensor.shape[self.axis(name)]
+    }
+
+    #[inline]
+    pub fn into_tensor(self) -> Tensor<Physical> {
+        self.tensor
+    }
+
+    /// 按名字重排维度，`names` 必须是现有维度名的一个排列。
+    pub fn transpose(self, names: &[Axis]) -> Self {
+        assert_eq!(
+            names.len(),
+            self.names.len(),
+            "cannot transpose {:?} to {names:?}",
+            self.names,
+        );
+        let perm = names.iter().map(|name| self.axis(name)).collect::<Vec<_>>();
+        Self {
+            tensor: self.tensor.transpose(&perm),
+            names: Names::from_slice(names),
+        }
+    }
+
+    /// 变形为 `dims` 指定的名字和长度。
+    ///
+    /// 变形前后都有的名字必须保持长度和相对顺序，只有新的名字由拆分或合并维度产生。
+    pub fn reshape(self, dims: &[(Axis, udim)]) -> Self {
+        let mut last = None;
+        for &(name, len) in dims {
+            let Some(i) = self.names.iter().position(|&n| n == name) else {
+                continue;
+            };
+            assert_eq!(
+                self.tensor.shape[i], len,
+                "axis `{name}` changes length in reshape",
+            );
+            assert!(
+                last < Some(i),
+                "reshape {:?} to {dims:?} reorders axes",
+                self.names,
+            );
+            last = Some(i);
+        }
+        let (names, shape): (Vec<_>, Vec<_>) = dims.iter().copied().unzip();
+        self.tensor.reshape(&shape).named(&names)
+    }
+}
+
+impl<Physical: Splitable> Named<Physical> {
+    /// 沿名为 `name` 的维度切分，各段保留维度名。
+    pub fn split(&self, name: &str, segments: &[udim]) -> VecDeque<Self> {
+        self.tensor
+            .split(self.axis(name), segments)
+            .into_iter()
+            .map(|tensor| Self {
+                tensor,
+                names: self.names.clone(),
+            })
+            .collect()
+    }
+}
+
+impl<Physical> Deref for Named<Physical> {
+    type Target = Tensor<Physical>;
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.tensor
+    }
+}
+
+impl<Physical> DerefMut for Named<Physical> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tensor
+    }
+}
+
+#[test]
+fn test_named() {
+    use digit_layout::types::F32;
+    use std::panic::catch_unwind;
+
+    let (nt, nh, dh) = (5, 4, 3);
+    let t = Named::new(F32, &[("nt", nt), ("d", nh * dh)], ());
+    assert_eq!(t.dim("d"), nh * dh);
+
+    let q = t.reshape(&[("nt", nt), ("nh", nh), ("dh", dh)]);
+    assert_eq!(q.shape(), &[nt, nh, dh]);
+    let q = q.transpose(&["nh", "nt", "dh"]);
+    assert_eq!(q.names(), &["nh", "nt", "dh"]);
+    assert_eq!(q.shape(), &[nh, nt, dh]);
+    assert_eq!(
+        q.pattern(),
+        Tensor::new(F32, &[nt, nh, dh], ())
+            .transpose(&[1, 0, 2])
+            .pattern()
+    );
+
+    let parts = q.split("nt", &[2, 3]);
+    assert_eq!(parts[1].shape(), &[nh, 3, dh]);
+    assert_eq!(parts[1].names(), q.names());
+
+    // 维度名写错、长度不符或顺序颠倒
+    assert!(catch_unwind(|| Tensor::new(F32, &[nt], ()).named(&["nt", "d"])).is_err());
+    assert!(catch_unwind(|| Tensor::new(F32, &[nt, nt], ()).named(&["nt", "nt"])).is_err());
+    assert!(catch_unwind(|| q.clone().transpose(&["nh", "nt", "nkvh"])).is_err());
+    assert!(catch_unwind(|| q.clone().reshape(&[("nh", nh), ("nt", nt * dh)])).is_err());
+    assert!(catch_unwind(|| q.clone().reshape(&[("nt", nt), ("nh", nh), ("dh", dh)])).is_err());
+    let merged = Tensor::new(F32, &[nh, nt, dh], ())
+        .named(&["nh", "nt", "dh"])
+        .reshape(&[("nh", nh), ("nt*dh", nt * dh)]);
+    assert_eq!(merged.shape(), &[nh, nt * dh]);
+}
@@ -1,4 +1,5 @@
 ﻿use crate::{idim, udim};
+use alloc::{vec, vec::Vec};
 use nalgebra::{DMatrix, DVector};
 use smallvec::SmallVec;
 
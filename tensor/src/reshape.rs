@@ -1,6 +1,7 @@
 ﻿use crate::{idim, idx_strides, pattern::Pattern, udim, Shape, Tensor};
+use alloc::vec::Vec;
+use core::iter::zip;
 use nalgebra::DVector;
-use std::iter::zip;
 
 impl<Physical> Tensor<Physical> {
     pub fn reshape(self, shape: &[udim]) -> Self {
@@ -60,7 +61,7 @@ impl<Physical> Tensor<Physical> {
         .take_while(|(a, b)| a == b)
         .count();
         if same_head + same_tail + 1 == current.len() {
-            // split: 原本的一个维度拆成多个，支持拆分物理连续的那一个维度
+            // split: 原本的一个维度拆成多个，拆出的维度按原维度的步长连续
             let axis = same_head;
             let insert_dims = &target[axis..target.len() - same_tail];
 
@@ -91,7 +92,7 @@ impl<Physical> Tensor<Physical> {
             while self.shape[i] == 1 {
                 i += 1;
             }
-            assert_eq!(self.pattern.0[i], 1);
+            let stride = self.pattern.0[i];
             i += 1;
 
             let (_, insert_pattern) = idx_strides(insert_dims);
@@ -105,7 +106,7 @@ impl<Physical> Tensor<Physical> {
                     pattern.push(loop {
                         match insert_dims[l] {
                             1 => l += 1,
-                            _ => break insert_pattern[l] as idim,
+                            _ => break insert_pattern[l] as idim * stride,
                         }
                     });
                     debug_assert_eq!(insert_dims[l], d);
@@ -128,7 +129,7 @@ impl<Physical> Tensor<Physical> {
                         }
                     });
                     debug_assert_eq!(self.shape[i], d);
-                    debug_assert_eq!(current[j], d);
+                    debug_assert_eq!(current[j + 1 - insert_dims.len()], d);
                     debug_assert_eq!(target[j], d);
                     i += 1;
                     j += 1;
@@ -1,5 +1,6 @@
-﻿use crate::{idim, pattern::Pattern, udim, Affine, Shape, Tensor};
-use std::{cmp::Ordering, iter::zip};
+use crate::{idim, pattern::Pattern, udim, Affine, Shape, ShapeError, Tensor};
+use alloc::vec::Vec;
+use core::{cmp::Ordering, iter::zip};
 
 impl<Physical> Tensor<Physical> {
     pub fn slice(self, dims: &[SliceDim]) -> Self {
@@ -10,6 +11,31 @@ impl<Physical> Tensor<Physical> {
             ..self
         }
     }
+
+    /// 检查参数的切片，维数不符或超出范围时返回错误而不是 panic。
+    ///
+    /// 与 [`slice`](Self::slice) 不同，超出维度的长度不会被截断，只有 `udim::MAX` 表示直到末端。
+    pub fn try_slice(self, dims: &[SliceDim]) -> Result<Self, ShapeError> {
+        if dims.len() != self.shape.len() {
+            return Err(ShapeError::Rank {
+                expected: self.shape.len(),
+                actual: dims.len(),
+            });
+        }
+        let meta = zip(dims, &self.shape)
+            .enumerate()
+            .map(|(axis, (d, &len))| {
+                d.checked_normalize(len)
+                    .ok_or(ShapeError::OutOfRange { axis, len })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (shape, affine) = affine(&meta);
+        Ok(Self {
+            shape,
+            pattern: Pattern(affine * self.pattern.0),
+            ..self
+        })
+    }
 }
 
 fn build(meta: &[SliceDim], input: &[udim]) -> (Shape, Affine) {
@@ -17,7 +43,11 @@ fn build(meta: &[SliceDim], input: &[udim]) -> (Shape, Affine) {
     let meta = zip(meta, input)
         .map(|(d, &len)| d.normalize(len))
         .collect::<Vec<_>>();
+    affine(&meta)
+}
 
+/// 从规范化的切片构造形状和变换矩阵。
+fn affine(meta: &[SliceDim]) -> (Shape, Affine) {
     let shape = meta.iter().map(|d| d.len).collect::<Shape>();
     let n = meta.len();
     let affine = Affine::from_fn(n + 1, n + 1, |r, c| {
@@ -49,6 +79,55 @@ fn test() {
     );
 }
 
+#[test]
+fn test_try_slice() {
+    use crate::slice;
+    use digit_layout::types::U8;
+
+    let t = Tensor::new(U8, &[5, 6], ());
+    let ok = t.clone().try_slice(&[slice![1 => 3], slice![=>]]).unwrap();
+    assert_eq!(ok.shape(), &[2, 6]);
+    assert_eq!(
+        ok.pattern(),
+        t.clone().slice(&[slice![1 => 3], slice![=>]]).pattern()
+    );
+    // 空切片可以从末端开始
+    assert_eq!(
+        t.clone()
+            .try_slice(&[slice![5=>], slice![=>]])
+            .unwrap()
+            .shape(),
+        &[0, 6]
+    );
+    assert_eq!(
+        t.clone()
+            .try_slice(&[slice![<-], slice![=2]])
+            .unwrap()
+            .shape(),
+        &[5, 1]
+    );
+
+    assert_eq!(
+        t.clone().try_slice(&[slice![=>]]).unwrap_err(),
+        ShapeError::Rank {
+            expected: 2,
+            actual: 1
+        }
+    );
+    for (dims, axis, len) in [
+        ([slice![=>], slice![=>7]], 1, 6),
+        ([slice![6=>], slice![=>]], 0, 5),
+        ([slice![=5], slice![=>]], 0, 5),
+        ([slice![=>], slice![6; -1; 1]], 1, 6),
+        ([slice![1 => 2 => 3], slice![=>]], 0, 5),
+    ] {
+        assert_eq!(
+            t.clone().try_slice(&dims).unwrap_err(),
+            ShapeError::OutOfRange { axis, len }
+        );
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct SliceDim {
     pub start: udim,
@@ -100,6 +179,50 @@ impl SliceDim {
             }
         }
     }
+
+    /// 规范化长度为 `len` 的维度上的切片，起始位置或长度超出范围时返回 `None`。
+    ///
+    /// `start` 为 `udim::MAX` 的反向切片从末端开始，`len` 为 `udim::MAX` 的切片直到末端。
+    pub fn checked_normalize(&self, len: udim) -> Option<Self> {
+        if len == 0 {
+            return (matches!(self.start, 0 | udim::MAX) && matches!(self.len, 0 | udim::MAX))
+                .then_some(Self {
+                    start: 0,
+                    step: 0,
+                    len: 0,
+                });
+        }
+        let (start, available) = match self.step.cmp(&0) {
+            Ordering::Greater => {
+                let step = self.step as udim;
+                let rest = len.checked_sub(self.start)?;
+                (self.start, rest.div_ceil(step))
+            }
+            Ordering::Equal => (self.start, if self.start < len { udim::MAX } else { 0 }),
+            Ordering::Less => {
+                let start = match self.start {
+                    udim::MAX => len.checked_sub(1)?,
+                    start if start < len => start,
+                    _ => return None,
+                };
+                (start, start / self.step.unsigned_abs() + 1)
+            }
+        };
+        let len = match self.len {
+            udim::MAX => available,
+            n if n <= available => n,
+            _ => return None,
+        };
+        // 广播的维度不能无限长
+        if len == udim::MAX {
+            return None;
+        }
+        Some(Self {
+            start,
+            step: self.step,
+            len,
+        })
+    }
 }
 
 #[macro_export]
@@ -1,8 +1,7 @@
-﻿use crate::{idim, pattern::Pattern, udim, Affine, Shape, Tensor};
-use std::{
-    collections::VecDeque,
+﻿use crate::{idim, pattern::Pattern, udim, Affine, Shape, ShapeError, Tensor};
+use alloc::{collections::VecDeque, rc::Rc, vec::Vec};
+use core::{
     ops::{Deref, DerefMut},
-    rc::Rc,
     slice::from_raw_parts_mut,
 };
 
@@ -66,6 +65,23 @@ impl<Physical: Splitable> Tensor<Physical> {
             })
             .collect()
     }
+
+    /// 检查参数的切分，`axis` 不存在或各段的总长超出这一维时返回错误而不是 panic。
+    pub fn try_split(&self, axis: usize, segments: &[udim]) -> Result<VecDeque<Self>, ShapeError> {
+        let Some(&len) = self.shape.get(axis) else {
+            return Err(ShapeError::Rank {
+                expected: self.shape.len(),
+                actual: axis + 1,
+            });
+        };
+        match segments
+            .iter()
+            .try_fold(0 as udim, |sum, &seg| sum.checked_add(seg))
+        {
+            Some(sum) if sum <= len => Ok(self.split(axis, segments)),
+            _ => Err(ShapeError::OutOfRange { axis, len }),
+        }
+    }
 }
 
 fn build(axis: usize, segments: &[udim], input: &[udim]) -> Vec<(Shape, Affine)> {
@@ -149,8 +165,33 @@ macro_rules! split {
     };
 }
 
+#[macro_export]
+macro_rules! try_split {
+    ($src:expr; [$axis:expr]: $($n:expr),+) => {
+        $src.try_split($axis, &[$($n as _),+]).map(|mut vec| {
+            ($((vec.pop_front().unwrap(),$n).0,)+)
+        })
+    };
+}
+
 #[test]
 fn test_macro() {
+    use crate::ShapeError;
     use digit_layout::types::U8;
     let (_a, _b, _c) = split!(Tensor::new(U8, &[10], ()); [0]: 2, 3, 4);
+
+    let (a, b) = try_split!(Tensor::new(U8, &[2, 10], ()); [1]: 4, 6).unwrap();
+    assert_eq!(a.shape(), &[2, 4]);
+    assert_eq!(b.shape(), &[2, 6]);
+    assert_eq!(
+        try_split!(Tensor::new(U8, &[2, 10], ()); [1]: 4, 7).unwrap_err(),
+        ShapeError::OutOfRange { axis: 1, len: 10 }
+    );
+    assert_eq!(
+        try_split!(Tensor::new(U8, &[2, 10], ()); [2]: 1).unwrap_err(),
+        ShapeError::Rank {
+            expected: 2,
+            actual: 3
+        }
+    );
 }
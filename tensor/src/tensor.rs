@@ -1,11 +1,15 @@
-﻿use crate::{idim, pattern::Pattern, udim, Shape};
-use digit_layout::DigitLayout;
-use nalgebra::DVector;
-use operators::{Argument, Operator, TensorLayout};
-use std::{
+use crate::{idim, pattern::Pattern, udim, Shape};
+use core::{
     mem::{align_of, size_of},
     ops::{Deref, DerefMut},
 };
+use digit_layout::DigitLayout;
+use nalgebra::DVector;
+#[cfg(feature = "operators")]
+use {
+    alloc::vec::Vec,
+    operators::{Argument, Operator, TensorLayout},
+};
 
 #[derive(Clone, Debug)]
 pub struct Tensor<Physical> {
@@ -161,6 +165,7 @@ impl<Physical> Tensor<Physical> {
         }
     }
 
+    #[cfg(feature = "operators")]
     pub fn layout(&self) -> TensorLayout {
         let dt = self.data_layout();
         let shape: Vec<Argument<usize>> = self
@@ -215,6 +220,7 @@ impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
     /// # Safety
     ///
     /// The caller must ensure that the `dst` can be a valid tensor physical.
+    #[cfg(feature = "operators")]
     pub unsafe fn reform_to_raw(&self, dst: &mut [u8]) {
         assert_eq!(self.bytes_size(), dst.len());
         use operators::{
@@ -252,6 +258,7 @@ impl<Physical: Deref<Target = [u8]>> Tensor<Physical> {
             .unwrap();
     }
 
+    #[cfg(feature = "operators")]
     pub fn reform_to<U>(&self, dst: &mut Tensor<U>)
     where
         U: DerefMut<Target = [u8]>,
@@ -317,4 +324,9 @@ fn test() {
     assert_eq!(t.pattern.0.as_slice(), &[20, 60, 0, 4, 2, 1, 0]);
     assert_eq!(t.contiguous_len(), 4);
     assert_eq!(t.is_contiguous(), false);
+
+    let t = Tensor::new(F32, &[1, 4, 8], ()).transpose(&[1, 0, 2]);
+    let t = t.reshape(&[2, 2, 8]);
+    assert_eq!(t.shape(), &[2, 2, 8]);
+    assert_eq!(t.pattern.0.as_slice(), &[16, 8, 1, 0]);
 }
@@ -0,0 +1,114 @@
+use crate::Tensor;
+use alloc::{vec, vec::Vec};
+use core::ops::{Deref, DerefMut};
+
+/// 在主机和设备存储之间复制数据的队列，例如 cuda 的流。
+///
+/// 复制到设备的操作排入队列后即可返回，之后在同一队列上的计算能看到复制的结果；
+/// 复制到主机的操作返回时数据已经可用，即等待队列中之前的操作完成。
+pub trait Transfer {
+    /// 设备存储的字节类型。
+    type Byte;
+    /// 在设备上分配的存储。
+    type Mem: DerefMut<Target = [Self::Byte]>;
+
+    /// 在设备上分配 `len` 字节。
+    fn malloc(&self, len: usize) -> Self::Mem;
+    /// 在队列上从主机复制到设备，`dst` 与 `src` 长度相同。
+    fn copy_h2d(&self, dst: &mut [Self::Byte], src: &[u8]);
+    /// 在队列上从设备复制到主机并等待完成，`dst` 与 `src` 长度相同。
+    fn copy_d2h(&self, dst: &mut [u8], src: &[Self::Byte]);
+}
+
+impl<P: Deref<Target = [u8]>> Tensor<P> {
+    /// 把主机张量复制到设备，复制整个物理存储并保留张量的布局。
+    pub fn to_device<Q: Transfer>(&self, queue: &Q) -> Tensor<Q::Mem> {
+        let src = &*self.physical;
+        let mut dst = queue.malloc(src.len());
+        queue.copy_h2d(&mut dst, src);
+        Tensor {
+            layout: self.layout,
+            shape: self.shape.clone(),
+            pattern: self.pattern.clone(),
+            physical: dst,
+        }
+    }
+
+    /// 把主机张量复制到布局相同的设备张量中。
+    pub fn copy_to_device<Q, U>(&self, dst: &mut Tensor<U>, queue: &Q)
+    where
+        Q: Transfer,
+        U: DerefMut<Target = [Q::Byte]>,
+    {
+        assert_eq!(self.layout, dst.layout);
+        assert_eq!(self.shape, dst.shape);
+        assert_eq!(self.pattern.0, dst.pattern.0);
+        queue.copy_h2d(&mut dst.physical, &self.physical);
+    }
+}
+
+impl<B, P: Deref<Target = [B]>> Tensor<P> {
+    /// 把设备张量复制到主机，复制整个物理存储并保留张量的布局。
+    pub fn to_host<Q: Transfer<Byte = B>>(&self, queue: &Q) -> Tensor<Vec<u8>> {
+        let src = &*self.physical;
+        let mut dst = vec![0; src.len()];
+        queue.copy_d2h(&mut dst, src);
+        Tensor {
+            layout: self.layout,
+            shape: self.shape.clone(),
+            pattern: self.pattern.clone(),
+            physical: dst,
+        }
+    }
+
+    /// 把设备张量复制到布局相同的主机张量中。
+    pub fn copy_to_host<Q, U>(&self, dst: &mut Tensor<U>, queue: &Q)
+    where
+        Q: Transfer<Byte = B>,
+        U: DerefMut<Target = [u8]>,
+    {
+        assert_eq!(self.layout, dst.layout);
+        assert_eq!(self.shape, dst.shape);
+        assert_eq!(self.pattern.0, dst.pattern.0);
+        queue.copy_d2h(&mut dst.physical, &self.physical);
+    }
+}
+
+#[test]
+fn test_transfer() {
+    use digit_layout::types::U8;
+    use std::cell::Cell;
+
+    /// 用主机内存模拟设备，记录复制的次数。
+    #[derive(Default)]
+    struct Mock(Cell<usize>);
+
+    impl Transfer for Mock {
+        type Byte = u8;
+        type Mem = Vec<u8>;
+
+        fn malloc(&self, len: usize) -> Self::Mem {
+            vec![0xff; len]
+        }
+        fn copy_h2d(&self, dst: &mut [u8], src: &[u8]) {
+            self.0.set(self.0.get() + 1);
+            dst.copy_from_slice(src);
+        }
+        fn copy_d2h(&self, dst: &mut [u8], src: &[u8]) {
+            self.0.set(self.0.get() + 1);
+            dst.copy_from_slice(src);
+        }
+    }
+
+    let queue = Mock::default();
+    let host = Tensor::new(U8, &[2, 3], (0..6).collect::<Vec<u8>>()).transpose(&[1, 0]);
+    let mut device = host.to_device(&queue);
+    assert_eq!(device.shape(), &[3, 2]);
+    assert_eq!(device.pattern(), host.pattern());
+
+    let mut back = device.to_host(&queue);
+    assert_eq!(back.physical(), host.physical());
+    device.copy_to_host(&mut back, &queue);
+    host.copy_to_device(&mut device, &queue);
+    assert_eq!(queue.0.get(), 4);
+}
@@ -1,12 +1,18 @@
-﻿use crate::{ByteDecoder, Tokenizer};
+use crate::{ByteDecoder, Data, Tokenizer, BYTE_PIECES};
 use common::utok;
-use std::{io::Result, path::Path};
+use serde_json::Value;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    io::{Error, ErrorKind::InvalidData, Result},
+    path::Path,
+};
 
 /// 由 tokenizer.model 文件定义的 bpe 分词器。
 ///
 /// 文件格式为 `[10, total_len, 10, str_len, [str;str_len], 21, [score;4], ..; vocab_size]`。
 pub struct BPE {
-    mmap: memmap2::Mmap,
+    data: Data,
     /// 保存每个序号对应的对象在文件中的偏移，用于从序号查询 token 字符串。
     offsets: Vec<usize>,
     /// 保存根据 token 字符串字典序排序的序号，用于从 token 字符串查询序号。
@@ -17,38 +23,131 @@ pub struct BPE {
 
 impl BPE {
     /// 打开 tokenizer.model 文件并构造一个 bpe 分词器。
+    #[inline]
     pub fn from_model_file(model_file: impl AsRef<Path>) -> Result<Self> {
-        // 打开文件
-        let file = std::fs::File::open(model_file)?;
-        let mmap = unsafe { memmap2::Mmap::map(&file) }?;
+        Self::new(Data::open(model_file)?)
+    }
+
+    /// 由 tokenizer.model 文件的内容构造一个 bpe 分词器。
+    #[inline]
+    pub fn from_model_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Self::new(Data::Owned(bytes))
+    }
+
+    /// 读取 transformers 的 tokenizer.json 文件并构造一个 bpe 分词器。
+    ///
+    /// 只支持带单字节词的 `BPE` 模型，即由 sentencepiece 转换而来的词表。
+    /// 合并得到的词以合并规则的次序为评分，越靠前评分越高；不由任何规则合并得到的词评分最低。
+    #[inline]
+    pub fn from_tokenizer_json(json_file: impl AsRef<Path>) -> Result<Self> {
+        Self::from_tokenizer_json_bytes(&std::fs::read(json_file)?)
+    }
+
+    /// 由 transformers 的 tokenizer.json 文件的内容构造一个 bpe 分词器，参见 [`BPE::from_tokenizer_json`]。
+    pub fn from_tokenizer_json_bytes(json: &[u8]) -> Result<Self> {
+        let invalid = |msg: &str| Error::new(InvalidData, format!("tokenizer.json: {msg}"));
+        let json: Value = serde_json::from_slice(json).map_err(|e| Error::new(InvalidData, e))?;
+        let model = &json["model"];
+        if model["type"] != "BPE" {
+            return Err(invalid("only BPE model is supported"));
+        }
+        // 收集词表和额外添加的词
+        let mut pieces = HashMap::new();
+        for (piece, id) in model["vocab"]
+            .as_object()
+            .ok_or_else(|| invalid("bad vocab"))?
+        {
+            let id = id.as_u64().ok_or_else(|| invalid("bad vocab"))?;
+            pieces.insert(id, piece.as_str());
+        }
+        for token in json["added_tokens"].as_array().into_iter().flatten() {
+            match (token["id"].as_u64(), token["content"].as_str()) {
+                (Some(id), Some(piece)) => pieces.insert(id, piece),
+                _ => return Err(invalid("bad added token")),
+            };
+        }
+        let pieces = (0..pieces.len() as u64)
+            .map(|id| pieces.get(&id).copied())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("token ids are not contiguous"))?;
+        if pieces.len() < BYTE_PIECES
+            || (0..=255u8).any(|b| pieces[b as usize + 3] != format!("<0x{b:02X}>"))
+        {
+            return Err(invalid("byte fallback pieces not found"));
+        }
+        // 合并规则可能是 "a b" 或 ["a", "b"]
+        let mut ranks = HashMap::new();
+        for (rank, merge) in model["merges"].as_array().into_iter().flatten().enumerate() {
+            let merged = match merge {
+                Value::String(s) => s.split_once(' ').map(|(a, b)| format!("{a}{b}")),
+                Value::Array(pair) => match pair.as_slice() {
+                    [Value::String(a), Value::String(b)] => Some(format!("{a}{b}")),
+                    _ => None,
+                },
+                _ => None,
+            }
+            .ok_or_else(|| invalid("bad merge"))?;
+            ranks.entry(merged).or_insert(rank);
+        }
+        // 转换为 tokenizer.model 的格式
+        let mut data = Vec::new();
+        for piece in pieces {
+            let len = u8::try_from(piece.len())
+                .ok()
+                .filter(|&len| len <= u8::MAX - 7)
+                .ok_or_else(|| invalid("piece too long"))?;
+            let score = ranks.get(piece).map_or(f32::MIN, |&rank| -(rank as f32));
+            data.extend([10, len + 7, 10, len]);
+            data.extend(piece.as_bytes());
+            data.push(21);
+            data.extend(score.to_le_bytes());
+        }
+        Self::new(Data::Owned(data))
+    }
+
+    fn new(data: Data) -> Result<Self> {
         // 遍历文件，标记所有词汇的位置并记录最大长度
         let mut max_piece_len = 0usize;
-        let offsets = (0..)
-            .scan(0usize, |offset, _| match &mmap[*offset..] {
-                [10, total_len, 10, str_len, ..] => {
-                    max_piece_len = max_piece_len.max(*str_len as usize);
-                    let next = *offset + 3;
-                    *offset += 2 + *total_len as usize;
-                    Some(next)
-                }
-                [..] => None,
-            })
-            .collect::<Vec<_>>();
-        // 对词汇表按字典序排序
-        let mut sorted_indices = (0..offsets.len() as utok).collect::<Vec<_>>();
-        sorted_indices.sort_by_key(|&i| {
-            let slice = &mmap[offsets[i as usize]..];
-            let len = slice[0] as usize;
-            std::str::from_utf8(&slice[1..][..len]).unwrap()
-        });
+        let mut offsets = Vec::new();
+        let mut offset = 0usize;
+        while let [10, total_len, 10, str_len, ..] = data[offset..] {
+            let (total_len, str_len) = (total_len as usize, str_len as usize);
+            // 词汇之后是评分的标记和 4 字节评分，都要在对象内
+            let end = offset + 2 + total_len;
+            if end > data.len()
+                || str_len + 7 > total_len
+                || data[offset + 4 + str_len] != 21
+                || std::str::from_utf8(&data[offset + 4..][..str_len]).is_err()
+            {
+                return Err(Error::new(
+                    InvalidData,
+                    format!("malformed piece #{} at byte {offset}", offsets.len()),
+                ));
+            }
+            max_piece_len = max_piece_len.max(str_len);
+            offsets.push(offset + 3);
+            offset = end;
+        }
+        // 未登录的字符按字节编码为 3 号之后的 256 个词
+        if !(BYTE_PIECES..=utok::MAX as usize).contains(&offsets.len()) {
+            return Err(Error::new(
+                InvalidData,
+                format!("unsupported vocabulary size {}", offsets.len()),
+            ));
+        }
         // 生成分词器
-        Ok(Self {
-            mmap,
+        let mut bpe = Self {
+            data,
             offsets,
-            sorted_indices,
-            max_piece_len: 0,
+            sorted_indices: Vec::new(),
+            max_piece_len,
             byte_pieces: ByteDecoder::new(),
-        })
+        };
+        // 对词汇表按字典序排序
+        let mut sorted_indices = (0..bpe.offsets.len() as utok).collect::<Vec<_>>();
+        sorted_indices.sort_by_key(|&i| bpe.get_piece(i));
+        bpe.sorted_indices = sorted_indices;
+        Ok(bpe)
     }
 
     /// 根据词汇查找代码。
@@ -64,16 +163,17 @@ impl BPE {
     #[inline]
     fn get_piece(&self, i: utok) -> &str {
         let offset = self.offsets[i as usize];
-        let slice = &self.mmap[offset..];
+        let slice = &self.data[offset..];
         let len = slice[0] as usize;
-        std::str::from_utf8(&slice[1..][..len]).unwrap()
+        // 构造时已检查
+        unsafe { std::str::from_utf8_unchecked(&slice[1..][..len]) }
     }
 
     /// 根据代码查找合词评分。
     #[inline]
     fn get_score(&self, i: utok) -> f32 {
         let offset = self.offsets[i as usize];
-        let slice = &self.mmap[offset..];
+        let slice = &self.data[offset..];
         let len = slice[0] as usize;
         let ptr = slice[len + 2..].as_ptr().cast::<f32>();
         unsafe { ptr.read_unaligned() }
@@ -102,44 +202,104 @@ impl Tokenizer for BPE {
             }
         });
 
-        fn map_pair(bpe: &BPE, tokens: &[utok], i: usize) -> Option<(utok, f32)> {
-            bpe.find_piece(&format!(
-                "{}{}",
-                bpe.get_piece(tokens[i]),
-                bpe.get_piece(tokens[i + 1])
-            ))
-            .map(|tok| (tok, bpe.get_score(tok)))
+        fn map_pair(bpe: &BPE, left: utok, right: utok) -> Option<(utok, f32)> {
+            bpe.find_piece(&format!("{}{}", bpe.get_piece(left), bpe.get_piece(right)))
+                .map(|tok| (tok, bpe.get_score(tok)))
         }
 
-        let mut merges = (0..tokens.len() - 1)
-            .map(|i| map_pair(self, &tokens, i))
+        // 用链表连接未合并的词，用堆选出评分最高的合并，评分相同时先合并靠后的
+        let mut prev = (0..tokens.len())
+            .map(|i| i.checked_sub(1))
+            .collect::<Vec<_>>();
+        let mut next = (1..=tokens.len())
+            .map(|i| Some(i).filter(|&i| i < tokens.len()))
             .collect::<Vec<_>>();
-        while let Some((i, (tok, _))) = merges
-            .iter()
-            .enumerate()
-            .filter_map(|(i, tok)| tok.map(|tok| (i, tok)))
-            .max_by(|(_, (_, a)), (_, (_, b))| a.total_cmp(b))
+        let mut alive = vec![true; tokens.len()];
+        let mut merges = BinaryHeap::new();
+        let push = |merges: &mut BinaryHeap<Merge>, tokens: &[utok], left: usize, right| {
+            if let Some((tok, score)) = map_pair(self, tokens[left], tokens[right]) {
+                merges.push(Merge {
+                    score,
+                    left,
+                    pair: [tokens[left], tokens[right]],
+                    tok,
+                });
+            }
+        };
+        for i in 1..tokens.len() {
+            push(&mut merges, &tokens, i - 1, i);
+        }
+        while let Some(Merge {
+            left, pair, tok, ..
+        }) = merges.pop()
         {
-            tokens[i] = tok;
-            tokens.remove(i + 1);
-            merges.remove(i);
-            if let Some(i) = i.checked_sub(1) {
-                merges[i] = map_pair(self, &tokens, i);
+            // 跳过已经失效的合并
+            let Some(right) = next[left].filter(|_| alive[left]) else {
+                continue;
+            };
+            if [tokens[left], tokens[right]] != pair {
+                continue;
+            }
+            tokens[left] = tok;
+            alive[right] = false;
+            next[left] = next[right];
+            if let Some(n) = next[right] {
+                prev[n] = Some(left);
+                push(&mut merges, &tokens, left, n);
             }
-            if i + 1 < merges.len() {
-                merges[i] = map_pair(self, &tokens, i);
+            if let Some(p) = prev[left] {
+                push(&mut merges, &tokens, p, left);
             }
         }
 
         tokens
+            .into_iter()
+            .zip(alive)
+            .filter_map(|(tok, alive)| alive.then_some(tok))
+            .collect()
     }
 
     #[inline]
-    fn decode(&self, token: utok) -> &str {
+    fn decode_bytes(&self, token: utok) -> &[u8] {
         self.byte_pieces.decode(self.get_piece(token))
     }
 }
 
+/// 一次候选的合并。
+struct Merge {
+    score: f32,
+    /// 合并的左侧词在初始词序列中的位置。
+    left: usize,
+    /// 合并时两侧的词，用于判断合并是否失效。
+    pair: [utok; 2],
+    tok: utok,
+}
+
+impl PartialEq for Merge {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Merge {}
+
+impl PartialOrd for Merge {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Merge {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then(self.left.cmp(&other.left))
+    }
+}
+
 #[test]
 fn read_tokenizer() {
     let Some(model_dir) = common::test_model::find() else {
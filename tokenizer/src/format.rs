@@ -0,0 +1,165 @@
+use crate::{BPECommonNormalizer, Normalizer, Tokenizer, VocabTxt, BPE};
+use std::{
+    fmt,
+    io::{
+        Error,
+        ErrorKind::{InvalidData, NotFound},
+        Result,
+    },
+    path::Path,
+    str::FromStr,
+};
+
+/// 模型目录中的分词器文件类型。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum TokenizerFormat {
+    /// sentencepiece 的 tokenizer.model。
+    Model,
+    /// transformers 的 tokenizer.json。
+    Json,
+    /// 由 vocabs.txt 预先构造的前缀树 vocabs.trie。
+    Trie,
+    /// 每行一个带引号词汇的 vocabs.txt。
+    Txt,
+}
+
+impl TokenizerFormat {
+    /// 自动识别时依次尝试的类型。
+    ///
+    /// tokenizer.model 优先于同时存在的 tokenizer.json；预先构造的前缀树文件由多个进程映射时共享内存，优先于 vocabs.txt。
+    pub const ALL: [Self; 4] = [Self::Model, Self::Json, Self::Trie, Self::Txt];
+
+    /// 这种类型的文件名。
+    #[inline]
+    pub const fn file_name(self) -> &'static str {
+        match self {
+            Self::Model => "tokenizer.model",
+            Self::Json => "tokenizer.json",
+            Self::Trie => "vocabs.trie",
+            Self::Txt => "vocabs.txt",
+        }
+    }
+
+    /// 识别模型目录中的分词器文件类型。
+    pub fn detect(model_dir: impl AsRef<Path>) -> Option<Self> {
+        let model_dir = model_dir.as_ref();
+        Self::ALL
+            .into_iter()
+            .find(|format| model_dir.join(format.file_name()).is_file())
+    }
+
+    /// 从模型目录加载这种类型的分词器和配套的规范化器。
+    pub fn load(
+        self,
+        model_dir: impl AsRef<Path>,
+    ) -> Result<(
+        Box<dyn Tokenizer + Send + Sync>,
+        Box<dyn Normalizer + Send + Sync>,
+    )> {
+        let path = model_dir.as_ref().join(self.file_name());
+        Ok(match self {
+            Self::Model => (
+                Box::new(BPE::from_model_file(path)?),
+                Box::new(BPECommonNormalizer),
+            ),
+            Self::Json => (
+                Box::new(BPE::from_tokenizer_json(path)?),
+                Box::new(BPECommonNormalizer),
+            ),
+            Self::Trie => (Box::new(VocabTxt::from_trie_file(path)?), Box::new(())),
+            Self::Txt => (Box::new(VocabTxt::from_txt_file(path)?), Box::new(())),
+        })
+    }
+
+    /// 由这种类型的文件的内容加载分词器和配套的规范化器，用于无法访问文件系统的环境。
+    pub fn load_bytes(
+        self,
+        bytes: Vec<u8>,
+    ) -> Result<(
+        Box<dyn Tokenizer + Send + Sync>,
+        Box<dyn Normalizer + Send + Sync>,
+    )> {
+        Ok(match self {
+            Self::Model => (
+                Box::new(BPE::from_model_bytes(bytes)?),
+                Box::new(BPECommonNormalizer),
+            ),
+            Self::Json => (
+                Box::new(BPE::from_tokenizer_json_bytes(&bytes)?),
+                Box::new(BPECommonNormalizer),
+            ),
+            Self::Trie => (Box::new(VocabTxt::from_trie_bytes(bytes)?), Box::new(())),
+            Self::Txt => {
+                let text = String::from_utf8(bytes).map_err(|e| Error::new(InvalidData, e))?;
+                (Box::new(VocabTxt::from_txt(&text)?), Box::new(()))
+            }
+        })
+    }
+
+    /// 加载模型目录中的分词器，未指定类型时自动识别。
+    pub fn load_from(
+        model_dir: impl AsRef<Path>,
+        format: Option<Self>,
+    ) -> Result<(
+        Box<dyn Tokenizer + Send + Sync>,
+        Box<dyn Normalizer + Send + Sync>,
+    )> {
+        let model_dir = model_dir.as_ref();
+        match format.or_else(|| Self::detect(model_dir)) {
+            Some(format) => format.load(model_dir),
+            None => Err(Error::new(NotFound, "tokenizer file not found")),
+        }
+    }
+}
+
+impl FromStr for TokenizerFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "model" | "tokenizer.model" => Ok(Self::Model),
+            "json" | "tokenizer.json" => Ok(Self::Json),
+            "trie" | "vocabs.trie" => Ok(Self::Trie),
+            "txt" | "vocabs.txt" => Ok(Self::Txt),
+            _ => Err(format!("unknown tokenizer format: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for TokenizerFormat {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.file_name())
+    }
+}
+
+#[test]
+fn test_detect() {
+    let dir = std::env::temp_dir().join(format!("tokenizer-format-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    assert_eq!(TokenizerFormat::detect(&dir), None);
+    assert!(TokenizerFormat::load_from(&dir, None).is_err());
+
+    let pieces = (0..=255u8)
+        .map(|b| format!("\"<0x{b:02X}>\"\n"))
+        .collect::<String>();
+    let text = format!("\"<unk>\"\n\"<s>\"\n\"</s>\"\n{pieces}\"a\"\n");
+    std::fs::write(dir.join("vocabs.txt"), text).unwrap();
+    assert_eq!(TokenizerFormat::detect(&dir), Some(TokenizerFormat::Txt));
+    std::fs::write(dir.join("tokenizer.json"), "{}").unwrap();
+    assert_eq!(TokenizerFormat::detect(&dir), Some(TokenizerFormat::Json));
+    // 自动识别为不支持的 tokenizer.json，指定类型时仍可加载
+    assert!(TokenizerFormat::load_from(&dir, None).is_err());
+    let (tokenizer, _) = TokenizerFormat::load_from(&dir, Some(TokenizerFormat::Txt)).unwrap();
+    assert_eq!(tokenizer.vocab_size(), 3 + 256 + 1);
+    // 不经过文件系统加载的分词器与从文件加载的一致
+    let bytes = std::fs::read(dir.join("vocabs.txt")).unwrap();
+    let (from_bytes, _) = TokenizerFormat::Txt.load_bytes(bytes).unwrap();
+    assert_eq!(from_bytes.encode("ab"), tokenizer.encode("ab"));
+    assert!(TokenizerFormat::Txt.load_bytes(vec![0xff]).is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+
+    assert_eq!("JSON".parse(), Ok(TokenizerFormat::Json));
+    assert_eq!("vocabs.trie".parse(), Ok(TokenizerFormat::Trie));
+    assert!("spm".parse::<TokenizerFormat>().is_err());
+}
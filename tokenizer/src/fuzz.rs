@@ -0,0 +1,259 @@
+//! 用随机和病态的输入检查分词器不会 panic，且分词结果能还原文本。
+
+use crate::{EncodeError, Tokenizer, VocabTxt, BPE, MAX_TEXT_LEN};
+use std::{fs, path::PathBuf};
+
+/// 构造测试词表的词汇：3 个特殊词、256 个单字节词和一些容易引起长前缀匹配或连续合并的词。
+fn pieces() -> Vec<String> {
+    let mut pieces = vec!["<unk>".to_string(), "<s>".into(), "</s>".into()];
+    pieces.extend((0..=255u8).map(|b| format!("<0x{b:02X}>")));
+    for c in ["a", "b", "▁", "你", "好"] {
+        pieces.extend((1..=8).map(|n| c.repeat(n)));
+    }
+    pieces.extend(["ab", "ba", "▁a", "a▁", "你好", "<0x", "<0xZZ>"].map(String::from));
+    pieces
+}
+
+/// 以 tokenizer.model 的格式写出词表，后合并的词评分更低。
+fn model_file(pieces: &[String]) -> Vec<u8> {
+    let mut ans = Vec::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        let len = piece.len() as u8;
+        ans.extend([10, len + 7, 10, len]);
+        ans.extend(piece.as_bytes());
+        ans.push(21);
+        ans.extend((-(i as f32)).to_le_bytes());
+    }
+    ans
+}
+
+fn temp_file(name: &str, content: impl AsRef<[u8]>) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tokenizer-fuzz-{}-{name}", std::process::id()));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+/// 不依赖外部库的伪随机数发生器。
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// 从容易组合成词表中词汇的字符和词表外的字符中随机组成文本。
+    fn text(&mut self) -> String {
+        const CHARS: &[char] = &[
+            'a', 'b', ' ', '▁', '你', '好', '<', '>', 'x', 'Z', '\0', '😀',
+        ];
+        let len = self.below(64);
+        (0..len).map(|_| CHARS[self.below(CHARS.len())]).collect()
+    }
+}
+
+fn inputs() -> Vec<String> {
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    let mut ans = (0..1000).map(|_| rng.text()).collect::<Vec<_>>();
+    ans.extend(["", " ", "<0x", "<0xZZ>", "\u{10ffff}"].map(String::from));
+    ans.push("a".repeat(100_000));
+    ans.push("ab".repeat(50_000));
+    ans.push("你好 ".repeat(20_000));
+    ans
+}
+
+/// 把词还原为字节，单字节词可能不是完整的 utf-8 字符。
+fn decode(tokenizer: &impl Tokenizer, tokens: &[u32]) -> Vec<u8> {
+    tokens
+        .iter()
+        .flat_map(|&t| tokenizer.decode_bytes(t))
+        .copied()
+        .collect()
+}
+
+/// 随机字节和截断的多字节字符。
+fn byte_inputs() -> Vec<Vec<u8>> {
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    let mut ans = (0..1000)
+        .map(|_| {
+            let len = rng.below(64);
+            (0..len).map(|_| rng.next() as u8).collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    ans.extend(inputs().into_iter().map(|s| {
+        let mut bytes = s.into_bytes();
+        bytes.pop();
+        bytes
+    }));
+    ans
+}
+
+#[test]
+fn fuzz_vocab_txt() {
+    let text = pieces()
+        .iter()
+        .map(|p| format!("\"{p}\"\n"))
+        .collect::<String>();
+    let vocab = VocabTxt::from_txt(&text).unwrap();
+    for input in inputs() {
+        let tokens = vocab.encode(&input);
+        assert!(tokens.iter().all(|&t| (t as usize) < vocab.vocab_size()));
+        assert_eq!(decode(&vocab, &tokens), input.as_bytes());
+    }
+    // 空词汇不会导致分词停滞
+    let vocab = VocabTxt::from_txt(&format!("\"\"\n{text}")).unwrap();
+    assert_eq!(vocab.encode("cde").len(), 3);
+}
+
+#[test]
+fn fuzz_bpe() {
+    let path = temp_file("fuzz.model", model_file(&pieces()));
+    let bpe = BPE::from_model_file(&path);
+    fs::remove_file(path).unwrap();
+    let bpe = bpe.unwrap();
+    assert_eq!(bpe.max_piece_len(), "你".len() * 8);
+    for input in inputs() {
+        let tokens = bpe.encode(&input);
+        assert!(tokens.iter().all(|&t| (t as usize) < bpe.vocab_size()));
+        assert_eq!(decode(&bpe, &tokens), input.replace(' ', "▁").as_bytes());
+    }
+    // 评分最高的合并优先，评分相同时先合并靠后的
+    assert_eq!(decode(&bpe, &bpe.encode("aaa")), b"aaa");
+    assert_eq!(bpe.encode("aaa").len(), 1);
+}
+
+/// 以 tokenizer.json 的格式写出词表，合并规则的次序与词汇的序号一致，使评分的顺序与 [`model_file`] 相同。
+fn json_file(pieces: &[String]) -> Vec<u8> {
+    let vocab = pieces
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.clone(), i.into()))
+        .collect::<serde_json::Map<_, _>>();
+    let merges = pieces
+        .iter()
+        .filter_map(|p| {
+            let (i, _) = p.char_indices().nth(1)?;
+            Some(format!("{} {}", &p[..i], &p[i..]))
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_vec(&serde_json::json!({
+        "added_tokens": [{ "id": 0, "content": "<unk>" }],
+        "model": { "type": "BPE", "vocab": vocab, "merges": merges },
+    }))
+    .unwrap()
+}
+
+#[test]
+fn tokenizer_json() {
+    let model = temp_file("json.model", model_file(&pieces()));
+    let json = temp_file("tokenizer.json", json_file(&pieces()));
+    let bpe = BPE::from_model_file(&model);
+    let from_json = BPE::from_tokenizer_json(&json);
+    fs::remove_file(model).unwrap();
+    fs::remove_file(json).unwrap();
+    let (bpe, from_json) = (bpe.unwrap(), from_json.unwrap());
+    assert_eq!(from_json.vocab_size(), bpe.vocab_size());
+    for input in inputs() {
+        assert_eq!(from_json.encode(&input), bpe.encode(&input));
+    }
+
+    // 不支持字节级 bpe 等没有单字节词的词表
+    let mut pieces = pieces();
+    pieces[3] = "Ā".into();
+    for content in [json_file(&pieces), b"{}".to_vec(), b"[".to_vec()] {
+        let path = temp_file("bad-tokenizer.json", content);
+        let bpe = BPE::from_tokenizer_json(&path);
+        fs::remove_file(path).unwrap();
+        assert!(bpe.is_err());
+    }
+}
+
+#[test]
+fn trie_file() {
+    let text = pieces()
+        .iter()
+        .map(|p| format!("\"{p}\"\n"))
+        .collect::<String>();
+    let vocab = VocabTxt::from_txt(&text).unwrap();
+    let path = std::env::temp_dir().join(format!("tokenizer-fuzz-{}.trie", std::process::id()));
+    vocab.save_trie(&path).unwrap();
+    let mapped = VocabTxt::from_trie_file(&path);
+    fs::remove_file(path).unwrap();
+    let mapped = mapped.unwrap();
+    assert_eq!(mapped.vocab_size(), vocab.vocab_size());
+    assert_eq!(mapped.max_piece_len(), vocab.max_piece_len());
+    for input in inputs() {
+        assert_eq!(mapped.encode(&input), vocab.encode(&input));
+    }
+    for t in 0..vocab.vocab_size() as u32 {
+        assert_eq!(mapped.decode_bytes(t), vocab.decode_bytes(t));
+    }
+}
+
+#[test]
+fn bytes_round_trip() {
+    let text = pieces()
+        .iter()
+        .map(|p| format!("\"{p}\"\n"))
+        .collect::<String>();
+    let vocab = VocabTxt::from_txt(&text).unwrap();
+    let path = temp_file("bytes.model", model_file(&pieces()));
+    let bpe = BPE::from_model_file(&path);
+    fs::remove_file(path).unwrap();
+    let bpe = bpe.unwrap();
+    for input in byte_inputs() {
+        assert_eq!(decode(&vocab, &vocab.encode_bytes(&input)), input);
+        let tokens = bpe.encode_bytes(&input);
+        let expected = String::from_utf8_lossy(&input).replace(' ', "▁");
+        assert_eq!(String::from_utf8_lossy(&decode(&bpe, &tokens)), expected);
+    }
+    // 不完整的字符按替换字符解码为文本
+    let tokens = vocab.encode_bytes(&"你".as_bytes()[..2]);
+    assert_eq!(tokens.len(), 2);
+    assert!(tokens.iter().all(|&t| vocab.decode(t) == "\u{FFFD}"));
+}
+
+#[test]
+fn malformed_vocab() {
+    assert!(VocabTxt::from_txt("\"a\"\nb\n").is_err());
+    assert!(VocabTxt::from_txt("\"a\"\n").is_err());
+
+    let mut file = model_file(&pieces());
+    for content in [
+        // 截断的词汇
+        file[..file.len() - 3].to_vec(),
+        // 非 utf-8 的词汇
+        {
+            file[4] = 0xff;
+            file.clone()
+        },
+        // 词表太小
+        model_file(&["a".into()]),
+    ] {
+        let path = temp_file("malformed.model", content);
+        let bpe = BPE::from_model_file(&path);
+        fs::remove_file(path).unwrap();
+        assert!(bpe.is_err());
+    }
+}
+
+#[test]
+fn text_too_long() {
+    let text = pieces()
+        .iter()
+        .map(|p| format!("\"{p}\"\n"))
+        .collect::<String>();
+    let vocab = VocabTxt::from_txt(&text).unwrap();
+    let long = "a".repeat(MAX_TEXT_LEN + 1);
+    assert_eq!(
+        vocab.try_encode(&long),
+        Err(EncodeError::TooLong(MAX_TEXT_LEN + 1))
+    );
+    assert_eq!(vocab.try_encode("aaa"), Ok(vocab.encode("aaa")));
+}
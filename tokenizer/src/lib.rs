@@ -1,20 +1,113 @@
 mod bpe;
+mod format;
 mod normalizer;
+mod trie;
 mod vocab_txt;
 
+#[cfg(test)]
+mod fuzz;
+
 use common::utok;
+use std::{error, fmt, io, ops::Deref, path::Path, str};
+
+/// 分词接受的最大文本字节数。
+pub const MAX_TEXT_LEN: usize = 1 << 20;
+
+/// 词表至少要包含 3 个特殊词和 256 个单字节词。
+const BYTE_PIECES: usize = 3 + 256;
 
 pub trait Tokenizer {
     fn vocab_size(&self) -> usize;
     fn max_piece_len(&self) -> usize;
     fn encode(&self, text: &str) -> Vec<utok>;
-    fn decode(&self, token: utok) -> &str;
+    /// 词对应的字节，单字节词可能不是完整的 utf-8 字符。
+    fn decode_bytes(&self, token: utok) -> &[u8];
+
+    /// 词是否是特殊词，即未知词、开始符和结束符。
+    #[inline]
+    fn is_special(&self, token: utok) -> bool {
+        token < 3
+    }
+
+    /// 词对应的文本，不是完整 utf-8 字符的单字节词解码为替换字符。
+    fn decode(&self, token: utok) -> &str {
+        str::from_utf8(self.decode_bytes(token)).unwrap_or("\u{FFFD}")
+    }
+
+    /// 对任意字节分词，不是合法 utf-8 的字节编码为单字节词，用 [`Tokenizer::decode_bytes`] 可以无损还原。
+    fn encode_bytes(&self, bytes: &[u8]) -> Vec<utok> {
+        let mut tokens = Vec::new();
+        for chunk in bytes.utf8_chunks() {
+            tokens.extend(self.encode(chunk.valid()));
+            tokens.extend(chunk.invalid().iter().map(|&b| b as utok + 3));
+        }
+        tokens
+    }
+
+    /// 检查文本长度后分词，用于不可信的输入。
+    fn try_encode(&self, text: &str) -> Result<Vec<utok>, EncodeError> {
+        if text.len() > MAX_TEXT_LEN {
+            return Err(EncodeError::TooLong(text.len()));
+        }
+        Ok(self.encode(text))
+    }
+}
+
+/// 分词失败的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EncodeError {
+    /// 文本的字节数超过 [`MAX_TEXT_LEN`]。
+    TooLong(usize),
+}
+
+impl error::Error for EncodeError {}
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLong(len) => write!(f, "text of {len} bytes exceeds {MAX_TEXT_LEN} bytes"),
+        }
+    }
 }
 
 pub use bpe::BPE;
+pub use format::TokenizerFormat;
 pub use normalizer::{BPECommonNormalizer, Normalizer};
 pub use vocab_txt::VocabTxt;
 
+/// 映射的文件或在内存中构造的等价内容。
+enum Data {
+    Owned(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl Data {
+    /// 映射文件，未启用 `mmap` 特性时读入内存。
+    fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        #[cfg(feature = "mmap")]
+        {
+            let file = std::fs::File::open(path)?;
+            Ok(Self::Mapped(unsafe { memmap2::Mmap::map(&file) }?))
+        }
+        #[cfg(not(feature = "mmap"))]
+        {
+            std::fs::read(path).map(Self::Owned)
+        }
+    }
+}
+
+impl Deref for Data {
+    type Target = [u8];
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Owned(vec) => vec,
+            #[cfg(feature = "mmap")]
+            Self::Mapped(mmap) => mmap,
+        }
+    }
+}
+
 struct ByteDecoder([u8; 256]);
 
 impl ByteDecoder {
@@ -26,13 +119,15 @@ impl ByteDecoder {
         ans
     }
 
-    fn decode<'a>(&'a self, piece: &'a str) -> &'a str {
-        if let Some(byte) = piece.strip_prefix("<0x").and_then(|s| s.strip_suffix('>')) {
-            let byte = u8::from_str_radix(byte, 16).unwrap();
-            let byte = std::slice::from_ref(&self.0[byte as usize]);
-            unsafe { std::str::from_utf8_unchecked(byte) }
-        } else {
-            piece
+    fn decode<'a>(&'a self, piece: &'a str) -> &'a [u8] {
+        // 形如 `<0x..>` 但不是合法字节的词汇按原样输出
+        match piece
+            .strip_prefix("<0x")
+            .and_then(|s| s.strip_suffix('>'))
+            .and_then(|s| u8::from_str_radix(s, 16).ok())
+        {
+            Some(byte) => std::slice::from_ref(&self.0[byte as usize]),
+            None => piece.as_bytes(),
         }
     }
 }
@@ -0,0 +1,307 @@
+use crate::Data;
+use common::utok;
+use std::{
+    collections::BTreeSet,
+    io::{Error, ErrorKind::InvalidData, Result},
+    str,
+};
+
+/// 词表的双数组前缀树，连同词汇保存在一块连续的字节中，可以直接写入文件并由多个进程映射。
+///
+/// 格式为 `[magic, version, num_slots, num_words, max_piece_len, blob_len]` 的头部，
+/// 之后是 `base`、`check`、`value` 三个长 `num_slots` 的数组、长 `num_words + 1` 的词汇偏移数组和所有词汇拼接成的字节，
+/// 数字都是小端 `u32`。
+///
+/// 状态 `s` 经过字节 `c` 转移到 `t = base[s] + c`，当且仅当 `check[t] == s`。根状态是 0，
+/// `value[s]` 是到达状态 `s` 的词汇的序号，不是词汇时为 [`NONE`]。
+pub(crate) struct Trie {
+    data: Data,
+    num_slots: usize,
+    num_words: usize,
+    max_piece_len: usize,
+}
+
+const MAGIC: [u8; 4] = *b"VTRI";
+const VERSION: u32 = 1;
+/// 头部的字数。
+const HEADER: usize = 6;
+/// 空位和非词汇状态的标记。
+const NONE: u32 = u32::MAX;
+/// 根状态的 `check`，不等于任何状态，使根状态不是空位也不会转移到自身。
+const ROOT: u32 = NONE - 1;
+
+impl Trie {
+    /// 为序号依次排列的词汇构造前缀树，重复的词汇取最后一个序号。
+    pub fn build<'a>(pieces: impl IntoIterator<Item = &'a str>) -> Self {
+        let pieces = pieces.into_iter().collect::<Vec<_>>();
+        let mut keys = pieces
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.as_bytes(), i as utok))
+            .collect::<Vec<_>>();
+        keys.sort();
+
+        let mut builder = Builder {
+            base: vec![0],
+            check: vec![ROOT],
+            value: vec![NONE],
+            free: BTreeSet::new(),
+            fails: vec![0],
+        };
+        builder.insert(0, &keys, 0);
+        let Builder {
+            base, check, value, ..
+        } = builder;
+
+        let blob_len = pieces.iter().map(|p| p.len()).sum::<usize>();
+        let max_piece_len = pieces.iter().map(|p| p.len()).max().unwrap_or(0);
+        let mut data = Vec::with_capacity(
+            (HEADER + base.len() * 3 + pieces.len() + 1) * size_of::<u32>() + blob_len,
+        );
+        data.extend(MAGIC);
+        for n in [
+            VERSION,
+            base.len() as _,
+            pieces.len() as _,
+            max_piece_len as _,
+            blob_len as _,
+        ] {
+            data.extend(n.to_le_bytes());
+        }
+        for n in [base, check, value].iter().flatten() {
+            data.extend(n.to_le_bytes());
+        }
+        let mut offset = 0u32;
+        data.extend(offset.to_le_bytes());
+        for p in &pieces {
+            offset += p.len() as u32;
+            data.extend(offset.to_le_bytes());
+        }
+        for p in &pieces {
+            data.extend(p.as_bytes());
+        }
+        Self::parse(Data::Owned(data)).unwrap()
+    }
+
+    /// 序列化的前缀树。
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    #[inline]
+    pub fn num_words(&self) -> usize {
+        self.num_words
+    }
+
+    #[inline]
+    pub fn max_piece_len(&self) -> usize {
+        self.max_piece_len
+    }
+
+    /// 查找 `text` 最长的一个非空前缀词汇，返回其字节数和序号。
+    pub fn longest_prefix(&self, text: &[u8]) -> Option<(usize, utok)> {
+        let mut s = 0;
+        let mut ans = None;
+        for (i, &c) in text.iter().enumerate() {
+            let t = self.base(s) as usize + c as usize;
+            if t >= self.num_slots || self.check(t) as usize != s {
+                break;
+            }
+            s = t;
+            match self.value(s) {
+                NONE => {}
+                tok => ans = Some((i + 1, tok)),
+            }
+        }
+        ans
+    }
+
+    /// 序号 `i` 对应的词汇。
+    pub fn piece(&self, i: utok) -> &str {
+        let i = i as usize;
+        let offsets = self.section(3);
+        let start = self.word(offsets + i) as usize;
+        let end = self.word(offsets + i + 1) as usize;
+        let blob = self.section(4) * size_of::<u32>();
+        // 解析时已检查
+        unsafe { str::from_utf8_unchecked(&self.data[blob..][start..end]) }
+    }
+
+    /// 检查头部和每个数组，保证之后的查询不会越界。
+    pub fn parse(data: Data) -> Result<Self> {
+        let invalid = |msg: &str| Error::new(InvalidData, format!("invalid vocab trie: {msg}"));
+        if data.len() < HEADER * size_of::<u32>() || data[..4] != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let header: [u32; HEADER] =
+            std::array::from_fn(|i| u32::from_le_bytes(data[i * 4..][..4].try_into().unwrap()));
+        if header[1] != VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let ans = Self {
+            num_slots: header[2] as _,
+            num_words: header[3] as _,
+            max_piece_len: header[4] as _,
+            data,
+        };
+        let blob_len = header[5] as usize;
+        let blob = ans.section(4) * size_of::<u32>();
+        if ans.num_slots == 0 || blob.checked_add(blob_len) != Some(ans.data.len()) {
+            return Err(invalid("truncated"));
+        }
+        if (0..ans.num_slots)
+            .map(|s| ans.value(s))
+            .any(|tok| tok != NONE && tok as usize >= ans.num_words)
+        {
+            return Err(invalid("token out of range"));
+        }
+        let offsets = ans.section(3);
+        let mut start = ans.word(offsets) as usize;
+        for i in 1..=ans.num_words {
+            let end = ans.word(offsets + i) as usize;
+            if start > end
+                || end > blob_len
+                || end - start > ans.max_piece_len
+                || str::from_utf8(&ans.data[blob..][start..end]).is_err()
+            {
+                return Err(invalid("bad piece"));
+            }
+            start = end;
+        }
+        Ok(ans)
+    }
+
+    /// 第 `i` 个数组在数据中的起始字号。
+    #[inline]
+    fn section(&self, i: usize) -> usize {
+        HEADER + self.num_slots * i.min(3) + (self.num_words + 1) * i.saturating_sub(3)
+    }
+
+    #[inline]
+    fn word(&self, i: usize) -> u32 {
+        u32::from_le_bytes(self.data[i * 4..][..4].try_into().unwrap())
+    }
+
+    #[inline]
+    fn base(&self, s: usize) -> u32 {
+        self.word(self.section(0) + s)
+    }
+
+    #[inline]
+    fn check(&self, s: usize) -> u32 {
+        self.word(self.section(1) + s)
+    }
+
+    #[inline]
+    fn value(&self, s: usize) -> u32 {
+        self.word(self.section(2) + s)
+    }
+}
+
+struct Builder {
+    base: Vec<u32>,
+    check: Vec<u32>,
+    value: Vec<u32>,
+    /// 参与搜索的空位，数组末尾之后都是空位。
+    free: BTreeSet<usize>,
+    /// 每个空位作为第一个子状态搜索失败的次数。
+    fails: Vec<u8>,
+}
+
+/// 空位搜索失败这么多次后不再参与搜索，避免反复扫描稠密的区域。
+const MAX_FAILS: u8 = 16;
+
+impl Builder {
+    /// 在状态 `s` 插入有共同的 `depth` 字节前缀的有序词汇。
+    fn insert(&mut self, s: usize, keys: &[(&[u8], utok)], depth: usize) {
+        let (ends, keys) = keys.split_at(keys.partition_point(|(k, _)| k.len() == depth));
+        if let Some(&(_, tok)) = ends.last() {
+            self.value[s] = tok;
+        }
+        if keys.is_empty() {
+            return;
+        }
+        // 按下一个字节分组
+        let mut children = Vec::new();
+        let mut start = 0;
+        for i in 1..=keys.len() {
+            if i == keys.len() || keys[i].0[depth] != keys[start].0[depth] {
+                children.push((keys[start].0[depth], start..i));
+                start = i;
+            }
+        }
+        let base = self.find_base(children.iter().map(|(c, _)| *c));
+        self.base[s] = base as _;
+        for (c, _) in &children {
+            let t = base + *c as usize;
+            self.check[t] = s as _;
+            self.free.remove(&t);
+        }
+        for (c, range) in children {
+            self.insert(base + c as usize, &keys[range], depth + 1);
+        }
+    }
+
+    /// 找到使所有子状态都落在空位的 `base`，并扩展数组。
+    fn find_base(&mut self, bytes: impl Iterator<Item = u8> + Clone) -> usize {
+        let first = bytes.clone().next().unwrap() as usize;
+        let Self {
+            check, free, fails, ..
+        } = self;
+        let is_free = |t: usize| check.get(t).is_none_or(|&c| c == NONE);
+        let mut exhausted = Vec::new();
+        let base = free
+            .range(first..)
+            .map(|&t| t - first)
+            .find(|&base| {
+                let found = bytes.clone().all(|c| is_free(base + c as usize));
+                if !found {
+                    let t = base + first;
+                    fails[t] += 1;
+                    if fails[t] >= MAX_FAILS {
+                        exhausted.push(t);
+                    }
+                }
+                found
+            })
+            .unwrap_or_else(|| check.len().max(first) - first);
+        for t in exhausted {
+            free.remove(&t);
+        }
+
+        let len = base + bytes.max().unwrap() as usize + 1;
+        if len > self.check.len() {
+            self.free.extend(self.check.len()..len);
+            self.base.resize(len, 0);
+            self.check.resize(len, NONE);
+            self.value.resize(len, NONE);
+            self.fails.resize(len, 0);
+        }
+        base
+    }
+}
+
+#[test]
+fn test_trie() {
+    let pieces = ["a", "ab", "abc", "b", "你好", "你", "", "ab"];
+    let trie = Trie::build(pieces);
+    assert_eq!(trie.num_words(), pieces.len());
+    assert_eq!(trie.max_piece_len(), "你好".len());
+    assert_eq!(trie.longest_prefix(b"abd"), Some((2, 7)));
+    assert_eq!(trie.longest_prefix(b"abc"), Some((3, 2)));
+    assert_eq!(trie.longest_prefix("你们".as_bytes()), Some((3, 5)));
+    assert_eq!(trie.longest_prefix(b"c"), None);
+    assert_eq!(trie.longest_prefix(b""), None);
+    assert_eq!(trie.longest_prefix(b"\0"), None);
+    for (i, p) in pieces.iter().enumerate() {
+        assert_eq!(trie.piece(i as _), *p);
+    }
+
+    let bytes = trie.as_bytes().to_vec();
+    assert!(Trie::parse(Data::Owned(bytes.clone())).is_ok());
+    assert!(Trie::parse(Data::Owned(bytes[..bytes.len() - 1].to_vec())).is_err());
+    let mut bad = bytes;
+    bad[0] = 0;
+    assert!(Trie::parse(Data::Owned(bad)).is_err());
+}
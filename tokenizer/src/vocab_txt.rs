@@ -1,40 +1,69 @@
-﻿use crate::{ByteDecoder, Tokenizer};
+﻿use crate::{trie::Trie, ByteDecoder, Data, Tokenizer, BYTE_PIECES};
 use common::utok;
-use memmap2::Mmap;
-use patricia_tree::PatriciaMap;
-use std::{fs::File, io::Result, path::Path};
+use std::{
+    fs,
+    io::{Error, ErrorKind::InvalidData, Result},
+    path::Path,
+};
 
 /// 一个基于朴素词表的分词器。
 pub struct VocabTxt {
-    /// 词表。
-    words: Vec<String>,
-    /// 词汇的前缀树。
-    trie: PatriciaMap<utok>,
-    /// 词汇的最大长度。
-    max_piece_len: usize,
+    /// 词汇及其前缀树。
+    trie: Trie,
     /// 单字节词汇转义。
     byte_pieces: ByteDecoder,
 }
 
 impl VocabTxt {
     pub fn from_txt_file(tokenizer: impl AsRef<Path>) -> Result<Self> {
-        let file = File::open(tokenizer)?;
-        let mmap = unsafe { Mmap::map(&file) }?;
-        let text = unsafe { std::str::from_utf8_unchecked(&mmap) };
+        let data = Data::open(tokenizer)?;
+        let text = std::str::from_utf8(&data).map_err(|e| Error::new(InvalidData, e))?;
+        Self::from_txt(text)
+    }
+
+    /// 映射 [`VocabTxt::save_trie`] 保存的前缀树文件，多个进程映射同一个文件时共享内存。
+    #[inline]
+    pub fn from_trie_file(trie: impl AsRef<Path>) -> Result<Self> {
+        Self::new(Trie::parse(Data::open(trie)?)?)
+    }
+
+    /// 由 [`VocabTxt::save_trie`] 保存的前缀树构造分词器。
+    #[inline]
+    pub fn from_trie_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Self::new(Trie::parse(Data::Owned(bytes))?)
+    }
+
+    /// 将词表及其前缀树保存为可以直接映射的文件。
+    pub fn save_trie(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path, self.trie.as_bytes())
+    }
+
+    /// 从每行一个带引号的词汇的文本构造分词器。
+    pub fn from_txt(text: &str) -> Result<Self> {
+        let pieces = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| {
+                line.strip_prefix('"')
+                    .and_then(|s| s.strip_suffix('"'))
+                    .ok_or_else(|| {
+                        Error::new(InvalidData, format!("line {} is not a quoted piece", i + 1))
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(Trie::build(pieces))
+    }
 
-        let mut words = Vec::new();
-        let mut trie = PatriciaMap::new();
-        let mut max_piece_len = 0;
-        for (i, line) in text.lines().enumerate() {
-            let piece = line.strip_prefix('"').unwrap().strip_suffix('"').unwrap();
-            max_piece_len = max_piece_len.max(piece.len());
-            words.push(piece.to_string());
-            trie.insert(piece, i as _);
+    fn new(trie: Trie) -> Result<Self> {
+        // 未登录的字符按字节编码为 3 号之后的 256 个词
+        if !(BYTE_PIECES..=utok::MAX as usize).contains(&trie.num_words()) {
+            return Err(Error::new(
+                InvalidData,
+                format!("unsupported vocabulary size {}", trie.num_words()),
+            ));
         }
         Ok(Self {
-            words,
             trie,
-            max_piece_len,
             byte_pieces: ByteDecoder::new(),
         })
     }
@@ -42,26 +71,28 @@ impl VocabTxt {
 
 impl Tokenizer for VocabTxt {
     fn vocab_size(&self) -> usize {
-        self.words.len()
+        self.trie.num_words()
     }
 
     #[inline]
     fn max_piece_len(&self) -> usize {
-        self.max_piece_len
+        self.trie.max_piece_len()
     }
 
     fn encode(&self, mut text: &str) -> Vec<utok> {
         let mut tokens = Vec::<utok>::new();
 
         while !text.is_empty() {
-            if let Some((pre, tok)) = self.trie.get_longest_common_prefix(text) {
-                tokens.push(*tok);
-                text = &text[pre.len()..];
-            } else {
-                let mut chars = text.chars();
-                let char = chars.next().unwrap();
-                tokens.extend(char.to_string().bytes().map(|b| (b + 3) as utok));
-                text = chars.as_str();
+            match self.trie.longest_prefix(text.as_bytes()) {
+                Some((len, tok)) => {
+                    tokens.push(tok);
+                    text = &text[len..];
+                }
+                None => {
+                    let len = text.chars().next().map_or(1, char::len_utf8);
+                    tokens.extend(text[..len].bytes().map(|b| b as utok + 3));
+                    text = &text[len..];
+                }
             }
         }
 
@@ -69,7 +100,7 @@ impl Tokenizer for VocabTxt {
     }
 
     #[inline]
-    fn decode(&self, token: utok) -> &str {
-        self.byte_pieces.decode(self.words[token as usize].as_str())
+    fn decode_bytes(&self, token: utok) -> &[u8] {
+        self.byte_pieces.decode(self.trie.piece(token))
     }
 }
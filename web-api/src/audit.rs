@@ -0,0 +1,132 @@
+//! 推理请求的审计日志。
+
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// 一次推理请求的审计记录。
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct AuditRecord {
+    /// 请求到达的时间，unix 毫秒时间戳。
+    pub timestamp: u64,
+    /// 请求指定的会话，匿名会话为 `None`。
+    pub session_id: Option<String>,
+    /// 客户端附加的用户标识。
+    pub user: Option<String>,
+    /// 客户端附加的不透明信息。
+    pub metadata: Option<String>,
+    /// 请求携带的消息。
+    pub prompts: Vec<String>,
+    /// 请求指定的生成参数。
+    pub parameters: serde_json::Value,
+    /// 生成的文本。
+    pub output: String,
+    /// 生成结束的原因。
+    pub finish_reason: Option<&'static str>,
+    /// 需要计算的提示词数量，不包括已缓存的部分。
+    pub prompt_tokens: usize,
+    /// 生成的词数。
+    pub completion_tokens: usize,
+    /// 从请求到达到生成第一段文本的毫秒数。
+    pub first_token_ms: Option<u64>,
+    /// 从请求到达到生成结束的毫秒数。
+    pub total_ms: u64,
+}
+
+/// 写入日志前修改记录，用于去除敏感信息。
+pub type Redactor = dyn Fn(&mut AuditRecord) + Send + Sync;
+
+/// JSONL 格式的审计日志，每个请求一行。
+///
+/// 文件由专用的线程写入，记录请求的异步任务不会阻塞在磁盘上；释放日志时等待已提交的记录写完。
+pub struct AuditLog {
+    lines: Option<Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+    redactors: Vec<Arc<Redactor>>,
+}
+
+impl AuditLog {
+    /// 打开 `path` 处的日志文件，新记录追加到文件末尾。
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = channel();
+        let writer = thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || write_lines(BufWriter::new(file), receiver))?;
+        Ok(Self {
+            lines: Some(sender),
+            writer: Some(writer),
+            redactors: Vec::new(),
+        })
+    }
+
+    /// 添加一个去除敏感信息的钩子，钩子按添加顺序执行。
+    pub fn with_redactor(mut self, redactor: Arc<Redactor>) -> Self {
+        self.redactors.push(redactor);
+        self
+    }
+
+    pub(crate) fn write(&self, mut record: AuditRecord) {
+        for redactor in &self.redactors {
+            redactor(&mut record);
+        }
+        let mut line = serde_json::to_string(&record).unwrap();
+        line.push('\n');
+        if let Some(lines) = &self.lines {
+            // 写入线程只在日志释放时退出
+            let _ = lines.send(line);
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        drop(self.lines.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// 逐行写入，每次写完队列中已有的行后刷新文件。
+fn write_lines(mut file: impl Write, lines: Receiver<String>) {
+    while let Ok(line) = lines.recv() {
+        let result = file.write_all(line.as_bytes()).and_then(|()| {
+            for line in lines.try_iter() {
+                file.write_all(line.as_bytes())?;
+            }
+            file.flush()
+        });
+        if let Err(e) = result {
+            warn!("Failed to write audit log: {e}");
+        }
+    }
+}
+
+#[test]
+fn test_redactor() {
+    let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+    let log = AuditLog::open(&path)
+        .unwrap()
+        .with_redactor(Arc::new(|r: &mut AuditRecord| r.prompts.clear()));
+    log.write(AuditRecord {
+        prompts: vec!["secret".into()],
+        output: "hello".into(),
+        ..Default::default()
+    });
+    // 释放时等待写入线程写完
+    drop(log);
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(!text.contains("secret"));
+    assert!(text.contains("hello"));
+    assert_eq!(text.lines().count(), 1);
+}
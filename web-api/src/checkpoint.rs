@@ -0,0 +1,168 @@
+//! 具名会话的检查点，服务崩溃重启后告知客户端哪些会话可以重新预填充恢复。
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, TryLockError},
+};
+
+/// 记录每个具名会话的对话位置，不保存缓存。
+///
+/// 会话变化后由服务定期写入文件，发生 panic 时立即写入；
+/// 被强制结束的进程最多丢失一个写入周期内的变化。
+pub struct Checkpoint {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// 本次运行中存在的会话。
+    live: HashMap<String, usize>,
+    /// 上次运行留下、尚未重新创建的会话。
+    recoverable: HashMap<String, usize>,
+    /// 上次写入后是否有变化。
+    dirty: bool,
+}
+
+impl Checkpoint {
+    /// 使用 `path` 处的检查点文件，文件中上次运行的会话都视为可恢复。
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let recoverable = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            state: Mutex::new(State {
+                recoverable,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// 上次运行留下的会话数。
+    #[inline]
+    pub fn num_recoverable(&self) -> usize {
+        self.state.lock().unwrap().recoverable.len()
+    }
+
+    /// 会话完成一次推理，对话位置为 `dialog_pos`。
+    pub(crate) fn record(&self, session_id: &str, dialog_pos: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.recoverable.remove(session_id);
+        state.live.insert(session_id.into(), dialog_pos);
+        state.dirty = true;
+    }
+
+    /// 从 `from` 复制出 `to`。
+    pub(crate) fn fork(&self, from: &str, to: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(&pos) = state.live.get(from) {
+            state.live.insert(to.into(), pos);
+            state.dirty = true;
+        }
+    }
+
+    /// 删除会话，返回它是否是上次运行留下的会话。
+    pub(crate) fn forget(&self, session_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.dirty = true;
+        state.live.remove(session_id);
+        state.recoverable.remove(session_id).is_some()
+    }
+
+    /// 上次运行留下的会话的对话位置。
+    pub(crate) fn recoverable(&self, session_id: &str) -> Option<usize> {
+        self.state
+            .lock()
+            .unwrap()
+            .recoverable
+            .get(session_id)
+            .copied()
+    }
+
+    /// 只保留仍然存在的会话，被清除的会话不再记录。
+    pub(crate) fn retain(&self, sessions: &HashSet<String>) {
+        let mut state = self.state.lock().unwrap();
+        let len = state.live.len();
+        state.live.retain(|k, _| sessions.contains(k));
+        state.dirty |= state.live.len() != len;
+    }
+
+    /// 有变化时写入文件。
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.dirty {
+            return Ok(());
+        }
+        self.write(&state)?;
+        state.dirty = false;
+        Ok(())
+    }
+
+    /// 发生 panic 时立即写入文件。
+    pub(crate) fn install_panic_hook(self: &Arc<Self>) {
+        let checkpoint = Arc::downgrade(self);
+        let prev = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if let Some(checkpoint) = checkpoint.upgrade() {
+                // 持有锁的线程可能正是 panic 的线程，不能等待
+                match checkpoint.state.try_lock() {
+                    Ok(state) => {
+                        let _ = checkpoint.write(&state);
+                    }
+                    Err(TryLockError::Poisoned(state)) => {
+                        let _ = checkpoint.write(&state.into_inner());
+                    }
+                    Err(TryLockError::WouldBlock) => {}
+                }
+            }
+            prev(info)
+        }));
+    }
+
+    /// 先写入临时文件再替换，写入中途崩溃不会破坏上一个检查点。
+    fn write(&self, state: &State) -> io::Result<()> {
+        let mut sessions = state.recoverable.clone();
+        sessions.extend(state.live.iter().map(|(k, &v)| (k.clone(), v)));
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, serde_json::to_vec(&sessions)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[test]
+fn test_checkpoint() {
+    let path = std::env::temp_dir().join(format!("checkpoint-{}.json", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let checkpoint = Checkpoint::open(&path).unwrap();
+    assert_eq!(checkpoint.num_recoverable(), 0);
+    checkpoint.record("a", 2);
+    checkpoint.fork("a", "b");
+    checkpoint.record("c", 4);
+    checkpoint.retain(&["a", "b"].into_iter().map(String::from).collect());
+    checkpoint.flush().unwrap();
+
+    // 重启后的会话在重新创建之前都可以恢复
+    let checkpoint = Checkpoint::open(&path).unwrap();
+    assert_eq!(checkpoint.num_recoverable(), 2);
+    assert_eq!(checkpoint.recoverable("a"), Some(2));
+    assert_eq!(checkpoint.recoverable("b"), Some(2));
+    assert_eq!(checkpoint.recoverable("c"), None);
+    checkpoint.record("a", 0);
+    assert_eq!(checkpoint.recoverable("a"), None);
+    assert!(checkpoint.forget("b"));
+    assert!(!checkpoint.forget("b"));
+    checkpoint.flush().unwrap();
+
+    let checkpoint = Checkpoint::open(&path).unwrap();
+    assert_eq!(checkpoint.recoverable("a"), Some(0));
+    assert_eq!(checkpoint.recoverable("b"), None);
+    fs::remove_file(&path).unwrap();
+}
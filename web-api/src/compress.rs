@@ -0,0 +1,168 @@
+//! 按 `Accept-Encoding` 压缩非流式的响应。
+
+use crate::response::full;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+    HeaderMap, Response,
+};
+use std::io::Write;
+
+/// 小于这个字节数的响应不压缩。
+const MIN_SIZE: usize = 1024;
+
+/// 支持的压缩方式，按 q 值相同时的优先顺序排列。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    const ALL: [Self; 3] = [Self::Brotli, Self::Gzip, Self::Deflate];
+
+    #[inline]
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// 选择客户端接受的 q 值最大的压缩方式，客户端不接受任何支持的方式时返回 `None`。
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let mut q = [None; 3];
+        let mut wildcard = None;
+        for item in headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+        {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let value = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(Some(1.), |q| q.parse::<f32>().ok());
+            let Some(value) = value else {
+                continue;
+            };
+            match Self::ALL
+                .iter()
+                .position(|e| e.name().eq_ignore_ascii_case(name))
+            {
+                Some(i) => q[i] = Some(value),
+                None if name == "*" => wildcard = Some(value),
+                None => {}
+            }
+        }
+        // 未列出的方式取通配符的 q 值，q 为 0 表示不接受
+        let mut best = None;
+        for (e, q) in Self::ALL.into_iter().zip(q) {
+            match q.or(wildcard) {
+                Some(q) if q > 0. && best.is_none_or(|(_, b)| q > b) => best = Some((e, q)),
+                _ => {}
+            }
+        }
+        best.map(|(e, _)| e)
+    }
+
+    fn encode(self, data: &[u8]) -> Vec<u8> {
+        use flate2::{
+            write::{GzEncoder, ZlibEncoder},
+            Compression,
+        };
+        match self {
+            Self::Brotli => {
+                let mut w = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                w.write_all(data).unwrap();
+                w.into_inner()
+            }
+            Self::Gzip => {
+                let mut w = GzEncoder::new(Vec::new(), Compression::default());
+                w.write_all(data).unwrap();
+                w.finish().unwrap()
+            }
+            // HTTP 的 deflate 是 zlib 格式
+            Self::Deflate => {
+                let mut w = ZlibEncoder::new(Vec::new(), Compression::default());
+                w.write_all(data).unwrap();
+                w.finish().unwrap()
+            }
+        }
+    }
+}
+
+/// 压缩足够大的非流式响应。
+pub(crate) async fn compress(
+    response: Response<BoxBody<Bytes, hyper::Error>>,
+    encoding: Option<Encoding>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let Some(encoding) = encoding else {
+        return Ok(response);
+    };
+    let streaming = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|t| t.as_bytes().starts_with(b"text/event-stream"));
+    if streaming || response.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = body.collect().await?.to_bytes();
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    if body.len() < MIN_SIZE {
+        return Ok(Response::from_parts(parts, full(body)));
+    }
+    let body = encoding.encode(&body);
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    Ok(Response::from_parts(parts, full(body)))
+}
+
+#[test]
+fn test_negotiate() {
+    let negotiate = |s: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(s).unwrap());
+        Encoding::negotiate(&headers)
+    };
+    assert_eq!(Encoding::negotiate(&HeaderMap::new()), None);
+    assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+    assert_eq!(negotiate("deflate, gzip;q=0.5"), Some(Encoding::Deflate));
+    assert_eq!(negotiate("br;q=0, *"), Some(Encoding::Gzip));
+    assert_eq!(negotiate("identity"), None);
+    assert_eq!(negotiate("*;q=0"), None);
+}
+
+#[test]
+fn test_encode() {
+    use std::io::Read;
+
+    let data = "logits ".repeat(1000);
+    for e in Encoding::ALL {
+        let compressed = e.encode(data.as_bytes());
+        assert!(compressed.len() < data.len());
+        let mut decoded = String::new();
+        match e {
+            Encoding::Brotli => brotli::Decompressor::new(&*compressed, 4096)
+                .read_to_string(&mut decoded)
+                .unwrap(),
+            Encoding::Gzip => flate2::read::GzDecoder::new(&*compressed)
+                .read_to_string(&mut decoded)
+                .unwrap(),
+            Encoding::Deflate => flate2::read::ZlibDecoder::new(&*compressed)
+                .read_to_string(&mut decoded)
+                .unwrap(),
+        };
+        assert_eq!(decoded, data);
+    }
+}
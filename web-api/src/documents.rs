@@ -0,0 +1,68 @@
+//! 已注册的文档，缓存预先计算，对话从文档会话的副本开始。
+
+use crate::schemas::Error;
+use causal_lm::CausalLM;
+use service::{CacheUsage, Session, SessionError};
+use std::{collections::HashMap, sync::Mutex};
+
+pub(crate) struct Documents<M: CausalLM>(Mutex<HashMap<String, Session<M>>>);
+
+impl<M: CausalLM> Default for Documents<M> {
+    #[inline]
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<M: CausalLM> Documents<M> {
+    #[inline]
+    pub fn contains(&self, id: &str) -> bool {
+        self.0.lock().unwrap().contains_key(id)
+    }
+
+    /// 加入计算好缓存的文档会话。
+    pub fn insert(&self, id: String, session: Session<M>) -> Result<(), Error> {
+        let mut documents = self.0.lock().unwrap();
+        if documents.contains_key(&id) {
+            return Err(Error::DuplicateDocument);
+        }
+        documents.insert(id, session);
+        Ok(())
+    }
+
+    /// 复制文档会话，作为新对话的开头。
+    pub fn fork(&self, id: &str) -> Result<Session<M>, Error> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(id)
+            .ok_or_else(|| Error::InvalidParam("document_id", format!("Unknown document: {id}")))?
+            .fork()
+            .map_err(|e| {
+                error!("Failed to fork document {id}: {e}");
+                Error::Session(SessionError::InvalidCache)
+            })
+    }
+
+    pub fn remove(&self, id: &str) -> Result<(), Error> {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(id)
+            .map(drop)
+            .ok_or(Error::DocumentNotFound)
+    }
+
+    /// 所有文档的缓存占用，按名字排序。
+    pub fn usage(&self) -> Vec<(String, CacheUsage)> {
+        let mut usage = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, s)| (id.clone(), s.cache_usage().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        usage.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        usage
+    }
+}
@@ -0,0 +1,146 @@
+//! 请求体和响应体的格式。
+
+use crate::schemas::Error;
+use hyper::{
+    header::{ACCEPT, CONTENT_TYPE},
+    HeaderMap,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+const JSON: &str = "application/json";
+const MSGPACK: &str = "application/msgpack";
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// 消息体的格式，默认为 JSON。
+///
+/// MessagePack 编码大量浮点数时比 JSON 更快更小，以结构体的字段名作为键，内容与 JSON 一一对应。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub(crate) enum Format {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl Format {
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime.trim().to_ascii_lowercase().as_str() {
+            JSON => Some(Self::Json),
+            MSGPACK | "application/x-msgpack" | "application/vnd.msgpack" => Some(Self::MsgPack),
+            _ => None,
+        }
+    }
+
+    /// 按 `Content-Type` 确定请求体的格式。
+    pub fn of_request(headers: &HeaderMap) -> Self {
+        headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| Self::from_mime(v.split(';').next().unwrap()))
+            .unwrap_or_default()
+    }
+
+    /// 按 `Accept` 选择响应体的格式，MessagePack 的 q 值不低于 JSON 时使用 MessagePack。
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        let mut json = 0f32;
+        let mut msgpack = 0f32;
+        for (mime, q) in accepted(headers) {
+            match Self::from_mime(mime) {
+                Some(Self::Json) => json = json.max(q),
+                Some(Self::MsgPack) => msgpack = msgpack.max(q),
+                None => {}
+            }
+        }
+        if msgpack > 0. && msgpack >= json {
+            Self::MsgPack
+        } else {
+            Self::Json
+        }
+    }
+
+    #[inline]
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Json => JSON,
+            Self::MsgPack => MSGPACK,
+        }
+    }
+
+    /// 解析请求体。
+    pub fn parse<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, Error> {
+        match self {
+            Self::Json => serde_json::from_slice(body).map_err(Error::WrongJson),
+            Self::MsgPack => rmp_serde::from_slice(body).map_err(Error::WrongMsgPack),
+        }
+    }
+
+    /// 序列化响应体。
+    pub fn serialize(self, body: &impl Serialize) -> Vec<u8> {
+        match self {
+            Self::Json => serde_json::to_vec(body).unwrap(),
+            Self::MsgPack => rmp_serde::to_vec_named(body).unwrap(),
+        }
+    }
+}
+
+/// `Accept` 中的每个类型和它的 q 值。
+fn accepted(headers: &HeaderMap) -> impl Iterator<Item = (&str, f32)> {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|item| {
+            let mut parts = item.split(';');
+            let mime = parts.next().unwrap().trim();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.), |q| q.parse().ok())
+                .unwrap_or(0.);
+            (mime, q)
+        })
+}
+
+/// `Accept` 中明确接受 `text/event-stream` 时，推理流以 Server-Sent Events 返回。
+pub(crate) fn accepts_events(headers: &HeaderMap) -> bool {
+    accepted(headers).any(|(mime, q)| mime.eq_ignore_ascii_case(EVENT_STREAM) && q > 0.)
+}
+
+#[test]
+fn test_format() {
+    use hyper::header::HeaderValue;
+
+    let headers = |name, value| {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    };
+    assert_eq!(Format::negotiate(&HeaderMap::new()), Format::Json);
+    assert_eq!(Format::negotiate(&headers(ACCEPT, "*/*")), Format::Json);
+    let accept = headers(ACCEPT, "application/msgpack, application/json;q=0.5");
+    assert_eq!(Format::negotiate(&accept), Format::MsgPack);
+    let accept = headers(ACCEPT, "application/msgpack;q=0.5, application/json");
+    assert_eq!(Format::negotiate(&accept), Format::Json);
+    let content_type = headers(CONTENT_TYPE, "application/x-msgpack; charset=binary");
+    assert_eq!(Format::of_request(&content_type), Format::MsgPack);
+    assert!(accepts_events(&headers(ACCEPT, "text/event-stream")));
+    assert!(!accepts_events(&headers(ACCEPT, "*/*")));
+    assert!(!accepts_events(&headers(ACCEPT, "text/event-stream;q=0")));
+
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    struct Report {
+        name: String,
+        logits: Vec<f32>,
+    }
+    let report = Report {
+        name: "x".into(),
+        logits: vec![0.12345679; 100],
+    };
+    for format in [Format::Json, Format::MsgPack] {
+        let bytes = format.serialize(&report);
+        assert_eq!(format.parse::<Report>(&bytes).unwrap(), report);
+    }
+    // 以字段名作为键，内容与 JSON 一致
+    let value = rmp_serde::from_slice::<serde_json::Value>(&Format::MsgPack.serialize(&report));
+    assert_eq!(value.unwrap()["name"], "x");
+    assert!(Format::MsgPack.serialize(&report).len() < Format::Json.serialize(&report).len());
+}
@@ -0,0 +1,250 @@
+//! 以 `Idempotency-Key` 去重推理请求。
+
+use crate::schemas::{Echo, Error, InferStream, Piece};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{
+    mpsc::{self, Sender},
+    watch,
+};
+
+/// 生成结束后保留结果的时长。
+const TTL: Duration = Duration::from_secs(600);
+/// 最多保留的结果数，超过时丢弃最早结束的结果。
+const CAPACITY: usize = 1024;
+
+/// 按键保存的推理结果，用于重放给重试的请求。
+#[derive(Default)]
+pub(crate) struct Replays(Mutex<HashMap<String, Arc<Mutex<Replay>>>>);
+
+struct Replay {
+    /// 请求的摘要，同一个键的请求必须相同。
+    fingerprint: u64,
+    /// 推理启动失败时为 `None`。
+    echo: Option<Echo>,
+    pieces: Vec<Piece>,
+    finished: Option<Instant>,
+    /// 收到新的输出或生成结束时通知客户端。
+    updated: watch::Sender<()>,
+}
+
+/// 接收输出的客户端，跳过已经收到的前 `skip` 字节文本。
+struct Subscriber {
+    sender: Sender<Piece>,
+    skip: usize,
+}
+
+impl Replays {
+    /// 键已存在时从 `resume_from` 字节处重放已有的结果并继续接收后续的输出，
+    /// 否则用 `start` 启动推理并记录输出。
+    ///
+    /// 推理的输出总是完整记录，每个客户端最多缓冲 `buffer` 段尚未发送的输出。
+    /// 同一个键的请求的摘要 `fingerprint` 不同时返回错误。
+    pub fn get_or_start(
+        &self,
+        key: String,
+        fingerprint: u64,
+        resume_from: usize,
+        buffer: usize,
+        start: impl FnOnce(Sender<Piece>) -> Result<Echo, Error>,
+    ) -> Result<InferStream, Error> {
+        let buffer = buffer.max(1);
+        let mut map = self.0.lock().unwrap();
+        // 正在启动的推理持有自己的锁，不等待它
+        map.retain(|_, r| {
+            r.try_lock()
+                .map_or(true, |r| r.finished.is_none_or(|t| t.elapsed() < TTL))
+        });
+
+        if let Some(replay) = map.get(&key).cloned() {
+            drop(map);
+            info!("Replay request with idempotency key {key} from {resume_from}");
+            return Replay::subscribe(&replay, fingerprint, resume_from, buffer);
+        }
+        // 无法续传已不存在的生成
+        if resume_from > 0 {
+            return Err(Error::StreamNotFound);
+        }
+        if map.len() >= CAPACITY && !evict(&mut map) {
+            drop(map);
+            warn!("Too many idempotent requests in flight, {key} not recorded");
+            let (sender, receiver) = mpsc::channel(buffer);
+            return Ok(InferStream {
+                echo: start(sender)?,
+                pieces: receiver,
+                offset: 0,
+                return_ids: false,
+            });
+        }
+
+        // 启动推理时只锁定这个键，同一个键的重试等待启动完成
+        let replay = Arc::new(Mutex::new(Replay {
+            fingerprint,
+            echo: None,
+            pieces: Vec::new(),
+            finished: None,
+            updated: watch::channel(()).0,
+        }));
+        let mut guard = replay.lock().unwrap();
+        map.insert(key.clone(), replay.clone());
+        drop(map);
+
+        let (sender, mut receiver) = mpsc::channel(buffer);
+        match start(sender) {
+            Ok(echo) => guard.echo = Some(echo),
+            Err(e) => {
+                guard.finished = Some(Instant::now());
+                drop(guard);
+                let mut map = self.0.lock().unwrap();
+                if map.get(&key).is_some_and(|r| Arc::ptr_eq(r, &replay)) {
+                    map.remove(&key);
+                }
+                return Err(e);
+            }
+        }
+        drop(guard);
+        let stream = Replay::subscribe(&replay, fingerprint, 0, buffer)?;
+        // 即使客户端断开，也继续接收输出以备重放
+        tokio::spawn(async move {
+            while let Some(piece) = receiver.recv().await {
+                let mut replay = replay.lock().unwrap();
+                replay.pieces.push(piece);
+                replay.updated.send_replace(());
+            }
+            let mut replay = replay.lock().unwrap();
+            replay.finished = Some(Instant::now());
+            replay.updated.send_replace(());
+        });
+        Ok(stream)
+    }
+}
+
+impl Replay {
+    /// 启动一个任务，按客户端接收的速度发送已记录和之后记录的输出。
+    fn subscribe(
+        replay: &Arc<Mutex<Self>>,
+        fingerprint: u64,
+        resume_from: usize,
+        buffer: usize,
+    ) -> Result<InferStream, Error> {
+        let (sender, receiver) = mpsc::channel(buffer);
+        let mut subscriber = Subscriber {
+            sender,
+            skip: resume_from,
+        };
+        let (echo, mut updated) = {
+            let replay = replay.lock().unwrap();
+            if replay.fingerprint != fingerprint {
+                return Err(Error::IdempotencyMismatch);
+            }
+            // 同一个键的推理启动失败
+            let Some(echo) = replay.echo.clone() else {
+                return Err(Error::StreamNotFound);
+            };
+            (echo, replay.updated.subscribe())
+        };
+        let replay = replay.clone();
+        tokio::spawn(async move {
+            let mut next = 0;
+            loop {
+                // 先订阅再读取，不会错过读取之后的输出
+                let (pieces, finished) = {
+                    let replay = replay.lock().unwrap();
+                    (replay.pieces[next..].to_vec(), replay.finished.is_some())
+                };
+                next += pieces.len();
+                for piece in pieces {
+                    if let Some(piece) = subscriber.skip(piece) {
+                        if subscriber.sender.send(piece).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                if finished || updated.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(InferStream {
+            pieces: receiver,
+            echo,
+            offset: resume_from,
+            return_ids: false,
+        })
+    }
+}
+
+/// 丢弃最早结束的结果，直到数量低于上限；推理中的结果不丢弃，全部在推理中时返回 `false`。
+fn evict(map: &mut HashMap<String, Arc<Mutex<Replay>>>) -> bool {
+    let mut finished = map
+        .iter()
+        .filter_map(|(k, r)| Some((r.try_lock().ok()?.finished?, k.clone())))
+        .collect::<Vec<_>>();
+    finished.sort_unstable();
+    let excess = (map.len() + 1).saturating_sub(CAPACITY);
+    for (_, key) in finished.into_iter().take(excess) {
+        map.remove(&key);
+    }
+    map.len() < CAPACITY
+}
+
+impl Subscriber {
+    /// 跳过客户端已经收到的文本，返回需要发送的部分。
+    fn skip(&mut self, piece: Piece) -> Option<Piece> {
+        match piece {
+            Piece::Text(s) if self.skip >= s.len() => {
+                self.skip -= s.len();
+                None
+            }
+            Piece::Text(s) if self.skip > 0 => {
+                let mut start = std::mem::take(&mut self.skip);
+                while !s.is_char_boundary(start) {
+                    start += 1;
+                }
+                Some(Piece::Text(s[start..].into()))
+            }
+            piece => Some(piece),
+        }
+    }
+}
+
+#[test]
+fn test_resume() {
+    let (sender, _receiver) = mpsc::channel(1);
+    let mut subscriber = Subscriber { sender, skip: 4 };
+    let text = ["ab", "cde", "fg"]
+        .into_iter()
+        .filter_map(|s| match subscriber.skip(Piece::Text(s.into())) {
+            Some(Piece::Text(s)) => Some(s),
+            _ => None,
+        })
+        .collect::<String>();
+    assert_eq!(text, "efg");
+}
+
+#[test]
+fn test_fingerprint() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let _rt = runtime.enter();
+
+    let replays = Replays::default();
+    let start = |_| Ok(Echo::default());
+    assert!(replays.get_or_start("a".into(), 1, 0, 1, start).is_ok());
+    // 相同的请求重放，不同的请求拒绝
+    assert!(replays
+        .get_or_start("a".into(), 1, 0, 1, |_| unreachable!())
+        .is_ok());
+    assert!(matches!(
+        replays.get_or_start("a".into(), 2, 0, 1, |_| unreachable!()),
+        Err(Error::IdempotencyMismatch)
+    ));
+    // 启动失败的键不保留
+    let failed = |_| Err(Error::InferenceFailed);
+    assert!(replays.get_or_start("b".into(), 1, 0, 1, failed).is_err());
+    assert!(replays.get_or_start("b".into(), 1, 0, 1, start).is_ok());
+}
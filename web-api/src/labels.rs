@@ -0,0 +1,70 @@
+//! 按客户端附加的 `metadata` 分组统计用量，分组限定在允许的取值中，避免无限增长。
+
+use crate::schemas::{MetadataUsage, Usage};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Mutex,
+};
+
+/// 不在允许的取值中或未指定 `metadata` 的请求归入的分组。
+const OTHER: &str = "other";
+
+/// 各分组启动以来的用量。
+pub(crate) struct MetricLabels {
+    allowed: HashSet<String>,
+    usage: Mutex<BTreeMap<String, MetadataUsage>>,
+}
+
+impl MetricLabels {
+    /// 只为 `allowed` 中的取值单独统计，其他的归入 `"other"`；`allowed` 为空时不统计。
+    pub fn new(allowed: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            allowed: allowed.into_iter().collect(),
+            usage: Default::default(),
+        }
+    }
+
+    /// 记录一次推理的用量。
+    pub fn record(&self, metadata: Option<&str>, usage: &Usage) {
+        if self.allowed.is_empty() {
+            return;
+        }
+        let label = metadata
+            .filter(|m| self.allowed.contains(*m))
+            .unwrap_or(OTHER);
+        let mut map = self.usage.lock().unwrap();
+        let entry = map.entry(label.into()).or_default();
+        entry.requests += 1;
+        entry.prompt_tokens += usage.prompt_tokens;
+        entry.completion_tokens += usage.completion_tokens;
+    }
+
+    /// 所有分组的用量，未统计时为空。
+    pub fn report(&self) -> BTreeMap<String, MetadataUsage> {
+        self.usage.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_labels() {
+    let usage = Usage {
+        prompt_tokens: 3,
+        completion_tokens: 2,
+        first_token_ms: None,
+        total_ms: 0,
+    };
+    let labels = MetricLabels::new(["batch".to_string()]);
+    labels.record(Some("batch"), &usage);
+    labels.record(Some("batch"), &usage);
+    labels.record(Some("job-42"), &usage);
+    labels.record(None, &usage);
+    let report = labels.report();
+    assert_eq!(report.keys().collect::<Vec<_>>(), ["batch", OTHER]);
+    assert_eq!(report["batch"].requests, 2);
+    assert_eq!(report[OTHER].prompt_tokens, 6);
+    assert_eq!(report[OTHER].completion_tokens, 4);
+
+    let labels = MetricLabels::new([]);
+    labels.record(Some("batch"), &usage);
+    assert!(labels.report().is_empty());
+}
@@ -1,58 +1,130 @@
 #![doc = include_str!("../README.md")]
 
+mod audit;
+mod checkpoint;
+mod compress;
+mod documents;
+mod format;
+mod idempotency;
+mod labels;
+mod limits;
+mod listen;
 mod manager;
+mod options;
+mod prefix;
+mod preset;
 mod response;
 mod schemas;
+mod shadow;
+mod webhook;
 
 use causal_lm::CausalLM;
-use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+use compress::{compress, Encoding};
+use format::{accepts_events, Format};
+use http_body_util::{combinators::BoxBody, BodyExt, Limited};
 use hyper::{
     body::{Bytes, Incoming},
+    header::HeaderValue,
     server::conn::http1,
     service::Service as HyperService,
-    Method, Request, Response, StatusCode,
+    Method, Request, Response,
 };
 use hyper_util::rt::TokioIo;
+use infinilm_schemas::{Version, VERSION_HEADER};
+use listen::Listener;
 use manager::ServiceManager;
-use response::{error, success, text_stream};
-use std::{
-    future::Future,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    pin::Pin,
-    sync::Arc,
-};
-use tokio::net::TcpListener;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use response::{error, event_stream, infer_stream, registered, report, scored, success};
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+use tokio::task::JoinSet;
+
+pub use audit::{AuditLog, AuditRecord, Redactor};
+pub use checkpoint::Checkpoint;
+pub use limits::Limits;
+pub use listen::{Listen, ParseListenError};
+pub use options::ServiceOptions;
+pub use preset::Presets;
+pub use shadow::Shadow;
+pub use webhook::{ParseWebhookError, Webhook, Webhooks};
 
 #[macro_use]
 extern crate log;
 
 pub async fn start_infer_service<M>(
     service: service::Service<M>,
-    port: u16,
-    session_capacity: Option<usize>,
+    listen: Vec<Listen>,
+    options: ServiceOptions,
 ) -> std::io::Result<()>
 where
     M: CausalLM + Send + Sync + 'static,
     M::Storage: Send,
 {
-    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
-    info!("start service at {addr}");
-
-    let app = App(Arc::new(ServiceManager::new(service, session_capacity)));
-    let listener = TcpListener::bind(addr).await?;
-    loop {
-        let app = app.clone();
-        let (stream, _) = listener.accept().await?;
+    let checkpoint = options.checkpoint.is_some();
+    let periodic = checkpoint || options.memory_limit.is_some();
+    let app = App(Arc::new(ServiceManager::new(service, options)));
+    // 定期写入检查点和检查内存，被强制结束的进程最多丢失一个周期内的变化
+    if periodic {
+        let manager = Arc::downgrade(&app.0);
         tokio::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(TokioIo::new(stream), app)
-                .await
-            {
-                warn!("Error serving connection: {err:?}");
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                match manager.upgrade() {
+                    Some(manager) => manager.maintain(),
+                    None => break,
+                }
             }
         });
     }
+    // 先绑定所有地址，任何一个失败都不启动服务
+    let mut listeners = Vec::with_capacity(listen.len());
+    for addr in &listen {
+        listeners.push(Listener::bind(addr).await?);
+        info!("start service at {addr}");
+    }
+    let mut set = JoinSet::new();
+    // 收到 Ctrl-C 或 SIGTERM 时写入检查点再退出，不丢失最后一个周期内的变化
+    if checkpoint {
+        let manager = app.0.clone();
+        set.spawn(async move {
+            tokio::signal::ctrl_c().await?;
+            info!("interrupted, writing checkpoint");
+            manager.checkpoint();
+            Ok(())
+        });
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut terminate = signal(SignalKind::terminate())?;
+            let manager = app.0.clone();
+            set.spawn(async move {
+                terminate.recv().await;
+                info!("terminated, writing checkpoint");
+                manager.checkpoint();
+                Ok(())
+            });
+        }
+    }
+    for listener in listeners {
+        let app = app.clone();
+        set.spawn(async move {
+            loop {
+                let app = app.clone();
+                let stream = listener.accept().await?;
+                tokio::spawn(async move {
+                    if let Err(err) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), app)
+                        .await
+                    {
+                        warn!("Error serving connection: {err:?}");
+                    }
+                });
+            }
+        });
+    }
+    match set.join_next().await {
+        Some(ret) => ret.unwrap(),
+        None => Ok(()),
+    }
 }
 
 struct App<M: CausalLM>(Arc<ServiceManager<M>>);
@@ -75,40 +147,92 @@ where
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
         let manager = self.0.clone();
+        let encoding = Encoding::negotiate(req.headers());
+        let input = Format::of_request(req.headers());
+        let output = Format::negotiate(req.headers());
+        let events = accepts_events(req.headers());
 
         macro_rules! response {
-            ($method:ident; $f:expr) => {
+            ($method:ident $(, $arg:expr)*; async $f:expr) => {
+                response!(@ $method $(, $arg)*; ret => $f(ret, output).await)
+            };
+            ($method:ident $(, $arg:expr)*; $f:expr) => {
+                response!(@ $method $(, $arg)*; ret => $f(output, ret))
+            };
+            (@ $method:ident $(, $arg:expr)*; $ret:ident => $response:expr) => {
                 Box::pin(async move {
-                    let whole_body = req.collect().await?.to_bytes();
-                    let req = serde_json::from_slice(&whole_body);
-                    Ok(match req {
-                        Ok(req) => match manager.$method(req) {
-                            Ok(ret) => $f(ret),
-                            Err(e) => error(e),
+                    let max = manager.limits().max_body_bytes;
+                    let whole_body = match Limited::new(req.into_body(), max).collect().await {
+                        Ok(body) => body.to_bytes(),
+                        // 除了超出限制，只可能是连接的错误
+                        Err(e) => match e.downcast::<hyper::Error>() {
+                            Ok(e) => return Err(*e),
+                            Err(_) => {
+                                return Ok(error(output, schemas::Error::TooLarge("body", max, None)))
+                            }
                         },
-                        Err(e) => error(schemas::Error::WrongJson(e)),
+                    };
+                    let ret = input
+                        .parse(&whole_body)
+                        .and_then(|req| manager.$method(req $(, $arg)*));
+                    Ok(match ret {
+                        Ok($ret) => $response,
+                        Err(e) => error(output, e),
                     })
                 })
             };
         }
 
-        match (req.method(), req.uri().path()) {
+        let response: Self::Future = match (req.method(), req.uri().path()) {
             (&Method::POST, "/infer") => {
-                response!(infer; |ret| text_stream(UnboundedReceiverStream::new(ret)))
+                let idempotency_key = req
+                    .headers()
+                    .get("idempotency-key")
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                if events {
+                    response!(infer, idempotency_key; async event_stream)
+                } else {
+                    response!(infer, idempotency_key; async infer_stream)
+                }
             }
+            (&Method::POST, "/completions") if events => response!(complete; async event_stream),
+            (&Method::POST, "/completions") => response!(complete; async infer_stream),
+            (&Method::POST, "/score") => response!(score; async scored),
             (&Method::POST, "/fork") => response!(fork ; success),
             (&Method::POST, "/drop") => response!(drop_; success),
+            (&Method::GET, "/cache") => {
+                let cache = manager.cache();
+                Box::pin(async move { Ok(report(output, cache)) })
+            }
+            (&Method::GET, "/throughput") => {
+                let throughput = manager.throughput();
+                Box::pin(async move { Ok(report(output, throughput)) })
+            }
+            (&Method::GET, "/admin/adapters") => {
+                let adapters = manager.adapters();
+                Box::pin(async move { Ok(report(output, adapters)) })
+            }
+            (&Method::POST, "/admin/adapters/load") => response!(load_adapter; success),
+            (&Method::POST, "/admin/adapters/unload") => response!(unload_adapter; success),
+            (&Method::GET, "/documents") => {
+                let documents = manager.documents();
+                Box::pin(async move { Ok(report(output, documents)) })
+            }
+            (&Method::POST, "/documents/register") => {
+                response!(register_document; async registered)
+            }
+            (&Method::POST, "/documents/drop") => response!(drop_document; success),
             // Return 404 Not Found for other routes.
-            _ => Box::pin(async move {
-                Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(
-                        Empty::<Bytes>::new()
-                            .map_err(|never| match never {})
-                            .boxed(),
-                    )
-                    .unwrap())
-            }),
-        }
+            _ => Box::pin(async move { Ok(error(output, schemas::Error::RouteNotFound)) }),
+        };
+        Box::pin(async move {
+            let mut response = compress(response.await?, encoding).await?;
+            response.headers_mut().insert(
+                VERSION_HEADER,
+                HeaderValue::from_static(Version::CURRENT.as_str()),
+            );
+            Ok(response)
+        })
     }
 }
@@ -0,0 +1,116 @@
+use crate::schemas::{Error, Sentence};
+
+/// 请求的大小限制。
+///
+/// 请求体在解析之前检查，句子数和字符数在分词之前检查，超出限制的请求直接返回错误，不会分配缓存。
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// 请求体的最大字节数。
+    pub max_body_bytes: usize,
+    /// 一次推理请求最多包含的句子数。
+    pub max_messages: usize,
+    /// 一次推理请求中所有句子的最多字符数。
+    pub max_chars: usize,
+    /// 一次推理请求中所有句子编码后的最多词数，`None` 表示不限制。
+    pub max_prompt_tokens: Option<usize>,
+    /// 为系统提示词和生成保留的词数，编码后的提示词不能超过模型的最大长度减去这个值，0 表示不保留。
+    pub reserved_tokens: usize,
+    /// 每个推理流中已生成但尚未发送给客户端的最多文本段数，客户端来不及接收时暂停生成。
+    pub stream_buffer: usize,
+}
+
+impl Default for Limits {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 4 << 20,
+            max_messages: 256,
+            max_chars: 1 << 20,
+            max_prompt_tokens: None,
+            reserved_tokens: 0,
+            stream_buffer: 64,
+        }
+    }
+}
+
+impl Limits {
+    /// 检查句子数和解码后的字符数。
+    pub(crate) fn check_messages(&self, messages: &[Sentence]) -> Result<(), Error> {
+        let n = messages.len();
+        if n > self.max_messages {
+            return Err(Error::TooLarge("messages", self.max_messages, Some(n)));
+        }
+        let chars = messages.iter().map(|m| m.content.chars().count()).sum();
+        if chars > self.max_chars {
+            return Err(Error::TooLarge("chars", self.max_chars, Some(chars)));
+        }
+        Ok(())
+    }
+
+    /// 是否限制了编码后的词数。
+    #[inline]
+    pub(crate) fn counts_tokens(&self) -> bool {
+        self.max_prompt_tokens.is_some() || self.reserved_tokens > 0
+    }
+
+    /// 检查编码后的词数，`max_seq_len` 是模型的最大长度。
+    pub(crate) fn check_tokens(&self, tokens: usize, max_seq_len: usize) -> Result<(), Error> {
+        let context = Some(max_seq_len.saturating_sub(self.reserved_tokens))
+            .filter(|_| self.reserved_tokens > 0);
+        match self.max_prompt_tokens.into_iter().chain(context).min() {
+            Some(max) if tokens > max => Err(Error::TooManyTokens(max, tokens)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_limits() {
+    let limits = Limits {
+        max_messages: 2,
+        max_chars: 5,
+        max_prompt_tokens: Some(3),
+        ..Default::default()
+    };
+    let sentence = |content: &str| Sentence {
+        role: "user".into(),
+        content: content.into(),
+        input_ids: None,
+    };
+
+    assert!(limits
+        .check_messages(&[sentence("你好"), sentence("abc")])
+        .is_ok());
+    assert!(matches!(
+        limits.check_messages(&[sentence("a"), sentence("b"), sentence("c")]),
+        Err(Error::TooLarge("messages", 2, Some(3))),
+    ));
+    assert!(matches!(
+        limits.check_messages(&[sentence("你好"), sentence("abcd")]),
+        Err(Error::TooLarge("chars", 5, Some(6))),
+    ));
+    assert!(limits.check_tokens(3, 128).is_ok());
+    assert!(matches!(
+        limits.check_tokens(4, 128),
+        Err(Error::TooManyTokens(3, 4))
+    ));
+
+    // 保留的词数与最大词数取较严格的一个
+    let limits = Limits {
+        max_prompt_tokens: Some(100),
+        reserved_tokens: 32,
+        ..Default::default()
+    };
+    assert!(limits.counts_tokens());
+    assert!(limits.check_tokens(96, 128).is_ok());
+    assert!(matches!(
+        limits.check_tokens(97, 128),
+        Err(Error::TooManyTokens(96, 97))
+    ));
+    assert!(matches!(
+        limits.check_tokens(101, 4096),
+        Err(Error::TooManyTokens(100, 101))
+    ));
+    assert!(!Limits::default().counts_tokens());
+    assert!(Limits::default().check_tokens(4096, 128).is_ok());
+}
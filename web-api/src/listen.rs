@@ -0,0 +1,133 @@
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    str::FromStr,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+#[cfg(unix)]
+use {std::path::PathBuf, tokio::net::UnixListener};
+
+/// 服务监听的地址。
+///
+/// 从字符串解析时，`unix:` 开头的是 Unix 域套接字的路径，只有端口号时监听所有 IPv4 地址，否则是 IPv4 或 IPv6 的套接字地址。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Listen {
+    /// TCP 地址。
+    Tcp(SocketAddr),
+    /// Unix 域套接字的路径。
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl From<u16> for Listen {
+    #[inline]
+    fn from(port: u16) -> Self {
+        Self::Tcp(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::UNSPECIFIED,
+            port,
+        )))
+    }
+}
+
+/// 无法解析的监听地址。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseListenError(String);
+
+impl fmt::Display for ParseListenError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid listen address: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseListenError {}
+
+impl FromStr for Listen {
+    type Err = ParseListenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            if !path.is_empty() {
+                return Ok(Self::Unix(path.into()));
+            }
+            return Err(ParseListenError(s.into()));
+        }
+        if let Ok(port) = s.parse::<u16>() {
+            return Ok(port.into());
+        }
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|_| ParseListenError(s.into()))
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// 连接的读写流。
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for T {}
+
+/// 绑定了地址的监听器。
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// 绑定地址。Unix 域套接字的路径上已有套接字文件时，认为是上次运行遗留的并删除。
+    pub async fn bind(listen: &Listen) -> io::Result<Self> {
+        match listen {
+            Listen::Tcp(addr) => TcpListener::bind(addr).await.map(Self::Tcp),
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                UnixListener::bind(path).map(Self::Unix)
+            }
+        }
+    }
+
+    /// 接受一个连接。
+    pub async fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            Self::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Self::Unix(listener) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("8000".parse(), Ok(Listen::from(8000)));
+    assert_eq!(
+        "127.0.0.1:80".parse(),
+        Ok(Listen::Tcp(([127, 0, 0, 1], 80).into()))
+    );
+    let v6 = "[::1]:80".parse::<Listen>().unwrap();
+    assert!(matches!(v6, Listen::Tcp(SocketAddr::V6(_))));
+    assert_eq!(v6.to_string(), "[::1]:80");
+    #[cfg(unix)]
+    assert_eq!(
+        "unix:/tmp/infer.sock".parse(),
+        Ok(Listen::Unix("/tmp/infer.sock".into()))
+    );
+    assert!("unix:".parse::<Listen>().is_err());
+    assert!("localhost".parse::<Listen>().is_err());
+}
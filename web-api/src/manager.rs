@@ -1,23 +1,144 @@
-use crate::schemas::{
-    AnonymousSessionId, DropSuccess, Drop_, Error, Fork, ForkSuccess, Infer, Sentence, SessionId,
+use crate::{
+    audit::{AuditLog, AuditRecord},
+    checkpoint::Checkpoint,
+    documents::Documents,
+    idempotency::Replays,
+    labels::MetricLabels,
+    limits::Limits,
+    options::ServiceOptions,
+    prefix::PrefixPool,
+    preset::Presets,
+    schemas::{
+        AdapterReport, AdapterStatus, AnonymousSessionId, CacheReport, CandidateScore, Complete,
+        DocumentReport, DocumentStatus, DropDocument, DropSuccess, Drop_, Echo, Error, Fork,
+        ForkSuccess, GenerationOverride, Infer, InferStream, LoadAdapter, LoadAdapterSuccess,
+        PendingDocument, PendingScore, Piece, RegisterDocument, RegisterDocumentSuccess, Score,
+        ScoreReport, Sentence, SessionCache, SessionId, ThroughputReport, UnloadAdapter,
+        UnloadAdapterSuccess, Usage,
+    },
+    shadow::{self, Shadow},
+    webhook::{WebhookEvent, Webhooks},
 };
 use base64::{engine::general_purpose, Engine};
 use causal_lm::CausalLM;
-use service::{Service, Session, SessionManager};
-use std::sync::Arc;
-use tokio::sync::mpsc::{self, UnboundedReceiver};
+use service::{ChatTemplate, Service, Session, SessionError, SessionManager};
+use std::{
+    collections::HashSet,
+    iter,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
 
 pub(crate) struct ServiceManager<M: CausalLM> {
     service: Service<M>,
     session_manager: SessionManager<SessionId, M>,
+    audit: Option<AuditLog>,
+    replays: Replays,
+    prefixes: Option<PrefixPool>,
+    documents: Documents<M>,
+    summarize_after: Option<usize>,
+    cache_budget: Option<usize>,
+    shadow: Option<Shadow>,
+    presets: Presets,
+    limits: Limits,
+    webhooks: Webhooks,
+    checkpoint: Option<Arc<Checkpoint>>,
+    labels: MetricLabels,
 }
 
 impl<M: CausalLM> ServiceManager<M> {
     #[inline]
-    pub fn new(service: Service<M>, capacity: Option<usize>) -> Self {
+    pub fn new(service: Service<M>, options: ServiceOptions) -> Self {
+        let checkpoint = options.checkpoint.map(Arc::new);
+        if let Some(checkpoint) = &checkpoint {
+            info!(
+                "{} sessions recoverable from checkpoint",
+                checkpoint.num_recoverable()
+            );
+            checkpoint.install_panic_hook();
+        }
         Self {
             service,
-            session_manager: SessionManager::new(capacity),
+            session_manager: SessionManager::new(options.session_capacity)
+                .with_budget(options.cache_budget)
+                .with_memory_limit(options.memory_limit),
+            audit: options.audit,
+            replays: Default::default(),
+            prefixes: Some(options.prefix_cache)
+                .filter(|&n| n > 0)
+                .map(PrefixPool::new),
+            documents: Default::default(),
+            summarize_after: options.summarize_after,
+            cache_budget: options.cache_budget,
+            shadow: options.shadow,
+            presets: options.presets,
+            limits: options.limits,
+            webhooks: options.webhooks,
+            checkpoint,
+            labels: MetricLabels::new(options.metric_labels),
+        }
+    }
+
+    #[inline]
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// 会话不存在时，检查它是否是重启前的会话。
+    fn not_found(&self, session_id: &str, e: SessionError) -> Error {
+        match (e, &self.checkpoint) {
+            (SessionError::NotFound, Some(checkpoint)) => checkpoint
+                .recoverable(session_id)
+                .map_or(Error::Session(e), Error::SessionRecoverable),
+            _ => Error::Session(e),
+        }
+    }
+
+    /// 记录具名会话完成推理后的对话位置。
+    fn record(&self, session_id: &SessionId, session: &Session<M>) {
+        if let (Some(checkpoint), SessionId::Permanent(id)) = (&self.checkpoint, session_id) {
+            checkpoint.record(id, session.dialog_pos())
+        }
+    }
+
+    /// 定期的维护：内存超限时清除空闲会话，并写入检查点。
+    pub fn maintain(&self) {
+        self.session_manager.shed();
+        self.checkpoint();
+    }
+
+    /// 将仍然存在的具名会话写入检查点。
+    pub fn checkpoint(&self) {
+        let Some(checkpoint) = &self.checkpoint else {
+            return;
+        };
+        let sessions = self
+            .session_manager
+            .usage()
+            .into_iter()
+            .filter_map(|(id, _)| match id {
+                SessionId::Permanent(id) => Some(id),
+                SessionId::Temporary(_) => None,
+            })
+            .collect::<HashSet<_>>();
+        checkpoint.retain(&sessions);
+        if let Err(e) = checkpoint.flush() {
+            warn!("Failed to write checkpoint: {e}");
+        }
+    }
+
+    /// 需要记录推理的结果。
+    #[inline]
+    fn reports(&self) -> bool {
+        self.audit.is_some() || !self.webhooks.is_empty()
+    }
+
+    /// 将一次推理的结果写入审计日志并推送到 webhook。
+    fn report(&self, record: AuditRecord) {
+        self.webhooks.send(WebhookEvent::finished(&record));
+        if let Some(audit) = &self.audit {
+            audit.write(record);
         }
     }
 }
@@ -28,99 +149,283 @@ where
     M::Storage: Send,
 {
     pub fn infer(
+        self: &Arc<Self>,
+        req: Infer,
+        idempotency_key: Option<String>,
+    ) -> Result<InferStream, Error> {
+        let resume_from = req.resume_from.unwrap_or(0);
+        let return_ids = req.return_ids.unwrap_or(false);
+        let session_id = req.session_id.clone();
+        let echo = req.echo.clone();
+        let stream = match idempotency_key {
+            Some(key) => {
+                let fingerprint = req.fingerprint();
+                self.replays.get_or_start(
+                    key,
+                    fingerprint,
+                    resume_from,
+                    self.limits.stream_buffer,
+                    |sender| self.start(req, sender),
+                )
+            }
+            // 没有键时无法找到要续传的生成
+            None if resume_from > 0 => Err(Error::StreamNotFound),
+            None => {
+                let (sender, receiver) = mpsc::channel(self.limits.stream_buffer.max(1));
+                let echo = self.start(req, sender)?;
+                Ok(InferStream {
+                    pieces: receiver,
+                    echo,
+                    offset: 0,
+                    return_ids: false,
+                })
+            }
+        }
+        .inspect_err(|e| {
+            let event = WebhookEvent::error(session_id, &echo, e.body());
+            self.webhooks.send(event)
+        })?;
+        // 重放的流是否返回词由重试的请求决定
+        Ok(InferStream {
+            return_ids,
+            ..stream
+        })
+    }
+
+    /// 启动推理，生成的文本发送到 `sender`，返回需要原样返回给客户端的信息。
+    fn start(
         self: &Arc<Self>,
         Infer {
             inputs: mut messages,
             encoding,
             session_id,
             dialog_pos,
-            temperature,
-            top_k,
-            top_p,
+            preset,
+            document_id,
+            generation,
+            echo,
+            ..
         }: Infer,
-    ) -> Result<UnboundedReceiver<String>, Error> {
-        match encoding.as_deref() {
-            Some("base64") | None => {
-                for m in &mut messages {
-                    let content = m.content.as_str();
-                    m.content = general_purpose::STANDARD
-                        .decode(content)
-                        .map(String::from_utf8)
-                        .map_err(|_| Error::ContentError(format!("Decode failed: {content}")))?
-                        .map_err(|_| Error::ContentError(format!("Decode failed: {content}")))?;
-                }
+        sender: mpsc::Sender<Piece>,
+    ) -> Result<Echo, Error> {
+        echo.check()?;
+        let generation = self.presets.resolve(preset.as_deref(), generation)?;
+        if let Some(name) = &generation.adapter {
+            if self.service.adapter(name).is_none() {
+                return Err(Error::InvalidParam(
+                    "adapter",
+                    format!("Unknown adapter: {name}"),
+                ));
             }
-            Some("text") => {}
-            Some(e) => return Err(Error::ContentError(format!("Unknown encoding: {e}"))),
+        }
+        if let Some(name) = &generation.steering {
+            if self.service.steering(name).is_none() {
+                return Err(Error::InvalidParam(
+                    "steering",
+                    format!("Unknown steering: {name}"),
+                ));
+            }
+        }
+        if let Some(name) = &generation.soft_prompt {
+            if self.service.soft_prompt(name).is_none() {
+                return Err(Error::InvalidParam(
+                    "soft_prompt",
+                    format!("Unknown soft prompt: {name}"),
+                ));
+            }
+        }
+        if let Some(template) = &generation.chat_template {
+            if ChatTemplate::new(template.as_str()).is_none() {
+                return Err(Error::InvalidParam(
+                    "chat_template",
+                    format!("`chat_template` must contain {}", ChatTemplate::PLACEHOLDER),
+                ));
+            }
+        }
+        decode(encoding.as_deref(), &mut messages)?;
+        for m in &messages {
+            if let Some(ids) = &m.input_ids {
+                self.check_ids(ids, &m.content)?;
+            }
+        }
+        // 分词之前检查大小，再检查编码后的长度；过长而无法分词的句子在启动推理之前拒绝
+        self.limits.check_messages(&messages)?;
+        let mut tokens = 0;
+        for m in &messages {
+            tokens += match &m.input_ids {
+                Some(ids) => ids.len(),
+                None => self.service.num_tokens(&m.content)?,
+            };
+        }
+        if self.limits.counts_tokens() {
+            self.limits
+                .check_tokens(tokens, self.service.max_seq_len())?;
+        }
+        if let Some(choices) = &generation.choices {
+            for choice in choices {
+                self.service.num_tokens(choice)?;
+            }
+        }
+        // 按文本复用前缀和复制到影子模型都无法处理以词给出的句子
+        let pretokenized = messages.iter().any(|m| m.input_ids.is_some());
+        // 文档只能作为新对话的开头
+        let document = match (&document_id, dialog_pos.unwrap_or(0)) {
+            (Some(id), 0) => Some(self.documents.fork(id)?),
+            (Some(_), _) => {
+                return Err(Error::InvalidParam(
+                    "document_id",
+                    "`document_id` requires zero dialog position".into(),
+                ))
+            }
+            (None, _) => None,
         };
 
         async fn infer<M: CausalLM>(
             session_id: &SessionId,
             session: &mut Session<M>,
             messages: Vec<Sentence>,
-            temperature: Option<f32>,
-            top_k: Option<usize>,
-            top_p: Option<f32>,
-            sender: mpsc::UnboundedSender<String>,
-        ) {
-            if let Some(temperature) = temperature {
-                session.sample.temperature = temperature;
-            }
-            if let Some(top_k) = top_k {
-                session.sample.top_k = top_k;
-            }
-            if let Some(top_p) = top_p {
-                session.sample.top_p = top_p;
-            }
+            generation: GenerationOverride,
+            sender: mpsc::Sender<Piece>,
+            echo: Echo,
+            manager: &ServiceManager<M>,
+        ) -> String {
+            let start = Instant::now();
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64);
+            generation.apply(session);
 
-            session.extend(messages.iter().map(|s| s.content.as_str()));
+            for m in &messages {
+                match &m.input_ids {
+                    Some(ids) => session.extend_tokens([ids.clone()]),
+                    // 句子在启动推理前检查过，只有加上对话模板后才可能过长
+                    None => {
+                        if let Err(e) = session.extend([m.content.as_str()]) {
+                            warn!("{session_id:?} inference skipped: {e}{echo}");
+                            return String::new();
+                        }
+                    }
+                }
+            }
             if session.dialog_pos() % 2 == 1 {
-                info!("{session_id:?} inference started");
+                info!("{session_id:?} inference started{echo}");
                 let mut busy = session.chat();
-                while let Some(s) = busy.decode().await {
-                    if let Err(e) = sender.send(s) {
+                let mut output = String::new();
+                let mut ids = Vec::new();
+                let mut first_token_ms = None;
+                while let Some(chunk) = busy.decode().await {
+                    ids.extend_from_slice(&chunk.tokens);
+                    if chunk.text.is_empty() {
+                        continue;
+                    }
+                    first_token_ms.get_or_insert_with(|| start.elapsed().as_millis() as u64);
+                    output.push_str(&chunk.text);
+                    if let Err(e) = sender.send(Piece::Text(chunk.into())).await {
                         warn!("Failed to send piece to {session_id:?} with error \"{e}\"");
                         break;
                     }
                 }
-                info!("{session_id:?} inference stopped");
+                let finish_reason = busy.finish_reason();
+                let usage = Usage {
+                    prompt_tokens: busy.num_prompt_tokens(),
+                    completion_tokens: busy.num_generated_tokens(),
+                    first_token_ms,
+                    total_ms: start.elapsed().as_millis() as u64,
+                };
+                if let Some(reason) = finish_reason {
+                    let _ = sender.send(Piece::Finish(reason, ids, usage)).await;
+                }
+                manager.labels.record(echo.metadata.as_deref(), &usage);
+                if manager.reports() {
+                    manager.report(AuditRecord {
+                        timestamp,
+                        session_id: match session_id {
+                            SessionId::Permanent(id) => Some(id.clone()),
+                            SessionId::Temporary(_) => None,
+                        },
+                        user: echo.user.clone(),
+                        metadata: echo.metadata.clone(),
+                        prompts: messages.into_iter().map(|s| s.content).collect(),
+                        parameters: serde_json::to_value(&generation).unwrap(),
+                        output: output.clone(),
+                        finish_reason: finish_reason.map(|r| r.as_str()),
+                        prompt_tokens: usage.prompt_tokens,
+                        completion_tokens: usage.completion_tokens,
+                        first_token_ms,
+                        total_ms: usage.total_ms,
+                    });
+                }
+                info!("{session_id:?} inference stopped{echo}");
+                output
             } else {
-                info!("{session_id:?} inference skipped");
+                info!("{session_id:?} inference skipped{echo}");
+                String::new()
+            }
+        }
+
+        /// 对话过长时总结较早的一半对话，总结最多占阈值的四分之一，失败时保留原来的对话。
+        async fn summarize<M: CausalLM>(
+            session_id: &SessionId,
+            session: &mut Session<M>,
+            threshold: usize,
+        ) {
+            if session.num_tokens() > threshold {
+                info!("{session_id:?} summarizing");
+                let turns = (session.dialog_pos() / 4).max(1);
+                if !session.summarize(turns, (threshold / 4).max(1)).await {
+                    warn!("{session_id:?} not summarized, dialog kept");
+                }
             }
         }
 
         match (session_id, dialog_pos.unwrap_or(0)) {
             (Some(session_id_str), 0) => {
-                let session_id = SessionId::Permanent(session_id_str);
+                let session_id = SessionId::Permanent(session_id_str.clone());
+                let mut created = false;
                 let mut session = self
                     .session_manager
-                    .take_or_register(session_id.clone(), || self.service.launch())
+                    .take_or_register(session_id.clone(), || {
+                        created = true;
+                        self.service.launch()
+                    })
                     .map_err(Error::Session)?;
-                let (sender, receiver) = mpsc::unbounded_channel();
+                if created {
+                    let event = WebhookEvent::session_created(session_id_str, &echo);
+                    self.webhooks.send(event);
+                }
                 let self_ = self.clone();
+                let echo_ = echo.clone();
                 tokio::spawn(async move {
-                    session.revert(0).unwrap();
+                    match document {
+                        Some(document) => session = document,
+                        None => session.revert(0).unwrap(),
+                    }
+                    self_.record(&session_id, &session);
                     infer(
                         &session_id,
                         &mut session,
                         messages,
-                        temperature,
-                        top_k,
-                        top_p,
+                        generation,
                         sender,
+                        echo_,
+                        &self_,
                     )
                     .await;
+                    if let Some(threshold) = self_.summarize_after {
+                        summarize(&session_id, &mut session, threshold).await;
+                    }
 
+                    self_.record(&session_id, &session);
                     self_.session_manager.restore(&session_id, session);
                 });
-                Ok(receiver)
+                Ok(echo)
             }
             (Some(session_id_str), p) => {
-                let session_id = SessionId::Permanent(session_id_str);
+                let session_id = SessionId::Permanent(session_id_str.clone());
                 let mut session = self
                     .session_manager
                     .take(&session_id)
-                    .map_err(Error::Session)?;
+                    .map_err(|e| self.not_found(&session_id_str, e))?;
                 if session.revert(p).is_err() {
                     let current = session.dialog_pos();
                     warn!(
@@ -129,49 +434,138 @@ where
                     self.session_manager.restore(&session_id, session);
                     return Err(Error::InvalidDialogPos(current));
                 }
-                let (sender, receiver) = mpsc::unbounded_channel();
                 let self_ = self.clone();
+                let echo_ = echo.clone();
                 tokio::spawn(async move {
                     info!("{session_id:?} reverted to {p}");
+                    self_.record(&session_id, &session);
                     infer(
                         &session_id,
                         &mut session,
                         messages,
-                        temperature,
-                        top_k,
-                        top_p,
+                        generation,
                         sender,
+                        echo_,
+                        &self_,
                     )
                     .await;
+                    if let Some(threshold) = self_.summarize_after {
+                        summarize(&session_id, &mut session, threshold).await;
+                    }
 
+                    self_.record(&session_id, &session);
                     self_.session_manager.restore(&session_id, session);
                 });
-                Ok(receiver)
+                Ok(echo)
+            }
+            (None, 0)
+                if messages.len() % 2 == 1
+                    && self.prefixes.is_some()
+                    && document.is_none()
+                    && !pretokenized =>
+            {
+                // 无状态模式，复用对话前缀相同的会话；会话保存在会话管理器中，受相同的限制
+                let mut dialog = messages
+                    .iter()
+                    .map(|m| m.content.clone())
+                    .collect::<Vec<_>>();
+                let shadow = self.shadow(&dialog, &generation);
+                let prefixes = self.prefixes.as_ref().unwrap();
+                // 保留的会话可能已被管理器清除
+                let template = generation.chat_template.clone();
+                let cached = prefixes.take(&dialog, template.as_deref());
+                let reused = cached.and_then(|(session_id, pos)| {
+                    let session = self.session_manager.take(&session_id).ok()?;
+                    Some((session_id, session, pos))
+                });
+                let (session_id, mut session, pos) = match reused {
+                    Some(reused) => reused,
+                    None => {
+                        let session_id = SessionId::Temporary(AnonymousSessionId::new());
+                        let session = self
+                            .session_manager
+                            .take_or_register(session_id.clone(), || self.service.launch())
+                            .map_err(Error::Session)?;
+                        (session_id, session, 0)
+                    }
+                };
+                session.revert(pos).unwrap();
+                // 复用的会话可能带有上一个请求的参数，未指定的参数应使用服务的默认值
+                session.sample = self.service.default_sample.clone();
+                session.stop = Default::default();
+                session.detokenize = self.service.default_detokenize;
+                info!("{session_id:?} reuses {pos} sentences");
+                messages.drain(..pos);
+
+                let self_ = self.clone();
+                let echo_ = echo.clone();
+                tokio::spawn(async move {
+                    let start = Instant::now();
+                    let output = infer(
+                        &session_id,
+                        &mut session,
+                        messages,
+                        generation,
+                        sender,
+                        echo_,
+                        &self_,
+                    )
+                    .await;
+                    let time = start.elapsed();
+                    dialog.push(output.clone());
+                    dialog.truncate(session.dialog_pos());
+                    self_.session_manager.restore(&session_id, session);
+                    let prefixes = self_.prefixes.as_ref().unwrap();
+                    if let Some(out) = prefixes.put(dialog, template, session_id.clone()) {
+                        // 可能已被管理器清除
+                        let _ = self_.session_manager.drop_(&out);
+                    }
+                    if let Some(shadow) = shadow {
+                        shadow::compare(&session_id, &output, time, shadow).await;
+                    }
+                });
+                Ok(echo)
             }
             (None, 0) => {
                 let session_id = SessionId::Temporary(AnonymousSessionId::new());
                 let mut session = self
                     .session_manager
-                    .take_or_register(session_id.clone(), || self.service.launch())
+                    .take_or_register(session_id.clone(), || {
+                        document.unwrap_or_else(|| self.service.launch())
+                    })
                     .map_err(Error::Session)?;
-                let (sender, receiver) = mpsc::unbounded_channel();
                 let self_ = self.clone();
+                let echo_ = echo.clone();
                 if messages.len() % 2 == 1 {
+                    let dialog = messages
+                        .iter()
+                        .map(|m| m.content.clone())
+                        .collect::<Vec<_>>();
+                    let shadow = if pretokenized {
+                        None
+                    } else {
+                        self.shadow(&dialog, &generation)
+                    };
                     tokio::spawn(async move {
-                        infer(
+                        let start = Instant::now();
+                        let output = infer(
                             &session_id,
                             &mut session,
                             messages,
-                            temperature,
-                            top_k,
-                            top_p,
+                            generation,
                             sender,
+                            echo_,
+                            &self_,
                         )
                         .await;
+                        let time = start.elapsed();
                         self_.session_manager.drop_(&session_id).unwrap();
+                        if let Some(shadow) = shadow {
+                            shadow::compare(&session_id, &output, time, shadow).await;
+                        }
                     });
                 }
-                Ok(receiver)
+                Ok(echo)
             }
             (None, _) => {
                 warn!("Temporary session must be created with zero dialog position");
@@ -180,6 +574,270 @@ where
         }
     }
 
+    /// 续写原始的提示词，不使用会话也不应用对话模板。
+    pub fn complete(self: &Arc<Self>, req: Complete) -> Result<InferStream, Error> {
+        let echo = req.echo.clone();
+        self.start_completion(req).inspect_err(|e| {
+            let event = WebhookEvent::error(None, &echo, e.body());
+            self.webhooks.send(event)
+        })
+    }
+
+    fn start_completion(
+        self: &Arc<Self>,
+        Complete {
+            prompt,
+            encoding,
+            input_ids,
+            preset,
+            return_ids,
+            prompt_logprobs,
+            generation,
+            echo,
+        }: Complete,
+    ) -> Result<InferStream, Error> {
+        echo.check()?;
+        let generation = self.presets.resolve(preset.as_deref(), generation)?;
+        if let Some(name) = generation.session_only() {
+            return Err(Error::InvalidParam(
+                name,
+                format!("`{name}` is not supported in completions"),
+            ));
+        }
+        let mut messages = [Sentence {
+            role: "user".into(),
+            content: prompt,
+            input_ids: None,
+        }];
+        let tokens = match input_ids {
+            Some(ids) => {
+                self.check_ids(&ids, &messages[0].content)?;
+                ids
+            }
+            None => {
+                decode(encoding.as_deref(), &mut messages)?;
+                self.limits.check_messages(&messages)?;
+                self.service.encode(&messages[0].content)?
+            }
+        };
+        if tokens.is_empty() {
+            return Err(Error::InvalidContent("Empty prompt".into()));
+        }
+        self.limits
+            .check_tokens(tokens.len(), self.service.max_seq_len())?;
+
+        let mut sample = self.service.default_sample.clone();
+        let mut stop = Default::default();
+        let mut detokenize = self.service.default_detokenize;
+        generation.apply_args(&mut sample, &mut stop, &mut detokenize);
+        let mut prompt_logprobs = prompt_logprobs.unwrap_or(false);
+        let mut generator =
+            self.service
+                .complete(tokens, sample, stop, detokenize, prompt_logprobs);
+
+        let (sender, receiver) = mpsc::channel(self.limits.stream_buffer.max(1));
+        let self_ = self.clone();
+        let echo_ = echo.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64);
+            info!("completion started{echo_}");
+            let mut output = String::new();
+            let mut ids = Vec::new();
+            let mut first_token_ms = None;
+            while let Some(chunk) = generator.decode().await {
+                // 收到第一段时预填充已经完成
+                if std::mem::take(&mut prompt_logprobs) {
+                    if let Some(logprobs) = generator.prompt_logprobs() {
+                        let _ = sender.send(Piece::PromptLogprobs(logprobs)).await;
+                    }
+                }
+                ids.extend_from_slice(&chunk.tokens);
+                if chunk.text.is_empty() {
+                    continue;
+                }
+                first_token_ms.get_or_insert_with(|| start.elapsed().as_millis() as u64);
+                output.push_str(&chunk.text);
+                if sender.send(Piece::Text(chunk.into())).await.is_err() {
+                    break;
+                }
+            }
+            let finish_reason = generator.finish_reason();
+            let usage = Usage {
+                prompt_tokens: generator.num_prompt_tokens(),
+                completion_tokens: generator.num_generated_tokens(),
+                first_token_ms,
+                total_ms: start.elapsed().as_millis() as u64,
+            };
+            if let Some(reason) = finish_reason {
+                let _ = sender.send(Piece::Finish(reason, ids, usage)).await;
+            }
+            self_.labels.record(echo_.metadata.as_deref(), &usage);
+            if self_.reports() {
+                let [message] = messages;
+                self_.report(AuditRecord {
+                    timestamp,
+                    session_id: None,
+                    user: echo_.user.clone(),
+                    metadata: echo_.metadata.clone(),
+                    prompts: vec![message.content],
+                    parameters: serde_json::to_value(&generation).unwrap(),
+                    output,
+                    finish_reason: finish_reason.map(|r| r.as_str()),
+                    prompt_tokens: usage.prompt_tokens,
+                    completion_tokens: usage.completion_tokens,
+                    first_token_ms,
+                    total_ms: usage.total_ms,
+                });
+            }
+            info!("completion stopped{echo_}");
+        });
+        Ok(InferStream {
+            pieces: receiver,
+            echo,
+            offset: 0,
+            return_ids: return_ids.unwrap_or(false),
+        })
+    }
+
+    /// 计算每个候选接在提示词之后的对数似然，所有候选同时发起，一起预填充。
+    pub fn score(
+        &self,
+        Score {
+            prompt,
+            encoding,
+            candidates,
+        }: Score,
+    ) -> Result<PendingScore, Error> {
+        if candidates.is_empty() {
+            return Err(Error::InvalidParam("candidates", "Empty candidates".into()));
+        }
+        let mut messages = iter::once(prompt)
+            .chain(candidates)
+            .map(|content| Sentence {
+                role: "user".into(),
+                content,
+                input_ids: None,
+            })
+            .collect::<Vec<_>>();
+        decode(encoding.as_deref(), &mut messages)?;
+        self.limits.check_messages(&messages)?;
+        // 提示词和候选分别编码，候选的词与提示词的词不会合并
+        let prompt = self.service.encode(&messages[0].content)?;
+        let candidates = messages[1..]
+            .iter()
+            .map(|m| self.service.encode(&m.content))
+            .collect::<Result<Vec<_>, _>>()?;
+        if prompt.is_empty() || candidates.iter().any(Vec::is_empty) {
+            return Err(Error::InvalidContent("Empty prompt or candidate".into()));
+        }
+        for candidate in &candidates {
+            self.limits
+                .check_tokens(prompt.len() + candidate.len(), self.service.max_seq_len())?;
+        }
+
+        let pending = candidates
+            .into_iter()
+            .map(|candidate| {
+                let n = candidate.len();
+                let mut tokens = prompt.clone();
+                tokens.extend(candidate);
+                (n, self.service.score(tokens))
+            })
+            .collect::<Vec<_>>();
+        Ok(PendingScore(tokio::spawn(async move {
+            let mut scores = Vec::with_capacity(pending.len());
+            for (n, score) in pending {
+                let logprobs = score.await.ok_or(Error::InferenceFailed)?;
+                scores.push(CandidateScore {
+                    logprob: logprobs[logprobs.len() - n..].iter().sum(),
+                    tokens: n,
+                });
+            }
+            let best = (0..scores.len())
+                .max_by(|&i, &j| scores[i].logprob.total_cmp(&scores[j].logprob))
+                .unwrap();
+            Ok(ScoreReport { best, scores })
+        })))
+    }
+
+    /// 检查以词给出的提示词：不能同时给出文本，词都在词表中。
+    fn check_ids(&self, ids: &[u32], content: &str) -> Result<(), Error> {
+        if !content.is_empty() {
+            return Err(Error::InvalidParam(
+                "input_ids",
+                "Text and `input_ids` are exclusive".into(),
+            ));
+        }
+        let vocab_size = self.service.vocab_size();
+        match ids.iter().find(|&&id| id as usize >= vocab_size) {
+            Some(id) => Err(Error::InvalidParam(
+                "input_ids",
+                format!("Invalid token id: {id}"),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// 按比例将无状态请求复制到影子模型。
+    fn shadow(
+        &self,
+        dialog: &[String],
+        generation: &GenerationOverride,
+    ) -> Option<JoinHandle<(String, Duration)>> {
+        self.shadow.as_ref()?.infer(dialog.to_vec(), generation)
+    }
+
+    pub fn cache(&self) -> CacheReport {
+        let sessions = self
+            .session_manager
+            .usage()
+            .into_iter()
+            .map(|(id, usage)| {
+                let busy = usage.is_none();
+                let usage = usage.unwrap_or_default();
+                SessionCache {
+                    session_id: id.name(),
+                    busy,
+                    tokens: usage.tokens,
+                    used_bytes: usage.used_bytes,
+                    allocated_bytes: usage.allocated_bytes,
+                }
+            })
+            .collect::<Vec<_>>();
+        let memory = self.session_manager.memory();
+        CacheReport {
+            budget: self.cache_budget,
+            used_bytes: sessions.iter().map(|s| s.used_bytes).sum(),
+            allocated_bytes: sessions.iter().map(|s| s.allocated_bytes).sum(),
+            sessions,
+            memory_limit: memory.limit,
+            resident_bytes: memory.resident_bytes,
+            evicted_sessions: memory.evicted_sessions,
+            rejected_sessions: memory.rejected_sessions,
+        }
+    }
+
+    pub fn throughput(&self) -> ThroughputReport {
+        let t = self.service.throughput();
+        ThroughputReport {
+            decode_tokens_per_sec: t.decode_tokens_per_sec,
+            prefill_tokens_per_sec: t.prefill_tokens_per_sec,
+            batch_occupancy: t.batch_occupancy,
+            decode_batch: t.decode_batch,
+            peak_batch: t.peak_batch,
+            cache_hit_rate: t.cache_hit_rate,
+            deferred_tasks: t.deferred_tasks,
+            queue_latency_ms: t.queue_latency_ms,
+            peak_queue_latency_ms: t.peak_queue_latency_ms,
+            scheduler: t.scheduler.into(),
+            timeouts: t.timeouts,
+            by_metadata: self.labels.report(),
+        }
+    }
+
     pub fn fork(
         &self,
         Fork {
@@ -188,15 +846,223 @@ where
         }: Fork,
     ) -> Result<ForkSuccess, Error> {
         self.session_manager
-            .fork(session_id.into(), new_session_id.into())
-            .map(|()| ForkSuccess)
-            .map_err(Error::Session)
+            .fork(session_id.clone().into(), new_session_id.clone().into())
+            .map_err(|e| self.not_found(&session_id, e))?;
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.fork(&session_id, &new_session_id);
+        }
+        let event = WebhookEvent::session_created(new_session_id, &Echo::default());
+        self.webhooks.send(event);
+        Ok(ForkSuccess)
     }
 
     pub fn drop_(&self, Drop_ { session_id }: Drop_) -> Result<DropSuccess, Error> {
-        self.session_manager
-            .drop_(&session_id.into())
-            .map(|()| DropSuccess)
-            .map_err(Error::Session)
+        let ret = self.session_manager.drop_(&session_id.clone().into());
+        // 删除重启前的会话也视为成功
+        let recoverable = match &self.checkpoint {
+            Some(checkpoint) if ret != Err(SessionError::Busy) => checkpoint.forget(&session_id),
+            _ => false,
+        };
+        match ret {
+            Err(SessionError::NotFound) if recoverable => Ok(DropSuccess),
+            ret => ret.map(|()| DropSuccess).map_err(Error::Session),
+        }
+    }
+
+    pub fn documents(&self) -> DocumentReport {
+        let documents = self
+            .documents
+            .usage()
+            .into_iter()
+            .map(|(document_id, usage)| DocumentStatus {
+                document_id,
+                tokens: usage.tokens,
+                used_bytes: usage.used_bytes,
+            })
+            .collect::<Vec<_>>();
+        DocumentReport {
+            used_bytes: documents.iter().map(|d| d.used_bytes).sum(),
+            documents,
+        }
+    }
+
+    /// 以文档开始一个会话并计算它的缓存，完成后注册。
+    pub fn register_document(
+        self: &Arc<Self>,
+        RegisterDocument {
+            document_id,
+            content,
+            encoding,
+        }: RegisterDocument,
+    ) -> Result<PendingDocument, Error> {
+        let mut messages = [Sentence {
+            role: "user".into(),
+            content,
+            input_ids: None,
+        }];
+        decode(encoding.as_deref(), &mut messages)?;
+        self.limits.check_messages(&messages)?;
+        if self.documents.contains(&document_id) {
+            return Err(Error::DuplicateDocument);
+        }
+        let mut session = self.service.launch();
+        session.ground(&messages[0].content)?;
+        // 文档之后为对话留出一半上下文
+        let max = self.service.max_seq_len() / 2;
+        let tokens = session.num_tokens();
+        if tokens > max {
+            return Err(Error::InvalidContent(format!(
+                "Document too long: {tokens} tokens, at most {max}"
+            )));
+        }
+        let self_ = self.clone();
+        Ok(PendingDocument(tokio::spawn(async move {
+            session.prefill().await;
+            info!("document {document_id} registered with {tokens} tokens");
+            self_
+                .documents
+                .insert(document_id, session)
+                .map(|()| RegisterDocumentSuccess)
+        })))
+    }
+
+    pub fn drop_document(
+        &self,
+        DropDocument { document_id }: DropDocument,
+    ) -> Result<DropSuccess, Error> {
+        self.documents.remove(&document_id).map(|()| DropSuccess)
+    }
+
+    pub fn adapters(&self) -> AdapterReport {
+        let adapters = self
+            .service
+            .adapters()
+            .into_iter()
+            .map(|a| AdapterStatus {
+                name: a.name,
+                bytes: a.bytes,
+            })
+            .collect::<Vec<_>>();
+        AdapterReport {
+            used_bytes: adapters.iter().map(|a| a.bytes).sum(),
+            adapters,
+        }
+    }
+
+    pub fn load_adapter(
+        &self,
+        LoadAdapter { name, path }: LoadAdapter,
+    ) -> Result<LoadAdapterSuccess, Error> {
+        info!("loading adapter {name} from {path}");
+        self.service
+            .load_adapter(&name, path)
+            .map(|()| LoadAdapterSuccess)
+            .map_err(Error::Adapter)
+    }
+
+    pub fn unload_adapter(
+        &self,
+        UnloadAdapter { name }: UnloadAdapter,
+    ) -> Result<UnloadAdapterSuccess, Error> {
+        info!("unloading adapter {name}");
+        self.service
+            .unload_adapter(&name)
+            .map(|()| UnloadAdapterSuccess)
+            .map_err(Error::Adapter)
     }
 }
+
+/// 按 `encoding` 解码句子的内容。
+fn decode(encoding: Option<&str>, messages: &mut [Sentence]) -> Result<(), Error> {
+    match encoding {
+        Some("base64") | None => {
+            for m in messages {
+                let content = m.content.as_str();
+                m.content = general_purpose::STANDARD
+                    .decode(content)
+                    .map(String::from_utf8)
+                    .map_err(|_| Error::InvalidContent(format!("Decode failed: {content}")))?
+                    .map_err(|_| Error::InvalidContent(format!("Decode failed: {content}")))?;
+            }
+        }
+        Some("text") => {}
+        Some(e) => {
+            return Err(Error::InvalidParam(
+                "encoding",
+                format!("Unknown encoding: {e}"),
+            ))
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_infer() {
+    use causal_lm::MockModel;
+    use hyper::StatusCode;
+    use service::MAX_TEXT_LEN;
+    use tokio::runtime::Builder;
+
+    let model_dir = MockModel::model_dir("web-api-infer");
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    let _rt = runtime.enter();
+
+    let reply = "ok".bytes().map(|b| b as u32 + 3);
+    let (service, _handle) = Service::<MockModel>::load(&model_dir, MockModel::script(reply));
+    let options = ServiceOptions {
+        prefix_cache: 4,
+        limits: Limits {
+            max_chars: usize::MAX,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let manager = Arc::new(ServiceManager::new(service, options));
+
+    let request = |inputs: &[&str], stop_token_ids: Option<Vec<u32>>| Infer {
+        inputs: inputs.iter().map(|&s| Sentence::user(s)).collect(),
+        encoding: Some("text".into()),
+        session_id: None,
+        dialog_pos: None,
+        resume_from: None,
+        preset: None,
+        document_id: None,
+        return_ids: None,
+        generation: GenerationOverride {
+            stop_token_ids,
+            ..Default::default()
+        },
+        echo: Default::default(),
+    };
+    let infer = |inputs: &[&str], stop_token_ids: Option<Vec<u32>>| {
+        let mut stream = manager
+            .infer(request(inputs, stop_token_ids), None)
+            .unwrap();
+        runtime.block_on(async {
+            let mut text = String::new();
+            while let Some(piece) = stream.pieces.recv().await {
+                if let Piece::Text(s) = piece {
+                    text.push_str(&s);
+                }
+            }
+            text
+        })
+    };
+
+    // 第一个请求在 `k` 处停止
+    assert_eq!(infer(&["Hi"], Some(vec![b'k' as u32 + 3])), "o");
+    // 第二个请求复用第一个请求的会话，但不继承它的停止词
+    assert_eq!(infer(&["Hi", "o", "Hey"], None), "ok");
+
+    // 过长而无法分词的句子在启动推理之前拒绝
+    let long = "a".repeat(MAX_TEXT_LEN + 1);
+    let Err(e) = manager.infer(request(&[&long], None), None) else {
+        panic!("too long text accepted")
+    };
+    assert_eq!(e.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    drop(manager);
+    runtime.shutdown_background();
+    let _ = std::fs::remove_dir_all(model_dir);
+}
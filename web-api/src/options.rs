@@ -0,0 +1,30 @@
+use crate::{AuditLog, Checkpoint, Limits, Presets, Shadow, Webhooks};
+
+/// 推理服务的选项，默认不启用任何可选的功能。
+#[derive(Default)]
+pub struct ServiceOptions {
+    /// 内存中最多缓存的会话数，`None` 使用会话管理器的默认值。
+    pub session_capacity: Option<usize>,
+    /// 记录每个推理请求的审计日志。
+    pub audit: Option<AuditLog>,
+    /// 为复用对话前缀保留的空闲匿名会话数，0 表示不复用。
+    pub prefix_cache: usize,
+    /// 具名会话超过这么多个词时总结较早的对话。
+    pub summarize_after: Option<usize>,
+    /// 所有会话分配的缓存最多占用的字节数，超过时清除空闲的会话。
+    pub cache_budget: Option<usize>,
+    /// 复制部分无状态请求的影子模型。
+    pub shadow: Option<Shadow>,
+    /// 按名字选择的生成参数预设。
+    pub presets: Presets,
+    /// 请求的大小限制。
+    pub limits: Limits,
+    /// 接收会话和推理事件的地址。
+    pub webhooks: Webhooks,
+    /// 具名会话的检查点。
+    pub checkpoint: Option<Checkpoint>,
+    /// 常驻内存的软上限，超过时清除空闲的会话并拒绝新的会话。
+    pub memory_limit: Option<usize>,
+    /// 吞吐量报告中单独统计用量的 `metadata` 取值，其他的取值归入 `"other"`，为空时不统计。
+    pub metric_labels: Vec<String>,
+}
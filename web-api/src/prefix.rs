@@ -0,0 +1,106 @@
+//! 无状态模式下按对话前缀复用会话。
+
+use crate::schemas::SessionId;
+use std::{collections::VecDeque, iter::zip, sync::Mutex};
+
+/// 空闲的匿名会话，以会话中的对话内容和对话模板索引，模板不同的会话缓存的词不同，不能复用。
+///
+/// 会话本身保存在会话管理器中，与其他会话共享容量、缓存预算和内存上限，
+/// 可能被管理器清除，这里只记录会话的标识。
+pub(crate) struct PrefixPool {
+    capacity: usize,
+    sessions: Mutex<VecDeque<Entry>>,
+}
+
+/// 池中的一个会话和编码它使用的对话模板。
+struct Entry {
+    dialog: Vec<String>,
+    template: Option<String>,
+    session_id: SessionId,
+}
+
+impl PrefixPool {
+    #[inline]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            sessions: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// 取出使用模板 `template` 且与 `dialog` 公共前缀最长的会话，返回会话的标识和公共前缀的句子数。
+    pub fn take(&self, dialog: &[String], template: Option<&str>) -> Option<(SessionId, usize)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let (i, len) = sessions
+            .iter()
+            .map(|e| {
+                if e.template.as_deref() == template {
+                    common_prefix(&e.dialog, dialog)
+                } else {
+                    0
+                }
+            })
+            .enumerate()
+            .max_by_key(|&(_, len)| len)
+            .filter(|&(_, len)| len > 0)?;
+        sessions.remove(i).map(|e| (e.session_id, len))
+    }
+
+    /// 放回以模板 `template` 编码了 `dialog` 的会话，超过容量时返回最早放回的会话，由调用者从管理器中清除。
+    pub fn put(
+        &self,
+        dialog: Vec<String>,
+        template: Option<String>,
+        session_id: SessionId,
+    ) -> Option<SessionId> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let out = if sessions.len() == self.capacity {
+            sessions.pop_front().map(|e| e.session_id)
+        } else {
+            None
+        };
+        sessions.push_back(Entry {
+            dialog,
+            template,
+            session_id,
+        });
+        out
+    }
+}
+
+#[inline]
+fn common_prefix(a: &[String], b: &[String]) -> usize {
+    zip(a, b).take_while(|(a, b)| a == b).count()
+}
+
+#[test]
+fn test_pool() {
+    use crate::schemas::AnonymousSessionId;
+
+    let pool = PrefixPool::new(2);
+    let dialog = |s: &str| s.split(' ').map(String::from).collect::<Vec<_>>();
+    let ids = [(); 3].map(|_| SessionId::Temporary(AnonymousSessionId::new()));
+    assert_eq!(pool.put(dialog("a b"), None, ids[0].clone()), None);
+    assert_eq!(pool.put(dialog("c d"), None, ids[1].clone()), None);
+    // 超过容量时交出最早放回的会话
+    assert_eq!(
+        pool.put(dialog("a e"), Some("[{prompt}]".into()), ids[2].clone()),
+        Some(ids[0].clone())
+    );
+    // 模板不同的会话不能复用
+    assert_eq!(pool.take(&dialog("a e f"), None), None);
+    assert_eq!(
+        pool.take(&dialog("a e f"), Some("[{prompt}]")),
+        Some((ids[2].clone(), 2))
+    );
+    assert_eq!(pool.take(&dialog("x"), None), None);
+}
+
+#[test]
+fn test_common_prefix() {
+    let a = ["a", "b", "c"].map(String::from);
+    let b = ["a", "b", "d", "e"].map(String::from);
+    assert_eq!(common_prefix(&a, &b), 2);
+    assert_eq!(common_prefix(&a, &b[..1]), 1);
+    assert_eq!(common_prefix(&a, &[]), 0);
+}
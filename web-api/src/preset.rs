@@ -0,0 +1,83 @@
+//! 按名字选择的生成参数预设。
+
+use crate::schemas::{Error, GenerationOverride};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+/// 生成参数预设，请求中的 `preset` 字段按名字选择。
+pub struct Presets(HashMap<String, GenerationOverride>);
+
+impl Default for Presets {
+    #[inline]
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl Presets {
+    /// 内置的预设：`precise`、`balanced` 和 `creative`。
+    pub fn builtin() -> Self {
+        let preset = |temperature, top_k, top_p| GenerationOverride {
+            temperature: Some(temperature),
+            top_k: Some(top_k),
+            top_p: Some(top_p),
+            ..Default::default()
+        };
+        Self(HashMap::from([
+            ("precise".into(), preset(0.2, 20, 0.5)),
+            ("balanced".into(), preset(0.7, 50, 0.9)),
+            ("creative".into(), preset(1.1, usize::MAX, 0.98)),
+        ]))
+    }
+
+    /// 从 json 文件加载运维定义的预设，文件是预设名到生成参数的映射。
+    ///
+    /// 同名的预设覆盖内置的预设。
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let presets: HashMap<String, GenerationOverride> = serde_json::from_reader(file)?;
+        let mut ans = Self::builtin();
+        ans.0.extend(presets);
+        Ok(ans)
+    }
+
+    /// 用预设 `name` 补全请求中未指定的参数。
+    pub(crate) fn resolve(
+        &self,
+        name: Option<&str>,
+        generation: GenerationOverride,
+    ) -> Result<GenerationOverride, Error> {
+        match name {
+            Some(name) => self
+                .0
+                .get(name)
+                .map(|preset| generation.or(preset))
+                .ok_or_else(|| Error::InvalidParam("preset", format!("Unknown preset: {name}"))),
+            None => Ok(generation),
+        }
+    }
+}
+
+#[test]
+fn test_resolve() {
+    let presets = Presets::builtin();
+    let generation = GenerationOverride {
+        temperature: Some(0.5),
+        ..Default::default()
+    };
+    let ans = presets.resolve(Some("precise"), generation).unwrap();
+    assert_eq!(ans.temperature, Some(0.5));
+    assert_eq!(ans.top_k, Some(20));
+    assert!(presets
+        .resolve(Some("unknown"), Default::default())
+        .is_err());
+    assert!(presets
+        .resolve(None, Default::default())
+        .unwrap()
+        .top_p
+        .is_none());
+}
@@ -1,52 +1,227 @@
 //! All HttpResponses in this App.
 
-use crate::schemas;
+use crate::{
+    format::Format,
+    schemas::{self, InferStream, PendingDocument, PendingScore, Piece, StreamEvent},
+};
 use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
 use hyper::{
     body::{Bytes, Frame},
-    header::CONTENT_TYPE,
-    Response, StatusCode,
+    header::{HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_TYPE, TRAILER},
+    HeaderMap, Response, StatusCode,
 };
 use serde::Serialize;
-use tokio_stream::{Stream, StreamExt};
+use service::FinishReason;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+/// 生成结束的原因放在这个 trailer 中。
+const FINISH_REASON: HeaderName = HeaderName::from_static("x-finish-reason");
+/// 请求要求时，生成的词以逗号分隔放在这个 trailer 中。
+const OUTPUT_IDS: HeaderName = HeaderName::from_static("x-output-ids");
+const USER: HeaderName = HeaderName::from_static("x-user");
+const METADATA: HeaderName = HeaderName::from_static("x-metadata");
+const STREAM_OFFSET: HeaderName = HeaderName::from_static("x-stream-offset");
+const PROMPT_LOGPROBS: HeaderName = HeaderName::from_static("x-prompt-logprobs");
+
+/// 以原始文本返回推理流，结束的信息放在 trailer 中。
+#[inline]
+pub(crate) async fn infer_stream(
+    stream: InferStream,
+    format: Format,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    stream_response(stream, format, false).await
+}
 
-pub fn text_stream(
-    s: impl Stream<Item = String> + Send + Sync + 'static,
+/// 以 Server-Sent Events 返回推理流，最后一个事件带有结束的信息和用量，之后是 `[DONE]`。
+#[inline]
+pub(crate) async fn event_stream(
+    stream: InferStream,
+    format: Format,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    stream_response(stream, format, true).await
+}
+
+async fn stream_response(
+    InferStream {
+        mut pieces,
+        echo,
+        offset,
+        return_ids,
+    }: InferStream,
+    format: Format,
+    events: bool,
 ) -> Response<BoxBody<Bytes, hyper::Error>> {
+    // 生成任何内容之前推理出错时返回错误，而不是只有 trailer 的流
+    let mut first = pieces.recv().await;
+    // 提示词的对数概率在生成之前计算，放在响应头中
+    let prompt_logprobs = match first {
+        Some(Piece::PromptLogprobs(logprobs)) => {
+            first = pieces.recv().await;
+            Some(logprobs)
+        }
+        _ => None,
+    };
+    if let Some(Piece::Finish(FinishReason::Error, ..)) = first {
+        return error(format, schemas::Error::InferenceFailed);
+    }
+    let pieces = tokio_stream::iter(first).chain(ReceiverStream::new(pieces));
+    let mut response = if events {
+        event_frames(pieces, return_ids)
+    } else {
+        text_stream(pieces, return_ids)
+    };
+    let headers = response.headers_mut();
+    headers.insert(STREAM_OFFSET, HeaderValue::from(offset));
+    if let Some(logprobs) = prompt_logprobs {
+        let logprobs = logprobs.iter().map(f32::to_string).collect::<Vec<_>>();
+        headers.insert(
+            PROMPT_LOGPROBS,
+            HeaderValue::try_from(logprobs.join(",")).unwrap(),
+        );
+    }
+    for (name, value) in [(USER, echo.user), (METADATA, echo.metadata)] {
+        if let Some(value) = value {
+            headers.insert(name, HeaderValue::try_from(value).unwrap());
+        }
+    }
+    response
+}
+
+fn text_stream(
+    s: impl Stream<Item = Piece> + Send + Sync + 'static,
+    return_ids: bool,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let frames = s.map(move |piece| match piece {
+        Piece::Text(s) => Ok(Frame::data(s.into())),
+        // 只可能在流的开头，已经放在响应头中
+        Piece::PromptLogprobs(_) => Ok(Frame::data(Bytes::new())),
+        Piece::Finish(reason, ids, _) => {
+            let mut trailers = HeaderMap::new();
+            trailers.insert(FINISH_REASON, HeaderValue::from_static(reason.as_str()));
+            if return_ids {
+                let ids = ids.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+                trailers.insert(OUTPUT_IDS, HeaderValue::try_from(ids).unwrap());
+            }
+            Ok(Frame::trailers(trailers))
+        }
+    });
+    let trailer = if return_ids {
+        "x-finish-reason, x-output-ids"
+    } else {
+        "x-finish-reason"
+    };
     Response::builder()
         .status(StatusCode::OK)
         .header(CONTENT_TYPE, "text/event-stream")
-        .body(StreamBody::new(s.map(|s| Ok(Frame::data(s.into())))).boxed())
+        .header(TRAILER, trailer)
+        .body(StreamBody::new(frames).boxed())
         .unwrap()
 }
 
-pub fn success(success: impl schemas::Success) -> Response<BoxBody<Bytes, hyper::Error>> {
-    #[derive(Serialize)]
-    struct SuccessResponse<'a> {
-        message: &'a str,
-    }
-    let body = SuccessResponse {
-        message: success.msg(),
-    };
-
+fn event_frames(
+    s: impl Stream<Item = Piece> + Send + Sync + 'static,
+    return_ids: bool,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let frames = s.map(move |piece| match piece {
+        Piece::Text(text) => Ok(Frame::data(event(&StreamEvent {
+            text,
+            ..Default::default()
+        }))),
+        // 只可能在流的开头，已经放在响应头中
+        Piece::PromptLogprobs(_) => Ok(Frame::data(Bytes::new())),
+        Piece::Finish(reason, ids, usage) => {
+            let last = StreamEvent {
+                text: String::new(),
+                finish_reason: reason.as_str().parse().ok(),
+                output_ids: return_ids.then_some(ids),
+                usage: Some(usage),
+            };
+            let mut data = event(&last).to_vec();
+            data.extend_from_slice(b"data: [DONE]\n\n");
+            Ok(Frame::data(data.into()))
+        }
+    });
     Response::builder()
         .status(StatusCode::OK)
-        .header(CONTENT_TYPE, "application/json")
-        .body(full(serde_json::to_string(&body).unwrap()))
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(StreamBody::new(frames).boxed())
         .unwrap()
 }
 
-pub fn error(e: schemas::Error) -> Response<BoxBody<Bytes, hyper::Error>> {
+/// Server-Sent Events 的一个事件，数据以 JSON 序列化，不会包含换行。
+fn event(data: &impl Serialize) -> Bytes {
+    let mut event = b"data: ".to_vec();
+    serde_json::to_writer(&mut event, data).unwrap();
+    event.extend_from_slice(b"\n\n");
+    event.into()
+}
+
+/// 等待文档的缓存计算完成。
+pub(crate) async fn registered(
+    PendingDocument(pending): PendingDocument,
+    format: Format,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    match pending.await {
+        Ok(Ok(ret)) => success(format, ret),
+        Ok(Err(e)) => error(format, e),
+        Err(_) => error(format, schemas::Error::InferenceFailed),
+    }
+}
+
+pub(crate) async fn scored(
+    PendingScore(pending): PendingScore,
+    format: Format,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    match pending.await {
+        Ok(Ok(ret)) => report(format, ret),
+        Ok(Err(e)) => error(format, e),
+        Err(_) => error(format, schemas::Error::InferenceFailed),
+    }
+}
+
+pub fn report(format: Format, body: impl Serialize) -> Response<BoxBody<Bytes, hyper::Error>> {
+    serialized(format, StatusCode::OK, &body)
+}
+
+pub fn success(
+    format: Format,
+    success: impl schemas::Success,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = schemas::SuccessBody {
+        message: success.msg().into(),
+    };
+    serialized(format, StatusCode::OK, &body)
+}
+
+pub fn error(format: Format, e: schemas::Error) -> Response<BoxBody<Bytes, hyper::Error>> {
+    serialized(format, e.status(), &e.body())
+}
+
+fn serialized(
+    format: Format,
+    status: StatusCode,
+    body: &impl Serialize,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
     Response::builder()
-        .status(e.status())
-        .header(CONTENT_TYPE, "application/json")
-        .body(full(serde_json::to_string(&e.body()).unwrap()))
+        .status(status)
+        .header(CONTENT_TYPE, format.mime())
+        .body(full(format.serialize(body)))
         .unwrap()
 }
 
 #[inline]
-fn full(chunk: impl Into<Bytes>) -> BoxBody<Bytes, hyper::Error> {
+pub(crate) fn full(chunk: impl Into<Bytes>) -> BoxBody<Bytes, hyper::Error> {
     Full::new(chunk.into())
         .map_err(|never| match never {})
         .boxed()
 }
+
+#[test]
+fn test_event() {
+    let text = StreamEvent {
+        text: "a\nb".into(),
+        ..Default::default()
+    };
+    assert_eq!(&event(&text)[..], b"data: {\"text\":\"a\\nb\"}\n\n");
+}
@@ -1,23 +1,333 @@
+use causal_lm::{CausalLM, SampleArgs, SampleStage};
 use hyper::StatusCode;
-use service::SessionError;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use service::{
+    AdapterError, ChatTemplate, DetokenizeArgs, EncodeError, FinishReason, Session, SessionError,
+    StopArgs, MAX_TEXT_LEN,
+};
+use std::{
+    fmt,
+    hash::{DefaultHasher, Hasher},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::{sync::mpsc::Receiver, task::JoinHandle};
 
-#[derive(serde::Deserialize)]
+pub(crate) use infinilm_schemas::v1::{
+    AdapterReport, AdapterStatus, CacheReport, CandidateScore, DocumentReport, DocumentStatus,
+    Drop as Drop_, DropDocument, ErrorBody, ErrorDetail, ErrorKind, Fork, LoadAdapter,
+    Message as Sentence, MetadataUsage, RegisterDocument, ScoreReport, ScoreRequest as Score,
+    SessionCache, StreamEvent, Success as SuccessBody, ThroughputReport, UnloadAdapter, Usage,
+};
+
+/// 与 [`v1::InferRequest`](infinilm_schemas::v1::InferRequest) 的格式相同，`test_v1_compat` 检查两者往返不变。
+#[derive(serde::Deserialize, serde::Serialize)]
 pub(crate) struct Infer {
     pub inputs: Vec<Sentence>,
     pub encoding: Option<String>,
     pub session_id: Option<String>,
     pub dialog_pos: Option<usize>,
+    /// 续传的请求与原来的请求只有这个字段不同，不计入摘要。
+    #[serde(skip_serializing)]
+    pub resume_from: Option<usize>,
+    pub preset: Option<String>,
+    pub document_id: Option<String>,
+    pub return_ids: Option<bool>,
+    #[serde(flatten)]
+    pub generation: GenerationOverride,
+    #[serde(flatten)]
+    pub echo: Echo,
+}
+
+/// 与 [`v1::CompleteRequest`](infinilm_schemas::v1::CompleteRequest) 的格式相同。
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct Complete {
+    #[serde(default)]
+    pub prompt: String,
+    pub encoding: Option<String>,
+    pub input_ids: Option<Vec<u32>>,
+    pub preset: Option<String>,
+    pub return_ids: Option<bool>,
+    pub prompt_logprobs: Option<bool>,
+    #[serde(flatten)]
+    pub generation: GenerationOverride,
+    #[serde(flatten)]
+    pub echo: Echo,
+}
+
+/// 客户端附加在请求上的不透明信息，记录在日志中并原样返回。
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default, Debug)]
+pub(crate) struct Echo {
+    pub user: Option<String>,
+    pub metadata: Option<String>,
+}
+
+impl Infer {
+    /// 除续传位置以外的请求内容的摘要，同一个幂等键的请求必须相同。
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&serde_json::to_vec(self).unwrap());
+        hasher.finish()
+    }
+}
+
+impl Echo {
+    /// 每个字段的最大字节数。
+    const MAX_LEN: usize = 256;
+
+    /// 检查字段能否放入响应头。
+    pub fn check(&self) -> Result<(), Error> {
+        for (name, value) in [("user", &self.user), ("metadata", &self.metadata)] {
+            if let Some(value) = value {
+                if value.len() > Self::MAX_LEN || !value.bytes().all(|b| (0x20..0x7f).contains(&b))
+                {
+                    return Err(Error::InvalidParam(
+                        name,
+                        format!(
+                            "`{name}` must be printable ASCII no longer than {} bytes",
+                            Self::MAX_LEN
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Echo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, " user={user}")?;
+        }
+        if let Some(metadata) = &self.metadata {
+            write!(f, " metadata={metadata}")?;
+        }
+        Ok(())
+    }
+}
+
+/// 推理的输出流。
+pub(crate) struct InferStream {
+    pub pieces: Receiver<Piece>,
+    pub echo: Echo,
+    /// 流中第一个字节在生成的文本中的位置。
+    pub offset: usize,
+    /// 在 trailer 中返回生成的词。
+    pub return_ids: bool,
+}
+
+/// 正在计算缓存的文档，计算完成后注册。
+pub(crate) struct PendingDocument(pub JoinHandle<Result<RegisterDocumentSuccess, Error>>);
+
+/// 正在计算的打分。
+pub(crate) struct PendingScore(pub JoinHandle<Result<ScoreReport, Error>>);
+
+/// 推理流中的一段输出。
+#[derive(Clone, Debug)]
+pub(crate) enum Piece {
+    /// 提示词的对数概率，总是流的第一项。
+    PromptLogprobs(Vec<f32>),
+    /// 生成的文本。
+    Text(String),
+    /// 生成结束的原因、生成的所有词和用量，总是流的最后一项。
+    Finish(FinishReason, Vec<u32>, Usage),
+}
+
+/// 请求中指定的生成参数，未指定的参数沿用会话中的值。
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default, Debug)]
+#[serde(default)]
+pub(crate) struct GenerationOverride {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_token_ids: Option<Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_new_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_timeout: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_special_tokens: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clean_up_tokenization_spaces: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_leading_space: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xtc_threshold: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xtc_probability: Option<f32>,
+    #[serde(
+        deserialize_with = "sample_order",
+        serialize_with = "serialize_sample_order",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sample_order: Option<Vec<SampleStage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub choices: Option<Vec<String>>,
+    /// 使用的适配器的名字，未指定时使用基础模型。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+    /// 使用的控制向量的名字。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steering: Option<String>,
+    /// 控制向量的强度，默认为 1。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steering_strength: Option<f32>,
+    /// 接在会话开头的软提示词的名字。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soft_prompt: Option<String>,
+    /// 替代模型默认模板的对话模板，`{prompt}` 处替换为提示词。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_template: Option<String>,
+}
+
+fn serialize_sample_order<S>(
+    order: &Option<Vec<SampleStage>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    use serde::Serialize;
+    order
+        .as_ref()
+        .map(|order| order.iter().map(SampleStage::name).collect::<Vec<_>>())
+        .serialize(serializer)
+}
+
+fn sample_order<'de, D>(deserializer: D) -> Result<Option<Vec<SampleStage>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::{de::Error, Deserialize};
+    Option::<Vec<String>>::deserialize(deserializer)?
+        .map(|order| {
+            order
+                .iter()
+                .map(|s| s.parse().map_err(D::Error::custom))
+                .collect()
+        })
+        .transpose()
 }
 
-#[derive(serde::Deserialize)]
-pub(crate) struct Sentence {
-    #[allow(unused)]
-    pub role: String,
-    pub content: String,
+impl GenerationOverride {
+    /// 用 `base` 补全未指定的参数。
+    pub fn or(self, base: &Self) -> Self {
+        macro_rules! or {
+            ($($ident:ident)+) => {
+                Self {$(
+                    $ident: self.$ident.or_else(|| base.$ident.clone()),
+                )+}
+            };
+        }
+        or! {
+            stop_token_ids
+            min_new_tokens
+            timeout
+            token_timeout
+            skip_special_tokens
+            clean_up_tokenization_spaces
+            strip_leading_space
+            temperature
+            top_k
+            top_p
+            xtc_threshold
+            xtc_probability
+            sample_order
+            choices
+            adapter
+            steering
+            steering_strength
+            soft_prompt
+            chat_template
+        }
+    }
+
+    pub fn apply<M: CausalLM>(&self, session: &mut Session<M>) {
+        self.apply_args(
+            &mut session.sample,
+            &mut session.stop,
+            &mut session.detokenize,
+        );
+        // 选项、适配器、控制向量、软提示词和对话模板只对本次请求生效
+        session.choices = self.choices.clone().unwrap_or_default();
+        session.adapter.clone_from(&self.adapter);
+        session.steering = self
+            .steering
+            .clone()
+            .map(|name| (name, self.steering_strength.unwrap_or(1.)));
+        session.soft_prompt.clone_from(&self.soft_prompt);
+        session.template = self.chat_template.clone().and_then(ChatTemplate::new);
+    }
+
+    /// 只修改采样、停止和解码参数，用于不经过会话的续写。
+    pub fn apply_args(
+        &self,
+        args: &mut SampleArgs,
+        stop: &mut StopArgs,
+        detokenize: &mut DetokenizeArgs,
+    ) {
+        if let Some(stop_token_ids) = &self.stop_token_ids {
+            stop.tokens.clone_from(stop_token_ids);
+        }
+        if let Some(min_new_tokens) = self.min_new_tokens {
+            stop.min_new_tokens = min_new_tokens;
+        }
+        if let Some(timeout) = self.timeout {
+            stop.timeout = Some(Duration::from_secs_f32(timeout));
+        }
+        if let Some(token_timeout) = self.token_timeout {
+            stop.token_timeout = Some(Duration::from_secs_f32(token_timeout));
+        }
+        if let Some(skip_special_tokens) = self.skip_special_tokens {
+            detokenize.skip_special_tokens = skip_special_tokens;
+        }
+        if let Some(clean_up_tokenization_spaces) = self.clean_up_tokenization_spaces {
+            detokenize.clean_up_tokenization_spaces = clean_up_tokenization_spaces;
+        }
+        if let Some(strip_leading_space) = self.strip_leading_space {
+            detokenize.strip_leading_space = strip_leading_space;
+        }
+
+        macro_rules! apply {
+            ($($ident:ident)+) => {
+                $(
+                    if let Some(val) = self.$ident {
+                        args.$ident = val;
+                    }
+                )+
+            };
+        }
+        apply! {
+            temperature
+            top_k
+            top_p
+            xtc_threshold
+            xtc_probability
+        }
+        if let Some(order) = &self.sample_order {
+            args.order.clone_from(order);
+        }
+    }
+
+    /// 续写不支持的参数中第一个被指定的参数名。
+    pub fn session_only(&self) -> Option<&'static str> {
+        [
+            ("choices", self.choices.is_some()),
+            ("adapter", self.adapter.is_some()),
+            ("steering", self.steering.is_some()),
+            ("steering_strength", self.steering_strength.is_some()),
+            ("soft_prompt", self.soft_prompt.is_some()),
+            ("chat_template", self.chat_template.is_some()),
+        ]
+        .into_iter()
+        .find_map(|(name, specified)| specified.then_some(name))
+    }
 }
 
 #[derive(Eq, PartialEq, Hash, Clone, Debug)]
@@ -50,19 +360,21 @@ impl From<AnonymousSessionId> for SessionId {
     }
 }
 
-#[derive(serde::Deserialize)]
-pub(crate) struct Fork {
-    pub session_id: String,
-    pub new_session_id: String,
-}
-
-#[derive(serde::Deserialize)]
-pub(crate) struct Drop_ {
-    pub session_id: String,
+impl SessionId {
+    /// 用于展示的会话名，匿名会话以 `#` 开头。
+    pub fn name(&self) -> String {
+        match self {
+            Self::Permanent(id) => id.clone(),
+            Self::Temporary(AnonymousSessionId(id)) => format!("#{id}"),
+        }
+    }
 }
 
 pub(crate) struct ForkSuccess;
 pub(crate) struct DropSuccess;
+pub(crate) struct LoadAdapterSuccess;
+pub(crate) struct UnloadAdapterSuccess;
+pub(crate) struct RegisterDocumentSuccess;
 
 pub trait Success {
     fn msg(&self) -> &str;
@@ -78,20 +390,55 @@ impl Success for DropSuccess {
         "drop success"
     }
 }
+impl Success for LoadAdapterSuccess {
+    fn msg(&self) -> &str {
+        "load success"
+    }
+}
+impl Success for UnloadAdapterSuccess {
+    fn msg(&self) -> &str {
+        "unload success"
+    }
+}
+impl Success for RegisterDocumentSuccess {
+    fn msg(&self) -> &str {
+        "register success"
+    }
+}
 
 #[derive(Debug)]
 pub(crate) enum Error {
     Session(SessionError),
+    /// 会话在服务重启时丢失，重启前的对话位置。
+    SessionRecoverable(usize),
+    Adapter(AdapterError),
     WrongJson(serde_json::Error),
-    ContentError(String),
+    WrongMsgPack(rmp_serde::decode::Error),
+    InvalidContent(String),
+    /// 参数错误：参数名和说明。
+    InvalidParam(&'static str, String),
     InvalidDialogPos(usize),
+    DocumentNotFound,
+    DuplicateDocument,
+    StreamNotFound,
+    /// 同一个幂等键的请求内容不同。
+    IdempotencyMismatch,
+    InferenceFailed,
+    /// 超出大小限制：限制的名字、上限和实际的大小。
+    TooLarge(&'static str, usize, Option<usize>),
+    /// 提示词过长：上限和实际的词数。
+    TooManyTokens(usize, usize),
+    RouteNotFound,
 }
 
-#[derive(serde::Serialize)]
-struct ErrorBody {
-    status: u16,
-    code: u16,
-    message: String,
+impl From<EncodeError> for Error {
+    /// 过长而无法分词的文本按超出大小限制处理。
+    #[inline]
+    fn from(e: EncodeError) -> Self {
+        match e {
+            EncodeError::TooLong(len) => Self::TooLarge("bytes", MAX_TEXT_LEN, Some(len)),
+        }
+    }
 }
 
 impl Error {
@@ -102,48 +449,231 @@ impl Error {
             Self::Session(NotFound) => StatusCode::NOT_FOUND,
             Self::Session(Busy) => StatusCode::NOT_ACCEPTABLE,
             Self::Session(Duplicate) => StatusCode::CONFLICT,
+            Self::Session(OutOfMemory) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::Session(MemoryLimit) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Session(InvalidCache) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::SessionRecoverable(_) => StatusCode::GONE,
+            Self::Adapter(AdapterError::Unsupported) => StatusCode::NOT_IMPLEMENTED,
+            Self::Adapter(AdapterError::Duplicate) => StatusCode::CONFLICT,
+            Self::Adapter(AdapterError::NotFound) => StatusCode::NOT_FOUND,
+            Self::Adapter(AdapterError::Load(_)) => StatusCode::BAD_REQUEST,
             Self::WrongJson(_) => StatusCode::BAD_REQUEST,
-            Self::ContentError(_) => StatusCode::BAD_REQUEST,
+            Self::WrongMsgPack(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidContent(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidParam(..) => StatusCode::BAD_REQUEST,
             Self::InvalidDialogPos(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            Self::DocumentNotFound => StatusCode::NOT_FOUND,
+            Self::DuplicateDocument => StatusCode::CONFLICT,
+            Self::StreamNotFound => StatusCode::GONE,
+            Self::IdempotencyMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::InferenceFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::TooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::TooManyTokens(..) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::RouteNotFound => StatusCode::NOT_FOUND,
         }
     }
 
-    #[inline]
-    pub fn body(&self) -> serde_json::Value {
-        macro_rules! error {
-            ($code:expr, $msg:expr) => {
-                ErrorBody {
-                    status: self.status().as_u16(),
-                    code: $code,
-                    message: $msg.into(),
-                }
-            };
+    /// 错误的种类，与错误一一对应。
+    pub const fn kind(&self) -> ErrorKind {
+        use SessionError::*;
+        match self {
+            Self::Session(NotFound) => ErrorKind::SessionNotFound,
+            Self::Session(Busy) => ErrorKind::SessionBusy,
+            Self::Session(Duplicate) => ErrorKind::DuplicateSession,
+            Self::Session(OutOfMemory) => ErrorKind::CacheExhausted,
+            Self::Session(MemoryLimit) => ErrorKind::MemoryLimitExceeded,
+            Self::Session(InvalidCache) => ErrorKind::InvalidCache,
+            Self::SessionRecoverable(_) => ErrorKind::SessionRecoverable,
+            Self::Adapter(AdapterError::Unsupported) => ErrorKind::AdaptersUnsupported,
+            Self::Adapter(AdapterError::Duplicate) => ErrorKind::DuplicateAdapter,
+            Self::Adapter(AdapterError::NotFound) => ErrorKind::AdapterNotFound,
+            Self::Adapter(AdapterError::Load(_)) => ErrorKind::AdapterLoadFailed,
+            Self::WrongJson(_) => ErrorKind::InvalidJson,
+            Self::WrongMsgPack(_) => ErrorKind::InvalidMsgPack,
+            Self::InvalidContent(_) => ErrorKind::InvalidContent,
+            Self::InvalidParam(..) => ErrorKind::InvalidParam,
+            Self::InvalidDialogPos(_) => ErrorKind::InvalidDialogPos,
+            Self::DocumentNotFound => ErrorKind::DocumentNotFound,
+            Self::DuplicateDocument => ErrorKind::DuplicateDocument,
+            Self::StreamNotFound => ErrorKind::StreamNotFound,
+            Self::IdempotencyMismatch => ErrorKind::IdempotencyMismatch,
+            Self::InferenceFailed => ErrorKind::InferenceFailed,
+            Self::TooLarge(..) => ErrorKind::TooLarge,
+            Self::TooManyTokens(..) => ErrorKind::TooManyTokens,
+            Self::RouteNotFound => ErrorKind::RouteNotFound,
         }
+    }
 
-        #[inline]
-        fn json(v: impl serde::Serialize) -> serde_json::Value {
-            serde_json::to_value(v).unwrap()
-        }
+    pub fn body(&self) -> ErrorBody {
+        let error = |code: u16, message: &str, param: Option<&str>, detail| ErrorBody {
+            status: self.status().as_u16(),
+            code,
+            message: message.into(),
+            kind: self.kind(),
+            param: param.map(Into::into),
+            detail,
+        };
 
         use SessionError::*;
         match self {
-            Self::Session(NotFound) => json(error!(0, "Session not found")),
-            Self::Session(Busy) => json(error!(0, "Session is busy")),
-            Self::Session(Duplicate) => json(error!(0, "Session ID already exists")),
-            Self::WrongJson(e) => json(error!(0, e.to_string())),
-            Self::ContentError(e) => json(error!(1, e)),
-            &Self::InvalidDialogPos(current_dialog_pos) => {
-                #[derive(serde::Serialize)]
-                struct ErrorBodyExtra {
-                    #[serde(flatten)]
-                    common: ErrorBody,
-                    current_dialog_pos: usize,
-                }
-                json(ErrorBodyExtra {
-                    common: error!(0, "Dialog position out of range"),
-                    current_dialog_pos,
-                })
+            Self::Session(NotFound) => error(0, "Session not found", None, None),
+            Self::Session(Busy) => error(0, "Session is busy", None, None),
+            Self::Session(Duplicate) => error(0, "Session ID already exists", None, None),
+            Self::Session(OutOfMemory) => error(0, "Cache budget exhausted", None, None),
+            Self::Session(MemoryLimit) => error(0, "Memory limit exceeded", None, None),
+            Self::Session(InvalidCache) => error(0, "Session cache is invalid", None, None),
+            &Self::SessionRecoverable(current_dialog_pos) => error(
+                0,
+                "Session lost in a restart, resend the dialog from position 0",
+                Some("session_id"),
+                Some(ErrorDetail::DialogPos { current_dialog_pos }),
+            ),
+            Self::Adapter(AdapterError::Unsupported) => {
+                error(0, "Adapters are not supported", None, None)
             }
+            Self::Adapter(AdapterError::Duplicate) => {
+                error(0, "Adapter already exists", None, None)
+            }
+            Self::Adapter(AdapterError::NotFound) => error(0, "Adapter not found", None, None),
+            Self::Adapter(AdapterError::Load(e)) => {
+                error(2, &format!("Failed to load adapter: {e}"), None, None)
+            }
+            Self::WrongJson(e) => error(0, &e.to_string(), None, None),
+            Self::WrongMsgPack(e) => error(0, &e.to_string(), None, None),
+            Self::InvalidContent(e) => error(1, e, None, None),
+            Self::InvalidParam(param, e) => error(1, e, Some(param), None),
+            &Self::InvalidDialogPos(current_dialog_pos) => error(
+                0,
+                "Dialog position out of range",
+                Some("dialog_pos"),
+                Some(ErrorDetail::DialogPos { current_dialog_pos }),
+            ),
+            Self::DocumentNotFound => error(0, "Document not found", None, None),
+            Self::DuplicateDocument => error(0, "Document ID already exists", None, None),
+            Self::StreamNotFound => error(0, "Stream not found", None, None),
+            Self::IdempotencyMismatch => error(
+                0,
+                "Idempotency key reused with a different request",
+                None,
+                None,
+            ),
+            Self::InferenceFailed => error(0, "Inference failed", None, None),
+            &Self::TooLarge(limit, max, actual) => error(
+                0,
+                &format!("Too large {limit}"),
+                None,
+                Some(ErrorDetail::Limit {
+                    limit: limit.into(),
+                    max,
+                    actual,
+                }),
+            ),
+            &Self::TooManyTokens(max, actual) => error(
+                0,
+                "Too many prompt tokens",
+                None,
+                Some(ErrorDetail::Limit {
+                    limit: "prompt_tokens".into(),
+                    max,
+                    actual: Some(actual),
+                }),
+            ),
+            Self::RouteNotFound => error(0, "Route not found", None, None),
         }
     }
 }
+
+#[test]
+fn test_v1_compat() {
+    use infinilm_schemas::v1::{self, CompleteRequest, InferRequest, Message};
+    use serde_json::{from_value, to_value};
+
+    // 服务端的生成参数填满所有字段，增加字段时必须在这里补上，v1 缺少的字段在往返后丢失
+    let generation = GenerationOverride {
+        stop_token_ids: Some(vec![2]),
+        min_new_tokens: Some(1),
+        timeout: Some(1.5),
+        token_timeout: Some(0.5),
+        skip_special_tokens: Some(true),
+        clean_up_tokenization_spaces: Some(false),
+        strip_leading_space: Some(true),
+        temperature: Some(0.7),
+        top_k: Some(5),
+        top_p: Some(0.9),
+        xtc_threshold: Some(0.1),
+        xtc_probability: Some(0.5),
+        sample_order: Some(SampleStage::DEFAULT_ORDER.to_vec()),
+        choices: Some(vec!["yes".into()]),
+        adapter: Some("a".into()),
+        steering: Some("s".into()),
+        steering_strength: Some(2.),
+        soft_prompt: Some("p".into()),
+        chat_template: Some("{prompt}".into()),
+    };
+    let json = to_value(&generation).unwrap();
+    let v1 = from_value::<v1::Generation>(json.clone()).unwrap();
+    assert_eq!(to_value(&v1).unwrap(), json);
+
+    // v1 的请求填满所有字段，服务端的请求类型往返后不变
+    let mut req = InferRequest::new([Message::user("Hi")]);
+    req.session_id = Some("a".into());
+    req.dialog_pos = Some(2);
+    req.resume_from = Some(3);
+    req.preset = Some("creative".into());
+    req.document_id = Some("d".into());
+    req.return_ids = Some(true);
+    req.generation = v1.clone();
+    req.user = Some("u".into());
+    req.metadata = Some("m".into());
+    let infer = from_value::<Infer>(to_value(&req).unwrap()).unwrap();
+    assert_eq!(infer.resume_from, Some(3));
+    assert_eq!(
+        infer.generation.sample_order.as_deref(),
+        Some(&SampleStage::DEFAULT_ORDER[..])
+    );
+    // 续传位置不参与序列化
+    req.resume_from = None;
+    assert_eq!(to_value(&infer).unwrap(), to_value(&req).unwrap());
+    assert_eq!(
+        from_value::<InferRequest>(to_value(&infer).unwrap()).unwrap(),
+        req
+    );
+
+    let mut req = CompleteRequest::new("Once");
+    req.input_ids = Some(vec![1, 2]);
+    req.preset = Some("creative".into());
+    req.return_ids = Some(true);
+    req.prompt_logprobs = Some(true);
+    req.generation = v1;
+    req.user = Some("u".into());
+    req.metadata = Some("m".into());
+    let complete = from_value::<Complete>(to_value(&req).unwrap()).unwrap();
+    assert_eq!(to_value(&complete).unwrap(), to_value(&req).unwrap());
+    assert_eq!(
+        from_value::<CompleteRequest>(to_value(&complete).unwrap()).unwrap(),
+        req
+    );
+
+    let body = Error::InvalidDialogPos(3).body();
+    assert_eq!(body.kind, ErrorKind::InvalidDialogPos);
+    assert_eq!(body.param.as_deref(), Some("dialog_pos"));
+    assert_eq!(
+        body.detail,
+        Some(ErrorDetail::DialogPos {
+            current_dialog_pos: 3
+        })
+    );
+    let body = Error::SessionRecoverable(4).body();
+    assert_eq!(body.status, 410);
+    assert_eq!(body.kind, ErrorKind::SessionRecoverable);
+    assert_eq!(
+        body.detail,
+        Some(ErrorDetail::DialogPos {
+            current_dialog_pos: 4
+        })
+    );
+    let body = Error::InvalidParam("adapter", "Unknown adapter: a".into()).body();
+    assert_eq!((body.status, body.code), (400, 1));
+    assert_eq!(body.kind, ErrorKind::InvalidParam);
+    assert_eq!(body.param.as_deref(), Some("adapter"));
+}
@@ -0,0 +1,120 @@
+//! 将部分请求复制到另一个模型，比较两者的输出和耗时。
+
+use crate::schemas::{GenerationOverride, SessionId};
+use causal_lm::CausalLM;
+use service::Service;
+use std::{
+    iter::zip,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+type Infer = dyn Fn(Vec<String>, &GenerationOverride) -> Option<JoinHandle<(String, Duration)>>
+    + Send
+    + Sync;
+
+/// 接收复制流量的影子模型。
+///
+/// 影子模型的输出只用于比较，不会返回给客户端。
+pub struct Shadow {
+    infer: Box<Infer>,
+    fraction: f64,
+    count: AtomicUsize,
+}
+
+impl Shadow {
+    /// 将比例为 `fraction` 的请求复制到 `service`。
+    pub fn new<M>(service: Service<M>, fraction: f64) -> Self
+    where
+        M: CausalLM + Send + Sync + 'static,
+        M::Storage: Send,
+    {
+        Self {
+            infer: Box::new(move |messages, generation| {
+                let mut session = service.launch();
+                generation.apply(&mut session);
+                // 影子模型无法分词的请求不复制
+                session.extend(messages.iter().map(String::as_str)).ok()?;
+                Some(tokio::spawn(async move {
+                    let start = Instant::now();
+                    let mut output = String::new();
+                    let mut busy = session.chat();
+                    while let Some(chunk) = busy.decode().await {
+                        output.push_str(&chunk.text);
+                    }
+                    (output, start.elapsed())
+                }))
+            }),
+            fraction: fraction.clamp(0., 1.),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// 按比例选中一个请求时，在影子模型上推理 `messages`。
+    pub(crate) fn infer(
+        &self,
+        messages: Vec<String>,
+        generation: &GenerationOverride,
+    ) -> Option<JoinHandle<(String, Duration)>> {
+        let n = self.count.fetch_add(1, Relaxed);
+        pick(n, self.fraction)
+            .then(|| (self.infer)(messages, generation))
+            .flatten()
+    }
+}
+
+/// 比较主模型与影子模型的输出，结果写入日志。
+pub(crate) async fn compare(
+    session_id: &SessionId,
+    output: &str,
+    time: Duration,
+    shadow: JoinHandle<(String, Duration)>,
+) {
+    let (shadow_output, shadow_time) = match shadow.await {
+        Ok(ans) => ans,
+        Err(e) => {
+            warn!("{session_id:?} shadow inference failed: {e}");
+            return;
+        }
+    };
+    let diff = match diverge(output, &shadow_output) {
+        None => "identical".to_string(),
+        Some(pos) => format!("diverged at byte {pos}"),
+    };
+    info!(
+        "{session_id:?} shadow {diff}, primary {} bytes in {time:?}, shadow {} bytes in {shadow_time:?}",
+        output.len(),
+        shadow_output.len(),
+    );
+    debug!("{session_id:?} primary output: {output:?}");
+    debug!("{session_id:?} shadow output: {shadow_output:?}");
+}
+
+/// 均匀地选出比例为 `fraction` 的请求，判断第 `n` 个请求是否被选中。
+#[inline]
+fn pick(n: usize, fraction: f64) -> bool {
+    ((n + 1) as f64 * fraction).floor() > (n as f64 * fraction).floor()
+}
+
+/// 两段文本第一个不同字符的字节位置，相同时返回 `None`。
+fn diverge(a: &str, b: &str) -> Option<usize> {
+    let pos = zip(a.char_indices(), b.chars())
+        .find(|((_, x), y)| x != y)
+        .map(|((i, _), _)| i);
+    pos.or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+}
+
+#[test]
+fn test_pick() {
+    assert_eq!((0..8).filter(|&n| pick(n, 0.25)).count(), 2);
+    assert_eq!((0..8).filter(|&n| pick(n, 1.)).count(), 8);
+    assert_eq!((0..8).filter(|&n| pick(n, 0.)).count(), 0);
+}
+
+#[test]
+fn test_diverge() {
+    assert_eq!(diverge("你好", "你好"), None);
+    assert_eq!(diverge("你好", "你们"), Some(3));
+    assert_eq!(diverge("abc", "ab"), Some(2));
+}
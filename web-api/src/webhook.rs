@@ -0,0 +1,267 @@
+//! 会话事件的 webhook，将用量和结束信息推送给外部的计费或分析系统。
+
+use crate::{
+    audit::AuditRecord,
+    schemas::{Echo, Error, ErrorBody, Usage},
+};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Bytes,
+    client::conn::http1,
+    header::{CONTENT_TYPE, HOST},
+    Method, Request, Uri,
+};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::TcpStream, time::timeout};
+
+/// 推送一个事件的最长时间，超时的事件丢弃。
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 接收事件的地址，只支持 `http`。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Webhook(Uri);
+
+/// 无法解析的 webhook 地址。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseWebhookError(String);
+
+impl fmt::Display for ParseWebhookError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid webhook url: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseWebhookError {}
+
+impl FromStr for Webhook {
+    type Err = ParseWebhookError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<Uri>() {
+            Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => Ok(Self(uri)),
+            _ => Err(ParseWebhookError(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for Webhook {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Webhook {
+    /// 以 JSON 请求体 POST 一个事件，返回响应的状态码。
+    async fn post(&self, body: Vec<u8>) -> Result<u16, String> {
+        let authority = self.0.authority().unwrap();
+        let addr = format!(
+            "{}:{}",
+            authority.host(),
+            authority.port_u16().unwrap_or(80)
+        );
+        let stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+        let (mut sender, conn) = http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::spawn(async move {
+            let _ = conn.await;
+        });
+
+        let path = self.0.path_and_query().map_or("/", |p| p.as_str());
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(HOST, authority.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap();
+        let response = sender.send_request(req).await.map_err(|e| e.to_string())?;
+        let status = response.status().as_u16();
+        let _ = response.into_body().collect().await;
+        Ok(status)
+    }
+}
+
+/// 按服务或按用户配置的 webhook。
+///
+/// 请求的 `user` 配置了地址时，事件只推送到这个地址，否则推送到服务的地址。
+#[derive(Clone, Default, Debug)]
+pub struct Webhooks {
+    server: Option<Arc<Webhook>>,
+    users: HashMap<String, Arc<Webhook>>,
+}
+
+impl Webhooks {
+    /// 所有事件推送到 `webhook`，为用户单独配置的除外。
+    pub fn new(webhook: Webhook) -> Self {
+        Self {
+            server: Some(Arc::new(webhook)),
+            users: HashMap::new(),
+        }
+    }
+
+    /// 请求的 `user` 与 `user` 相同时，事件推送到 `webhook`。
+    pub fn with_user(mut self, user: impl Into<String>, webhook: Webhook) -> Self {
+        self.users.insert(user.into(), Arc::new(webhook));
+        self
+    }
+
+    /// 没有配置任何地址。
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.server.is_none() && self.users.is_empty()
+    }
+
+    fn target(&self, user: Option<&str>) -> Option<&Arc<Webhook>> {
+        user.and_then(|user| self.users.get(user))
+            .or(self.server.as_ref())
+    }
+
+    /// 在后台推送事件，失败只记录日志，不影响请求。
+    pub(crate) fn send(&self, event: WebhookEvent) {
+        let Some(webhook) = self.target(event.user.as_deref()).cloned() else {
+            return;
+        };
+        let body = serde_json::to_vec(&event).unwrap();
+        tokio::spawn(async move {
+            match timeout(TIMEOUT, webhook.post(body)).await {
+                Ok(Ok(200..=299)) => {}
+                Ok(Ok(status)) => {
+                    warn!("Webhook {webhook} responded {status} to {}", event.event)
+                }
+                Ok(Err(e)) => warn!("Failed to send {} to webhook {webhook}: {e}", event.event),
+                Err(_) => warn!("Sending {} to webhook {webhook} timed out", event.event),
+            }
+        });
+    }
+}
+
+/// 推送给 webhook 的事件。
+#[derive(Serialize, Clone, Default, Debug)]
+pub(crate) struct WebhookEvent {
+    /// 事件的种类：`session.created`、`completion.finished` 或 `error`。
+    pub event: &'static str,
+    /// 事件发生的时间，unix 毫秒时间戳。
+    pub timestamp: u64,
+    /// 事件所属的会话，匿名会话和续写没有。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+    /// 生成结束的原因。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<&'static str>,
+    /// 推理的用量，推理开始之前的事件没有。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// 与返回给客户端相同的错误。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+impl WebhookEvent {
+    /// 创建了一个会话。
+    pub fn session_created(session_id: String, echo: &Echo) -> Self {
+        Self {
+            event: "session.created",
+            timestamp: now_ms(),
+            session_id: Some(session_id),
+            user: echo.user.clone(),
+            metadata: echo.metadata.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// 请求被拒绝。
+    pub fn error(session_id: Option<String>, echo: &Echo, error: ErrorBody) -> Self {
+        Self {
+            event: "error",
+            timestamp: now_ms(),
+            session_id,
+            user: echo.user.clone(),
+            metadata: echo.metadata.clone(),
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+
+    /// 一次推理结束，推理出错时是错误事件。
+    pub fn finished(record: &AuditRecord) -> Self {
+        let failed = record.finish_reason == Some("error");
+        Self {
+            event: if failed {
+                "error"
+            } else {
+                "completion.finished"
+            },
+            timestamp: now_ms(),
+            session_id: record.session_id.clone(),
+            user: record.user.clone(),
+            metadata: record.metadata.clone(),
+            finish_reason: record.finish_reason,
+            usage: Some(Usage {
+                prompt_tokens: record.prompt_tokens,
+                completion_tokens: record.completion_tokens,
+                first_token_ms: record.first_token_ms,
+                total_ms: record.total_ms,
+            }),
+            error: failed.then(|| Error::InferenceFailed.body()),
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[test]
+fn test_webhooks() {
+    assert!("https://example.com/hook".parse::<Webhook>().is_err());
+    assert!("/hook".parse::<Webhook>().is_err());
+    let server = "http://127.0.0.1:9000/events".parse::<Webhook>().unwrap();
+    let alice = "http://billing:8080/alice?key=1"
+        .parse::<Webhook>()
+        .unwrap();
+    let hooks = Webhooks::new(server.clone()).with_user("alice", alice.clone());
+    assert_eq!(**hooks.target(Some("alice")).unwrap(), alice);
+    assert_eq!(**hooks.target(Some("bob")).unwrap(), server);
+    assert_eq!(**hooks.target(None).unwrap(), server);
+    let users = Webhooks::default().with_user("alice", alice);
+    assert!(users.target(None).is_none());
+    assert!(Webhooks::default().is_empty());
+
+    let event = WebhookEvent::finished(&AuditRecord {
+        session_id: Some("s".into()),
+        finish_reason: Some("stop"),
+        prompt_tokens: 3,
+        completion_tokens: 5,
+        total_ms: 7,
+        ..Default::default()
+    });
+    let json = serde_json::to_value(&event).unwrap();
+    assert_eq!(json["event"], "completion.finished");
+    assert_eq!(json["session_id"], "s");
+    assert_eq!(json["usage"]["completion_tokens"], 5);
+    assert!(json.get("error").is_none());
+
+    let event = WebhookEvent::finished(&AuditRecord {
+        finish_reason: Some("error"),
+        ..Default::default()
+    });
+    assert_eq!(event.event, "error");
+    assert!(event.error.is_some());
+}
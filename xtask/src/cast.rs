@@ -1,6 +1,13 @@
 ﻿use std::{fs, path::PathBuf, time::Instant};
 
-use digit_layout::types::{BF16, F16, F32};
+use common::{
+    fp8::{F8E4M3, F8E5M2},
+    quant::{block_bytes, Q4_0, Q8_0},
+};
+use digit_layout::{
+    types::{BF16, F16, F32},
+    DigitLayout,
+};
 
 #[derive(Args, Default)]
 pub(crate) struct CastArgs {
@@ -13,20 +20,44 @@ pub(crate) struct CastArgs {
     /// Target model type.
     #[clap(long)]
     dt: Option<String>,
+    /// Data type of the token embeddings, the same as `dt` by default.
+    #[clap(long)]
+    embed_dt: Option<String>,
+    /// Data type of lm_head, the same as `dt` by default.
+    #[clap(long)]
+    lm_head_dt: Option<String>,
+    /// Data type of the projection matrices, the same as `dt` by default.
+    #[clap(long)]
+    matrix_dt: Option<String>,
+}
+
+/// 解析数据类型的名字。
+pub(crate) fn parse_dt(name: &str) -> DigitLayout {
+    match name {
+        "f32" | "float" | "float32" => F32,
+        "f16" | "half" | "float16" => F16,
+        "bf16" | "bfloat16" => BF16,
+        "f8e4m3" | "float8_e4m3fn" => F8E4M3,
+        "f8e5m2" | "float8_e5m2" => F8E5M2,
+        "q8_0" => Q8_0,
+        "q4_0" => Q4_0,
+        ty => panic!("Unknown data type: \"{ty}\""),
+    }
 }
 
 impl CastArgs {
     pub fn invode(self) {
-        let ty = match self.dt.as_deref() {
-            Some("f32") | Some("float") | Some("float32") | None => F32,
-            Some("f16") | Some("half") | Some("float16") => F16,
-            Some("bf16") | Some("bfloat16") => BF16,
-            Some(ty) => panic!("Unknown data type: \"{ty}\""),
-        };
+        let ty = self.dt.as_deref().map_or(F32, parse_dt);
+        let embed_ty = self.embed_dt.as_deref().map_or(ty, parse_dt);
+        let lm_head_ty = self.lm_head_dt.as_deref().map_or(ty, parse_dt);
+        let matrix_ty = self.matrix_dt.as_deref().map_or(ty, parse_dt);
+        if block_bytes(matrix_ty).is_some() {
+            panic!("Quantized matrices can't be saved, pass --matrix-dt when loading the model instead");
+        }
         let model_dir = PathBuf::from(self.model);
 
         let time = Instant::now();
-        let model = llama::Storage::load_safetensors(&model_dir).unwrap();
+        let model = llama::Storage::load(&model_dir).unwrap();
         println!("load model ... {:?}", time.elapsed());
 
         let target = self.target.map(PathBuf::from).unwrap_or_else(|| {
@@ -44,7 +75,9 @@ impl CastArgs {
         fs::create_dir_all(&target).unwrap();
 
         let time = Instant::now();
-        let model = model.cast(ty);
+        let model = model
+            .cast_parts(ty, embed_ty, lm_head_ty)
+            .cast_matrices(matrix_ty);
         println!("cast data type ... {:?}", time.elapsed());
 
         let time = Instant::now();
@@ -62,5 +95,7 @@ impl CastArgs {
 
         copy_file("tokenizer.model");
         copy_file("vocabs.txt");
+        copy_file("vocabs.trie");
+        copy_file("generation_config.json");
     }
 }
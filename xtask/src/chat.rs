@@ -21,8 +21,13 @@ impl Task for ChatArgs {
         M::Storage: Send,
         M::Error: Debug,
     {
-        let (mut service, _handle) = Service::<M>::load(&self.inference.model, meta);
-        service.default_sample = self.inference.sample_args();
+        let (mut service, _handle) = Service::<M>::load_with_tokenizer(
+            &self.inference.model,
+            meta,
+            self.inference.tokenizer(),
+        );
+        service.default_sample = self.inference.sample_args(service.default_sample.clone());
+        service.default_truncation = self.inference.truncation();
         Chatting {
             service,
             current: 0,
@@ -148,21 +153,27 @@ impl<M: CausalLM> Chatting<M> {
                 self.sessions.insert(self.current, self.service.launch());
                 println!("Create new session {}.", self.current);
             }
-            ["/fork"] => {
-                let new = self.session().fork();
-                self.current = self.next_id;
-                self.next_id += 1;
-                self.sessions.insert(self.current, new);
-                println!("Fork session to {}.", self.current);
-            }
+            ["/fork"] => match self.session().fork() {
+                Ok(new) => {
+                    self.current = self.next_id;
+                    self.next_id += 1;
+                    self.sessions.insert(self.current, new);
+                    println!("Fork session to {}.", self.current);
+                }
+                Err(e) => println!("Failed to fork session: {e}"),
+            },
             ["/fork", n] => match n.parse() {
                 Ok(target_id) => {
                     if let Some(s) = self.sessions.get(&target_id) {
-                        let new = s.fork();
-                        self.current = self.next_id;
-                        self.next_id += 1;
-                        self.sessions.insert(self.current, new);
-                        println!("Fork session {} to {}.", target_id, self.current);
+                        match s.fork() {
+                            Ok(new) => {
+                                self.current = self.next_id;
+                                self.next_id += 1;
+                                self.sessions.insert(self.current, new);
+                                println!("Fork session {} to {}.", target_id, self.current);
+                            }
+                            Err(e) => println!("Failed to fork session: {e}"),
+                        }
                     } else {
                         println!("Invalid session ID.");
                     }
@@ -227,9 +238,13 @@ impl<M: CausalLM> Chatting<M> {
     async fn infer(&mut self, text: &str) {
         print_now!("{}", "AI: ".green());
         let session = self.session_mut();
-        session.extend([text]);
+        if let Err(e) = session.extend([text]) {
+            println!("{e}");
+            return;
+        }
         let mut busy = session.chat();
-        while let Some(s) = busy.decode().await {
+        while let Some(chunk) = busy.decode().await {
+            let s = String::from(chunk);
             match &*s {
                 "\\n" => println!(),
                 _ => print_now!("{s}"),
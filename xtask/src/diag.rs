@@ -0,0 +1,268 @@
+use common::{
+    fp8::{F8E4M3, F8E5M2},
+    Blob,
+};
+use common_cpu::{CpuKernels, KernelsA, ThisThread};
+use digit_layout::{types::F32, DigitLayout};
+use llama::{cast_to, Storage, Weight};
+use std::{
+    hint::black_box,
+    iter::zip,
+    thread,
+    time::{Duration, Instant},
+};
+use tensor::{slice, udim, Tensor};
+
+/// 每项测试重复的次数，取最快的一次。
+const REPEAT: usize = 5;
+/// 带宽测试复制的字节数。
+const COPY_SIZE: usize = 256 << 20;
+/// 算力测试每个线程的迭代次数。
+const FLOP_STEPS: usize = 1 << 22;
+/// 算力测试每个线程的独立累加器数量。
+const FLOP_LANES: usize = 64;
+/// 估计 8 位浮点数权重的误差使用的输入行数。
+const FP8_ROWS: udim = 4;
+
+#[derive(Args, Default)]
+pub(crate) struct DiagArgs {
+    /// Model directory.
+    #[clap(short, long)]
+    model: String,
+    /// Number of tokens in a prefill batch, 64 by default.
+    #[clap(long)]
+    batch: Option<usize>,
+    /// Number of threads used by multi-core tests, all cores by default.
+    #[clap(long)]
+    threads: Option<usize>,
+}
+
+impl DiagArgs {
+    pub fn run(self) {
+        let storage = Storage::load(&self.model).unwrap();
+        let config = &storage.config;
+        let threads = self
+            .threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+        let batch = self.batch.unwrap_or(64) as udim;
+
+        println!("Memory bandwidth (copy {} MiB)", COPY_SIZE >> 20);
+        let bw1 = bandwidth(1);
+        let bwn = bandwidth(threads);
+        println!("  {:>3} thread(s): {bw1:>10.2} GB/s", 1);
+        println!(
+            "  {threads:>3} thread(s): {bwn:>10.2} GB/s, scaling {:.2}x",
+            bwn / bw1
+        );
+        println!();
+
+        println!("f32 multiply-add throughput");
+        let flops1 = gflops(1);
+        let flopsn = gflops(threads);
+        println!("  {:>3} thread(s): {flops1:>10.2} GFLOPS", 1);
+        println!(
+            "  {threads:>3} thread(s): {flopsn:>10.2} GFLOPS, scaling {:.2}x ({:.0}% efficiency)",
+            flopsn / flops1,
+            flopsn / flops1 / threads as f64 * 100.
+        );
+        println!();
+
+        let kernels = CpuKernels::default();
+        // 使用外部算子库时逐个形状与内置的算子对比
+        let builtin = kernels
+            .provider_name()
+            .map(|_| CpuKernels::with_provider(None));
+        println!(
+            "GEMM on model shapes ({:?}, {} kernels)",
+            config.dt,
+            kernels.provider_name().unwrap_or("builtin"),
+        );
+        let layer = &storage.layers[0];
+        let mut decode = Duration::ZERO;
+        let mut prefill = Duration::ZERO;
+        let mut weight_bytes = 0;
+        for (name, w) in [
+            ("qkv", &layer.att_qkv),
+            ("o", &layer.att_o),
+            ("gate_up", &layer.mlp_gate_up),
+            ("down", &layer.mlp_down),
+        ] {
+            let (t1, f1) = gemm(&kernels, w, 1);
+            let (tn, fb) = gemm(&kernels, w, batch);
+            decode += t1;
+            prefill += tn;
+            weight_bytes += nbytes(w);
+            println!(
+                "  {name:>8} {:?}: n=1 {f1:>8.2} GFLOPS, n={batch} {fb:>8.2} GFLOPS",
+                w.shape()
+            );
+            if let Some(builtin) = &builtin {
+                compare(builtin, w, batch, f1, fb);
+            }
+        }
+        let (head1, h1) = gemm(&kernels, &storage.lm_head, 1);
+        let (_, hb) = gemm(&kernels, &storage.lm_head, batch);
+        println!(
+            "  {:>8} {:?}: n=1 {h1:>8.2} GFLOPS, n={batch} {hb:>8.2} GFLOPS",
+            "lm_head",
+            storage.lm_head.shape()
+        );
+        if let Some(builtin) = &builtin {
+            compare(builtin, &storage.lm_head, batch, h1, hb);
+        }
+        let nlayers = config.nlayers;
+        let decode = decode * nlayers + head1;
+        let prefill = prefill * nlayers + head1;
+        let weight_bytes = weight_bytes * nlayers as usize + nbytes(&storage.lm_head);
+        println!();
+
+        // 8 位浮点数的权重节省一半的带宽，但引入量化误差
+        println!("FP8 weights (relative error of {FP8_ROWS} random rows, n=1 throughput)");
+        for (name, w) in [
+            ("qkv", &layer.att_qkv),
+            ("o", &layer.att_o),
+            ("gate_up", &layer.mlp_gate_up),
+            ("down", &layer.mlp_down),
+        ] {
+            for (ty, dt) in [("e4m3", F8E4M3), ("e5m2", F8E5M2)] {
+                let (error, flops) = fp8(&kernels, w, dt);
+                println!(
+                    "  {name:>8} {ty}: error {:>7.3}%, n=1 {flops:>8.2} GFLOPS",
+                    error * 100.
+                );
+            }
+        }
+        println!();
+
+        println!("Expected throughput (attention excluded)");
+        println!(
+            "  decode  memory bound: {:>10.2} tokens/s ({:.2} GiB weights per token)",
+            bwn * 1e9 / weight_bytes as f64,
+            weight_bytes as f64 / (1u64 << 30) as f64
+        );
+        println!(
+            "  decode  kernels     : {:>10.2} tokens/s",
+            decode.as_secs_f64().recip()
+        );
+        println!(
+            "  prefill kernels     : {:>10.2} tokens/s",
+            batch as f64 / prefill.as_secs_f64()
+        );
+    }
+}
+
+/// 用 `threads` 个线程复制内存，返回读写合计的带宽，单位为 GB/s。
+fn bandwidth(threads: usize) -> f64 {
+    let src = vec![1u8; COPY_SIZE];
+    let mut dst = vec![0u8; COPY_SIZE];
+    let chunk = COPY_SIZE.div_ceil(threads);
+    let time = best(|| {
+        thread::scope(|s| {
+            for (src, dst) in zip(src.chunks(chunk), dst.chunks_mut(chunk)) {
+                s.spawn(move || dst.copy_from_slice(src));
+            }
+        })
+    });
+    black_box(&dst);
+    (2 * COPY_SIZE) as f64 / time.as_secs_f64() / 1e9
+}
+
+/// 用 `threads` 个线程执行乘加，返回合计的算力，单位为 GFLOPS。
+fn gflops(threads: usize) -> f64 {
+    fn mul_add() -> f32 {
+        let mut acc = [0f32; FLOP_LANES];
+        for _ in 0..FLOP_STEPS {
+            for a in &mut acc {
+                *a = *a * 0.999 + 0.001;
+            }
+            black_box(&mut acc);
+        }
+        acc.iter().sum()
+    }
+
+    let time = best(|| {
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| black_box(mul_add()));
+            }
+        })
+    });
+    (2 * FLOP_LANES * FLOP_STEPS * threads) as f64 / time.as_secs_f64() / 1e9
+}
+
+/// 计算 `n` 行输入与权重 `w` 的矩阵乘，返回耗时和算力。
+fn gemm(kernels: &CpuKernels, w: &Tensor<Weight>, n: udim) -> (Duration, f64) {
+    let &[k, m] = w.shape() else { panic!() };
+    let dt = w.data_layout();
+    let x = Tensor::alloc(dt, &[n, k], |len| {
+        let mut blob = Blob::new(len);
+        blob.fill(0);
+        blob
+    });
+    let mut y = Tensor::alloc(dt, &[n, m], Blob::new);
+    kernels.mat_mul(&mut y, 0., &x, w, 1., &ThisThread);
+    let time = best(|| kernels.mat_mul(&mut y, 0., &x, w, 1., &ThisThread));
+    let flops = 2. * n as f64 * k as f64 * m as f64;
+    (time, flops / time.as_secs_f64() / 1e9)
+}
+
+/// 将 `w` 转换为 8 位浮点数 `dt`，返回随机输入的矩阵乘结果的相对误差和单行输入的算力。
+fn fp8(kernels: &CpuKernels, w: &Tensor<Weight>, dt: DigitLayout) -> (f64, f64) {
+    let &[k, m] = w.shape() else { panic!() };
+    let act = w.data_layout();
+    let w8 = cast_to(w, dt);
+    let x = {
+        let mut x = Tensor::alloc(F32, &[FP8_ROWS, k], Blob::new);
+        let data = tensor::reslice_mut::<u8, f32>(x.physical_mut());
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = (i as f32 * 0.618).sin();
+        }
+        cast_to(&x, act)
+    };
+
+    let mut y = Tensor::alloc(act, &[FP8_ROWS, m], Blob::new);
+    let mut y8 = Tensor::alloc(act, &[FP8_ROWS, m], Blob::new);
+    kernels.mat_mul(&mut y, 0., &x, w, 1., &ThisThread);
+    kernels.mat_mul(&mut y8, 0., &x, &w8, 1., &ThisThread);
+    let (y, y8) = (cast_to(&y, F32), cast_to(&y8, F32));
+    let y = tensor::reslice::<u8, f32>(y.as_slice());
+    let y8 = tensor::reslice::<u8, f32>(y8.as_slice());
+    let (diff, norm) = zip(y, y8).fold((0., 0.), |(diff, norm), (&a, &b)| {
+        let d = (a - b) as f64;
+        (diff + d * d, norm + (a as f64) * (a as f64))
+    });
+
+    let x = x.slice(&[slice![=>1], slice![=>]]);
+    let mut y = Tensor::alloc(act, &[1, m], Blob::new);
+    let time = best(|| kernels.mat_mul(&mut y, 0., &x, &w8, 1., &ThisThread));
+    let flops = 2. * k as f64 * m as f64;
+    ((diff / norm).sqrt(), flops / time.as_secs_f64() / 1e9)
+}
+
+/// 打印内置的算子在同一形状上的算力和外部算子库相对它的加速比。
+fn compare(builtin: &CpuKernels, w: &Tensor<Weight>, n: udim, f1: f64, fb: f64) {
+    let (_, g1) = gemm(builtin, w, 1);
+    let (_, gb) = gemm(builtin, w, n);
+    println!(
+        "  {:>8} builtin: n=1 {g1:>8.2} GFLOPS ({:.2}x), n={n} {gb:>8.2} GFLOPS ({:.2}x)",
+        "",
+        f1 / g1,
+        fb / gb
+    );
+}
+
+#[inline]
+fn nbytes(w: &Tensor<Weight>) -> usize {
+    w.shape().iter().product::<udim>() as usize * w.data_layout().nbytes()
+}
+
+fn best(mut f: impl FnMut()) -> Duration {
+    (0..REPEAT)
+        .map(|_| {
+            let time = Instant::now();
+            f();
+            time.elapsed()
+        })
+        .min()
+        .unwrap()
+}
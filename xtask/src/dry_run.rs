@@ -0,0 +1,69 @@
+use crate::InferenceArgs;
+use common::Architecture;
+use llama::{CheckReport, Storage};
+use service::TokenizerFormat;
+use std::time::Instant;
+
+/// 检查模型目录而不加载模型，打印报告，没有发现问题时返回 `true`。
+pub(crate) fn dry_run(args: &InferenceArgs) -> bool {
+    let arch = args.model_type();
+    if arch != Architecture::Llama {
+        println!("dry run of {arch:?} models is not supported");
+        return false;
+    }
+
+    let time = Instant::now();
+    let CheckReport {
+        config,
+        files,
+        tensors,
+        mut problems,
+    } = Storage::check_safetensors(&args.model)
+        .unwrap_or_else(|e| panic!("Failed to read the model: {e:?}"));
+    println!("architecture: {arch:?}");
+    println!(
+        "config: dt = {:?}, layers = {}, d = {}, heads = {}/{}, di = {}, voc = {}, max_seq_len = {}",
+        config.dt,
+        config.nlayers,
+        config.d,
+        config.nh,
+        config.nkvh,
+        config.di,
+        config.voc,
+        config.max_seq_len,
+    );
+    println!("weights: {tensors} tensors in {files} files");
+
+    // 分词器的词必须都在词嵌入中，开始符和结束符必须能解码
+    match TokenizerFormat::load_from(&args.model, args.tokenizer()) {
+        Ok((tokenizer, _)) => {
+            let n = tokenizer.vocab_size();
+            println!("tokenizer: {n} pieces");
+            if n > config.voc as usize {
+                problems.push(format!(
+                    "tokenizer has {n} pieces, more than vocab_size {}",
+                    config.voc
+                ));
+            }
+            let special = [config.bos_token]
+                .into_iter()
+                .chain(config.eos_tokens.iter().copied());
+            for token in special {
+                if token as usize >= n {
+                    problems.push(format!("special token {token} is out of the tokenizer"));
+                }
+            }
+        }
+        Err(e) => problems.push(format!("failed to load tokenizer: {e}")),
+    }
+
+    for problem in &problems {
+        println!("error: {problem}");
+    }
+    println!(
+        "dry run ... {} problems, {:?}",
+        problems.len(),
+        time.elapsed()
+    );
+    problems.is_empty()
+}
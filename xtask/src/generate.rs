@@ -27,7 +27,11 @@ impl Task for GenerateArgs {
         M::Storage: Send,
         M::Error: Debug,
     {
-        let (service, _handle) = Service::<M>::load(&self.inference.model, meta);
+        let (service, _handle) = Service::<M>::load_with_tokenizer(
+            &self.inference.model,
+            meta,
+            self.inference.tokenizer(),
+        );
 
         let prompt = if Path::new(&self.prompt).is_file() {
             println!("prompt from file: {}", self.prompt);
@@ -39,10 +43,12 @@ impl Task for GenerateArgs {
 
         let max_steps = self.max_steps.unwrap_or(usize::MAX);
         let mut steps = 0;
-        let mut generator = service.generate(&*prompt, Some(self.inference.sample_args()));
+        let sample = self.inference.sample_args(service.default_sample.clone());
+        let mut generator = service.generate(&*prompt, Some(sample)).unwrap();
 
         let time = Instant::now();
-        while let Some(s) = generator.decode().await {
+        while let Some(chunk) = generator.decode().await {
+            let s = String::from(chunk);
             match &*s {
                 "\\n" => println!(),
                 _ => print_now!("{s}"),
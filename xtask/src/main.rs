@@ -1,15 +1,22 @@
 mod cast;
 mod chat;
 mod deploy;
+mod diag;
+mod dry_run;
 mod generate;
 mod list_turbo;
 mod service;
+mod verify;
+mod vocab_trie;
 
-use causal_lm::{CausalLM, SampleArgs};
+use causal_lm::{CausalLM, SampleArgs, SampleStage};
 use clap::Parser;
+use common::{Architecture, BlobOptions, FileLoadError, HugePages, Supported};
+use common_cpu::Accumulation;
 use deploy::DeployArgs;
 use service::ServiceArgs;
-use std::{ffi::c_int, fmt, num::ParseIntError, str::FromStr};
+use service::{TokenizerFormat, Truncation};
+use std::{ffi::c_int, fmt, num::ParseIntError, path::PathBuf, process::exit, str::FromStr};
 use time::UtcOffset;
 
 #[macro_use]
@@ -24,6 +31,9 @@ fn main() {
         Generate(args) => args.run(),
         Chat(chat) => chat.run(),
         Service(service) => service.run(),
+        Diag(diag) => diag.run(),
+        VocabTrie(args) => args.run(),
+        Verify(args) => args.run(),
     }
 }
 
@@ -49,6 +59,12 @@ enum Commands {
     Chat(chat::ChatArgs),
     /// Start the service
     Service(ServiceArgs),
+    /// Measure CPU throughput on the model's shapes and estimate tokens per second
+    Diag(diag::DiagArgs),
+    /// Build a memory-mappable trie from the model's vocabs.txt
+    VocabTrie(vocab_trie::VocabTrieArgs),
+    /// Verify the SHA-256 checksums of the weight files against a manifest
+    Verify(verify::VerifyArgs),
 }
 
 #[derive(Args, Default)]
@@ -56,9 +72,12 @@ struct InferenceArgs {
     /// Model directory.
     #[clap(short, long)]
     model: String,
-    /// Model type, maybe "llama", "mixtral", "llama" by default.
+    /// Model type, maybe "llama", "mistral" or "mixtral", detected from `architectures` or `model_type` in config.json by default.
     #[clap(long)]
     model_type: Option<String>,
+    /// Tokenizer file type, maybe "model", "json", "trie" or "txt", detected from the model directory by default.
+    #[clap(long)]
+    tokenizer: Option<String>,
 
     /// Log level, may be "off", "trace", "debug", "info" or "error".
     #[clap(long)]
@@ -73,19 +92,79 @@ struct InferenceArgs {
     /// Random sample top-p.
     #[clap(long)]
     top_p: Option<f32>,
+    /// XTC sample threshold.
+    #[clap(long)]
+    xtc_threshold: Option<f32>,
+    /// XTC sample probability.
+    #[clap(long)]
+    xtc_probability: Option<f32>,
+    /// Sample stages in order, separated by ",", maybe "temperature", "top-k", "top-p" or "xtc".
+    #[clap(long, value_delimiter = ',')]
+    sample_order: Option<Vec<SampleStage>>,
+    /// Drop whole oldest turns instead of tokens when the context overflows, keeping the first message.
+    #[clap(long)]
+    drop_oldest_turns: bool,
+
+    /// Huge pages used by CPU memory, maybe "transparent" or "explicit".
+    #[clap(long)]
+    huge_pages: Option<String>,
+    /// Lock weights and caches in CPU memory to avoid swapping.
+    #[clap(long)]
+    mlock: bool,
+    /// Verify the weight files against "SHA256SUMS" in the model directory before loading.
+    #[clap(long)]
+    verify_weights: bool,
+    /// Check the config, tensors and tokenizer of the model without loading it, then exit.
+    #[clap(long)]
+    dry_run: bool,
+    /// Profile time per layer and kernel, print a table on exit and write folded stacks for flamegraph to this file.
+    #[clap(long)]
+    profile: Option<String>,
+    /// Pre-transpose projection matrices and lm_head at load time, CPU only.
+    #[clap(long)]
+    pretranspose: bool,
+    /// Directory to cache pre-transposed weights, implies --pretranspose.
+    #[clap(long)]
+    weight_cache: Option<String>,
+    /// Compute only about this many candidate logits exactly by screening, CPU only, for greedy or low-temperature sampling.
+    #[clap(long)]
+    lm_head_top: Option<usize>,
+    /// Split lm_head by vocab into this many shards computed in parallel, keeping only the candidates sampling needs, CPU only.
+    #[clap(long)]
+    lm_head_shards: Option<usize>,
+    /// Multiply matrices row by row so results do not depend on batch composition, slower prefill and batched decoding, CPU only.
+    #[clap(long)]
+    batch_invariant: bool,
+    /// Accumulate f16 ops in f32, comma separated "rms_norm", "softmax", "attention" or "all", slower attention, CPU only.
+    #[clap(long)]
+    f32_accumulate: Option<String>,
+    /// Scale of the attention scores, overrides `attn_scale` in config.json, 1/sqrt(head_dim) by default, CPU only.
+    #[clap(long)]
+    attn_scale: Option<f32>,
+    /// Data type of the token embeddings, maybe "f16", "bf16" or "f32", CPU only.
+    #[clap(long)]
+    embed_dt: Option<String>,
+    /// Data type of lm_head, maybe "f16", "bf16" or "f32", CPU only.
+    #[clap(long)]
+    lm_head_dt: Option<String>,
+    /// Data type of the projection matrices, maybe "f8e4m3" or "f8e5m2" to store them in 8 bits, or "q8_0" or "q4_0" to quantize them in blocks, CPU only.
+    #[clap(long)]
+    matrix_dt: Option<String>,
+    /// LoRA adapter kept resident and selected by name per request, the format is "name=dir", repeatable, CPU only.
+    #[clap(long)]
+    adapter: Vec<String>,
+    /// Control vector selected by name per request, the format is "name=file.safetensors", repeatable, CPU only.
+    #[clap(long)]
+    steering: Vec<String>,
+    /// Soft prompt (prompt tuning embeddings) selected by name per request, the format is "name=file.safetensors", repeatable, CPU only.
+    #[clap(long)]
+    soft_prompt: Vec<String>,
 
     /// Select turbo hardware, the format is "ty:detail".
     #[clap(long)]
     turbo: Option<String>,
 }
 
-/// TODO 应该根据参数自动识别模型
-#[derive(PartialEq)]
-enum ModelType {
-    Llama,
-    Mixtral,
-}
-
 impl InferenceArgs {
     fn init_log(&self) {
         use log::LevelFilter;
@@ -115,6 +194,24 @@ impl InferenceArgs {
             .unwrap();
     }
 
+    fn init_memory(&self) {
+        let huge_pages = match self.huge_pages.as_deref().map(str::to_lowercase).as_deref() {
+            None | Some("off" | "none" | "disabled") => HugePages::Disabled,
+            Some("transparent" | "thp") => HugePages::Transparent,
+            Some("explicit" | "hugetlb") => HugePages::Explicit,
+            Some(other) => panic!("Unknown huge pages option: {other}"),
+        };
+        BlobOptions {
+            huge_pages,
+            mlock: self.mlock,
+        }
+        .set_global();
+    }
+
+    fn init_profile(&self) {
+        common::profiler::set_enabled(self.profile.is_some());
+    }
+
     fn turbo(&self) -> (&str, &str) {
         if let Some(turbo) = self.turbo.as_ref() {
             if let Some((ty, detail)) = turbo.split_once(':') {
@@ -127,25 +224,45 @@ impl InferenceArgs {
         }
     }
 
-    #[inline]
-    fn model_type(&self) -> ModelType {
-        if let Some(model_type) = self.model_type.as_ref() {
-            match model_type.to_lowercase().as_str() {
-                "llama" => ModelType::Llama,
-                "mixtral" => ModelType::Mixtral,
-                _ => panic!("Unsupported model type: {model_type}"),
-            }
-        } else {
-            ModelType::Llama
+    fn model_type(&self) -> Architecture {
+        match self.model_type.as_deref() {
+            Some(name) => Architecture::from_name(name)
+                .unwrap_or_else(|| panic!("Unsupported model type: {name}, {Supported}")),
+            None => Architecture::detect(&self.model).unwrap_or_else(|e| match e {
+                FileLoadError::Io(e) => panic!("{e}"),
+                FileLoadError::Json(e) => panic!("Invalid config.json: {e}"),
+            }),
         }
     }
 
     #[inline]
-    fn sample_args(&self) -> SampleArgs {
+    fn tokenizer(&self) -> Option<TokenizerFormat> {
+        self.tokenizer
+            .as_ref()
+            .map(|tokenizer| tokenizer.parse().unwrap_or_else(|e| panic!("{e}")))
+    }
+
+    #[inline]
+    fn sample_args(&self, default: SampleArgs) -> SampleArgs {
         SampleArgs {
-            temperature: self.temperature.unwrap_or(0.),
-            top_k: self.top_k.unwrap_or(usize::MAX),
-            top_p: self.top_p.unwrap_or(1.),
+            temperature: self.temperature.unwrap_or(default.temperature),
+            top_k: self.top_k.unwrap_or(default.top_k),
+            top_p: self.top_p.unwrap_or(default.top_p),
+            xtc_threshold: self.xtc_threshold.unwrap_or(default.xtc_threshold),
+            xtc_probability: self.xtc_probability.unwrap_or(default.xtc_probability),
+            order: self.sample_order.clone().unwrap_or(default.order),
+            ..default
+        }
+    }
+
+    #[inline]
+    fn truncation(&self) -> Truncation {
+        if self.drop_oldest_turns {
+            Truncation::DropOldestTurns {
+                preserve_first: true,
+            }
+        } else {
+            Truncation::Window
         }
     }
 }
@@ -167,6 +284,23 @@ trait Task: Sized {
     fn run(self) {
         // 初始化日志器
         self.inference().init_log();
+        // 校验权重文件
+        let args = self.inference();
+        if args.verify_weights {
+            assert!(
+                verify::verify(args.model.as_ref(), None),
+                "Weight files do not match the checksums"
+            );
+        }
+        // 只检查模型文件
+        if args.dry_run {
+            exit(if dry_run::dry_run(args) { 0 } else { 1 });
+        }
+        // 设置内存分配选项
+        self.inference().init_memory();
+        // 启用性能分析
+        self.inference().init_profile();
+        let profile = self.inference().profile.clone();
         // 启动 tokio 运行时
         let runtime = tokio::runtime::Runtime::new().unwrap();
         // 如果感知到 cuda 环境则初始化
@@ -182,10 +316,29 @@ trait Task: Sized {
 
         let (turbo, _detail) = self.inference().turbo();
         match self.inference().model_type() {
-            ModelType::Llama => match turbo.to_ascii_lowercase().as_str() {
+            Architecture::Llama => match turbo.to_ascii_lowercase().as_str() {
                 "" => {
-                    use llama_cpu::Transformer as M;
-                    runtime.block_on(self.typed::<M>(()));
+                    use llama_cpu::{ModelLoadMeta, Transformer as M};
+                    let args = self.inference();
+                    let meta = ModelLoadMeta {
+                        pretranspose: args.pretranspose || args.weight_cache.is_some(),
+                        cache_dir: args.weight_cache.clone().map(Into::into),
+                        lm_head_top: args.lm_head_top,
+                        lm_head_shards: args.lm_head_shards.unwrap_or(0),
+                        embed_dt: args.embed_dt.as_deref().map(cast::parse_dt),
+                        lm_head_dt: args.lm_head_dt.as_deref().map(cast::parse_dt),
+                        matrix_dt: args.matrix_dt.as_deref().map(cast::parse_dt),
+                        adapters: args.adapter.iter().map(|s| named_path(s)).collect(),
+                        steering: args.steering.iter().map(|s| named_path(s)).collect(),
+                        soft_prompts: args.soft_prompt.iter().map(|s| named_path(s)).collect(),
+                        batch_invariant: args.batch_invariant,
+                        accumulation: args
+                            .f32_accumulate
+                            .as_deref()
+                            .map_or_else(Default::default, parse_accumulation),
+                        attn_scale: args.attn_scale,
+                    };
+                    runtime.block_on(self.typed::<M>(meta));
                 }
                 #[cfg(detected_cuda)]
                 "nv" | "nvidia" => match &*_detail
@@ -224,7 +377,7 @@ trait Task: Sized {
                 },
                 _ => panic!("Turbo environment not detected"),
             },
-            ModelType::Mixtral => {
+            Architecture::Mixtral => {
                 use mixtral_cpu::MixtralCPU as M;
                 runtime.block_on(self.typed::<M>(()));
             }
@@ -242,6 +395,43 @@ trait Task: Sized {
         }
         // 关闭 tokio 运行时
         runtime.shutdown_background();
+        // 输出性能分析结果
+        if let Some(path) = profile {
+            dump_profile(&path);
+        }
+    }
+}
+
+/// 解析形如 `name=path` 的参数。
+fn named_path(s: &str) -> (String, PathBuf) {
+    let (name, path) = s
+        .split_once('=')
+        .unwrap_or_else(|| panic!("expected name=path, got {s}"));
+    (name.into(), path.into())
+}
+
+/// 解析以逗号分隔的以 f32 累加的算子。
+fn parse_accumulation(s: &str) -> Accumulation {
+    let mut ans = Accumulation::default();
+    for op in s.split(',').map(str::trim) {
+        match op {
+            "all" => ans = Accumulation::ALL,
+            "rms_norm" => ans.rms_norm = true,
+            "softmax" => ans.softmax = true,
+            "attention" => ans.attention = true,
+            op => panic!("Unknown op to accumulate in f32: \"{op}\""),
+        }
+    }
+    ans
+}
+
+/// 打印性能分析表格，并将折叠栈写入 `path`。
+fn dump_profile(path: &str) {
+    let profile = common::profiler::take();
+    println!();
+    print!("{profile}");
+    if let Err(e) = std::fs::write(path, profile.folded()) {
+        log::error!("Failed to write profile to {path}: {e}");
     }
 }
 
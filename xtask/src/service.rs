@@ -1,19 +1,108 @@
-﻿use crate::{InferenceArgs, Task};
+use crate::{InferenceArgs, Task};
 use causal_lm::CausalLM;
-use service::Service;
-use std::fmt::Debug;
-use web_api::start_infer_service;
+use service::{FairShare, Prompts, Service, SharedPrefix, ShortestFirst};
+use std::{fmt::Debug, sync::Arc, time::Duration};
+use web_api::{
+    start_infer_service, AuditLog, Checkpoint, Limits, Listen, Presets, ServiceOptions, Shadow,
+    Webhook, Webhooks,
+};
 
 #[derive(Args, Default)]
 pub struct ServiceArgs {
     #[clap(flatten)]
     pub inference: InferenceArgs,
-    /// Port to bind the service to
+    /// Port to bind the service to on all IPv4 addresses.
     #[clap(short, long)]
-    pub port: u16,
+    pub port: Option<u16>,
+    /// More addresses to bind the service to, such as "[::]:8000" or "unix:/run/infer.sock", repeatable.
+    #[clap(long)]
+    pub listen: Vec<Listen>,
     /// Maximum number of sessions to cache in memory.
     #[clap(long)]
     pub max_cache: Option<usize>,
+    /// Append a JSONL audit record of every inference request to this file.
+    #[clap(long)]
+    pub audit_log: Option<String>,
+    /// Number of idle anonymous sessions kept to reuse conversation prefixes, 0 to disable.
+    #[clap(long, default_value_t = 0)]
+    pub prefix_cache: usize,
+    /// Summarize the older half of a session once it grows beyond this many tokens.
+    #[clap(long)]
+    pub summarize_after: Option<usize>,
+    /// Replace a builtin prompt used to summarize sessions or ground documents, such as "summarize=Summarize the dialog above.", repeatable.
+    ///
+    /// Keys are "summarize", "summary-prefix", "summary-reply", "document-prefix" and "document-reply".
+    #[clap(long)]
+    pub prompt: Vec<String>,
+    /// A `metadata` value whose usage is reported separately by `GET /throughput`, repeatable; other values are grouped as "other".
+    #[clap(long)]
+    pub metric_label: Vec<String>,
+    /// Maximum bytes of KV cache used by one session, older context is dropped beyond it.
+    #[clap(long)]
+    pub session_cache_budget: Option<usize>,
+    /// Maximum bytes of KV cache allocated by all sessions, idle sessions are evicted beyond it.
+    #[clap(long)]
+    pub cache_budget: Option<usize>,
+    /// Duplicate a fraction of stateless requests to the model in this directory, loaded on CPU, and log the differences.
+    #[clap(long)]
+    pub shadow_model: Option<String>,
+    /// Fraction of stateless requests duplicated to the shadow model.
+    #[clap(long, default_value_t = 0.1)]
+    pub shadow_fraction: f64,
+    /// JSON file mapping preset names to generation parameters, added to the builtin presets.
+    #[clap(long)]
+    pub presets: Option<String>,
+    /// Milliseconds to wait for concurrently arriving requests when idle, so that they are prefilled in one batch.
+    #[clap(long)]
+    pub prefill_window_ms: Option<u64>,
+    /// Scheduling strategy of batched steps, maybe "fcfs", "shortest-first", "fair-share" or "shared-prefix".
+    #[clap(long)]
+    pub scheduler: Option<String>,
+    /// Maximum number of tasks in one batched step, the scheduler defers the rest.
+    #[clap(long)]
+    pub max_batch: Option<usize>,
+    /// Maximum number of decoding tasks in one batched step, prefilling tasks are not limited.
+    #[clap(long)]
+    pub max_decode_batch: Option<usize>,
+    /// Minimum milliseconds between the starts of two batched steps, trading latency for throughput.
+    #[clap(long)]
+    pub step_pacing_ms: Option<u64>,
+    /// Maximum bytes of a request body.
+    #[clap(long)]
+    pub max_body_bytes: Option<usize>,
+    /// Maximum number of messages in one inference request.
+    #[clap(long)]
+    pub max_messages: Option<usize>,
+    /// Maximum total characters of the messages in one inference request.
+    #[clap(long)]
+    pub max_chars: Option<usize>,
+    /// Maximum total tokens of the messages in one inference request.
+    #[clap(long)]
+    pub max_prompt_tokens: Option<usize>,
+    /// Tokens of the context reserved for system prompts and generation, rejecting longer prompts at admission.
+    #[clap(long)]
+    pub reserved_tokens: Option<usize>,
+    /// Maximum pieces of generated text buffered for one stream, generation pauses while the client lags behind.
+    #[clap(long)]
+    pub stream_buffer: Option<usize>,
+    /// HTTP url to post session creation, completion finish and error events to, with usage.
+    #[clap(long)]
+    pub webhook: Option<Webhook>,
+    /// Post the events of requests from one user to another url instead, such as "alice=http://billing:8080/alice", repeatable.
+    #[clap(long)]
+    pub user_webhook: Vec<String>,
+    /// Checkpoint the dialog positions of named sessions to this file, so that clients can re-prefill them after a crash.
+    #[clap(long)]
+    pub checkpoint: Option<String>,
+    /// Soft limit of the resident memory in bytes, idle sessions are evicted and new sessions rejected beyond it.
+    #[clap(long)]
+    pub memory_limit: Option<usize>,
+    /// Answer this prompt before serving and exit if the answer is empty, garbled or doesn't end, catching tokenizer mismatches.
+    #[clap(long)]
+    pub self_test: Option<String>,
+    /// Maximum tokens of the self-test answer.
+    #[clap(long, default_value_t = 64)]
+    pub self_test_tokens: usize,
 }
 
 impl Task for ServiceArgs {
@@ -27,10 +116,112 @@ impl Task for ServiceArgs {
         M::Storage: Send,
         M::Error: Debug,
     {
-        let (mut service, _handle) = Service::<M>::load(&self.inference.model, meta);
-        service.default_sample = self.inference.sample_args();
-        start_infer_service(service, self.port, self.max_cache.filter(|&c| c < 256))
-            .await
-            .unwrap();
+        let (mut service, _handle) = Service::<M>::load_with_tokenizer(
+            &self.inference.model,
+            meta,
+            self.inference.tokenizer(),
+        );
+        service.default_sample = self.inference.sample_args(service.default_sample.clone());
+        service.default_truncation = self.inference.truncation();
+        service.default_cache_budget = self.session_cache_budget;
+        if !self.prompt.is_empty() {
+            let mut prompts = Prompts::default();
+            for arg in &self.prompt {
+                let (key, text) = arg
+                    .split_once('=')
+                    .unwrap_or_else(|| panic!("Invalid prompt: {arg}"));
+                let slot = match key {
+                    "summarize" => &mut prompts.summarize,
+                    "summary-prefix" => &mut prompts.summary_prefix,
+                    "summary-reply" => &mut prompts.summary_reply,
+                    "document-prefix" => &mut prompts.document_prefix,
+                    "document-reply" => &mut prompts.document_reply,
+                    _ => panic!("Unknown prompt: {key}"),
+                };
+                *slot = text.into();
+            }
+            service.default_prompts = Arc::new(prompts);
+        }
+        if let Some(ms) = self.prefill_window_ms {
+            service.set_prefill_window(Duration::from_millis(ms));
+        }
+        match self.scheduler.as_deref() {
+            None | Some("fcfs") => {}
+            Some("shortest-first") => service.set_scheduler(Arc::new(ShortestFirst)),
+            Some("fair-share") => service.set_scheduler(Arc::new(FairShare)),
+            Some("shared-prefix") => service.set_scheduler(Arc::new(SharedPrefix)),
+            Some(other) => panic!("Unknown scheduler: {other}"),
+        }
+        if let Some(max_batch) = self.max_batch {
+            service.set_max_batch(max_batch);
+        }
+        if let Some(max_decode) = self.max_decode_batch {
+            service.set_max_decode_batch(max_decode);
+        }
+        if let Some(ms) = self.step_pacing_ms {
+            service.set_step_pacing(Duration::from_millis(ms));
+        }
+        // 自检通过之前不绑定地址，就绪检查失败
+        if let Some(prompt) = &self.self_test {
+            match service.self_test(prompt, self.self_test_tokens).await {
+                Ok(answer) => log::info!("Self test passed: {answer:?}"),
+                Err(e) => panic!("Self test failed: {e}"),
+            }
+        }
+        let audit = self
+            .audit_log
+            .map(|path| AuditLog::open(path).expect("Failed to open audit log"));
+        let presets = self.presets.map_or_else(Presets::builtin, |path| {
+            Presets::load(path).expect("Failed to load generation presets")
+        });
+        let shadow = self.shadow_model.map(|model| {
+            let (mut shadow, _handle) =
+                Service::<llama_cpu::Transformer>::load(model, Default::default());
+            shadow.default_sample = self.inference.sample_args(shadow.default_sample.clone());
+            shadow.default_truncation = self.inference.truncation();
+            Shadow::new(shadow, self.shadow_fraction)
+        });
+        let default = Limits::default();
+        let limits = Limits {
+            max_body_bytes: self.max_body_bytes.unwrap_or(default.max_body_bytes),
+            max_messages: self.max_messages.unwrap_or(default.max_messages),
+            max_chars: self.max_chars.unwrap_or(default.max_chars),
+            max_prompt_tokens: self.max_prompt_tokens,
+            reserved_tokens: self.reserved_tokens.unwrap_or(default.reserved_tokens),
+            stream_buffer: self.stream_buffer.unwrap_or(default.stream_buffer),
+        };
+        let webhooks = self.user_webhook.iter().fold(
+            self.webhook.map_or_else(Webhooks::default, Webhooks::new),
+            |webhooks, arg| {
+                let (user, url) = arg
+                    .split_once('=')
+                    .unwrap_or_else(|| panic!("Invalid user webhook: {arg}"));
+                webhooks.with_user(user, url.parse().unwrap())
+            },
+        );
+        let checkpoint = self
+            .checkpoint
+            .map(|path| Checkpoint::open(path).expect("Failed to open checkpoint"));
+        let mut listen = self.listen;
+        listen.extend(self.port.map(Listen::from));
+        assert!(
+            !listen.is_empty(),
+            "No address to bind, use --port or --listen"
+        );
+        let options = ServiceOptions {
+            session_capacity: self.max_cache.filter(|&c| c < 256),
+            audit,
+            prefix_cache: self.prefix_cache,
+            summarize_after: self.summarize_after,
+            cache_budget: self.cache_budget,
+            shadow,
+            presets,
+            limits,
+            webhooks,
+            checkpoint,
+            memory_limit: self.memory_limit,
+            metric_labels: self.metric_label,
+        };
+        start_infer_service(service, listen, options).await.unwrap();
     }
 }
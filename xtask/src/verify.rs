@@ -0,0 +1,61 @@
+use common::checksum::{self, Status, MANIFEST};
+use std::{
+    path::{Path, PathBuf},
+    process::exit,
+    time::Instant,
+};
+
+#[derive(Args, Default)]
+pub(crate) struct VerifyArgs {
+    /// Model directory.
+    #[clap(short, long)]
+    model: String,
+    /// Checksum manifest in `sha256sum` format, "SHA256SUMS" in the model directory by default.
+    #[clap(long)]
+    manifest: Option<String>,
+    /// Write the checksums of the weight files to the manifest instead of verifying.
+    #[clap(long)]
+    write: bool,
+}
+
+impl VerifyArgs {
+    pub fn run(self) {
+        let model_dir = PathBuf::from(self.model);
+        let manifest = self.manifest.map(PathBuf::from);
+        if self.write {
+            let path = manifest.unwrap_or_else(|| model_dir.join(MANIFEST));
+            let time = Instant::now();
+            checksum::write_manifest(&model_dir, &path).unwrap();
+            println!("write {} ... {:?}", path.display(), time.elapsed());
+        } else if !verify(&model_dir, manifest.as_deref()) {
+            exit(1);
+        }
+    }
+}
+
+/// 按清单校验模型目录中的文件并打印结果，全部通过时返回 `true`。
+pub(crate) fn verify(model_dir: &Path, manifest: Option<&Path>) -> bool {
+    let time = Instant::now();
+    let report = checksum::verify(model_dir, manifest)
+        .unwrap_or_else(|e| panic!("Failed to verify checksums: {e:?}"));
+    let mut ok = true;
+    for (name, status) in &report {
+        match status {
+            Status::Ok => println!("{name}: OK"),
+            Status::Mismatch { expected, actual } => {
+                ok = false;
+                println!("{name}: FAILED, expected {expected}, got {actual}")
+            }
+            Status::Missing => {
+                ok = false;
+                println!("{name}: MISSING")
+            }
+            Status::Unlisted => {
+                ok = false;
+                println!("{name}: NOT IN MANIFEST")
+            }
+        }
+    }
+    println!("verify {} files ... {:?}", report.len(), time.elapsed());
+    ok
+}
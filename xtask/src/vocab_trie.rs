@@ -0,0 +1,27 @@
+use std::{path::PathBuf, time::Instant};
+use tokenizer::{Tokenizer, VocabTxt};
+
+#[derive(Args, Default)]
+pub(crate) struct VocabTrieArgs {
+    /// Model directory containing `vocabs.txt`.
+    #[clap(short, long)]
+    model: String,
+}
+
+impl VocabTrieArgs {
+    pub fn run(self) {
+        let model_dir = PathBuf::from(self.model);
+
+        let time = Instant::now();
+        let vocab = VocabTxt::from_txt_file(model_dir.join("vocabs.txt")).unwrap();
+        println!(
+            "build trie of {} pieces ... {:?}",
+            vocab.vocab_size(),
+            time.elapsed()
+        );
+
+        let time = Instant::now();
+        vocab.save_trie(model_dir.join("vocabs.trie")).unwrap();
+        println!("save vocabs.trie ... {:?}", time.elapsed());
+    }
+}